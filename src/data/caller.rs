@@ -0,0 +1,63 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{cell::RefCell, collections::HashMap};
+
+/// Frames beyond this depth aren't walked, which bounds the cost for deep stacks.
+const MAX_DEPTH: usize = 64;
+
+/// Functions of these paths create spans on behalf of their caller, which is what they're
+/// attributed to: `tracing` and the layers themselves, the standard library, and the wrappers of
+/// this crate.
+const SKIPPED: [&str; 10] = [
+    "backtrace::",
+    "std::",
+    "core::",
+    "alloc::",
+    "tracing::",
+    "tracing_core::",
+    "tracing_subscriber::",
+    "tracing_profile::data::caller",
+    "tracing_profile::layers::graph::Layer",
+    "tracing_profile::iter::",
+];
+
+thread_local! {
+    /// The symbolicated functions of every instruction pointer seen on the thread, innermost
+    /// inlined function first. Symbolicating is the expensive part, so it is done once per
+    /// call site.
+    static FUNCTIONS: RefCell<HashMap<usize, Vec<String>>> = RefCell::new(HashMap::new());
+}
+
+/// The function which created the current span: the innermost function on the stack which isn't
+/// part of `tracing`, the standard library or this crate. `None` if there is none within
+/// `MAX_DEPTH` frames or the binary has no symbols.
+pub fn caller() -> Option<String> {
+    let mut ips = Vec::with_capacity(MAX_DEPTH);
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as usize);
+        ips.len() < MAX_DEPTH
+    });
+    FUNCTIONS.with(|functions| {
+        let mut functions = functions.borrow_mut();
+        ips.into_iter().find_map(|ip| {
+            let names = functions.entry(ip).or_insert_with(|| symbolicate(ip));
+            names.iter().find(|name| !is_skipped(name)).cloned()
+        })
+    })
+}
+
+fn symbolicate(ip: usize) -> Vec<String> {
+    let mut names = vec![];
+    backtrace::resolve(ip as *mut std::ffi::c_void, |symbol| {
+        if let Some(name) = symbol.name() {
+            // without the hash suffix
+            names.push(format!("{name:#}"));
+        }
+    });
+    names
+}
+
+fn is_skipped(name: &str) -> bool {
+    // trait methods are named `<Type as Trait>::method`
+    let name = name.trim_start_matches('<');
+    SKIPPED.iter().any(|prefix| name.starts_with(prefix))
+}
@@ -0,0 +1,107 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::time::{Duration, Instant};
+
+/// The clock `TimingLayer` measures spans with.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum ClockSource {
+    /// `std::time::Instant`, the monotonic clock of the OS.
+    #[default]
+    Instant,
+    /// The time stamp counter of the CPU, read with `rdtsc`, which is cheaper to read and finer
+    /// grained than `Instant`. `SpanTimings` report the counted cycles, and durations converted
+    /// with the frequency measured by `Tsc::calibrate`.
+    ///
+    /// The counter must be invariant, i.e. tick at a constant rate in all power states and be
+    /// synchronized between cores, which holds for x86 CPUs of the last decade.
+    #[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+    Tsc(Tsc),
+}
+
+impl ClockSource {
+    /// Measures the frequency of the time stamp counter, see `Tsc::calibrate`.
+    #[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+    pub fn tsc() -> Self {
+        Self::Tsc(Tsc::calibrate())
+    }
+
+    /// The cycle counter, 0 for clocks without one.
+    pub(crate) fn cycles(&self) -> u64 {
+        match self {
+            Self::Instant => 0,
+            #[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+            Self::Tsc(_) => Tsc::now(),
+        }
+    }
+
+    /// Advances `last` and `last_cycles` to now and returns the time and cycles since.
+    #[cfg_attr(
+        not(all(feature = "tsc", target_arch = "x86_64")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn advance(&self, last: &mut Instant, last_cycles: &mut u64) -> (Duration, u64) {
+        match self {
+            Self::Instant => {
+                let now = Instant::now();
+                let elapsed = now - *last;
+                *last = now;
+                (elapsed, 0)
+            }
+            #[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+            Self::Tsc(tsc) => {
+                let now = Tsc::now();
+                let cycles = now.saturating_sub(*last_cycles);
+                *last_cycles = now;
+                (tsc.duration(cycles), cycles)
+            }
+        }
+    }
+}
+
+/// The time stamp counter of x86 CPUs and its measured frequency.
+#[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tsc {
+    cycles_per_ns: f64,
+}
+
+#[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+impl Tsc {
+    /// How long the counter is compared to `Instant` by `calibrate`.
+    const CALIBRATION: Duration = Duration::from_millis(10);
+
+    /// Measures the frequency of the counter by comparing it to `Instant` while spinning for
+    /// 10ms, which is accurate to well below a percent. If the thread migrates to a core whose
+    /// counter is behind, the measurement is repeated, and after a few failures 1 GHz is assumed.
+    pub fn calibrate() -> Self {
+        let cycles_per_ns = (0..3)
+            .map(|_| {
+                let start = Instant::now();
+                let start_cycles = Self::now();
+                while start.elapsed() < Self::CALIBRATION {
+                    std::hint::spin_loop();
+                }
+                let cycles = Self::now().saturating_sub(start_cycles);
+                cycles as f64 / start.elapsed().as_nanos() as f64
+            })
+            .find(|&rate| rate > 0.0)
+            .unwrap_or(1.0);
+        Self { cycles_per_ns }
+    }
+
+    /// Reads the counter.
+    pub fn now() -> u64 {
+        // SAFETY: `rdtsc` is available on every x86_64 CPU.
+        unsafe { std::arch::x86_64::_rdtsc() }
+    }
+
+    /// The measured frequency in cycles per nanosecond, i.e. GHz.
+    pub fn cycles_per_ns(&self) -> f64 {
+        self.cycles_per_ns
+    }
+
+    /// Converts cycles to time using the measured frequency.
+    pub fn duration(&self, cycles: u64) -> Duration {
+        Duration::from_nanos((cycles as f64 / self.cycles_per_ns) as u64)
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::BTreeMap, time::Duration};
+
+/// A span that has been closed, as passed to the `CallbackLayer` callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosedSpan {
+    pub id: u64,
+    /// `None` for root spans.
+    pub parent_id: Option<u64>,
+    pub name: &'static str,
+    /// Total time the span was entered, summed over all of its enters.
+    pub duration: Duration,
+    /// Number of spans on the stack including this one, i.e. 1 for root spans.
+    pub depth: u64,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl ClosedSpan {
+    /// Serializes the span as a single line of JSON, e.g.
+    /// `{"id":2,"parent_id":1,"name":"child","duration_ns":3194,"depth":2,"fields":{"a":"b"}}`.
+    /// `parent_id` is `null` for root spans.
+    pub(crate) fn to_json(&self) -> String {
+        let mut json = format!("{{\"id\":{},\"parent_id\":", self.id);
+        match self.parent_id {
+            Some(parent_id) => json.push_str(&parent_id.to_string()),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"name\":");
+        push_json_string(&mut json, self.name);
+        json.push_str(&format!(
+            ",\"duration_ns\":{},\"depth\":{},\"fields\":{{",
+            self.duration.as_nanos(),
+            self.depth
+        ));
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&mut json, key);
+            json.push(':');
+            push_json_string(&mut json, value);
+        }
+        json.push_str("}}");
+        json
+    }
+}
+
+pub(crate) fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
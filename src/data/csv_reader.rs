@@ -0,0 +1,573 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::Path,
+};
+
+/// A single row of the file written by `CsvLayer`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize, serde::Deserialize))]
+pub struct CsvRow {
+    /// Only present if the layer was configured to write it.
+    pub seq: Option<u64>,
+    /// `B` or `E` if the layer was configured to write separate begin and end rows.
+    pub phase: Option<String>,
+    pub id: u64,
+    pub parent_id: u64,
+    pub elapsed_ns: u64,
+    pub start_ns: u64,
+    pub end_ns: u64,
+    /// Only present if the layer was configured to write it.
+    pub start_rfc3339: Option<String>,
+    pub end_rfc3339: Option<String>,
+    /// Time between the creation of the span and its first enter, only present if the layer
+    /// was configured to write it.
+    pub schedule_latency_ns: Option<u64>,
+    pub thread_id: String,
+    pub thread_name: String,
+    pub span_name: String,
+    pub file_name: String,
+    pub call_depth: u64,
+    /// Resolved from the field dictionary if the file was written with one, see
+    /// `CsvReader::with_field_dictionary`.
+    pub fields: BTreeMap<String, String>,
+    /// The id of the fields in the field dictionary, only present if the layer was configured
+    /// to write one.
+    pub field_set: Option<u64>,
+    /// Peak RSS of the process in bytes, only present for root spans if the layer was
+    /// configured to sample it.
+    pub peak_rss: Option<u64>,
+    pub allocations: Option<u64>,
+}
+
+/// Streams rows from a file written by `CsvLayer` with `RowFormat::Binary`.
+///
+/// ```no_run
+/// use tracing_profile::BinaryReader;
+///
+/// for row in BinaryReader::open("/tmp/output.bin").unwrap() {
+///     let row = row.unwrap();
+///     println!("{} took {}ns", row.span_name, row.elapsed_ns);
+/// }
+/// ```
+#[cfg(feature = "binary")]
+pub struct BinaryReader<R> {
+    reader: R,
+}
+
+#[cfg(feature = "binary")]
+impl BinaryReader<BufReader<File>> {
+    pub fn open<T: AsRef<Path>>(path: T) -> std::io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+#[cfg(feature = "binary")]
+impl<R: std::io::Read> BinaryReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    fn read_row(&mut self) -> std::io::Result<Option<CsvRow>> {
+        use std::io::Read;
+
+        let mut len = [0; 4];
+        // the end of the file is only expected between records
+        if self.reader.read(&mut len[..1])? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut len[1..])?;
+        // don't trust the length of a corrupt file with the allocation
+        let len = u32::from_le_bytes(len) as usize;
+        let mut record = vec![];
+        (&mut self.reader)
+            .take(len as u64)
+            .read_to_end(&mut record)?;
+        if record.len() < len {
+            return Err(invalid_data(&format!(
+                "truncated record: {} of {len} bytes",
+                record.len()
+            )));
+        }
+        postcard::from_bytes(&record)
+            .map(Some)
+            .map_err(|e| invalid_data(&format!("invalid record: {e}")))
+    }
+}
+
+#[cfg(feature = "binary")]
+impl<R: std::io::Read> Iterator for BinaryReader<R> {
+    type Item = std::io::Result<CsvRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_row().transpose()
+    }
+}
+
+/// A span read back from the CSV file along with all of its children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvTreeNode {
+    pub row: CsvRow,
+    pub children: Vec<CsvTreeNode>,
+}
+
+/// Streams rows from a file written by `CsvLayer`.
+///
+/// ```no_run
+/// use tracing_profile::CsvReader;
+///
+/// for row in CsvReader::open("/tmp/output.csv").unwrap() {
+///     let row = row.unwrap();
+///     println!("{} took {}ns", row.span_name, row.elapsed_ns);
+/// }
+/// ```
+pub struct CsvReader<R> {
+    reader: R,
+    header: Option<HashMap<String, usize>>,
+    delimiter: char,
+    /// the field maps of the `field_set` ids
+    field_sets: HashMap<u64, BTreeMap<String, String>>,
+}
+
+impl CsvReader<BufReader<File>> {
+    pub fn open<T: AsRef<Path>>(path: T) -> std::io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            header: None,
+            delimiter: ',',
+            field_sets: HashMap::new(),
+        }
+    }
+
+    /// Reads the `field_dictionary` written by the layer along with the file, so the `fields`
+    /// of the rows hold the field maps their `field_set` refers to. Set the delimiter first.
+    pub fn with_field_dictionary<T: AsRef<Path>>(mut self, path: T) -> std::io::Result<Self> {
+        let mut dictionary =
+            CsvReader::new(BufReader::new(File::open(path)?)).with_delimiter(self.delimiter);
+        // the header
+        dictionary.read_record()?;
+        while let Some(cells) = dictionary.read_record()? {
+            let [id, metadata] = cells.as_slice() else {
+                return Err(invalid_data(&format!("invalid field set: {cells:?}")));
+            };
+            let id = id
+                .parse()
+                .map_err(|_| invalid_data(&format!("invalid field set id: {id}")))?;
+            self.field_sets.insert(id, parse_fields(metadata)?);
+        }
+        Ok(self)
+    }
+
+    /// Reads a file written with another `CsvConfig::delimiter`.
+    pub fn with_delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Consumes the reader, yielding each root span as soon as it (and therefore all of its
+    /// children, which are written first) has been read. Begin rows are skipped. Spans whose
+    /// parent row never arrives, e.g. because the file was cut off before the parent closed, are
+    /// yielded at the end of the file as roots with their nonzero `parent_id`.
+    pub fn trees(self) -> CsvTrees<R> {
+        CsvTrees {
+            reader: self,
+            pending: HashMap::new(),
+            orphans: VecDeque::new(),
+        }
+    }
+
+    /// Reads one record, which may span several lines if a quoted cell contains a newline.
+    fn read_record(&mut self) -> std::io::Result<Option<Vec<String>>> {
+        let mut line = String::new();
+        loop {
+            if self.reader.read_line(&mut line)? == 0 {
+                return if line.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(invalid_data("unterminated quoted cell"))
+                };
+            }
+            if line.chars().filter(|c| *c == '"').count() % 2 == 0 {
+                break;
+            }
+        }
+
+        let line = line.trim_end_matches(['\n', '\r']);
+        Ok(Some(split_record(line, self.delimiter)))
+    }
+
+    fn parse_header(header: Vec<String>) -> HashMap<String, usize> {
+        header
+            .into_iter()
+            .enumerate()
+            // regardless of `CsvConfig::header_case`
+            .map(|(i, name)| (name.to_lowercase(), i))
+            .collect()
+    }
+
+    fn parse_row(header: &HashMap<String, usize>, cells: &[String]) -> std::io::Result<CsvRow> {
+        let cell = |name: &str| {
+            header
+                .get(name)
+                .and_then(|i| cells.get(*i))
+                .map(String::as_str)
+                .unwrap_or_default()
+        };
+        let number = |name: &str| -> std::io::Result<u64> {
+            let value = cell(name);
+            if value.is_empty() {
+                return Ok(0);
+            }
+            value
+                .parse()
+                .map_err(|_| invalid_data(&format!("invalid {name}: {value}")))
+        };
+
+        let optional = |name: &str| -> std::io::Result<Option<u64>> {
+            match cell(name) {
+                "" => Ok(None),
+                _ => number(name).map(Some),
+            }
+        };
+
+        Ok(CsvRow {
+            seq: if header.contains_key("seq") {
+                Some(number("seq")?)
+            } else {
+                None
+            },
+            phase: header
+                .contains_key("phase")
+                .then(|| cell("phase").to_string()),
+            id: number("id")?,
+            parent_id: number("parent_id")?,
+            elapsed_ns: number("elapsed_ns")?,
+            start_ns: number("start_ns")?,
+            end_ns: number("end_ns")?,
+            start_rfc3339: header
+                .contains_key("start_rfc3339")
+                .then(|| cell("start_rfc3339").to_string()),
+            end_rfc3339: header
+                .contains_key("end_rfc3339")
+                .then(|| cell("end_rfc3339").to_string()),
+            schedule_latency_ns: optional("schedule_latency_ns")?,
+            thread_id: cell("thread_id").into(),
+            thread_name: cell("thread_name").into(),
+            span_name: cell("span_name").into(),
+            file_name: cell("file_name").into(),
+            call_depth: number("call_depth")?,
+            fields: match header.contains_key("metadata") {
+                true => parse_fields(cell("metadata"))?,
+                false => BTreeMap::new(),
+            },
+            field_set: optional("field_set")?,
+            peak_rss: optional("peak_rss")?,
+            allocations: optional("allocations")?,
+        })
+    }
+}
+
+impl<R: BufRead + Seek> CsvReader<R> {
+    /// Continues reading at the row starting at byte `offset`, e.g. from `CsvIndex::offset`.
+    pub fn seek(&mut self, offset: u64) -> std::io::Result<()> {
+        if self.header.is_none() {
+            let header = self
+                .read_record()?
+                .ok_or_else(|| invalid_data("missing header"))?;
+            self.header = Some(Self::parse_header(header));
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for CsvReader<R> {
+    type Item = std::io::Result<CsvRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.header.is_none() {
+            let header = match self.read_record() {
+                Ok(header) => header?,
+                Err(e) => return Some(Err(e)),
+            };
+            self.header = Some(Self::parse_header(header));
+        }
+
+        let cells = match self.read_record() {
+            Ok(cells) => cells?,
+            Err(e) => return Some(Err(e)),
+        };
+        let header = self.header.as_ref().expect("header was read above");
+        let mut row = Self::parse_row(header, &cells);
+        if let Ok(row) = &mut row {
+            if let Some(fields) = row.field_set.and_then(|id| self.field_sets.get(&id)) {
+                row.fields = fields.clone();
+            }
+        }
+        Some(row)
+    }
+}
+
+/// The byte offsets of the rows of every span, read from the `index_file` written by `CsvLayer`.
+///
+/// ```no_run
+/// use tracing_profile::{CsvIndex, CsvReader};
+///
+/// let index = CsvIndex::open("/tmp/output.csv.idx").unwrap();
+/// let mut reader = CsvReader::open("/tmp/output.csv").unwrap();
+/// reader.seek(index.offset(42).unwrap()).unwrap();
+/// let row = reader.next().unwrap().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CsvIndex {
+    offsets: HashMap<u64, u64>,
+}
+
+impl CsvIndex {
+    pub fn open<T: AsRef<Path>>(path: T) -> std::io::Result<Self> {
+        let mut offsets = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines().skip(1) {
+            let line = line?;
+            let parsed = line
+                .split_once(',')
+                .and_then(|(id, offset)| Some((id.parse().ok()?, offset.parse().ok()?)));
+            let Some((id, offset)) = parsed else {
+                return Err(invalid_data(&format!("invalid index entry: {line}")));
+            };
+            offsets.insert(id, offset);
+        }
+        Ok(Self { offsets })
+    }
+
+    /// Returns the offset of the last row of the span, i.e. its end row if the file has phases.
+    /// The registry reuses the ids of closed spans, in which case this is the last span with
+    /// the id.
+    pub fn offset(&self, id: u64) -> Option<u64> {
+        self.offsets.get(&id).copied()
+    }
+}
+
+/// Iterator over complete root spans, see [`CsvReader::trees`].
+pub struct CsvTrees<R> {
+    reader: CsvReader<R>,
+    pending: HashMap<u64, Vec<CsvTreeNode>>,
+    /// the pending spans once the end of the file is reached
+    orphans: VecDeque<CsvTreeNode>,
+}
+
+impl<R: BufRead> Iterator for CsvTrees<R> {
+    type Item = std::io::Result<CsvTreeNode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for row in self.reader.by_ref() {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => return Some(Err(e)),
+            };
+            // the matching end row carries the complete span
+            if row.phase.as_deref() == Some("B") {
+                continue;
+            }
+            let node = CsvTreeNode {
+                children: self.pending.remove(&row.id).unwrap_or_default(),
+                row,
+            };
+            if node.row.parent_id == 0 {
+                return Some(Ok(node));
+            }
+            self.pending
+                .entry(node.row.parent_id)
+                .or_default()
+                .push(node);
+        }
+        let mut orphans: Vec<_> = self.pending.drain().collect();
+        orphans.sort_by_key(|(parent_id, _)| *parent_id);
+        self.orphans
+            .extend(orphans.into_iter().flat_map(|(_, nodes)| nodes));
+        self.orphans.pop_front().map(Ok)
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Splits a record into cells, handling RFC 4180 quoting.
+fn split_record(line: &str, delimiter: char) -> Vec<String> {
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                cell.push('"');
+                chars.next();
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if cell.is_empty() => in_quotes = true,
+            c if c == delimiter && !in_quotes => cells.push(std::mem::take(&mut cell)),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell);
+    cells
+}
+
+/// Parses the `{"key":"value"; "count":42; ...}` metadata column.
+fn parse_fields(cell: &str) -> std::io::Result<BTreeMap<String, String>> {
+    let invalid = || invalid_data(&format!("invalid metadata: {cell}"));
+    let mut fields = BTreeMap::new();
+    let Some(mut rest) = cell.strip_prefix('{').and_then(|x| x.strip_suffix('}')) else {
+        return Err(invalid());
+    };
+    while !rest.is_empty() {
+        let (key, value) = parse_json_string(rest).ok_or_else(invalid)?;
+        let value = value.strip_prefix(':').ok_or_else(invalid)?;
+        let (value, next) = match parse_json_string(value) {
+            Some(parsed) => parsed,
+            // numbers and booleans are unquoted
+            None => match value.find("; ") {
+                Some(end) => (value[..end].to_string(), &value[end..]),
+                None => (value.to_string(), ""),
+            },
+        };
+        fields.insert(key, value);
+        rest = match next.strip_prefix("; ") {
+            Some(next) => next,
+            None if next.is_empty() => next,
+            None => return Err(invalid()),
+        };
+    }
+    Ok(fields)
+}
+
+/// Parses the quoted and escaped string at the start of `s`, returning it unescaped with the
+/// rest of `s`.
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                'u' => {
+                    let hex: String = (0..4).filter_map(|_| chars.next()).map(|x| x.1).collect();
+                    string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::CsvLayer;
+
+    #[test]
+    fn read_back_layer_output() {
+        let path = std::env::temp_dir().join("tracing_profile_csv_reader.csv");
+        let layer = CsvLayer::new(&path);
+        let flush = layer.flush_guard();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let root = debug_span!("root span");
+            let _root = root.enter();
+            let child = debug_span!("child span", field1 = "value1", field2 = 42);
+            let _child = child.enter();
+        });
+        // rows are written by a background thread
+        drop(flush);
+
+        let rows = CsvReader::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        let (child, root) = (&rows[0], &rows[1]);
+        assert_eq!(child.span_name, "child span");
+        assert_eq!(child.parent_id, root.id);
+        assert_eq!(child.call_depth, 2);
+        assert_eq!(child.elapsed_ns, child.end_ns - child.start_ns);
+        assert_eq!(
+            child.fields,
+            BTreeMap::from([
+                ("field1".to_string(), "value1".to_string()),
+                ("field2".to_string(), "42".to_string()),
+            ])
+        );
+        assert_eq!(root.span_name, "root span");
+        assert_eq!(root.parent_id, 0);
+        assert!(root.fields.is_empty());
+
+        let trees = CsvReader::open(&path)
+            .unwrap()
+            .trees()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            trees,
+            vec![CsvTreeNode {
+                row: root.clone(),
+                children: vec![CsvTreeNode {
+                    row: child.clone(),
+                    children: vec![],
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn orphans_at_end_of_file() {
+        let csv = "id,parent_id,span_name\n3,2,grandchild\n4,0,root\n5,1,child\n";
+        let trees = CsvReader::new(csv.as_bytes())
+            .trees()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let ids: Vec<_> = trees.iter().map(|tree| tree.row.id).collect();
+        assert_eq!(ids, [4, 5, 3]);
+    }
+
+    #[test]
+    fn quoted_cells() {
+        assert_eq!(
+            split_record(r#"1,"a,b""c",{}"#, ','),
+            vec!["1".to_string(), "a,b\"c".to_string(), "{}".to_string()]
+        );
+    }
+    #[test]
+    fn escaped_fields() {
+        let fields = BTreeMap::from([
+            ("path".to_string(), r#"C:\dir\"a"; "b":1"#.to_string()),
+            ("lines".to_string(), "one\ntwo\u{1}".to_string()),
+        ]);
+        let cell = crate::layers::csv::format_fields(&fields);
+        assert_eq!(parse_fields(&cell).unwrap(), fields);
+        assert_eq!(
+            parse_fields(r#"{"count":42; "ok":true}"#).unwrap(),
+            BTreeMap::from([
+                ("count".to_string(), "42".to_string()),
+                ("ok".to_string(), "true".to_string()),
+            ])
+        );
+        assert!(parse_fields(r#"{"unterminated:1}"#).is_err());
+    }
+}
@@ -1,26 +1,108 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt::Write};
 
-pub struct FieldVisitor<'a>(pub &'a mut BTreeMap<String, String>);
+use super::closed_span::push_json_string;
 
-impl<'a> tracing::field::Visit for FieldVisitor<'a> {
+/// The value of a span field, with the type it was recorded with. `Debug` and `Display` values
+/// and errors are formatted into `Str`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl FieldValue {
+    /// The value as JSON: numbers and booleans are unquoted, strings and non-finite floats
+    /// are quoted and escaped.
+    pub(crate) fn to_json(&self) -> String {
+        let quoted = |x: &str| {
+            let mut json = String::new();
+            push_json_string(&mut json, x);
+            json
+        };
+        match self {
+            Self::Float(x) if !x.is_finite() => quoted(&x.to_string()),
+            Self::Str(x) => quoted(x),
+            x => x.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(x) => x.fmt(f),
+            Self::Uint(x) => x.fmt(f),
+            Self::Float(x) => x.fmt(f),
+            Self::Bool(x) => x.fmt(f),
+            Self::Str(x) => x.fmt(f),
+        }
+    }
+}
+
+impl From<FieldValue> for String {
+    fn from(value: FieldValue) -> Self {
+        match value {
+            FieldValue::Str(x) => x,
+            x => x.to_string(),
+        }
+    }
+}
+
+/// Records the fields of a span into a map of `FieldValue`s, or of strings for layers which
+/// only display them.
+pub struct FieldVisitor<'a, V = String> {
+    fields: &'a mut BTreeMap<String, V>,
+    max_len: Option<usize>,
+}
+
+impl<'a, V: From<FieldValue>> FieldVisitor<'a, V> {
+    pub fn new(fields: &'a mut BTreeMap<String, V>) -> Self {
+        Self::with_max_len(fields, None)
+    }
+
+    /// String values longer than `max_len` characters are truncated and end with `…`.
+    pub fn with_max_len(fields: &'a mut BTreeMap<String, V>, max_len: Option<usize>) -> Self {
+        Self { fields, max_len }
+    }
+
+    fn insert(&mut self, field: &tracing::field::Field, value: FieldValue) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn insert_str(&mut self, field: &tracing::field::Field, mut value: String) {
+        if let Some((end, _)) = self
+            .max_len
+            .and_then(|max_len| value.char_indices().nth(max_len))
+        {
+            value.truncate(end);
+            value.push('…');
+        }
+        self.insert(field, FieldValue::Str(value));
+    }
+}
+
+impl<V: From<FieldValue>> tracing::field::Visit for FieldVisitor<'_, V> {
     fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.0.insert(field.name().to_string(), value.to_string());
+        self.insert(field, FieldValue::Float(value));
     }
 
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.0.insert(field.name().to_string(), value.to_string());
+        self.insert(field, FieldValue::Int(value));
     }
 
     fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
-        self.0.insert(field.name().to_string(), value.to_string());
+        self.insert(field, FieldValue::Uint(value));
     }
 
     fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
-        self.0.insert(field.name().to_string(), value.to_string());
+        self.insert(field, FieldValue::Bool(value));
     }
 
     fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
-        self.0.insert(field.name().to_string(), value.to_string());
+        self.insert_str(field, value.to_string());
     }
 
     fn record_error(
@@ -28,11 +110,43 @@ impl<'a> tracing::field::Visit for FieldVisitor<'a> {
         field: &tracing::field::Field,
         value: &(dyn std::error::Error + 'static),
     ) {
-        self.0.insert(field.name().to_string(), value.to_string());
+        self.insert_str(field, value.to_string());
     }
 
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.0
-            .insert(field.name().to_string(), format!("{:?}", value));
+        let value = match self.max_len {
+            Some(max_len) => {
+                // one more character than fits is enough to know the value gets truncated
+                let mut writer = BoundedWriter {
+                    value: String::new(),
+                    remaining: max_len + 1,
+                };
+                // formatting is aborted with an error once the writer is full
+                let _ = write!(writer, "{:?}", value);
+                writer.value
+            }
+            None => format!("{:?}", value),
+        };
+        self.insert_str(field, value);
+    }
+}
+
+/// Stops formatting once `remaining` characters have been written, so huge `Debug` values which
+/// are truncated anyway are never formatted completely.
+struct BoundedWriter {
+    value: String,
+    remaining: usize,
+}
+
+impl Write for BoundedWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        for c in s.chars() {
+            if self.remaining == 0 {
+                return Err(std::fmt::Error);
+            }
+            self.value.push(c);
+            self.remaining -= 1;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,85 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::thread::ThreadId;
+
+/// Guards of the slices a span has open, innermost last.
+///
+/// A span can be entered again before it exits, e.g. by a recursive call or on another thread.
+/// Every exit ends the slice begun by the latest enter on the same thread, so nested slices stay
+/// balanced instead of the outer one being ended early.
+#[derive(Debug)]
+pub struct GuardStack<T> {
+    guards: Vec<(ThreadId, T)>,
+}
+
+impl<T> Default for GuardStack<T> {
+    fn default() -> Self {
+        Self { guards: vec![] }
+    }
+}
+
+impl<T> GuardStack<T> {
+    pub fn push(&mut self, guard: T) {
+        self.guards.push((std::thread::current().id(), guard));
+    }
+
+    /// Removes the latest guard pushed by the current thread.
+    pub fn pop(&mut self) -> Option<T> {
+        let thread = std::thread::current().id();
+        let i = self.guards.iter().rposition(|(x, _)| *x == thread)?;
+        Some(self.guards.remove(i).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Records the begin and end of a slice like `perfetto_sys::TraceEvent`.
+    struct Slice {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Slice {
+        fn new(name: &'static str, events: &Arc<Mutex<Vec<String>>>) -> Self {
+            events.lock().unwrap().push(format!("B {name}"));
+            Self {
+                name,
+                events: events.clone(),
+            }
+        }
+    }
+
+    impl Drop for Slice {
+        fn drop(&mut self) {
+            self.events.lock().unwrap().push(format!("E {}", self.name));
+        }
+    }
+
+    #[test]
+    fn reentered_span() {
+        let events = Arc::default();
+        let mut span = GuardStack::default();
+        let mut child = GuardStack::default();
+
+        // span entered, child entered and exited, span entered again and exited twice
+        span.push(Slice::new("outer", &events));
+        child.push(Slice::new("child", &events));
+        drop(child.pop());
+        span.push(Slice::new("inner", &events));
+        drop(span.pop());
+        std::thread::scope(|scope| {
+            // another thread can't end the slices of this one
+            scope.spawn(|| assert!(span.pop().is_none()));
+        });
+        drop(span.pop());
+        assert!(span.pop().is_none());
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            ["B outer", "B child", "E child", "B inner", "E inner", "E outer"]
+        );
+    }
+}
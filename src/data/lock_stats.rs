@@ -0,0 +1,65 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LockResult, Mutex, MutexGuard, TryLockError,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often the internal `Mutex` of a layer was contended and how long threads waited for it.
+///
+/// A large `waited` means the profiler itself serializes the threads and distorts the
+/// measurements.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockContention {
+    pub acquisitions: u64,
+    /// acquisitions which had to wait for another thread
+    pub contended: u64,
+    /// total time spent waiting
+    pub waited: Duration,
+}
+
+impl std::fmt::Display for LockContention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "profiler lock contended {}/{} times, waited {:.2?}",
+            self.contended, self.acquisitions, self.waited
+        )
+    }
+}
+
+/// Measures the contention of a `Mutex`. Uncontended acquisitions only cost a `try_lock`.
+#[derive(Debug, Default)]
+pub struct LockStats {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    waited_ns: AtomicU64,
+}
+
+impl LockStats {
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> LockResult<MutexGuard<'a, T>> {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match mutex.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(e)) => Err(e),
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let result = mutex.lock();
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.waited_ns
+                    .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                result
+            }
+        }
+    }
+
+    pub fn contention(&self) -> LockContention {
+        LockContention {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            waited: Duration::from_nanos(self.waited_ns.load(Ordering::Relaxed)),
+        }
+    }
+}
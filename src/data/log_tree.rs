@@ -13,6 +13,14 @@ impl std::fmt::Display for LogTree {
 }
 
 impl LogTree {
+    /// Width of the connectors in front of the labels at `depth`, where the root is at depth 0.
+    pub fn prefix_width(depth: usize) -> usize {
+        match depth {
+            0 => 0,
+            _ => 3 * (depth - 1) + 4,
+        }
+    }
+
     fn display_children(&self, f: &mut std::fmt::Formatter, spaces: Vec<bool>) -> std::fmt::Result {
         for (i, child) in self.children.iter().enumerate() {
             let is_last = i == self.children.len() - 1;
@@ -1,11 +1,52 @@
+#[cfg(feature = "backtrace")]
+mod caller;
+mod clock;
+mod closed_span;
+mod cpu_time;
+mod csv_reader;
 mod field_visitor;
+#[cfg(feature = "perfetto")]
+mod guard_stack;
+mod lock_stats;
 mod log_tree;
+mod os_thread_id;
+#[cfg(feature = "perfetto")]
+mod perfetto_trace;
+mod resource_usage;
 mod span_metadata;
+mod span_timings;
+mod span_uid;
+mod stack_probe;
 mod storage_utils;
+mod thousands;
+mod tree_diff;
+mod tree_node;
 
-pub use field_visitor::FieldVisitor;
+#[cfg(feature = "backtrace")]
+pub use caller::caller;
+pub use clock::ClockSource;
+#[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+pub use clock::Tsc;
+pub(crate) use closed_span::push_json_string;
+pub use closed_span::ClosedSpan;
+pub use cpu_time::thread_cpu_time;
+#[cfg(feature = "binary")]
+pub use csv_reader::BinaryReader;
+pub use csv_reader::{CsvIndex, CsvReader, CsvRow, CsvTreeNode, CsvTrees};
+pub use field_visitor::{FieldValue, FieldVisitor};
+#[cfg(feature = "perfetto")]
+pub use guard_stack::GuardStack;
+pub use lock_stats::{LockContention, LockStats};
 pub use log_tree::LogTree;
+pub use os_thread_id::os_thread_id;
+#[cfg(feature = "perfetto")]
+pub use perfetto_trace::{current_thread_id as perfetto_thread_id, PerfettoTraceWriter};
+pub use resource_usage::ResourceUsage;
 pub use span_metadata::*;
-#[cfg(feature = "perf_counters")]
-pub use storage_utils::with_span_storage;
-pub use storage_utils::{insert_to_span_storage, with_span_storage_mut};
+pub use span_timings::SpanTimings;
+pub use span_uid::SpanUid;
+pub use stack_probe::StackProbe;
+pub use storage_utils::{insert_to_span_storage, with_span_storage, with_span_storage_mut};
+pub use thousands::format_thousands;
+pub use tree_diff::{diff_trees, Regression};
+pub use tree_node::TreeNode;
@@ -0,0 +1,17 @@
+// Copyright 2024 Ulvetanna Inc.
+
+/// The id the kernel knows the current thread by, e.g. the `tid` `perf` and `top` report.
+#[cfg(target_os = "linux")]
+pub fn os_thread_id() -> u64 {
+    // SAFETY: gettid has no preconditions and can't fail.
+    unsafe { libc::gettid() as u64 }
+}
+
+/// A number which is unique for every call, so callers must ask once per thread.
+#[cfg(not(target_os = "linux"))]
+pub fn os_thread_id() -> u64 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
@@ -0,0 +1,307 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+// field numbers from perfetto/protos/perfetto/trace/*.proto
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+const TRACK_UUID: u32 = 1;
+const TRACK_THREAD: u32 = 4;
+const THREAD_PID: u32 = 1;
+const THREAD_TID: u32 = 2;
+const THREAD_NAME: u32 = 5;
+const EVENT_DEBUG_ANNOTATION: u32 = 4;
+const EVENT_TYPE: u32 = 9;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_NAME: u32 = 23;
+const ANNOTATION_STRING_VALUE: u32 = 6;
+const ANNOTATION_NAME: u32 = 10;
+
+const SLICE_BEGIN: u64 = 1;
+const SLICE_END: u64 = 2;
+
+/// the packets written by this process all belong to a single sequence
+const SEQUENCE_ID: u64 = 1;
+
+thread_local! {
+    static THREAD_ID: u64 = {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    };
+}
+
+/// Returns the track of the current thread. Doesn't need the writer, so it can be called without
+/// locking it.
+pub fn current_thread_id() -> u64 {
+    THREAD_ID.with(|id| *id)
+}
+
+/// Writes slices as a `.perfetto-trace` protobuf file which can be opened in the Perfetto UI
+/// without a running tracing service.
+///
+/// Every packet is a `Trace.packet` field on its own, so the file is a valid trace after each
+/// write and can be appended to.
+pub struct PerfettoTraceWriter<W> {
+    writer: W,
+    init_time: Instant,
+    threads: HashMap<u64, Option<String>>,
+    described: HashSet<u64>,
+}
+
+impl<W: Write> PerfettoTraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            init_time: Instant::now(),
+            threads: HashMap::new(),
+            described: HashSet::new(),
+        }
+    }
+
+    /// Remembers the name of the thread with the track `tid` for the track descriptor.
+    pub fn register_thread(&mut self, tid: u64, name: Option<String>) {
+        self.threads.entry(tid).or_insert(name);
+    }
+
+    /// Writes a slice on the track of `thread`, which was returned by `current_thread_id`.
+    pub fn slice(
+        &mut self,
+        thread: u64,
+        name: &str,
+        fields: &BTreeMap<String, String>,
+        begin: Instant,
+        end: Instant,
+    ) -> std::io::Result<()> {
+        if self.described.insert(thread) {
+            let mut descriptor = Message::default();
+            descriptor.varint(THREAD_PID, std::process::id() as u64);
+            descriptor.varint(THREAD_TID, thread);
+            if let Some(Some(name)) = self.threads.get(&thread) {
+                descriptor.string(THREAD_NAME, name);
+            }
+            let mut track = Message::default();
+            track.varint(TRACK_UUID, thread);
+            track.message(TRACK_THREAD, &descriptor);
+            let mut packet = Message::default();
+            packet.varint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+            packet.message(PACKET_TRACK_DESCRIPTOR, &track);
+            self.packet(packet)?;
+        }
+
+        let mut event = Message::default();
+        event.varint(EVENT_TYPE, SLICE_BEGIN);
+        event.varint(EVENT_TRACK_UUID, thread);
+        event.string(EVENT_NAME, name);
+        for (key, value) in fields {
+            let mut annotation = Message::default();
+            annotation.string(ANNOTATION_NAME, key);
+            annotation.string(ANNOTATION_STRING_VALUE, value);
+            event.message(EVENT_DEBUG_ANNOTATION, &annotation);
+        }
+        self.event(begin, event)?;
+
+        let mut event = Message::default();
+        event.varint(EVENT_TYPE, SLICE_END);
+        event.varint(EVENT_TRACK_UUID, thread);
+        self.event(end, event)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn event(&mut self, time: Instant, event: Message) -> std::io::Result<()> {
+        let timestamp = time.saturating_duration_since(self.init_time).as_nanos() as u64;
+        let mut packet = Message::default();
+        packet.varint(PACKET_TIMESTAMP, timestamp);
+        packet.varint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        packet.message(PACKET_TRACK_EVENT, &event);
+        self.packet(packet)
+    }
+
+    fn packet(&mut self, packet: Message) -> std::io::Result<()> {
+        let mut trace = Message::default();
+        trace.message(TRACE_PACKET, &packet);
+        self.writer.write_all(&trace.0)
+    }
+}
+
+/// An encoded protobuf message.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, field: u32, value: u64) {
+        self.raw_varint((field as u64) << 3);
+        self.raw_varint(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, value: &Message) {
+        self.bytes(field, &value.0);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.raw_varint((field as u64) << 3 | 2);
+        self.raw_varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::{PrintTreeConfig, PrintTreeLayer};
+
+    /// Decodes the fields of a protobuf message into `(field, value)` pairs. Varints are
+    /// returned as their little endian bytes.
+    fn decode(mut message: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        fn varint(message: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let (byte, rest) = message.split_first().expect("truncated varint");
+                *message = rest;
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            value
+        }
+
+        let mut fields = vec![];
+        while !message.is_empty() {
+            let key = varint(&mut message);
+            let value = match key & 7 {
+                0 => varint(&mut message).to_le_bytes().to_vec(),
+                2 => {
+                    let len = varint(&mut message) as usize;
+                    let (value, rest) = message.split_at(len);
+                    message = rest;
+                    value.to_vec()
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push(((key >> 3) as u32, value));
+        }
+        fields
+    }
+
+    type Event = Vec<(u32, Vec<u8>)>;
+
+    /// Returns the number of track descriptors and the timestamped events of the trace file.
+    fn read_trace(path: &std::path::Path) -> (usize, Vec<(u64, Event)>) {
+        let trace = std::fs::read(path).unwrap();
+        let mut events = vec![];
+        let mut tracks = 0;
+        for (field, packet) in decode(&trace) {
+            assert_eq!(field, TRACE_PACKET);
+            let mut timestamp = 0;
+            for (field, value) in decode(&packet) {
+                match field {
+                    PACKET_TIMESTAMP => timestamp = u64::from_le_bytes(value.try_into().unwrap()),
+                    PACKET_TRACK_DESCRIPTOR => tracks += 1,
+                    PACKET_TRACK_EVENT => events.push((timestamp, decode(&value))),
+                    _ => {}
+                }
+            }
+        }
+        (tracks, events)
+    }
+
+    fn event_type(event: &Event) -> Option<u64> {
+        event
+            .iter()
+            .find(|(field, _)| *field == EVENT_TYPE)
+            .map(|(_, value)| value[0] as u64)
+    }
+
+    fn export(path: &std::path::Path, f: impl FnOnce()) {
+        let config = PrintTreeConfig {
+            perfetto_trace: Some(path.into()),
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry().with(PrintTreeLayer::new(config));
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    #[test]
+    fn export_graph() {
+        let path = std::env::temp_dir().join("tracing_profile_export_graph.perfetto-trace");
+        export(&path, || {
+            let _root = debug_span!("root span").entered();
+            for _ in 0..2 {
+                drop(debug_span!("child span", field = "value").entered());
+            }
+        });
+
+        let (tracks, events) = read_trace(&path);
+        let names: Vec<_> = events
+            .iter()
+            .filter(|(_, event)| event_type(event) == Some(SLICE_BEGIN))
+            .filter_map(|(_, event)| event.iter().find(|(field, _)| *field == EVENT_NAME))
+            .map(|(_, name)| String::from_utf8(name.clone()).unwrap())
+            .collect();
+        assert_eq!(tracks, 1);
+        assert_eq!(names, ["root span", "child span", "child span"]);
+        assert_eq!(
+            events
+                .iter()
+                .filter(|(_, event)| event_type(event) == Some(SLICE_END))
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn reentered_span() {
+        let path = std::env::temp_dir().join("tracing_profile_reentered_span.perfetto-trace");
+        export(&path, || {
+            let _root = debug_span!("root span").entered();
+            let child = debug_span!("child span");
+            for _ in 0..2 {
+                drop(child.enter());
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+        });
+
+        // every slice is written as a begin followed by its end
+        let (_, events) = read_trace(&path);
+        let slices: Vec<_> = events
+            .chunks(2)
+            .map(|slice| {
+                assert_eq!(event_type(&slice[0].1), Some(SLICE_BEGIN));
+                assert_eq!(event_type(&slice[1].1), Some(SLICE_END));
+                (slice[0].0, slice[1].0)
+            })
+            .collect();
+        assert_eq!(slices.len(), 3);
+        let (first, second) = (slices[1], slices[2]);
+        assert!(first.1 <= second.0, "{slices:?}");
+        assert!(
+            slices[0].0 <= first.0 && second.1 <= slices[0].1,
+            "{slices:?}"
+        );
+    }
+}
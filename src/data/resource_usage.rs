@@ -0,0 +1,51 @@
+/// Resource usage of the whole process, sampled when a root span exits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// Peak resident set size of the process in bytes.
+    pub peak_rss: Option<u64>,
+    /// Total number of allocations. Only the application knows this, e.g. by counting them in
+    /// its global allocator, so `sample` leaves it empty.
+    pub allocations: Option<u64>,
+}
+
+impl ResourceUsage {
+    /// Samples the peak RSS of the process, if the platform supports measuring it.
+    pub fn sample() -> Self {
+        Self {
+            peak_rss: peak_rss(),
+            allocations: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(peak_rss) = self.peak_rss {
+            parts.push(format!(
+                "peak rss {:.2} MiB",
+                peak_rss as f64 / (1024.0 * 1024.0)
+            ));
+        }
+        if let Some(allocations) = self.allocations {
+            parts.push(format!("{allocations} allocations"));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(unix)]
+fn peak_rss() -> Option<u64> {
+    // SAFETY: an all zero rusage is valid, and `getrusage` only writes to it.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `usage` is a valid, writable rusage.
+    let result = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    // `ru_maxrss` is in bytes on macOS and in kilobytes everywhere else
+    let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    (result == 0).then(|| usage.ru_maxrss as u64 * unit)
+}
+
+#[cfg(not(unix))]
+fn peak_rss() -> Option<u64> {
+    None
+}
@@ -1,20 +1,85 @@
-use std::{collections::BTreeMap, time::Instant};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use super::FieldValue;
 
 #[derive(Debug)]
 pub struct CsvMetadata {
+    /// the value of the `id` column
+    pub id: u64,
     pub start_time: Option<u64>,
+    /// time the span was created, in nanoseconds since the layer was created
+    pub created: u64,
+    /// time between the creation and the first enter
+    pub schedule_latency: Option<u64>,
     pub call_depth: u64,
-    pub fields: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, FieldValue>,
+    /// set when a child span is created, spans with children are never skipped as trivial
+    pub has_children: bool,
 }
 
 #[derive(Debug)]
 #[cfg(feature = "perfetto")]
 pub struct PerfettoMetadata {
-    pub trace_guard: Option<perfetto_sys::TraceEvent>,
+    pub trace_guards: super::GuardStack<crate::layers::perfetto::Slice>,
 }
 
 #[derive(Debug)]
 pub struct GraphMetadata {
+    /// the `SpanUid` of the span, which keys its node
+    pub uid: u64,
     pub start_time: Option<Instant>,
+    pub created: Instant,
+    pub first_enter: Option<Instant>,
     pub fields: BTreeMap<String, String>,
+    /// `error!` events recorded within the span since it last exited
+    pub errors: usize,
+    /// `warn!` events recorded within the span since it last exited
+    pub warnings: usize,
+    /// messages of the events annotated below the span since it last exited, with the time since
+    /// it was entered
+    pub events: Vec<(Duration, String)>,
+    /// names of the phase markers emitted within the span since it last exited, with the time
+    /// they were emitted
+    pub phases: Vec<(Instant, String)>,
+    /// measures the stack usage while the span is entered, if it is displayed
+    pub stack: Option<super::StackProbe>,
+    /// number of spans below this one which exited, added up by the children as they exit
+    pub descendants: usize,
+    /// execution time of the children which exited
+    pub children_duration: Duration,
+    /// the thread the span is entered on, `None` while it isn't entered
+    pub entered_on: Option<std::thread::ThreadId>,
+    /// busy time of the children which exited, except for the time they ran within this span on
+    /// its thread, which is already part of its execution time
+    pub concurrent_busy_time: Duration,
+    /// wall intervals of the children which exited, only kept to compute the own wall time
+    pub children_intervals: Vec<(Instant, Instant)>,
+    /// the `SpanUid` of the parent the node of the span waited for when it last exited
+    pub tree_parent: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct HistogramMetadata {
+    pub start_time: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CallbackMetadata {
+    pub start_time: Option<Instant>,
+    pub duration: Duration,
+    pub depth: u64,
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct SummaryMetadata {
+    pub start_time: Option<Instant>,
+    pub start_cpu_time: Option<Duration>,
+    /// wall time of the children which exited since the span was entered
+    pub children_time: Duration,
+    /// number of spans on the stack up to and including this one, 1 for roots
+    pub depth: usize,
 }
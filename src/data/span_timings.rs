@@ -0,0 +1,57 @@
+use std::time::{Duration, Instant};
+
+use super::ClockSource;
+
+/// Busy and idle time of a span, stored in the span's extensions by `TimingLayer`.
+///
+/// This mirrors the timings `tracing_subscriber::fmt` records with `FmtSpan::CLOSE`: busy time
+/// is the time the span was entered and idle time is the time between the span's creation and
+/// close during which it wasn't entered, e.g. an instrumented future waiting to be polled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanTimings {
+    pub busy: Duration,
+    pub idle: Duration,
+    /// CPU cycles of the busy time, 0 unless `TimingLayer` counts cycles, e.g. with
+    /// `ClockSource::Tsc`.
+    pub busy_cycles: u64,
+    /// CPU cycles of the idle time, 0 unless `TimingLayer` counts cycles.
+    pub idle_cycles: u64,
+    last: Instant,
+    last_cycles: u64,
+}
+
+impl SpanTimings {
+    pub(crate) fn new(clock: &ClockSource) -> Self {
+        Self {
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            busy_cycles: 0,
+            idle_cycles: 0,
+            last: Instant::now(),
+            last_cycles: clock.cycles(),
+        }
+    }
+
+    /// The time the span was last entered or exited, `None` if the timings count cycles, which
+    /// don't read `Instant`.
+    pub(crate) fn last_transition(&self) -> Option<Instant> {
+        // only `ClockSource::Instant` advances `last`, and it doesn't count cycles
+        (self.last_cycles == 0).then_some(self.last)
+    }
+
+    pub(crate) fn on_enter(&mut self, clock: &ClockSource) {
+        let (time, cycles) = clock.advance(&mut self.last, &mut self.last_cycles);
+        self.idle += time;
+        self.idle_cycles += cycles;
+    }
+
+    pub(crate) fn on_exit(&mut self, clock: &ClockSource) {
+        let (time, cycles) = clock.advance(&mut self.last, &mut self.last_cycles);
+        self.busy += time;
+        self.busy_cycles += cycles;
+    }
+
+    pub(crate) fn on_close(&mut self, clock: &ClockSource) {
+        self.on_enter(clock)
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// Id of a span which is unique for the whole run.
+///
+/// `tracing` may hand the id of a closed span to a new span, so layers which keep data about a
+/// span after it closed, or write ids for later analysis, key spans by this id instead. It is
+/// stored in the extensions of the span, so all layers of a subscriber agree on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanUid(pub u64);
+
+impl SpanUid {
+    /// Returns the id of the span, assigning the next one if no layer did so yet. Must not be
+    /// called while the extensions of the span are borrowed, usually it is called in
+    /// `on_new_span`.
+    pub fn assign<S>(span: &SpanRef<'_, S>) -> u64
+    where
+        S: for<'lookup> LookupSpan<'lookup>,
+    {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+
+        let mut extensions = span.extensions_mut();
+        if let Some(uid) = extensions.get_mut::<SpanUid>() {
+            return uid.0;
+        }
+        let uid = NEXT.fetch_add(1, Ordering::Relaxed);
+        extensions.insert(SpanUid(uid));
+        uid
+    }
+
+    /// Returns the id assigned to the span, `None` if no layer assigned one.
+    pub fn of<S>(span: &SpanRef<'_, S>) -> Option<u64>
+    where
+        S: for<'lookup> LookupSpan<'lookup>,
+    {
+        span.extensions().get::<SpanUid>().map(|uid| uid.0)
+    }
+}
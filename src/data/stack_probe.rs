@@ -0,0 +1,53 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::cell::Cell;
+
+thread_local! {
+    /// The deepest stack address seen on the thread since the innermost measured span was
+    /// entered.
+    static LOWEST: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+/// The approximate stack pointer of the caller, assuming the stack grows downwards.
+#[inline(never)]
+fn stack_pointer() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// Measures how far the stack of the thread grows while a span is entered.
+///
+/// The stack is only probed when spans are entered and exited and when `sample` is called, so
+/// the usage is a lower bound: deep calls without spans of their own aren't seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackProbe {
+    start: usize,
+    /// `LOWEST` of the enclosing span, restored on exit
+    outer_lowest: usize,
+}
+
+impl StackProbe {
+    /// Starts measuring when the span is entered.
+    pub fn enter() -> Self {
+        let start = stack_pointer();
+        Self {
+            start,
+            outer_lowest: LOWEST.replace(start),
+        }
+    }
+
+    /// Probes the current depth of the stack, e.g. when an event is emitted.
+    pub fn sample() {
+        let current = stack_pointer();
+        LOWEST.set(LOWEST.get().min(current));
+    }
+
+    /// Stops measuring when the span exits on the thread which entered it and returns the number
+    /// of bytes the stack grew by at most while it was entered.
+    pub fn exit(self) -> usize {
+        Self::sample();
+        let lowest = LOWEST.get();
+        // the enclosing span was entered for as long, so it used at least as much
+        LOWEST.set(lowest.min(self.outer_lowest));
+        self.start.saturating_sub(lowest)
+    }
+}
@@ -1,7 +1,7 @@
 use tracing::span;
 use tracing_subscriber::registry::LookupSpan;
 
-use crate::err_msg;
+use crate::{err_msg, ProfileError};
 
 /// Register storage of the given type with the span.
 pub fn insert_to_span_storage<T, S>(
@@ -14,7 +14,9 @@ pub fn insert_to_span_storage<T, S>(
     for<'lookup> S: LookupSpan<'lookup>,
 {
     let Some(span) = ctx.span(id) else {
-        return err_msg!("failed to get span");
+        return err_msg!(ProfileError::SpanNotFound {
+            context: std::any::type_name::<T>()
+        });
     };
 
     span.extensions_mut().insert(storage);
@@ -31,20 +33,23 @@ pub fn with_span_storage_mut<T, S>(
     for<'lookup> S: LookupSpan<'lookup>,
 {
     let Some(span) = ctx.span(id) else {
-        return err_msg!("failed to get span");
+        return err_msg!(ProfileError::SpanNotFound {
+            context: std::any::type_name::<T>()
+        });
     };
 
     let mut extensions = span.extensions_mut();
     let Some(storage) = extensions.get_mut::<T>() else {
-        return err_msg!("Failed to get storage");
+        return err_msg!(ProfileError::StorageMissing {
+            context: std::any::type_name::<T>()
+        });
     };
 
     f(storage)
 }
 
 /// Perform operation with immutable span storage value.
-#[cfg(feature = "perf_counters")]
-pub fn with_span_storage<T, S: tracing::Subscriber>(
+pub fn with_span_storage<T, S>(
     id: &span::Id,
     ctx: tracing_subscriber::layer::Context<'_, S>,
     f: impl FnOnce(&T),
@@ -54,12 +59,16 @@ pub fn with_span_storage<T, S: tracing::Subscriber>(
     for<'lookup> S: LookupSpan<'lookup>,
 {
     let Some(span) = ctx.span(id) else {
-        return err_msg!("failed to get span");
+        return err_msg!(ProfileError::SpanNotFound {
+            context: std::any::type_name::<T>()
+        });
     };
 
     let extensions = span.extensions();
     let Some(storage) = extensions.get::<T>() else {
-        return err_msg!("Failed to get storage");
+        return err_msg!(ProfileError::StorageMissing {
+            context: std::any::type_name::<T>()
+        });
     };
 
     f(storage)
@@ -0,0 +1,33 @@
+// Copyright 2024 Ulvetanna Inc.
+
+/// Formats `value` with `separator` between groups of three digits, e.g. `1,000,000`.
+pub fn format_thousands(value: u64, separator: Option<char>) -> String {
+    let digits = value.to_string();
+    let Some(separator) = separator else {
+        return digits;
+    };
+    let mut result = String::with_capacity(digits.len() * 4 / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        // `usize::is_multiple_of` needs Rust 1.87
+        #[allow(clippy::manual_is_multiple_of)]
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            result.push(separator);
+        }
+        result.push(digit);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separators() {
+        assert_eq!(format_thousands(1000000, Some(',')), "1,000,000");
+        assert_eq!(format_thousands(282256, Some('_')), "282_256");
+        assert_eq!(format_thousands(999, Some(',')), "999");
+        assert_eq!(format_thousands(0, Some(',')), "0");
+        assert_eq!(format_thousands(1000000, None), "1000000");
+    }
+}
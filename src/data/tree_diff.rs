@@ -0,0 +1,144 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{cmp::Reverse, collections::HashMap, time::Duration};
+
+use super::CsvTreeNode;
+
+/// A span name which took longer in the current run than in the baseline, see [`diff_trees`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Regression {
+    pub span_name: String,
+    /// Total duration of all spans with this name in the baseline.
+    pub baseline: Duration,
+    /// Total duration of all spans with this name in the current run.
+    pub current: Duration,
+}
+
+impl Regression {
+    pub fn delta(&self) -> Duration {
+        self.current.saturating_sub(self.baseline)
+    }
+
+    /// The slowdown relative to the baseline, e.g. `50.0` if the spans took 1.5 times as long.
+    pub fn percent(&self) -> f64 {
+        if self.baseline.is_zero() {
+            return f64::INFINITY;
+        }
+        100.0 * self.delta().as_secs_f64() / self.baseline.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for Regression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {:.2?} -> {:.2?} (+{:.2?}, +{:.2}%)",
+            self.span_name,
+            self.baseline,
+            self.current,
+            self.delta(),
+            self.percent()
+        )
+    }
+}
+
+/// Compares the total duration of every span name in two runs, e.g. trees read back with
+/// `CsvReader::trees`, and returns the names which got slower by more than `threshold_percent`
+/// and by more than `min_delta`, largest slowdown first.
+///
+/// Span names which don't appear in the baseline are not reported.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use tracing_profile::{diff_trees, CsvReader};
+///
+/// let read = |path| CsvReader::open(path).unwrap().trees().collect::<Result<Vec<_>, _>>();
+/// let baseline = read("/tmp/baseline.csv").unwrap();
+/// let current = read("/tmp/output.csv").unwrap();
+/// for regression in diff_trees(&baseline, &current, 10.0, Duration::from_millis(1)) {
+///     println!("{regression}");
+/// }
+/// ```
+pub fn diff_trees(
+    baseline: &[CsvTreeNode],
+    current: &[CsvTreeNode],
+    threshold_percent: f64,
+    min_delta: Duration,
+) -> Vec<Regression> {
+    let baseline = total_durations(baseline);
+    let mut regressions: Vec<_> = total_durations(current)
+        .into_iter()
+        .filter_map(|(span_name, current)| {
+            let baseline = *baseline.get(&span_name)?;
+            Some(Regression {
+                span_name,
+                baseline,
+                current,
+            })
+        })
+        .filter(|x| x.delta() > min_delta && x.percent() > threshold_percent)
+        .collect();
+    regressions.sort_by_key(|x| Reverse(x.delta()));
+    regressions
+}
+
+fn total_durations(trees: &[CsvTreeNode]) -> HashMap<String, Duration> {
+    fn add(node: &CsvTreeNode, totals: &mut HashMap<String, Duration>) {
+        *totals.entry(node.row.span_name.clone()).or_default() +=
+            Duration::from_nanos(node.row.elapsed_ns);
+        for child in &node.children {
+            add(child, totals);
+        }
+    }
+
+    let mut totals = HashMap::new();
+    for tree in trees {
+        add(tree, &mut totals);
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CsvRow;
+
+    fn node(name: &str, elapsed_ms: u64, children: Vec<CsvTreeNode>) -> CsvTreeNode {
+        CsvTreeNode {
+            row: CsvRow {
+                span_name: name.into(),
+                elapsed_ns: elapsed_ms * 1_000_000,
+                ..Default::default()
+            },
+            children,
+        }
+    }
+
+    #[test]
+    fn reports_regression() {
+        let baseline = [node(
+            "root",
+            100,
+            vec![node("parse", 20, vec![]), node("prove", 70, vec![])],
+        )];
+        let current = [node(
+            "root",
+            150,
+            vec![
+                node("parse", 21, vec![]),
+                node("prove", 70, vec![]),
+                node("prove", 55, vec![]),
+                node("new span", 5, vec![]),
+            ],
+        )];
+
+        let regressions = diff_trees(&baseline, &current, 10.0, Duration::from_millis(2));
+        let names: Vec<_> = regressions.iter().map(|x| x.span_name.as_str()).collect();
+        // parse only got 5% slower
+        assert_eq!(names, ["prove", "root"]);
+        assert_eq!(regressions[0].delta(), Duration::from_millis(55));
+        assert_eq!(
+            regressions[1].to_string(),
+            "root: 100.00ms -> 150.00ms (+50.00ms, +50.00%)"
+        );
+    }
+}
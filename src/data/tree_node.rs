@@ -0,0 +1,115 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{collections::BTreeMap, time::Duration};
+
+use super::closed_span::push_json_string;
+
+/// A node of a snapshot of the call graph taken by `PrintTreeLayer::snapshot`, or of a completed
+/// tree written with `TreeFormat::Json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeNode {
+    pub name: String,
+    /// For open spans the time since they were last entered, for exited spans their execution
+    /// time.
+    pub duration: Duration,
+    /// Whether the span is still entered, i.e. `duration` keeps growing.
+    pub open: bool,
+    /// Fields of exited spans, open spans are listed without their fields.
+    pub fields: BTreeMap<String, String>,
+    pub children: Vec<TreeNode>,
+}
+
+impl TreeNode {
+    /// Serializes the tree as a single line of JSON, e.g.
+    /// `{"name":"root","duration_ns":3194,"open":true,"fields":{},"children":[...]}`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        self.push_json(&mut json);
+        json
+    }
+
+    fn push_json(&self, json: &mut String) {
+        json.push_str("{\"name\":");
+        push_json_string(json, &self.name);
+        json.push_str(&format!(
+            ",\"duration_ns\":{},\"open\":{},\"fields\":{{",
+            self.duration.as_nanos(),
+            self.open
+        ));
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(json, key);
+            json.push(':');
+            push_json_string(json, value);
+        }
+        json.push_str("},\"children\":[");
+        for (i, child) in self.children.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            child.push_json(json);
+        }
+        json.push_str("]}");
+    }
+
+    /// Serializes the tree as a YAML document with the share of every node in the time of this
+    /// one, e.g.
+    /// ```yaml
+    /// ---
+    /// name: "root"
+    /// duration_ns: 3194
+    /// percent: 100.00
+    /// fields: {}
+    /// children:
+    ///   - name: "child"
+    ///     ...
+    /// ```
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> String {
+        let mut yaml = "---\n".to_string();
+        self.push_yaml(&mut yaml, "", "", self.duration.as_nanos());
+        yaml.pop();
+        yaml
+    }
+
+    /// Writes the keys of the node indented by `indent`, with `first` before the first key.
+    #[cfg(feature = "yaml")]
+    fn push_yaml(&self, yaml: &mut String, first: &str, indent: &str, root_ns: u128) {
+        let percent = match root_ns {
+            0 => 0.0,
+            _ => self.duration.as_nanos() as f64 / root_ns as f64 * 100.0,
+        };
+        // double quoted YAML strings escape like JSON strings
+        yaml.push_str(first);
+        yaml.push_str("name: ");
+        push_json_string(yaml, &self.name);
+        yaml.push_str(&format!(
+            "\n{indent}duration_ns: {}\n{indent}percent: {percent:.2}\n",
+            self.duration.as_nanos()
+        ));
+        match self.fields.is_empty() {
+            true => yaml.push_str(&format!("{indent}fields: {{}}\n")),
+            false => yaml.push_str(&format!("{indent}fields:\n")),
+        }
+        for (key, value) in &self.fields {
+            yaml.push_str(&format!("{indent}  "));
+            push_json_string(yaml, key);
+            yaml.push_str(": ");
+            push_json_string(yaml, value);
+            yaml.push('\n');
+        }
+        match self.children.is_empty() {
+            true => yaml.push_str(&format!("{indent}children: []\n")),
+            false => yaml.push_str(&format!("{indent}children:\n")),
+        }
+        for child in &self.children {
+            child.push_yaml(
+                yaml,
+                &format!("{indent}  - "),
+                &format!("{indent}    "),
+                root_ns,
+            );
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+/// Wraps a value whose drop is expensive, e.g. a large collection, so the time spent dropping it
+/// is measured as a `[drop]` span with a `type` field, a child of the span entered at that time.
+///
+/// Declare the wrapped value after entering the span, so it is dropped while the span is still
+/// entered and the drop shows up in its tree:
+///
+/// ```
+/// use tracing_profile::DropTimer;
+///
+/// let _span = tracing::info_span!("build index").entered();
+/// let index = DropTimer::new(vec![0u64; 1 << 20]);
+/// assert_eq!(index.len(), 1 << 20);
+/// // `[drop] [ 1.20ms | 8.00% ] { type = alloc::vec::Vec<u64> }` below `build index`
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct DropTimer<T> {
+    value: ManuallyDrop<T>,
+}
+
+impl<T> DropTimer<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: ManuallyDrop::new(value),
+        }
+    }
+
+    /// Returns the value, whose drop is no longer measured.
+    pub fn into_inner(self) -> T {
+        let mut this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped, so the value is taken exactly once.
+        unsafe { ManuallyDrop::take(&mut this.value) }
+    }
+}
+
+impl<T> Deref for DropTimer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for DropTimer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for DropTimer<T> {
+    fn drop(&mut self) {
+        let _span = tracing::info_span!("[drop]", r#type = std::any::type_name::<T>()).entered();
+        // SAFETY: the value is dropped exactly once, here.
+        unsafe { ManuallyDrop::drop(&mut self.value) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::{PrintTreeConfig, PrintTreeLayer, PrintTreeSink, TreeFormat};
+
+    struct Expensive;
+
+    impl Drop for Expensive {
+        fn drop(&mut self) {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn drop_time_in_tree() {
+        let path = std::env::temp_dir().join("tracing_profile_drop_timer.txt");
+        let config = PrintTreeConfig::default()
+            .with_output(TreeFormat::Ascii, PrintTreeSink::File(path.clone()));
+        let subscriber = tracing_subscriber::registry().with(PrintTreeLayer::new(config));
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = tracing::info_span!("root span").entered();
+            let _value = DropTimer::new(Expensive);
+            drop(tracing::info_span!("work").entered());
+        });
+
+        let tree = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = tree.lines().collect();
+        assert!(lines[0].starts_with("root span ["));
+        let drop_line = lines
+            .iter()
+            .find(|line| line.contains("[drop]"))
+            .unwrap_or_else(|| panic!("{tree}"));
+        assert!(drop_line.ends_with("{ type = tracing_profile::drop_timer::tests::Expensive }"));
+        let millis: f64 = drop_line
+            .split("[drop] [ ")
+            .nth(1)
+            .and_then(|x| x.split("ms |").next())
+            .and_then(|x| x.parse().ok())
+            .unwrap_or_else(|| panic!("{drop_line}"));
+        assert!(millis >= 20.0, "{drop_line}");
+    }
+}
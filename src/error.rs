@@ -0,0 +1,158 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::sync::{Arc, RwLock};
+
+/// Diagnostics reported by the layers of this crate.
+///
+/// Errors are printed to stderr unless a callback is installed with [`set_error_callback`].
+/// With the `panic` feature enabled the program panics after reporting the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProfileError {
+    /// The span wasn't found in the registry. `context` is the hook which looked it up.
+    SpanNotFound { context: &'static str },
+    /// The span doesn't carry the storage of a layer. `context` is the hook or the storage type.
+    StorageMissing { context: &'static str },
+    /// A layer's internal `Mutex` was poisoned by a panicking thread.
+    MutexPoisoned,
+    /// Writing output failed.
+    WriteFailed(std::io::ErrorKind),
+    /// An event didn't have the expected fields.
+    InvalidEvent(String),
+    /// A span was closed without ever being entered, usually because the guard returned by
+    /// `enter()` wasn't held. Only reported once per span name and never panics.
+    NeverEntered { name: &'static str },
+    /// The children of a span took longer in total than the span itself, usually because they
+    /// ran concurrently, which the inclusive times of the tree misrepresent. Only reported once
+    /// per span name and never panics.
+    ChildrenExceedParent {
+        name: String,
+        parent: std::time::Duration,
+        children: std::time::Duration,
+    },
+    /// A config option can't be honored by the constructor named by `context`, which ignores it.
+    IgnoredOption {
+        option: &'static str,
+        context: &'static str,
+    },
+    /// A counter of the calling thread couldn't be opened or read, e.g. because the process ran
+    /// out of file descriptors. The spans it was meant to count are left out.
+    CounterFailed(std::io::ErrorKind),
+    /// A span closed after the output named by `context` was finished, e.g. the Parquet file
+    /// after its footer was written, so it is left out. Only reported once per output and never
+    /// panics.
+    OutputFinished { context: &'static str },
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SpanNotFound { context } => write!(f, "failed to get span {context}"),
+            Self::StorageMissing { context } => write!(f, "failed to get storage {context}"),
+            Self::MutexPoisoned => write!(f, "failed to get mutex"),
+            Self::WriteFailed(kind) => write!(f, "failed to write output: {kind}"),
+            Self::InvalidEvent(event) => write!(f, "invalid event: {event}"),
+            Self::NeverEntered { name } => {
+                write!(f, "span `{name}` was closed without ever being entered")
+            }
+            Self::ChildrenExceedParent {
+                name,
+                parent,
+                children,
+            } => write!(
+                f,
+                "the children of span `{name}` took {children:.2?} in total, more than the span \
+                 itself ({parent:.2?}), they probably overlapped"
+            ),
+            Self::IgnoredOption { option, context } => {
+                write!(f, "`{option}` is ignored by {context}")
+            }
+            Self::CounterFailed(kind) => write!(f, "failed to read counter: {kind}"),
+            Self::OutputFinished { context } => {
+                write!(
+                    f,
+                    "{context} was already finished, later spans are left out"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+type ErrorCallback = Arc<dyn Fn(&ProfileError) + Send + Sync>;
+
+static ERROR_CALLBACK: RwLock<Option<ErrorCallback>> = RwLock::new(None);
+
+/// Installs a callback which receives all errors instead of them being printed to stderr.
+pub fn set_error_callback(callback: impl Fn(&ProfileError) + Send + Sync + 'static) {
+    let mut error_callback = ERROR_CALLBACK.write().unwrap_or_else(|e| e.into_inner());
+    *error_callback = Some(Arc::new(callback));
+}
+
+pub(crate) fn report(error: ProfileError) {
+    warn(&error);
+    if cfg!(feature = "panic") {
+        panic!("{error}");
+    }
+}
+
+/// Reports the error without panicking, even with the `panic` feature.
+pub(crate) fn warn(error: &ProfileError) {
+    // the callback may report errors itself or install another callback
+    let error_callback = ERROR_CALLBACK
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    match error_callback {
+        Some(callback) => callback(error),
+        None => eprintln!("{error}"),
+    }
+}
+
+/// Installs an error callback collecting the errors of all tests.
+#[cfg(test)]
+pub(crate) fn collected_errors() -> &'static std::sync::Mutex<Vec<ProfileError>> {
+    static ERRORS: std::sync::Mutex<Vec<ProfileError>> = std::sync::Mutex::new(vec![]);
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| set_error_callback(|error| ERRORS.lock().unwrap().push(error.clone())));
+    &ERRORS
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::data::with_span_storage_mut;
+
+    struct NeverInserted;
+
+    /// Looks up storage which no layer inserts.
+    struct MissingStorageLayer;
+
+    impl<S> tracing_subscriber::Layer<S> for MissingStorageLayer
+    where
+        S: tracing::Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        fn on_enter(&self, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            with_span_storage_mut(id, ctx, |_: &mut NeverInserted| {});
+        }
+    }
+
+    #[test]
+    fn storage_missing_reaches_callback() {
+        let errors = collected_errors();
+
+        let subscriber = tracing_subscriber::registry().with(MissingStorageLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(debug_span!("root span").entered());
+        });
+
+        let context = std::any::type_name::<NeverInserted>();
+        assert!(errors
+            .lock()
+            .unwrap()
+            .contains(&ProfileError::StorageMissing { context }));
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2024 Ulvetanna Inc.
+use tracing_subscriber::{prelude::*, Registry};
+
+type BoxedLayer = Box<dyn tracing_subscriber::Layer<Registry> + Send + Sync>;
+
+/// Work which is deferred until the `ProfileGuard` is dropped, see [`Flush`].
+pub struct FlushHandle(Box<dyn FnOnce() + Send>);
+
+impl FlushHandle {
+    pub fn new(flush: impl FnOnce() + Send + 'static) -> Self {
+        Self(Box::new(flush))
+    }
+
+    pub fn flush(self) {
+        (self.0)()
+    }
+}
+
+/// Layers which buffer or aggregate data that has to be written out at the end of the program.
+///
+/// Layers installed as the global default are never dropped, so their `Drop` impls don't run.
+/// The handle returned here is run instead when the `ProfileGuard` is dropped.
+pub trait Flush {
+    /// Returns `None` for layers which write everything right away.
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        None
+    }
+}
+
+/// Collects the layers to install and returns a [`ProfileGuard`] which flushes them.
+///
+/// ```no_run
+/// use tracing_profile::*;
+///
+/// fn main() {
+///     let _guard = ProfileBuilder::new()
+///         .with(PrintTreeLayer::default())
+///         .with(CsvLayer::new("/tmp/output.csv"))
+///         .with(SummaryLayer::new())
+///         .init();
+///
+///     // the CSV file is complete and the summary printed when `_guard` is dropped
+/// }
+/// ```
+#[derive(Default)]
+pub struct ProfileBuilder {
+    layers: Vec<BoxedLayer>,
+    flush_handles: Vec<FlushHandle>,
+}
+
+impl ProfileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a layer which is flushed when the guard is dropped.
+    pub fn with<L>(mut self, layer: L) -> Self
+    where
+        L: tracing_subscriber::Layer<Registry> + Flush + Send + Sync + 'static,
+    {
+        self.flush_handles.extend(layer.flush_handle());
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Adds a layer which doesn't need to be flushed, e.g. one from another crate.
+    pub fn with_layer<L>(mut self, layer: L) -> Self
+    where
+        L: tracing_subscriber::Layer<Registry> + Send + Sync + 'static,
+    {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Returns the subscriber without installing it, e.g. to use it with
+    /// `tracing::subscriber::with_default`.
+    pub fn build(self) -> (impl tracing::Subscriber + Send + Sync, ProfileGuard) {
+        let subscriber = tracing_subscriber::registry().with(self.layers);
+        let guard = ProfileGuard {
+            flush_handles: self.flush_handles,
+        };
+        (subscriber, guard)
+    }
+
+    /// Installs the layers as the global default subscriber.
+    ///
+    /// Panics if a global default subscriber has already been set.
+    pub fn init(self) -> ProfileGuard {
+        let (subscriber, guard) = self.build();
+        subscriber.init();
+        guard
+    }
+}
+
+/// Flushes the installed layers when dropped, e.g. at the end of `main`: waits until the CSV
+/// writer thread has written every row and prints the summaries and histograms.
+#[must_use = "the layers are flushed when the guard is dropped"]
+pub struct ProfileGuard {
+    flush_handles: Vec<FlushHandle>,
+}
+
+impl Drop for ProfileGuard {
+    fn drop(&mut self) {
+        for handle in self.flush_handles.drain(..) {
+            handle.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+
+    use super::*;
+    use crate::{CsvLayer, CsvReader, SummaryLayer};
+
+    #[test]
+    fn flush_on_drop() {
+        let path = std::env::temp_dir().join("tracing_profile_guard.csv");
+        let (subscriber, guard) = ProfileBuilder::new()
+            .with(CsvLayer::new(&path))
+            .with(SummaryLayer::new())
+            .build();
+        let dispatch = tracing::Dispatch::new(subscriber);
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _root = debug_span!("root span").entered();
+            for _ in 0..100 {
+                drop(debug_span!("child span").entered());
+            }
+        });
+
+        let summary = dispatch.downcast_ref::<SummaryLayer>().unwrap();
+        assert!(!summary.is_printed());
+        drop(guard);
+
+        // the layers are still installed, but every row is written
+        let rows = CsvReader::open(&path).unwrap().count();
+        assert_eq!(rows, 101);
+        assert!(summary.is_printed());
+    }
+}
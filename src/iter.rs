@@ -0,0 +1,80 @@
+// Copyright 2024 Ulvetanna Inc.
+use tracing::span::EnteredSpan;
+
+/// Wraps `iter` so every item it yields is processed inside an info span named `item` with an
+/// `iter` field set to `name`. Items below the relevance threshold of `PrintTreeLayer` are
+/// aggregated into a single node of the tree, while slower items are listed individually:
+/// `item [ 12.00ms | 1.20% ] (100 calls) { iter = rows }`.
+///
+/// The span of an item is entered when it is yielded and exits when the next item is requested
+/// or the iterator is dropped, so it measures the work done with the item. The time spent
+/// producing the items is measured by the enclosing span. The iterator isn't `Send` while a
+/// span is entered, since spans must exit on the thread which entered them.
+///
+/// ```
+/// let sum: u64 = tracing_profile::profile_iter("rows", 0..100u64).map(|x| x * 2).sum();
+/// assert_eq!(sum, 9900);
+/// ```
+pub fn profile_iter<I: IntoIterator>(name: &str, iter: I) -> ProfileIter<I::IntoIter> {
+    ProfileIter {
+        name: name.into(),
+        iter: iter.into_iter(),
+        item: None,
+    }
+}
+
+/// The iterator returned by `profile_iter`.
+pub struct ProfileIter<I> {
+    name: String,
+    iter: I,
+    /// the span of the item yielded last
+    item: Option<EnteredSpan>,
+}
+
+impl<I: Iterator> Iterator for ProfileIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.item = None;
+        let item = self.iter.next()?;
+        self.item = Some(tracing::info_span!("item", iter = self.name.as_str()).entered());
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::{PrintTreeConfig, PrintTreeLayer, PrintTreeSink, TreeFormat};
+
+    #[test]
+    fn span_per_item() {
+        let path = std::env::temp_dir().join("tracing_profile_profile_iter.txt");
+        let config = PrintTreeConfig::default()
+            .with_relevant_above_percent(100.0)
+            .with_output(TreeFormat::Ascii, PrintTreeSink::File(path.clone()));
+        let subscriber = tracing_subscriber::registry().with(PrintTreeLayer::new(config));
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = tracing::info_span!("pipeline").entered();
+            for _ in profile_iter("rows", 0..7) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let tree = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = tree.lines().filter(|line| !line.is_empty()).collect();
+        assert!(lines[0].starts_with("pipeline ["), "{tree}");
+        assert_eq!(lines.len(), 2, "{tree}");
+        assert!(lines[1].contains("item ["), "{tree}");
+        assert!(lines[1].contains("(7 calls)"), "{tree}");
+        assert!(lines[1].ends_with("{ iter = rows }"), "{tree}");
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::callback;
+use crate::{err_msg, ProfileError};
+
+/// What `BudgetLayer` does when a span exceeds its budget.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetAction {
+    /// Panic in `on_close`, failing the test which closed the span.
+    #[default]
+    Panic,
+    /// Record the violation, see `BudgetLayer::violations`.
+    Record,
+}
+
+/// A span which took longer than its budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetViolation {
+    pub name: &'static str,
+    pub duration: Duration,
+    pub budget: Duration,
+}
+
+impl std::fmt::Display for BudgetViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "span `{}` took {:.2?} which exceeded its budget of {:.2?}",
+            self.name, self.duration, self.budget
+        )
+    }
+}
+
+/// BudgetLayer (internally called layer::budget)
+/// This Layer checks that spans stay under a latency budget, turning the profiler into a
+/// regression guard for tests. The duration of a span is the total time it was entered.
+///
+/// example:
+/// ```
+/// use std::{collections::HashMap, time::Duration};
+/// use tracing_profile::BudgetLayer;
+/// use tracing_subscriber::prelude::*;
+///
+/// let budgets = HashMap::from([("db_query".to_string(), Duration::from_millis(5))]);
+/// tracing_subscriber::registry()
+///     .with(BudgetLayer::new(budgets))
+///     .init();
+/// ```
+pub struct Layer {
+    violations: Arc<Mutex<Vec<BudgetViolation>>>,
+    inner: callback::Layer,
+}
+
+impl Layer {
+    /// Panics when a span exceeds its budget.
+    pub fn new(budgets: HashMap<String, Duration>) -> Self {
+        Self::with_action(budgets, BudgetAction::Panic)
+    }
+
+    pub fn with_action(budgets: HashMap<String, Duration>, action: BudgetAction) -> Self {
+        let violations: Arc<Mutex<Vec<BudgetViolation>>> = Default::default();
+        let inner = {
+            let violations = violations.clone();
+            callback::Layer::new(move |span| {
+                let Some(budget) = budgets.get(span.name).copied() else {
+                    return;
+                };
+                if span.duration <= budget {
+                    return;
+                }
+
+                let violation = BudgetViolation {
+                    name: span.name,
+                    duration: span.duration,
+                    budget,
+                };
+                match action {
+                    BudgetAction::Panic => panic!("{violation}"),
+                    BudgetAction::Record => {
+                        let Ok(mut violations) = violations.lock() else {
+                            return err_msg!(ProfileError::MutexPoisoned);
+                        };
+                        violations.push(violation);
+                    }
+                }
+            })
+        };
+        Self { violations, inner }
+    }
+
+    /// Returns the violations recorded with `BudgetAction::Record`.
+    pub fn violations(&self) -> Vec<BudgetViolation> {
+        match self.violations.lock() {
+            Ok(violations) => violations.clone(),
+            Err(_) => {
+                err_msg!(ProfileError::MutexPoisoned);
+                vec![]
+            }
+        }
+    }
+}
+
+impl crate::Flush for Layer {}
+
+callback::forward_to_callback!(Layer);
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    fn spans() {
+        let _scope = debug_span!("db_query").entered();
+        std::thread::sleep(Duration::from_millis(10));
+        drop(debug_span!("cache_lookup").entered());
+    }
+
+    fn budgets() -> HashMap<String, Duration> {
+        HashMap::from([
+            ("db_query".to_string(), Duration::from_millis(5)),
+            ("cache_lookup".to_string(), Duration::from_secs(1)),
+        ])
+    }
+
+    #[test]
+    fn record_violation() {
+        let dispatch = tracing::Dispatch::new(
+            tracing_subscriber::registry()
+                .with(Layer::with_action(budgets(), BudgetAction::Record)),
+        );
+        tracing::dispatcher::with_default(&dispatch, spans);
+
+        let violations = dispatch.downcast_ref::<Layer>().unwrap().violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].name, "db_query");
+        assert_eq!(violations[0].budget, Duration::from_millis(5));
+        assert!(violations[0].duration >= Duration::from_millis(10));
+    }
+
+    #[test]
+    #[should_panic(expected = "span `db_query` took")]
+    fn panic_on_violation() {
+        let subscriber = tracing_subscriber::registry().with(Layer::new(budgets()));
+        tracing::subscriber::with_default(subscriber, spans);
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{collections::BTreeMap, time::Instant};
+
+use tracing::span;
+
+use crate::{
+    data::{with_span_storage_mut, CallbackMetadata, ClosedSpan, FieldVisitor},
+    err_msg, ProfileError,
+};
+
+/// CallbackLayer (internally called layer::callback)
+/// This Layer calls the given function for every closed span, which makes it easy to export spans
+/// to arbitrary sinks without implementing `tracing_subscriber::Layer`.
+///
+/// example:
+/// ```
+/// use tracing_profile::CallbackLayer;
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///     .with(CallbackLayer::new(|span| {
+///         println!("{} took {:?}", span.name, span.duration)
+///     }))
+///     .init();
+/// ```
+pub struct Layer {
+    callback: Box<dyn Fn(&ClosedSpan) + Send + Sync>,
+}
+
+impl Layer {
+    pub fn new(callback: impl Fn(&ClosedSpan) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Box::new(callback),
+        }
+    }
+}
+
+impl crate::Flush for Layer {}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_new_span"
+            });
+        };
+
+        let parent_depth = span
+            .parent()
+            .as_ref()
+            .and_then(|p| p.extensions().get::<CallbackMetadata>().map(|x| x.depth))
+            .unwrap_or_default();
+
+        let mut storage = CallbackMetadata {
+            start_time: None,
+            duration: Default::default(),
+            depth: parent_depth + 1,
+            fields: BTreeMap::new(),
+        };
+        attrs.record(&mut FieldVisitor::new(&mut storage.fields));
+
+        span.extensions_mut().insert(storage);
+    }
+
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |storage: &mut CallbackMetadata| {
+            values.record(&mut FieldVisitor::new(&mut storage.fields));
+        });
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |storage: &mut CallbackMetadata| {
+            storage.start_time.replace(Instant::now());
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |storage: &mut CallbackMetadata| {
+            if let Some(start_time) = storage.start_time.take() {
+                storage.duration += start_time.elapsed();
+            }
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(&id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_close"
+            });
+        };
+        let Some(storage) = span.extensions_mut().remove::<CallbackMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_close"
+            });
+        };
+
+        (self.callback)(&ClosedSpan {
+            id: id.into_u64(),
+            parent_id: span.parent().map(|p| p.id().into_u64()),
+            name: span.name(),
+            duration: storage.duration,
+            depth: storage.depth,
+            fields: storage.fields,
+        });
+    }
+}
+
+/// Implements `tracing_subscriber::Layer` for a layer wrapping a `callback::Layer` in its `inner`
+/// field by forwarding every hook to it.
+macro_rules! forward_to_callback {
+    ($layer:ty) => {
+        impl<S> tracing_subscriber::Layer<S> for $layer
+        where
+            S: tracing::Subscriber,
+            S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+        {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                id: &tracing::span::Id,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                self.inner.on_new_span(attrs, id, ctx)
+            }
+
+            fn on_record(
+                &self,
+                id: &tracing::span::Id,
+                values: &tracing::span::Record<'_>,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                self.inner.on_record(id, values, ctx)
+            }
+
+            fn on_enter(
+                &self,
+                id: &tracing::span::Id,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                self.inner.on_enter(id, ctx)
+            }
+
+            fn on_exit(
+                &self,
+                id: &tracing::span::Id,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                self.inner.on_exit(id, ctx)
+            }
+
+            fn on_close(
+                &self,
+                id: tracing::span::Id,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                self.inner.on_close(id, ctx)
+            }
+        }
+    };
+}
+
+pub(crate) use forward_to_callback;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn fires_once_per_closed_span() {
+        let closed = Arc::new(Mutex::new(vec![]));
+        let layer = {
+            let closed = closed.clone();
+            Layer::new(move |span| closed.lock().unwrap().push(span.clone()))
+        };
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let root = debug_span!("root span");
+            let _root = root.enter();
+            let child = debug_span!("child span", field1 = "value1");
+            // entered twice, closed once
+            drop(child.enter());
+            drop(child.enter());
+        });
+
+        let closed = closed.lock().unwrap();
+        assert_eq!(closed.len(), 2);
+        let (child, root) = (&closed[0], &closed[1]);
+        assert_eq!(child.name, "child span");
+        assert_eq!(child.parent_id, Some(root.id));
+        assert_eq!(child.depth, 2);
+        assert_eq!(
+            child.fields,
+            BTreeMap::from([("field1".to_string(), "value1".to_string())])
+        );
+        assert_eq!(root.name, "root span");
+        assert_eq!(root.parent_id, None);
+        assert_eq!(root.depth, 1);
+        assert!(root.duration >= child.duration);
+    }
+}
@@ -1,11 +1,26 @@
-use std::io::Write;
-use std::path::Path;
-use std::sync::mpsc;
-use std::{collections::BTreeMap, time::Instant};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc, Arc, Mutex,
+};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+use thread_local::ThreadLocal;
+#[cfg(feature = "rfc3339")]
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tracing::span;
+use tracing_subscriber::registry::{ExtensionsMut, LookupSpan, SpanRef};
 
-use crate::data::{with_span_storage_mut, CsvMetadata, FieldVisitor};
-use crate::err_msg;
+use crate::data::{
+    os_thread_id, push_json_string, with_span_storage_mut, CsvMetadata, FieldValue, FieldVisitor,
+    ResourceUsage, SpanTimings, SpanUid,
+};
+use crate::layers::graph::parent_override;
+use crate::{err_msg, Flush, FlushHandle, ProfileError};
 
 /// CsvLayer (internally called layer::csv)  
 /// This Layer emits logs in CSV format, allowing for fine grained analysis.
@@ -44,36 +59,823 @@ use crate::err_msg;
 /// cat /tmp/output.csv
 ///
 /// id,parent_id,elapsed_ns,span_name,file_name,call_depth,metadata
-/// 2,1,3194,child span1,src/lib.rs,2,{"field1":"value1"}
-/// 4,3,1105,child span3,src/lib.rs,3,{"field3":"value3"}
-/// 5,3,1013,child span4,src/lib.rs,3,{"field4":"value4"}
-/// 3,1,34166,child span2,src/lib.rs,2,{"field2":"value2"}
+/// 2,1,3194,child span1,src/lib.rs,2,"{""field1"":""value1""}"
+/// 4,3,1105,child span3,src/lib.rs,3,"{""field3"":""value3""}"
+/// 5,3,1013,child span4,src/lib.rs,3,"{""field4"":""value4""}"
+/// 3,1,34166,child span2,src/lib.rs,2,"{""field2"":""value2""}"
 /// 1,0,79099,root span,src/lib.rs,1,{}
 /// ```
-
+///
+/// Rust tools can read the file back with `CsvReader`.
 pub struct Layer {
-    tx: mpsc::Sender<String>,
+    sink: Sink,
     init_time: Instant,
+    /// wall-clock time at `init_time`, the anchor of the RFC 3339 columns
+    #[cfg(feature = "rfc3339")]
+    init_wall_time: std::time::SystemTime,
+    config: Config,
+    next_seq: AtomicU64,
+    /// `Some` if `Config::summary_file` is set
+    summary: Option<NameSummary>,
+    /// `Some` if `Config::field_dictionary` is set
+    field_dictionary: Option<FieldDictionary>,
+    /// the rows held back until the root of their tree exits, by the id of the root, if
+    /// `Config::emit_only_above` is set
+    held_trees: Mutex<HashMap<u64, Vec<LogRow>>>,
+}
+
+/// CsvLayer configuration (internally called layer::csv::Config)
+#[derive(Debug)]
+pub struct Config {
+    /// Whether to prepend a `seq` column numbering the rows in the order the spans exited.
+    /// Rows are written by a background thread, so this recovers the exit order
+    /// deterministically.
+    pub sequence_number: bool,
+
+    /// String field values longer than this many characters are truncated and end with `…`.
+    pub max_field_len: Option<usize>,
+
+    /// Samples the resource usage of the process when a root span exits and appends it as the
+    /// `peak_rss` and `allocations` columns, which are empty for all other spans.
+    pub root_resources: Option<fn() -> ResourceUsage>,
+
+    /// Whether to write a `B` row when a span is entered and an `E` row when it exits, marked in
+    /// a `phase` column, instead of a single row on exit. Begin rows have `start_ns == end_ns`,
+    /// so consumers see spans which never exit.
+    pub phases: bool,
+
+    /// Spans without fields or children which took less than this are not written, to keep
+    /// only the interesting spans. Ignored if `phases` is set, since the begin row is written
+    /// before the duration is known.
+    pub skip_trivial: Option<Duration>,
+
+    /// Holds the rows of every tree back until its root exits, and drops them unless the root
+    /// took longer than this, e.g. to only write the slow requests of a server. The
+    /// `summary_file` only counts the rows which are written.
+    pub emit_only_above: Option<Duration>,
+
+    /// Whether to add a `schedule_latency_ns` column with the time between the creation of a span
+    /// and its first enter, e.g. the time a task waited in a queue before it was polled.
+    pub schedule_latency: bool,
+
+    /// Whether to add a `busy_ratio` column with the time a span was entered over the time from
+    /// its creation to its close, e.g. to spot I/O bound async spans in a spreadsheet. The ratio
+    /// is only known when the span closes, so the row of its last exit is held back until then,
+    /// and the rows of earlier exits leave the column empty.
+    pub busy_ratio: bool,
+
+    /// Whether to add `start_rfc3339` and `end_rfc3339` columns with the wall-clock time the
+    /// span was entered and exited in UTC, e.g. `2024-05-01T12:34:56.123456789Z`, to correlate
+    /// spans with logs. The `start_ns` and `end_ns` columns are kept for precise durations.
+    #[cfg(feature = "rfc3339")]
+    pub rfc3339: bool,
+
+    /// Writes an `id,offset` line with the byte offset of every row to this file, so tools can
+    /// seek to the row of a span without scanning the whole CSV file, see `CsvIndex`.
+    pub index_file: Option<PathBuf>,
+
+    /// Writes a `span_name,count,total_ns,mean_ns,max_ns` row per span name to this file when the
+    /// layer is flushed or dropped. The totals cover the spans written to the CSV file, so spans
+    /// skipped by `skip_trivial` aren't counted.
+    pub summary_file: Option<PathBuf>,
+
+    /// Whether the `id` and `parent_id` columns hold ids which are unique for the whole run
+    /// instead of the ids of `tracing`, which may be handed to a new span once a span closed.
+    /// Rows of different spans then never share an id, even in long runs, but the ids no longer
+    /// match `span.id()`.
+    pub unique_ids: bool,
+
+    /// Formats the `metadata` column, defaults to `{"key":"value"; ...}`.
+    pub field_serializer: Option<Box<dyn FieldSerializer>>,
+
+    /// Writes every distinct field map once to this file, as `field_set,metadata` rows, and
+    /// replaces the `metadata` column with a `field_set` column holding the id of the row's
+    /// field map. This keeps files small when many spans share large identical fields. Read
+    /// them back with `CsvReader::with_field_dictionary`.
+    pub field_dictionary: Option<PathBuf>,
+
+    /// Separates the cells, e.g. `;` for spreadsheets in locales which use `,` as the decimal
+    /// separator. Cells containing the delimiter are quoted.
+    pub delimiter: char,
+
+    /// Case of the column names in the header.
+    pub header_case: HeaderCase,
+
+    /// Whether every thread writes its rows to a file of its own, named after the thread id the
+    /// kernel reports, e.g. `output.4711.csv` for `output.csv`. The threads don't share a writer,
+    /// so they never wait for each other. The files are created when a thread first exits a span,
+    /// and the `index_file` isn't written.
+    pub per_thread_files: bool,
+
+    /// Whether to take the times a span is entered and exited from the `SpanTimings` which
+    /// `TimingLayer` records, instead of reading the clock again. `TimingLayer` must be added
+    /// before this layer. Spans without `SpanTimings`, or whose timings count cycles, are timed
+    /// by this layer as usual.
+    pub shared_timings: bool,
+
+    /// Whether spans which are created and closed without ever being entered get a row.
+    pub never_entered: NeverEntered,
+
+    /// How the rows are encoded, CSV text by default.
+    pub format: RowFormat,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sequence_number: false,
+            max_field_len: None,
+            root_resources: None,
+            phases: false,
+            skip_trivial: None,
+            emit_only_above: None,
+            schedule_latency: false,
+            busy_ratio: false,
+            #[cfg(feature = "rfc3339")]
+            rfc3339: false,
+            index_file: None,
+            summary_file: None,
+            unique_ids: false,
+            field_serializer: None,
+            field_dictionary: None,
+            delimiter: ',',
+            header_case: HeaderCase::Lower,
+            per_thread_files: false,
+            shared_timings: false,
+            never_entered: NeverEntered::Skip,
+            format: RowFormat::Csv,
+        }
+    }
+}
+
+/// Case of the column names in the header of the CSV file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderCase {
+    /// `span_name`
+    #[default]
+    Lower,
+    /// `SPAN_NAME`
+    Upper,
+    /// `Span_name`
+    Capitalized,
+}
+
+impl HeaderCase {
+    fn apply(self, column: &str) -> String {
+        match self {
+            HeaderCase::Lower => column.into(),
+            HeaderCase::Upper => column.to_uppercase(),
+            HeaderCase::Capitalized => {
+                let mut chars = column.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// What to do with spans which are closed without ever being entered, e.g. the span of a future
+/// which was dropped before it was polled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NeverEntered {
+    /// They don't exit, so they have no row.
+    #[default]
+    Skip,
+    /// Writes a row when they close, with `start_ns == end_ns` at their creation, and adds a
+    /// `never_entered` column which is `true` for these rows and `false` for all others.
+    Emit,
+}
+
+/// How `CsvLayer` encodes the rows.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RowFormat {
+    /// A line of text per row, below the header.
+    #[default]
+    Csv,
+    /// Every row as a little-endian `u32` length followed by the row as a `CsvRow` encoded with
+    /// postcard, without a header. The records are smaller and cheaper to write than CSV lines,
+    /// for spans which exit at a high frequency. Read them back with `BinaryReader`.
+    ///
+    /// The `busy_ratio` and `never_entered` columns aren't encoded, and the fields are always
+    /// encoded as a map, so `field_serializer` and `field_dictionary` are ignored.
+    #[cfg(feature = "binary")]
+    Binary,
+}
+
+/// Formats the fields of a span into the `metadata` cell, e.g. to match your analysis tooling.
+///
+/// The returned string is written as is, so it must be a valid CSV cell: quote it if it can
+/// contain the delimiter, quotes or newlines.
+pub trait FieldSerializer: Send + Sync {
+    fn serialize(&self, fields: &BTreeMap<String, FieldValue>) -> String;
+}
+
+impl std::fmt::Debug for dyn FieldSerializer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FieldSerializer")
+    }
+}
+
+/// The distinct field maps written to `Config::field_dictionary`, by their metadata cell.
+#[derive(Clone)]
+struct FieldDictionary(Arc<Mutex<FieldSets>>);
+
+struct FieldSets {
+    ids: HashMap<String, u64>,
+    out: BufWriter<std::fs::File>,
+}
+
+impl FieldDictionary {
+    fn create(path: &Path, delimiter: char) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(std::fs::File::create(path)?);
+        writeln!(out, "field_set{delimiter}metadata")?;
+        Ok(Self(Arc::new(Mutex::new(FieldSets {
+            ids: HashMap::new(),
+            out,
+        }))))
+    }
+
+    /// Returns the id of the fields, adding them to the dictionary if they are new.
+    fn id(&self, metadata: String, delimiter: char) -> Option<u64> {
+        let Ok(mut dictionary) = self.0.lock() else {
+            err_msg!(ProfileError::MutexPoisoned);
+            return None;
+        };
+        let FieldSets { ids, out } = &mut *dictionary;
+        if let Some(id) = ids.get(&metadata) {
+            return Some(*id);
+        }
+        let id = ids.len() as u64;
+        if let Err(e) = writeln!(out, "{id}{delimiter}{metadata}") {
+            err_msg!(ProfileError::WriteFailed(e.kind()));
+        }
+        ids.insert(metadata, id);
+        Some(id)
+    }
+
+    fn flush(&self) {
+        let Ok(mut dictionary) = self.0.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        if let Err(e) = dictionary.out.flush() {
+            err_msg!(ProfileError::WriteFailed(e.kind()));
+        }
+    }
+}
+
+/// Aggregated durations of the spans with the same name.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct NameStats {
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+}
+
+type NameSummary = Arc<Mutex<BTreeMap<String, NameStats>>>;
+
+/// Writes the summary rows, replacing the previous summary.
+fn write_summary(path: &Path, summary: &NameSummary) -> std::io::Result<()> {
+    // a panicking thread may hold the lock, the stats are still worth writing
+    let summary = summary.lock().unwrap_or_else(|e| e.into_inner());
+    let mut f = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(f, "span_name,count,total_ns,mean_ns,max_ns")?;
+    for (name, stats) in summary.iter() {
+        writeln!(
+            f,
+            "{},{},{},{},{}",
+            escape(name, ','),
+            stats.count,
+            stats.total_ns,
+            stats.total_ns / stats.count,
+            stats.max_ns
+        )?;
+    }
+    f.flush()
+}
+
+enum Sink {
+    /// rows are written by a background thread
+    Channel(mpsc::Sender<Message>),
+    /// rows are written inline by the thread exiting the span
+    Synchronous(Arc<Mutex<RowWriter<Box<dyn Write + Send>>>>),
+    /// rows are written inline to the file of the thread exiting the span
+    PerThread {
+        path: PathBuf,
+        header: String,
+        writers: Arc<ThreadLocal<Mutex<Option<ThreadFile>>>>,
+    },
+}
+
+/// The file of a thread. `ThreadLocal` hands the slot of an exited thread to the next new one,
+/// which opens a file of its own.
+struct ThreadFile {
+    thread: std::thread::ThreadId,
+    writer: RowWriter<BufWriter<std::fs::File>>,
+}
+
+impl ThreadFile {
+    fn create(path: &Path, header: &str) -> std::io::Result<Self> {
+        let file = std::fs::File::create(thread_path(path, os_thread_id()))?;
+        let mut writer = RowWriter {
+            out: BufWriter::new(file),
+            offset: 0,
+            index: None,
+        };
+        writer.write(header.as_bytes())?;
+        Ok(Self {
+            thread: std::thread::current().id(),
+            writer,
+        })
+    }
+}
+
+/// The file of the thread with the given id for `path`, e.g. `output.4711.csv` for `output.csv`.
+fn thread_path(path: &Path, tid: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => {
+            path.with_file_name(format!("{stem}.{tid}.{}", extension.to_string_lossy()))
+        }
+        None => path.with_file_name(format!("{stem}.{tid}")),
+    }
+}
+
+/// Messages to the background writer thread.
+enum Message {
+    Row {
+        id: u64,
+        row: Vec<u8>,
+    },
+    /// Acknowledged once all rows sent before have been written and synced.
+    Flush(mpsc::Sender<()>),
 }
 
 impl Layer {
     pub fn new<T: AsRef<Path>>(output_file: T) -> Self {
+        Self::new_with_config(output_file, Config::default())
+    }
+
+    pub fn new_with_config<T: AsRef<Path>>(output_file: T, config: Config) -> Self {
+        if config.per_thread_files {
+            return Self::with_sink(
+                Sink::PerThread {
+                    path: output_file.as_ref().into(),
+                    header: LogRow::header(&config),
+                    writers: Default::default(),
+                },
+                config,
+            );
+        }
         // this should panic. that way the user doesn't waste a bunch of time running their program just to find out there is no log file.
-        let mut f = std::fs::File::create(output_file).expect("CsvLogger failed to open file");
-        let (tx, rx) = mpsc::channel::<String>();
+        let f = std::fs::File::create(output_file).expect("CsvLogger failed to open file");
+        Self::spawn_writer(f, std::fs::File::sync_all, config)
+    }
+
+    /// Creates a layer whose background thread writes the rows to `writer` instead of a file,
+    /// e.g. an in-memory buffer, a socket or stderr.
+    pub fn with_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Self::with_writer_and_config(writer, Config::default())
+    }
+
+    /// `per_thread_files` is reported and ignored, since there is no path to derive the files of
+    /// the threads from.
+    pub fn with_writer_and_config<W: Write + Send + 'static>(writer: W, config: Config) -> Self {
+        if config.per_thread_files {
+            err_msg!(ProfileError::IgnoredOption {
+                option: "per_thread_files",
+                context: "CsvLayer::with_writer_and_config"
+            });
+        }
+        Self::spawn_writer(writer, |_| Ok(()), config)
+    }
+
+    /// Blocks until every row of the spans which exited before has been written and synced to
+    /// disk, e.g. before a checkpoint or `std::process::exit`. The summary file is rewritten too.
+    pub fn flush(&self) {
+        if let Some(handle) = self.flush_handle() {
+            handle.flush();
+        }
+    }
+
+    /// Returns a guard which flushes the layer when it is dropped, like `flush`. The guard
+    /// doesn't borrow the layer, so it can be kept at the end of `main` after the layer was
+    /// installed in a subscriber which is never dropped. Dropping the guard blocks until the
+    /// writer thread has written and synced every row queued before.
+    pub fn flush_guard(&self) -> FlushGuard {
+        FlushGuard(self.flush_handle())
+    }
+
+    /// Spawns the thread which writes the rows to `out`, and syncs it with `sync` when the layer
+    /// is flushed.
+    fn spawn_writer<W: Write + Send + 'static>(
+        out: W,
+        sync: fn(&W) -> std::io::Result<()>,
+        config: Config,
+    ) -> Self {
+        let mut writer = RowWriter::new(out, &config);
+        let (tx, rx) = mpsc::channel::<Message>();
+        let header = LogRow::header(&config);
         std::thread::spawn(move || {
-            let _ = f.write(LogRow::header().as_bytes());
+            let _ = writer.write(header.as_bytes());
             while let Ok(msg) = rx.recv() {
-                let _ = f.write(msg.as_bytes());
+                match msg {
+                    Message::Row { id, row } => {
+                        let _ = writer.write_row(id, &row);
+                    }
+                    Message::Flush(ack) => {
+                        let _ = writer.flush();
+                        let _ = sync(&writer.out);
+                        let _ = ack.send(());
+                    }
+                }
             }
 
-            let _ = f.sync_all();
+            let _ = writer.flush();
+            let _ = sync(&writer.out);
+        });
+        Self::with_sink(Sink::Channel(tx), config)
+    }
+
+    /// Creates a layer that writes each row to `writer` from within `on_exit` instead of
+    /// spawning a background thread, for environments where threads aren't available or wanted.
+    ///
+    /// The write happens under a `Mutex` while the span is exiting, so the cost of formatting
+    /// and writing the row (and any contention on the lock) is added to the measured time of
+    /// the parent span. Wrap slow writers in a `BufWriter` to keep this small.
+    pub fn new_synchronous<W: Write + Send + 'static>(writer: W) -> Self {
+        Self::new_synchronous_with_config(writer, Config::default())
+    }
+
+    pub fn new_synchronous_with_config<W: Write + Send + 'static>(
+        writer: W,
+        config: Config,
+    ) -> Self {
+        let mut writer = RowWriter::new(Box::new(writer) as Box<dyn Write + Send>, &config);
+        let _ = writer.write(LogRow::header(&config).as_bytes());
+        Self::with_sink(Sink::Synchronous(Arc::new(Mutex::new(writer))), config)
+    }
+
+    fn with_sink(sink: Sink, config: Config) -> Self {
+        let field_dictionary = config.field_dictionary.as_ref().map(|path| {
+            FieldDictionary::create(path, config.delimiter)
+                .expect("CsvLogger failed to open field dictionary")
         });
         Self {
-            tx,
+            sink,
             init_time: Instant::now(),
+            #[cfg(feature = "rfc3339")]
+            init_wall_time: std::time::SystemTime::now(),
+            summary: config.summary_file.as_ref().map(|_| Default::default()),
+            field_dictionary,
+            config,
+            next_seq: AtomicU64::new(0),
+            held_trees: Mutex::default(),
+        }
+    }
+
+    /// The id written to the `parent_id` column of the span, `None` for roots.
+    fn parent_id<'a, S>(
+        &self,
+        ctx: &'a tracing_subscriber::layer::Context<'_, S>,
+        span: &SpanRef<'a, S>,
+        fields: &BTreeMap<String, FieldValue>,
+    ) -> Option<u64>
+    where
+        S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        if !self.config.unique_ids {
+            return parent_override(fields).or_else(|| span.parent().map(|p| p.id().into_u64()));
+        }
+        let parent = match parent_override(fields) {
+            Some(parent_id) => ctx.span(&span::Id::from_u64(parent_id)),
+            None => span.parent(),
+        };
+        parent.as_ref().and_then(SpanUid::of)
+    }
+
+    fn log_row<'a, S>(
+        &self,
+        ctx: &'a tracing_subscriber::layer::Context<'_, S>,
+        span: &SpanRef<'a, S>,
+        storage: &CsvMetadata,
+        phase: Option<Phase>,
+        start_ns: u64,
+        end_ns: u64,
+    ) -> LogRow
+    where
+        S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        let parent_id = self.parent_id(ctx, span, &storage.fields);
+        LogRow {
+            delimiter: self.config.delimiter,
+            seq: self
+                .config
+                .sequence_number
+                .then(|| self.next_seq.fetch_add(1, Ordering::Relaxed)),
+            phase,
+            id: storage.id,
+            parent_id: parent_id.unwrap_or_default(),
+            span_name: span.name().into(),
+            file_name: span
+                .metadata()
+                .file()
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            start_ns,
+            end_ns,
+            #[cfg(feature = "rfc3339")]
+            rfc3339: self
+                .config
+                .rfc3339
+                .then(|| (self.wall_clock(start_ns), self.wall_clock(end_ns))),
+            #[cfg(not(feature = "rfc3339"))]
+            rfc3339: None,
+            schedule_latency: self
+                .config
+                .schedule_latency
+                .then_some(storage.schedule_latency),
+            busy_ratio: self.config.busy_ratio.then_some(None),
+            never_entered: (self.config.never_entered == NeverEntered::Emit).then_some(false),
+            thread_id: format!("{:?}", std::thread::current().id()),
+            thread_name: format!("{:?}", std::thread::current().name()),
+            call_depth: storage.call_depth,
+            metadata: match self.config.format {
+                RowFormat::Csv => self.metadata_cell(&storage.fields),
+                #[cfg(feature = "binary")]
+                RowFormat::Binary => String::new(),
+            },
+            #[cfg(feature = "binary")]
+            fields: match self.config.format {
+                RowFormat::Csv => BTreeMap::new(),
+                RowFormat::Binary => storage
+                    .fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.to_string()))
+                    .collect(),
+            },
+            resources: self
+                .config
+                .root_resources
+                .map(|sample| match (parent_id, phase) {
+                    (None, None | Some(Phase::End)) => sample(),
+                    _ => ResourceUsage::default(),
+                }),
+        }
+    }
+
+    /// The `metadata` cell of the fields, or the `field_set` cell if they are in the dictionary.
+    fn metadata_cell(&self, fields: &BTreeMap<String, FieldValue>) -> String {
+        let metadata = match &self.config.field_serializer {
+            Some(serializer) => serializer.serialize(fields),
+            None => escape(&format_fields(fields), self.config.delimiter).into_owned(),
+        };
+        match &self.field_dictionary {
+            Some(dictionary) => dictionary
+                .id(metadata, self.config.delimiter)
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            None => metadata,
+        }
+    }
+
+    fn is_trivial(&self, storage: &CsvMetadata, elapsed_ns: u64) -> bool {
+        match self.config.skip_trivial {
+            Some(min_duration) if !self.config.phases => {
+                storage.fields.is_empty()
+                    && !storage.has_children
+                    && Duration::from_nanos(elapsed_ns) < min_duration
+            }
+            _ => false,
         }
     }
+
+    /// Holds the row of an exit back until the span exits again or closes, which fills in the
+    /// busy ratio, and writes the row held back before.
+    fn hold<S>(
+        &self,
+        span: &SpanRef<'_, S>,
+        extensions: &mut ExtensionsMut<'_>,
+        row: LogRow,
+        busy_ns: u64,
+    ) where
+        S: for<'lookup> LookupSpan<'lookup>,
+    {
+        match extensions.get_mut::<HeldRow>() {
+            Some(held) => {
+                let previous = std::mem::replace(&mut held.row, row);
+                held.busy_ns += busy_ns;
+                self.emit_in_tree(span, previous);
+            }
+            None => extensions.insert(HeldRow { row, busy_ns }),
+        }
+    }
+
+    /// Writes the row, or holds it back with the other rows of its tree if `emit_only_above` is
+    /// set. The rows of a tree are written when the exit row of its root arrives and the root
+    /// took longer than the threshold, and dropped otherwise.
+    fn emit_in_tree<S>(&self, span: &SpanRef<'_, S>, row: LogRow)
+    where
+        S: for<'lookup> LookupSpan<'lookup>,
+    {
+        let Some(threshold) = self.config.emit_only_above else {
+            return self.emit_row(&row);
+        };
+        let root = span.scope().last().map_or(span.id(), |root| root.id());
+        let Ok(mut trees) = self.held_trees.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        if root != span.id() || row.phase == Some(Phase::Begin) {
+            return trees.entry(root.into_u64()).or_default().push(row);
+        }
+        let rows = trees.remove(&root.into_u64()).unwrap_or_default();
+        drop(trees);
+        if Duration::from_nanos(row.end_ns - row.start_ns) <= threshold {
+            return;
+        }
+        for row in rows.iter().chain([&row]) {
+            if row.phase != Some(Phase::Begin) && row.never_entered != Some(true) {
+                self.add_to_summary(row);
+            }
+            self.emit_row(row);
+        }
+    }
+
+    /// Drops the rows of the tree of a closing root which arrived after its last exit.
+    fn drop_held_tree(&self, root: &span::Id) {
+        if self.config.emit_only_above.is_none() {
+            return;
+        }
+        let Ok(mut trees) = self.held_trees.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        trees.remove(&root.into_u64());
+    }
+
+    /// The current time in nanoseconds since `init_time`, taken from the timings of the span if
+    /// they are shared.
+    fn now(&self, timings: Option<&SpanTimings>) -> u64 {
+        let now = timings
+            .filter(|_| self.config.shared_timings)
+            .and_then(SpanTimings::last_transition)
+            .unwrap_or_else(Instant::now);
+        now.saturating_duration_since(self.init_time).as_nanos() as u64
+    }
+
+    /// Formats a time relative to `init_time` as RFC 3339.
+    #[cfg(feature = "rfc3339")]
+    fn wall_clock(&self, ns: u64) -> String {
+        let time = OffsetDateTime::from(self.init_wall_time + Duration::from_nanos(ns));
+        time.format(&Rfc3339).unwrap_or_default()
+    }
+
+    fn add_to_summary(&self, log_row: &LogRow) {
+        let Some(summary) = &self.summary else {
+            return;
+        };
+        let Ok(mut summary) = summary.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        let elapsed_ns = log_row.end_ns - log_row.start_ns;
+        let stats = summary.entry(log_row.span_name.clone()).or_default();
+        stats.count += 1;
+        stats.total_ns += elapsed_ns;
+        stats.max_ns = stats.max_ns.max(elapsed_ns);
+    }
+
+    /// Returns a handle which blocks until every row of the spans which exited before has been
+    /// written.
+    fn rows_flush_handle(&self) -> FlushHandle {
+        match &self.sink {
+            Sink::Channel(tx) => {
+                let tx = tx.clone();
+                FlushHandle::new(move || {
+                    let (ack, done) = mpsc::channel();
+                    if tx.send(Message::Flush(ack)).is_ok() {
+                        let _ = done.recv();
+                    }
+                })
+            }
+            Sink::Synchronous(writer) => {
+                let writer = writer.clone();
+                FlushHandle::new(move || {
+                    let Ok(mut writer) = writer.lock() else {
+                        return err_msg!(ProfileError::MutexPoisoned);
+                    };
+                    if let Err(e) = writer.flush() {
+                        err_msg!(ProfileError::WriteFailed(e.kind()));
+                    }
+                })
+            }
+            Sink::PerThread { writers, .. } => {
+                let writers = writers.clone();
+                FlushHandle::new(move || {
+                    for file in writers.iter() {
+                        let Ok(mut file) = file.lock() else {
+                            return err_msg!(ProfileError::MutexPoisoned);
+                        };
+                        if let Some(Err(e)) = file.as_mut().map(|file| file.writer.flush()) {
+                            err_msg!(ProfileError::WriteFailed(e.kind()));
+                        }
+                    }
+                })
+            }
+        }
+    }
+
+    fn emit_row(&self, row: &LogRow) {
+        let bytes = match self.config.format {
+            RowFormat::Csv => format!("{row}\n").into_bytes(),
+            #[cfg(feature = "binary")]
+            RowFormat::Binary => row.to_binary(),
+        };
+        self.emit(row.id, bytes);
+    }
+
+    fn emit(&self, id: u64, row: Vec<u8>) {
+        match &self.sink {
+            Sink::Channel(tx) => {
+                let _ = tx.send(Message::Row { id, row });
+            }
+            Sink::Synchronous(writer) => {
+                let Ok(mut writer) = writer.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                if let Err(e) = writer.write_row(id, &row) {
+                    err_msg!(ProfileError::WriteFailed(e.kind()));
+                }
+            }
+            Sink::PerThread {
+                path,
+                header,
+                writers,
+            } => {
+                let Ok(mut file) = writers.get_or_default().lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                let thread = std::thread::current().id();
+                if !matches!(&*file, Some(file) if file.thread == thread) {
+                    match ThreadFile::create(path, header) {
+                        Ok(created) => *file = Some(created),
+                        Err(e) => return err_msg!(ProfileError::WriteFailed(e.kind())),
+                    }
+                }
+                if let Some(Err(e)) = file.as_mut().map(|file| file.writer.write_row(id, &row)) {
+                    err_msg!(ProfileError::WriteFailed(e.kind()));
+                }
+            }
+        }
+    }
+}
+
+/// Flushes a `CsvLayer` when dropped, see `CsvLayer::flush_guard`.
+#[must_use = "the layer is flushed when the guard is dropped"]
+pub struct FlushGuard(Option<FlushHandle>);
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            handle.flush();
+        }
+    }
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        if let (Some(path), Some(summary)) = (&self.config.summary_file, &self.summary) {
+            if let Err(e) = write_summary(path, summary) {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        }
+    }
+}
+
+impl Flush for Layer {
+    /// Blocks until every row of the spans which exited before has been written, then writes the
+    /// summary rows, if any.
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let rows = self.rows_flush_handle();
+        // the field sets are written before the rows which refer to them
+        let rows = match self.field_dictionary.clone() {
+            Some(dictionary) => FlushHandle::new(move || {
+                dictionary.flush();
+                rows.flush();
+            }),
+            None => rows,
+        };
+        let (Some(path), Some(summary)) = (self.config.summary_file.clone(), self.summary.clone())
+        else {
+            return Some(rows);
+        };
+        Some(FlushHandle::new(move || {
+            rows.flush();
+            if let Err(e) = write_summary(&path, &summary) {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        }))
+    }
 }
 
 impl<S> tracing_subscriber::Layer<S> for Layer
@@ -97,57 +899,78 @@ where
         values: &span::Record<'_>,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
         with_span_storage_mut(id, ctx, |storage: &mut CsvMetadata| {
-            let mut visitor = FieldVisitor(&mut storage.fields);
+            let mut visitor =
+                FieldVisitor::with_max_len(&mut storage.fields, self.config.max_field_len);
             values.record(&mut visitor);
         });
     }
 
     fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        with_span_storage_mut::<CsvMetadata, _>(id, ctx, |storage| {
-            storage
-                .start_time
-                .replace(self.init_time.elapsed().as_nanos() as u64);
-        });
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_enter"
+            });
+        };
+        let mut extensions = span.extensions_mut();
+        let start_time = self.now(extensions.get_mut::<SpanTimings>().as_deref());
+        let Some(storage) = extensions.get_mut::<CsvMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_enter"
+            });
+        };
+        storage.start_time.replace(start_time);
+        storage
+            .schedule_latency
+            .get_or_insert(start_time.saturating_sub(storage.created));
+        if self.config.phases {
+            let log_row = self.log_row(
+                &ctx,
+                &span,
+                storage,
+                Some(Phase::Begin),
+                start_time,
+                start_time,
+            );
+            self.emit_in_tree(&span, log_row);
+        }
     }
 
     fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
         if let Some(span) = ctx.span(id) {
-            let parent = span.parent();
-            if let Some(storage) = span.extensions_mut().get_mut::<CsvMetadata>() {
-                let end_time = self.init_time.elapsed().as_nanos() as u64;
+            let mut extensions = span.extensions_mut();
+            let end_time = self.now(extensions.get_mut::<SpanTimings>().as_deref());
+            if let Some(storage) = extensions.get_mut::<CsvMetadata>() {
                 let start_time = storage.start_time.unwrap_or(end_time);
-                let thread_id = format!("{:?}", std::thread::current().id());
-                let thread_name = format!("{:?}", std::thread::current().name());
-
-                let fields = std::mem::take(&mut storage.fields);
-
-                let log_row = LogRow {
-                    id: span.id().into_u64(),
-                    parent_id: parent
-                        .as_ref()
-                        .map(|p| p.id().into_u64())
-                        .unwrap_or_default(),
-                    span_name: span.name().into(),
-                    file_name: span
-                        .metadata()
-                        .file()
-                        .map(|x| x.to_string())
-                        .unwrap_or_default(),
-                    start_ns: start_time,
-                    end_ns: end_time,
-                    thread_id,
-                    thread_name,
-                    call_depth: storage.call_depth,
-                    fields,
-                };
-                let msg = format!("{log_row}\n");
-                let _ = self.tx.send(msg);
+                if self.is_trivial(storage, end_time - start_time) {
+                    storage.fields.clear();
+                    return;
+                }
+                let phase = self.config.phases.then_some(Phase::End);
+                let log_row = self.log_row(&ctx, &span, storage, phase, start_time, end_time);
+                storage.fields.clear();
+                // the rows held back are counted once they are written
+                if self.config.emit_only_above.is_none() {
+                    self.add_to_summary(&log_row);
+                }
+                if self.config.busy_ratio {
+                    return self.hold(&span, &mut extensions, log_row, end_time - start_time);
+                }
+                self.emit_in_tree(&span, log_row);
             } else {
-                err_msg!("failed to get storage on_exit");
+                err_msg!(ProfileError::StorageMissing { context: "on_exit" });
             }
         } else {
-            err_msg!("failed to get span on_exit");
+            err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
         }
     }
 
@@ -157,34 +980,168 @@ where
         id: &span::Id,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
         let Some(span) = ctx.span(id) else {
-            err_msg!("failed to get span on_new_span");
+            err_msg!(ProfileError::SpanNotFound {
+                context: "on_new_span"
+            });
             return;
         };
 
-        let parent_call_depth = span
-            .parent()
+        let mut fields = BTreeMap::new();
+        // warning: the library user must use #[instrument(skip_all)] or else too much data will be logged
+        let mut visitor = FieldVisitor::with_max_len(&mut fields, self.config.max_field_len);
+        attrs.record(&mut visitor);
+
+        let parent = match parent_override(&fields) {
+            Some(parent_id) => ctx.span(&span::Id::from_u64(parent_id)),
+            None => span.parent(),
+        };
+        let parent_call_depth = parent
             .as_ref()
-            .and_then(|p| p.extensions().get::<CsvMetadata>().map(|x| x.call_depth))
+            .and_then(|p| {
+                let mut extensions = p.extensions_mut();
+                let parent = extensions.get_mut::<CsvMetadata>()?;
+                parent.has_children = true;
+                Some(parent.call_depth)
+            })
             .unwrap_or_default();
 
-        let mut storage = CsvMetadata {
+        let storage = CsvMetadata {
+            id: match self.config.unique_ids {
+                true => SpanUid::assign(&span),
+                false => id.into_u64(),
+            },
             start_time: None,
+            created: self.init_time.elapsed().as_nanos() as u64,
+            schedule_latency: None,
             call_depth: parent_call_depth + 1,
-            fields: BTreeMap::new(),
+            fields,
+            has_children: false,
         };
 
-        // warning: the library user must use #[instrument(skip_all)] or else too much data will be logged
-        let mut visitor = FieldVisitor(&mut storage.fields);
-        attrs.record(&mut visitor);
-
         let mut extensions = span.extensions_mut();
         extensions.insert(storage);
     }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(&id, &ctx) {
+            return;
+        }
+        if !self.config.busy_ratio && self.config.never_entered == NeverEntered::Skip {
+            return self.drop_held_tree(&id);
+        }
+        let Some(span) = ctx.span(&id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_close"
+            });
+        };
+        let mut extensions = span.extensions_mut();
+        let held = extensions.remove::<HeldRow>();
+        let Some(storage) = extensions.get_mut::<CsvMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_close"
+            });
+        };
+        if storage.start_time.is_none() && self.config.never_entered == NeverEntered::Emit {
+            let phase = self.config.phases.then_some(Phase::End);
+            let created = storage.created;
+            let mut row = self.log_row(&ctx, &span, storage, phase, created, created);
+            row.never_entered = Some(true);
+            if self.config.busy_ratio {
+                row.busy_ratio = Some(Some(0.0));
+            }
+            return self.emit_in_tree(&span, row);
+        }
+        // spans which never exited have no row
+        let Some(HeldRow { mut row, busy_ns }) = held else {
+            return self.drop_held_tree(&id);
+        };
+        let wall_ns = self.now(None).saturating_sub(storage.created);
+        let ratio = match wall_ns {
+            0 => 0.0,
+            _ => (busy_ns as f64 / wall_ns as f64).min(1.0),
+        };
+        row.busy_ratio = Some(Some(ratio));
+        self.emit_in_tree(&span, row);
+    }
+}
+
+/// The row of the last exit of a span, held back until its busy ratio is known.
+struct HeldRow {
+    row: LogRow,
+    /// the time the span was entered, summed over its exits
+    busy_ns: u64,
+}
+
+/// Writes rows to the CSV file and their offsets to the index file, if any.
+struct RowWriter<W> {
+    out: W,
+    /// number of bytes written to `out`
+    offset: u64,
+    index: Option<BufWriter<std::fs::File>>,
+}
+
+impl<W: Write> RowWriter<W> {
+    fn new(out: W, config: &Config) -> Self {
+        let index = config.index_file.as_ref().map(|path| {
+            let file = std::fs::File::create(path).expect("CsvLogger failed to open index file");
+            let mut index = BufWriter::new(file);
+            let _ = index.write_all(b"id,offset\n");
+            index
+        });
+        Self {
+            out,
+            offset: 0,
+            index,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_row(&mut self, id: u64, row: &[u8]) -> std::io::Result<()> {
+        if let Some(index) = &mut self.index {
+            writeln!(index, "{id},{}", self.offset)?;
+        }
+        self.write(row)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()?;
+        match &mut self.index {
+            Some(index) => index.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Value of the `phase` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Begin,
+    End,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Begin => f.write_str("B"),
+            Phase::End => f.write_str("E"),
+        }
+    }
 }
 
 #[derive(Debug)]
 struct LogRow {
+    delimiter: char,
+    seq: Option<u64>,
+    phase: Option<Phase>,
     id: u64,
     parent_id: u64,
     span_name: String,
@@ -192,42 +1149,1179 @@ struct LogRow {
     call_depth: u64,
     start_ns: u64,
     end_ns: u64,
+    /// `Some` if the RFC 3339 columns are written
+    rfc3339: Option<(String, String)>,
+    /// `Some` if the column is written, with an empty value for spans which were never entered
+    schedule_latency: Option<Option<u64>>,
+    /// `Some` if the column is written, with an empty value until the span closes
+    busy_ratio: Option<Option<f64>>,
+    /// `Some` if the column is written
+    never_entered: Option<bool>,
     thread_id: String,
     thread_name: String,
+    metadata: String,
+    /// the fields of binary rows, which have no `metadata` cell
+    #[cfg(feature = "binary")]
     fields: BTreeMap<String, String>,
+    /// `Some` if the resource columns are written, with empty values for non-root spans
+    resources: Option<ResourceUsage>,
 }
 
 impl LogRow {
-    fn header<'a>() -> &'a str {
-        "id,parent_id,elapsed_ns,start_ns,end_ns,thread_id,thread_name,span_name,file_name,call_depth,metadata\n"
+    fn header(config: &Config) -> String {
+        if config.format != RowFormat::Csv {
+            return String::new();
+        }
+        let mut columns = vec![];
+        if config.sequence_number {
+            columns.push("seq");
+        }
+        if config.phases {
+            columns.push("phase");
+        }
+        columns.extend(["id", "parent_id", "elapsed_ns", "start_ns", "end_ns"]);
+        #[cfg(feature = "rfc3339")]
+        if config.rfc3339 {
+            columns.extend(["start_rfc3339", "end_rfc3339"]);
+        }
+        if config.schedule_latency {
+            columns.push("schedule_latency_ns");
+        }
+        if config.busy_ratio {
+            columns.push("busy_ratio");
+        }
+        if config.never_entered == NeverEntered::Emit {
+            columns.push("never_entered");
+        }
+        columns.extend([
+            "thread_id",
+            "thread_name",
+            "span_name",
+            "file_name",
+            "call_depth",
+            match config.field_dictionary {
+                Some(_) => "field_set",
+                None => "metadata",
+            },
+        ]);
+        if config.root_resources.is_some() {
+            columns.extend(["peak_rss", "allocations"]);
+        }
+        let columns: Vec<_> = columns
+            .into_iter()
+            .map(|column| config.header_case.apply(column))
+            .collect();
+        format!("{}\n", columns.join(&config.delimiter.to_string()))
+    }
+}
+
+#[cfg(feature = "binary")]
+impl LogRow {
+    /// The length-prefixed record of `RowFormat::Binary`.
+    fn to_binary(&self) -> Vec<u8> {
+        let resources = self.resources.unwrap_or_default();
+        let row = crate::CsvRow {
+            seq: self.seq,
+            phase: self.phase.map(|phase| phase.to_string()),
+            id: self.id,
+            parent_id: self.parent_id,
+            elapsed_ns: self.end_ns - self.start_ns,
+            start_ns: self.start_ns,
+            end_ns: self.end_ns,
+            start_rfc3339: self.rfc3339.as_ref().map(|(start, _)| start.clone()),
+            end_rfc3339: self.rfc3339.as_ref().map(|(_, end)| end.clone()),
+            schedule_latency_ns: self.schedule_latency.flatten(),
+            thread_id: self.thread_id.clone(),
+            thread_name: self.thread_name.clone(),
+            span_name: self.span_name.clone(),
+            file_name: self.file_name.clone(),
+            call_depth: self.call_depth,
+            fields: self.fields.clone(),
+            field_set: None,
+            peak_rss: resources.peak_rss,
+            allocations: resources.allocations,
+        };
+        // the length is filled in once the row is encoded behind it
+        let mut record = postcard::to_extend(&row, vec![0; 4]).expect("CsvRow is serializable");
+        let len = (record.len() - 4) as u32;
+        record[..4].copy_from_slice(&len.to_le_bytes());
+        record
     }
 }
 
 impl std::fmt::Display for LogRow {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let kv: Vec<_> = self
-            .fields
-            .iter()
-            .map(|(k, v)| format!("\"{k}\":\"{v}\""))
-            .collect();
-        // desired: a json string that pandas can parse
-        // needs the outer quote ' marks to be omitted
-        // the comma is replaced with a semicolon to ensure pandas doesn't interpret it as a new column
-        let fields = format!("{{{}}}", kv.join("; "));
+        let d = self.delimiter;
+        if let Some(seq) = self.seq {
+            write!(f, "{seq}{d}")?;
+        }
+        if let Some(phase) = self.phase {
+            write!(f, "{phase}{d}")?;
+        }
         write!(
             f,
-            "{},{},{},{},{},{},{},{},{},{},{}",
+            "{}{d}{}{d}{}{d}{}{d}{}{d}",
             self.id,
             self.parent_id,
             self.end_ns - self.start_ns,
             self.start_ns,
             self.end_ns,
+        )?;
+        if let Some((start, end)) = &self.rfc3339 {
+            write!(f, "{start}{d}{end}{d}")?;
+        }
+        if let Some(latency) = self.schedule_latency {
+            let cell = latency.map(|x| x.to_string()).unwrap_or_default();
+            write!(f, "{cell}{d}")?;
+        }
+        if let Some(ratio) = self.busy_ratio {
+            let cell = ratio.map(|x| format!("{x:.4}")).unwrap_or_default();
+            write!(f, "{cell}{d}")?;
+        }
+        if let Some(never_entered) = self.never_entered {
+            write!(f, "{never_entered}{d}")?;
+        }
+        write!(
+            f,
+            "{}{d}{}{d}{}{d}{}{d}{}{d}{}",
             self.thread_id,
-            self.thread_name,
-            self.span_name,
-            self.file_name,
+            escape(&self.thread_name, d),
+            escape(&self.span_name, d),
+            escape(&self.file_name, d),
             self.call_depth,
-            fields
-        )
+            self.metadata
+        )?;
+        if let Some(resources) = self.resources {
+            let cell = |x: Option<u64>| x.map(|x| x.to_string()).unwrap_or_default();
+            write!(
+                f,
+                "{d}{}{d}{}",
+                cell(resources.peak_rss),
+                cell(resources.allocations)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a cell which contains the delimiter, a quote or a line break, doubling the quotes
+/// inside it, as RFC 4180 requires.
+pub(crate) fn escape(cell: &str, delimiter: char) -> Cow<'_, str> {
+    let quote = cell.contains([delimiter, '"', '\n', '\r']);
+    match quote {
+        true => Cow::Owned(format!("\"{}\"", cell.replace('"', "\"\""))),
+        false => Cow::Borrowed(cell),
+    }
+}
+
+/// A field value in the metadata column.
+pub(crate) trait MetadataValue {
+    fn to_json(&self) -> String;
+}
+
+/// Fields recorded as strings, whose type is unknown.
+impl MetadataValue for String {
+    fn to_json(&self) -> String {
+        let mut json = String::new();
+        push_json_string(&mut json, self);
+        json
+    }
+}
+
+impl MetadataValue for FieldValue {
+    fn to_json(&self) -> String {
+        FieldValue::to_json(self)
+    }
+}
+
+/// Formats the fields for the metadata column.
+pub(crate) fn format_fields<V: MetadataValue>(fields: &BTreeMap<String, V>) -> String {
+    let kv: Vec<_> = fields
+        .iter()
+        .map(|(k, v)| {
+            let mut entry = String::new();
+            push_json_string(&mut entry, k);
+            format!("{entry}:{}", v.to_json())
+        })
+        .collect();
+    // desired: a json string that pandas can parse
+    // needs the outer quote ' marks to be omitted
+    // the comma is replaced with a semicolon to ensure pandas doesn't interpret it as a new column
+    format!("{{{}}}", kv.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::layers::shared_buffer::SharedBuffer;
+
+    /// Collects written bytes and checks they are written from the expected thread.
+    #[derive(Clone)]
+    struct TestWriter {
+        buffer: SharedBuffer,
+        thread: std::thread::ThreadId,
+    }
+
+    impl Write for TestWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            assert_eq!(std::thread::current().id(), self.thread);
+            self.buffer.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A synchronous layer writing to a buffer from the current thread.
+    fn synchronous_layer(config: Config) -> (Layer, Arc<Mutex<Vec<u8>>>) {
+        let writer = TestWriter {
+            buffer: SharedBuffer::default(),
+            thread: std::thread::current().id(),
+        };
+        let buffer = writer.buffer.0.clone();
+        (Layer::new_synchronous_with_config(writer, config), buffer)
+    }
+
+    #[test]
+    fn synchronous() {
+        let (layer, buffer) = synchronous_layer(Config::default());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = debug_span!("root span");
+            drop(span.enter());
+
+            // the row is already written when the span exits
+            let output = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+            let lines: Vec<_> = output.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert_eq!(
+                format!("{}\n", lines[0]),
+                LogRow::header(&Config::default())
+            );
+            assert!(lines[1].contains(",root span,"));
+        });
+    }
+
+    #[test]
+    fn with_writer() {
+        #[derive(Clone, Default)]
+        struct BackgroundWriter(SharedBuffer);
+
+        impl Write for BackgroundWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                // rows are written by the background thread
+                assert_eq!(std::thread::current().name(), None);
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let writer = BackgroundWriter::default();
+        let layer = Layer::with_writer(writer.clone());
+        let flush = layer.flush_handle().unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            drop(debug_span!("child span").entered());
+        });
+        flush.flush();
+
+        let output = writer.0.output();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 3, "{output}");
+        assert_eq!(
+            format!("{}\n", lines[0]),
+            LogRow::header(&Config::default())
+        );
+        assert!(lines[1].contains(",child span,"));
+        assert!(lines[2].contains(",root span,"));
+    }
+
+    #[test]
+    fn writer_ignores_per_thread_files() {
+        let errors = crate::error::collected_errors();
+        let config = Config {
+            per_thread_files: true,
+            ..Default::default()
+        };
+        drop(Layer::with_writer_and_config(std::io::sink(), config));
+
+        let ignored = ProfileError::IgnoredOption {
+            option: "per_thread_files",
+            context: "CsvLayer::with_writer_and_config",
+        };
+        assert!(errors.lock().unwrap().contains(&ignored));
+    }
+
+    #[test]
+    fn flush_guard() {
+        let path = std::env::temp_dir().join("tracing_profile_flush_guard.csv");
+        let layer = Layer::new(&path);
+        let guard = layer.flush_guard();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _root = debug_span!("root span").entered();
+            for _ in 0..100 {
+                drop(debug_span!("child span").entered());
+            }
+        });
+        drop(guard);
+
+        // the layer is still installed, but every row is on disk
+        assert_eq!(crate::CsvReader::open(&path).unwrap().count(), 101);
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            drop(debug_span!("checkpoint").entered());
+            dispatch.downcast_ref::<Layer>().unwrap().flush();
+        });
+        assert_eq!(crate::CsvReader::open(&path).unwrap().count(), 102);
+    }
+
+    #[test]
+    fn sequence_number() {
+        let config = Config {
+            sequence_number: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            for _ in 0..3 {
+                drop(debug_span!("child span").entered());
+            }
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let seq: Vec<_> = rows.iter().map(|row| row.seq).collect();
+        assert_eq!(seq, [Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(rows[3].span_name, "root span");
+    }
+
+    #[test]
+    fn quoting() {
+        let (layer, buffer) = synchronous_layer(Config::default());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(debug_span!("load \"a\", b", msg = "a,b\"c").entered());
+            drop(debug_span!("line\nbreak").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let text = String::from_utf8(output.clone()).unwrap();
+        assert!(text.contains(r#","load ""a"", b","#), "{text}");
+        assert!(text.contains(r#","{""msg"":""a,b\""c""}""#), "{text}");
+
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].span_name, "load \"a\", b");
+        assert_eq!(rows[0].fields["msg"], "a,b\"c");
+        assert_eq!(rows[0].file_name, file!());
+        assert_eq!(rows[1].span_name, "line\nbreak");
+    }
+
+    #[test]
+    fn typed_fields() {
+        let (layer, buffer) = synchronous_layer(Config::default());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(
+                debug_span!(
+                    "typed",
+                    count = 42u64,
+                    delta = -1i64,
+                    ratio = 0.5,
+                    enabled = true,
+                    name = "42",
+                    level = %tracing::Level::INFO
+                )
+                .entered(),
+            );
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let text = String::from_utf8(output.clone()).unwrap();
+        let metadata = text.lines().nth(1).unwrap().rsplit_once(",\"{").unwrap().1;
+        assert_eq!(
+            metadata,
+            r#"""count"":42; ""delta"":-1; ""enabled"":true; ""level"":""INFO""; ""name"":""42""; ""ratio"":0.5}""#
+        );
+
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let fields: Vec<_> = rows[0]
+            .fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("count", "42"),
+                ("delta", "-1"),
+                ("enabled", "true"),
+                ("level", "INFO"),
+                ("name", "42"),
+                ("ratio", "0.5")
+            ]
+        );
+    }
+
+    #[test]
+    fn max_field_len() {
+        let config = Config {
+            max_field_len: Some(5),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = debug_span!("root span", short = "abc", long = "SELECT * FROM t").entered();
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows[0].fields["short"], "abc");
+        assert_eq!(rows[0].fields["long"], "SELEC…");
+    }
+
+    #[test]
+    fn root_resources() {
+        let config = Config {
+            root_resources: Some(ResourceUsage::sample),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            drop(debug_span!("child span").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows[0].peak_rss, None);
+        assert_eq!(rows[1].allocations, None);
+        #[cfg(unix)]
+        {
+            // somewhere between a megabyte and a terabyte
+            let peak_rss = rows[1].peak_rss.unwrap();
+            assert!((1 << 20..1 << 40).contains(&peak_rss), "{peak_rss}");
+        }
+    }
+
+    #[test]
+    fn debug_value_capped() {
+        /// Debug-only value which counts how many of its elements were formatted.
+        struct Huge(std::sync::atomic::AtomicUsize);
+
+        impl std::fmt::Debug for Huge {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                for _ in 0..1_000_000 {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                    f.write_str("0123456789")?;
+                }
+                Ok(())
+            }
+        }
+
+        let config = Config {
+            max_field_len: Some(15),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let huge = Huge(Default::default());
+        tracing::subscriber::with_default(subscriber, || {
+            drop(debug_span!("root span", huge = ?huge).entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows[0].fields["huge"], "012345678901234…");
+        // formatting stopped as soon as the value was known to be truncated
+        assert_eq!(huge.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn field_serializer() {
+        struct KeyValue;
+
+        impl FieldSerializer for KeyValue {
+            fn serialize(&self, fields: &BTreeMap<String, FieldValue>) -> String {
+                let kv: Vec<_> = fields.iter().map(|(k, v)| format!("{k}={v}")).collect();
+                kv.join("|")
+            }
+        }
+
+        let config = Config {
+            field_serializer: Some(Box::new(KeyValue)),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(debug_span!("root span", a = 1, b = "two").entered());
+        });
+
+        let output = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+        let row = output.lines().nth(1).unwrap();
+        assert!(
+            row.ends_with(",root span,src/layers/csv.rs,1,a=1|b=two"),
+            "{row}"
+        );
+    }
+
+    #[test]
+    fn phases() {
+        let config = Config {
+            phases: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            drop(debug_span!("child span").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let phases: Vec<_> = rows
+            .iter()
+            .map(|row| (row.phase.as_deref().unwrap(), row.span_name.as_str()))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                ("B", "root span"),
+                ("B", "child span"),
+                ("E", "child span"),
+                ("E", "root span"),
+            ]
+        );
+        assert_eq!(rows[0].id, rows[3].id);
+        assert_eq!(rows[1].id, rows[2].id);
+        assert_eq!(rows[1].start_ns, rows[1].end_ns);
+        assert_eq!(rows[1].start_ns, rows[2].start_ns);
+    }
+
+    #[test]
+    fn skip_trivial() {
+        let config = Config {
+            skip_trivial: Some(Duration::from_millis(5)),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            drop(debug_span!("trivial").entered());
+            drop(debug_span!("with fields", x = 1).entered());
+            let slow = debug_span!("slow").entered();
+            std::thread::sleep(Duration::from_millis(10));
+            drop(slow);
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let names: Vec<_> = rows.iter().map(|row| row.span_name.as_str()).collect();
+        // the root is kept because it has children
+        assert_eq!(names, ["with fields", "slow", "root span"]);
+    }
+
+    #[test]
+    fn emit_only_above() {
+        let path = std::env::temp_dir().join("tracing_profile_emit_only_above_summary.csv");
+        let config = Config {
+            emit_only_above: Some(Duration::from_millis(20)),
+            summary_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let flush = layer.flush_handle().unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for (root, sleep) in [("fast", 0), ("slow", 30), ("fast", 0)] {
+                let _root = debug_span!("root", kind = root).entered();
+                let _child = debug_span!("child").entered();
+                std::thread::sleep(Duration::from_millis(sleep));
+            }
+        });
+        flush.flush();
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let names: Vec<_> = rows.iter().map(|row| row.span_name.as_str()).collect();
+        assert_eq!(names, ["child", "root"]);
+        assert_eq!(rows[1].fields["kind"], "slow");
+
+        let summary = std::fs::read_to_string(&path).unwrap();
+        let counts: Vec<_> = summary
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').take(2).collect::<Vec<_>>().join(","))
+            .collect();
+        assert_eq!(counts, ["child,1", "root,1"]);
+    }
+
+    #[test]
+    fn index_file() {
+        let dir = std::env::temp_dir();
+        let (path, index_path) = (
+            dir.join("tracing_profile_index.csv"),
+            dir.join("tracing_profile.idx"),
+        );
+        let config = Config {
+            index_file: Some(index_path.clone()),
+            ..Default::default()
+        };
+        let layer = Layer::new_with_config(&path, config);
+        let flush = layer.flush_handle().unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let mut target = 0;
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            // keep the spans open so their ids aren't reused
+            let spans: Vec<_> = (0..10).map(|i| debug_span!("child span", i)).collect();
+            for span in &spans {
+                drop(span.enter());
+            }
+            target = spans[5].id().unwrap().into_u64();
+        });
+        flush.flush();
+
+        let index = crate::CsvIndex::open(&index_path).unwrap();
+        let mut reader = crate::CsvReader::open(&path).unwrap();
+        reader.seek(index.offset(target).unwrap()).unwrap();
+        let row = reader.next().unwrap().unwrap();
+        assert_eq!(row.id, target);
+        assert_eq!(row.fields["i"], "5");
+    }
+
+    #[test]
+    fn schedule_latency() {
+        let config = Config {
+            schedule_latency: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let queued = debug_span!("queued");
+            std::thread::sleep(Duration::from_millis(20));
+            drop(queued.enter());
+            drop(debug_span!("immediate").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let queued = rows[0].schedule_latency_ns.unwrap();
+        let immediate = rows[1].schedule_latency_ns.unwrap();
+        assert!(queued >= 20_000_000, "{queued}");
+        assert!(immediate < 20_000_000, "{immediate}");
+    }
+
+    #[cfg(feature = "rfc3339")]
+    #[test]
+    fn rfc3339() {
+        let config = Config {
+            rfc3339: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let before = OffsetDateTime::now_utc();
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            drop(debug_span!("child span").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for row in &rows {
+            let parse = |x: &Option<String>| {
+                OffsetDateTime::parse(x.as_deref().unwrap(), &Rfc3339).unwrap()
+            };
+            let (start, end) = (parse(&row.start_rfc3339), parse(&row.end_rfc3339));
+            assert!(before <= start && start <= end, "{row:?}");
+            assert_eq!((end - start).whole_nanoseconds() as u64, row.elapsed_ns);
+        }
+    }
+
+    #[test]
+    fn summary_file() {
+        let path = std::env::temp_dir().join("tracing_profile_summary.csv");
+        let config = Config {
+            summary_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let flush = layer.flush_handle().unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            for i in 0..3 {
+                let _child = debug_span!("child span").entered();
+                std::thread::sleep(Duration::from_millis(i));
+            }
+        });
+        flush.flush();
+
+        let output = buffer.lock().unwrap().clone();
+        let mut expected = BTreeMap::<String, NameStats>::new();
+        for row in crate::CsvReader::new(output.as_slice()) {
+            let row = row.unwrap();
+            let stats = expected.entry(row.span_name).or_default();
+            stats.count += 1;
+            stats.total_ns += row.elapsed_ns;
+            stats.max_ns = stats.max_ns.max(row.elapsed_ns);
+        }
+
+        let summary = std::fs::read_to_string(&path).unwrap();
+        let mut lines = summary.lines();
+        assert_eq!(
+            lines.next(),
+            Some("span_name,count,total_ns,mean_ns,max_ns")
+        );
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(name, stats)| {
+                format!(
+                    "{name},{},{},{},{}",
+                    stats.count,
+                    stats.total_ns,
+                    stats.total_ns / stats.count,
+                    stats.max_ns
+                )
+            })
+            .collect();
+        assert_eq!(lines.collect::<Vec<_>>(), expected);
+        assert!(expected[0].starts_with("child span,3,"));
+    }
+
+    #[test]
+    fn unique_ids() {
+        let config = Config {
+            unique_ids: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = crate::layers::reused_ids::ReusedIds::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            let child = |parent_id: u64| {
+                drop(
+                    debug_span!(parent: None, "child span", profile.parent_id = parent_id)
+                        .entered(),
+                )
+            };
+            let first = debug_span!("first span");
+            let id = first.id().unwrap().into_u64();
+            first.in_scope(|| child(id));
+            drop(first);
+            let second = debug_span!("second span");
+            assert_eq!(second.id().unwrap().into_u64(), id);
+            second.in_scope(|| child(id));
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let names: Vec<_> = rows.iter().map(|row| row.span_name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "child span",
+                "first span",
+                "child span",
+                "second span",
+                "root span"
+            ]
+        );
+        let ids: std::collections::HashSet<_> = rows.iter().map(|row| row.id).collect();
+        assert_eq!(ids.len(), rows.len());
+        assert_eq!(rows[0].parent_id, rows[1].id);
+        assert_eq!(rows[2].parent_id, rows[3].id);
+        assert_eq!(rows[1].parent_id, rows[4].id);
+    }
+
+    #[test]
+    fn delimiter() {
+        let config = Config {
+            delimiter: ';',
+            header_case: HeaderCase::Capitalized,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(debug_span!("a;b", list = "1;2", price = "1,5").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let text = String::from_utf8(output.clone()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Id;Parent_id;Elapsed_ns;Start_ns;End_ns;Thread_id;Thread_name;Span_name;File_name;\
+             Call_depth;Metadata"
+        );
+        assert!(lines[1]
+            .contains(r#";"a;b";src/layers/csv.rs;1;"{""list"":""1;2""; ""price"":""1,5""}""#));
+
+        let rows = crate::CsvReader::new(output.as_slice())
+            .with_delimiter(';')
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows[0].span_name, "a;b");
+        assert_eq!(rows[0].fields["list"], "1;2");
+        assert_eq!(rows[0].fields["price"], "1,5");
+    }
+
+    /// Collects the busy time `TimingLayer` measured for every closed span.
+    struct BusyTimes(Arc<Mutex<Vec<Duration>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for BusyTimes
+    where
+        S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let busy = span.extensions().get::<SpanTimings>().unwrap().busy;
+            self.0.lock().unwrap().push(busy);
+        }
+    }
+
+    #[test]
+    fn shared_timings() {
+        let busy = Arc::new(Mutex::new(vec![]));
+        let config = Config {
+            shared_timings: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TimingLayer::default())
+            .with(layer)
+            .with(BusyTimes(busy.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            std::thread::sleep(Duration::from_millis(1));
+            drop(debug_span!("child span").entered());
+        });
+
+        let output = buffer.lock().unwrap().clone();
+        let rows = crate::CsvReader::new(output.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let elapsed: Vec<_> = rows
+            .iter()
+            .map(|row| Duration::from_nanos(row.end_ns - row.start_ns))
+            .collect();
+        // the same instants as the timings, a measurement of its own would differ
+        assert_eq!(elapsed, *busy.lock().unwrap());
+        assert!(elapsed[1] >= Duration::from_millis(1));
+    }
+
+    /// Sleeps on every poll and returns `Pending` until polled `polls` times.
+    struct Polled {
+        polls: usize,
+    }
+
+    impl std::future::Future for Polled {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<()> {
+            std::thread::sleep(Duration::from_millis(5));
+            self.polls -= 1;
+            if self.polls == 0 {
+                std::task::Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn busy_ratio() {
+        use std::future::Future;
+        use tracing::Instrument;
+
+        let config = Config {
+            busy_ratio: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let mut task =
+                std::pin::pin!(Polled { polls: 3 }.instrument(debug_span!("polled task")));
+            let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+            while task.as_mut().poll(&mut cx).is_pending() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let output = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+        let mut lines = output.lines();
+        let header: Vec<_> = lines.next().unwrap().split(',').collect();
+        let column = |name: &str| header.iter().position(|x| *x == name).unwrap();
+        let rows: Vec<Vec<_>> = lines.map(|line| line.split(',').collect()).collect();
+        // one row per poll, and one for dropping the future within the span
+        assert_eq!(rows.len(), 4);
+        let (last, earlier) = rows.split_last().unwrap();
+        // only the row written at the close has the ratio
+        assert!(earlier
+            .iter()
+            .all(|row| row[column("busy_ratio")].is_empty()));
+        let ratio: f64 = last[column("busy_ratio")].parse().unwrap();
+        assert!(0.0 < ratio && ratio < 1.0, "{ratio}");
+
+        let busy: u64 = rows
+            .iter()
+            .map(|row| row[column("elapsed_ns")].parse::<u64>().unwrap())
+            .sum();
+        let first_start: u64 = rows[0][column("start_ns")].parse().unwrap();
+        let last_end: u64 = last[column("end_ns")].parse().unwrap();
+        // the span lives a little longer than from its first enter to its last exit
+        let upper = busy as f64 / (last_end - first_start) as f64;
+        assert!(ratio <= upper + 0.0001, "{ratio} > {upper}");
+        assert!(ratio > upper - 0.05, "{ratio} < {upper}");
+        // 15ms polled out of 35ms
+        assert!((0.3..0.6).contains(&ratio), "{ratio}");
+    }
+
+    #[test]
+    fn per_thread_files() {
+        let dir = std::env::temp_dir().join("tracing_profile_per_thread");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            per_thread_files: true,
+            ..Default::default()
+        };
+        let dispatch = tracing::Dispatch::new(
+            tracing_subscriber::registry()
+                .with(Layer::new_with_config(dir.join("output.csv"), config)),
+        );
+        std::thread::scope(|scope| {
+            for i in 0..2 {
+                let dispatch = &dispatch;
+                std::thread::Builder::new()
+                    .name(format!("worker {i}"))
+                    .spawn_scoped(scope, move || {
+                        tracing::dispatcher::with_default(dispatch, || {
+                            for _ in 0..3 {
+                                drop(debug_span!("work", worker = i).entered());
+                            }
+                        })
+                    })
+                    .unwrap();
+            }
+        });
+        // flushes the files
+        drop(dispatch);
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 2, "{files:?}");
+        let mut workers = vec![];
+        for file in files {
+            let name = file.file_name().unwrap().to_string_lossy().into_owned();
+            let tid = name
+                .strip_prefix("output.")
+                .and_then(|x| x.strip_suffix(".csv"))
+                .unwrap_or_else(|| panic!("{name}"));
+            assert!(tid.parse::<u64>().is_ok(), "{name}");
+
+            let rows = crate::CsvReader::open(&file)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(rows.len(), 3);
+            // every row of a file was written by the same thread
+            let worker = &rows[0].fields["worker"];
+            assert!(rows.iter().all(|row| row.fields["worker"] == *worker
+                && row.thread_name == format!("Some(\"worker {worker}\")")));
+            workers.push(worker.clone());
+        }
+        workers.sort();
+        assert_eq!(workers, ["0", "1"]);
+    }
+
+    #[test]
+    fn field_dictionary() {
+        let path = std::env::temp_dir().join("tracing_profile_field_dictionary.csv");
+        let dictionary = std::env::temp_dir().join("tracing_profile_field_dictionary.fields.csv");
+        let config = Config {
+            field_dictionary: Some(dictionary.clone()),
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let flush = crate::Flush::flush_handle(&layer).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                drop(debug_span!("request", client = "a,b", large = "x".repeat(100)).entered());
+            }
+            drop(debug_span!("other", client = "c").entered());
+        });
+        flush.flush();
+        std::fs::write(&path, buffer.lock().unwrap().as_slice()).unwrap();
+
+        let entries = std::fs::read_to_string(&dictionary).unwrap();
+        let entries: Vec<_> = entries.lines().collect();
+        assert_eq!(entries.len(), 3, "{entries:?}");
+        assert_eq!(entries[0], "field_set,metadata");
+        assert!(entries[1].starts_with(r#"0,"{""client"":""a,b""; ""large"":""xxx"#));
+        assert_eq!(entries[2], r#"1,"{""client"":""c""}""#);
+
+        let rows = crate::CsvReader::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let field_sets: Vec<_> = rows.iter().map(|row| row.field_set).collect();
+        assert_eq!(field_sets, [Some(0), Some(0), Some(0), Some(1)]);
+        assert!(rows.iter().all(|row| row.fields.is_empty()));
+
+        let rows = crate::CsvReader::open(&path)
+            .unwrap()
+            .with_field_dictionary(&dictionary)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows[2].fields["client"], "a,b");
+        assert_eq!(rows[2].fields["large"].len(), 100);
+        assert_eq!(rows[3].fields["client"], "c");
+    }
+
+    #[test]
+    fn never_entered() {
+        let rows = |never_entered| {
+            let config = Config {
+                never_entered,
+                ..Default::default()
+            };
+            let (layer, buffer) = synchronous_layer(config);
+            let subscriber = tracing_subscriber::registry().with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                drop(debug_span!("entered").entered());
+                drop(debug_span!("cancelled", task = 1));
+            });
+            let output = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+            output.lines().map(String::from).collect::<Vec<_>>()
+        };
+
+        let skipped = rows(NeverEntered::Skip);
+        assert_eq!(skipped.len(), 2, "{skipped:?}");
+        assert!(!skipped[0].contains("never_entered"));
+        assert!(skipped[1].contains(",entered,"));
+
+        let emitted = rows(NeverEntered::Emit);
+        assert_eq!(emitted.len(), 3, "{emitted:?}");
+        let header: Vec<_> = emitted[0].split(',').collect();
+        let column = header.iter().position(|c| *c == "never_entered").unwrap();
+        let cells = |row: &str| row.split(',').map(String::from).collect::<Vec<_>>();
+        let entered = cells(&emitted[1]);
+        assert_eq!(
+            entered[header.iter().position(|c| *c == "span_name").unwrap()],
+            "entered"
+        );
+        assert_eq!(entered[column], "false");
+        let cancelled = cells(&emitted[2]);
+        assert_eq!(cancelled[column], "true");
+        assert!(emitted[2].contains(",cancelled,"));
+        assert!(emitted[2].ends_with(r#""{""task"":1}""#));
+        // elapsed_ns, start_ns == end_ns
+        assert_eq!(cancelled[2], "0");
+        assert_eq!(cancelled[3], cancelled[4]);
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn binary_format() {
+        let config = Config {
+            format: RowFormat::Binary,
+            schedule_latency: true,
+            ..Default::default()
+        };
+        let (layer, buffer) = synchronous_layer(config);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            for i in 0..2 {
+                drop(debug_span!("child span", i, name = "a,b\n").entered());
+            }
+        });
+
+        let buffer = buffer.lock().unwrap();
+        let rows: Vec<_> = crate::BinaryReader::new(buffer.as_slice())
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(rows.len(), 3, "{rows:?}");
+        let root = &rows[2];
+        assert_eq!(root.span_name, "root span");
+        assert_eq!((root.parent_id, root.call_depth), (0, 1));
+        assert!(root.fields.is_empty());
+        assert!(root.schedule_latency_ns.is_some());
+        for (i, child) in rows[..2].iter().enumerate() {
+            assert_eq!(child.span_name, "child span");
+            assert_eq!((child.parent_id, child.call_depth), (root.id, 2));
+            assert_eq!(child.elapsed_ns, child.end_ns - child.start_ns);
+            assert!(child.start_ns >= root.start_ns && child.end_ns <= root.end_ns);
+            assert!(child.file_name.ends_with("csv.rs"));
+            assert_eq!(child.fields["i"], i.to_string());
+            assert_eq!(child.fields["name"], "a,b\n");
+        }
+
+        // a truncated record is an error rather than the end of the rows
+        let truncated = crate::BinaryReader::new(&buffer[..buffer.len() - 1]);
+        assert!(truncated.last().unwrap().is_err());
+        // so is a corrupt length, without allocating it
+        let mut corrupt = u32::MAX.to_le_bytes().to_vec();
+        corrupt.extend_from_slice(&buffer[4..]);
+        let error = crate::BinaryReader::new(corrupt.as_slice())
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
     }
 }
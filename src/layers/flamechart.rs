@@ -0,0 +1,192 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::ThreadId,
+    time::Instant,
+};
+
+use tracing::span;
+
+use crate::{err_msg, Flush, FlushHandle, ProfileError};
+
+/// FlamechartLayer (internally called layer::flamechart)
+/// This Layer writes the time spent in every stack of spans as timestamped folded stacks, in the
+/// order the spans ran:
+/// ```bash
+/// 0 root span 1520
+/// 1520 root span;child span1 3194
+/// 4714 root span 880
+/// ```
+///
+/// Every line holds the start of the interval in nanoseconds since the layer was created, the
+/// stack of entered spans on the thread and the length of the interval in nanoseconds. The lines
+/// of each thread are ordered by their timestamps. Drop the timestamps to render a time-ordered
+/// flamechart with inferno:
+/// ```bash
+/// cut -d' ' -f2- trace.folded | inferno-flamegraph --flamechart > flamechart.svg
+/// ```
+pub struct Layer {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    threads: Mutex<HashMap<ThreadId, ThreadStack>>,
+    init_time: Instant,
+}
+
+/// The spans entered on a thread.
+#[derive(Default)]
+struct ThreadStack {
+    names: Vec<&'static str>,
+    /// nanoseconds since `init_time` at which `names` became the stack
+    since: u64,
+}
+
+impl Layer {
+    pub fn new<T: AsRef<Path>>(output_file: T) -> Self {
+        // this should panic, like `CsvLayer`, so the user doesn't run their program for nothing
+        let f = std::fs::File::create(output_file).expect("FlamechartLayer failed to open file");
+        Self::with_writer(BufWriter::new(f))
+    }
+
+    /// Writes the folded stacks to `writer`. The lines are written while the spans are entered
+    /// and exited, so wrap slow writers in a `BufWriter`.
+    pub fn with_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            threads: Default::default(),
+            init_time: Instant::now(),
+        }
+    }
+
+    /// Writes the interval which ends now for the current stack of the thread and replaces the
+    /// stack with `update`.
+    fn transition(&self, update: impl FnOnce(&mut Vec<&'static str>)) {
+        let now = self.init_time.elapsed().as_nanos() as u64;
+        let Ok(mut threads) = self.threads.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        let stack = threads.entry(std::thread::current().id()).or_default();
+        if !stack.names.is_empty() && now > stack.since {
+            let Ok(mut writer) = self.writer.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            let line = format!(
+                "{} {} {}\n",
+                stack.since,
+                stack.names.join(";"),
+                now - stack.since
+            );
+            if let Err(e) = writer.write_all(line.as_bytes()) {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        }
+        update(&mut stack.names);
+        stack.since = now;
+    }
+}
+
+impl Flush for Layer {
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let writer = self.writer.clone();
+        Some(FlushHandle::new(move || {
+            let Ok(mut writer) = writer.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            if let Err(e) = writer.flush() {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        }))
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        // marks the spans created while profiling is paused, which are then left out
+        crate::pause::skip_new_span(id, &ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_enter"
+            });
+        };
+        self.transition(|names| names.push(span.name()));
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
+        };
+        self.transition(|names| {
+            // spans are usually exited in reverse order, but guards can be dropped in any order
+            if let Some(i) = names.iter().rposition(|name| *name == span.name()) {
+                names.remove(i);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::layers::shared_buffer::SharedBuffer;
+
+    #[test]
+    fn timestamped_stacks() {
+        let buffer = SharedBuffer::default();
+        let subscriber = tracing_subscriber::registry().with(Layer::with_writer(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root").entered();
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            {
+                let _child = debug_span!("child").entered();
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                drop(debug_span!("grandchild").entered());
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        });
+
+        let output = buffer.output();
+        let lines: Vec<(u64, &str, u64)> = output
+            .lines()
+            .map(|line| {
+                let (timestamp, rest) = line.split_once(' ').unwrap();
+                let (stack, duration) = rest.rsplit_once(' ').unwrap();
+                (timestamp.parse().unwrap(), stack, duration.parse().unwrap())
+            })
+            .collect();
+
+        let stacks: Vec<_> = lines.iter().map(|(_, stack, _)| *stack).collect();
+        assert_eq!(stacks[..2], ["root", "root;child"]);
+        assert_eq!(stacks[stacks.len() - 2..], ["root;child", "root"]);
+        // the grandchild is entered and exited right away, it may not take a measurable time
+        assert!(stacks.len() == 4 || stacks[2] == "root;child;grandchild");
+        for pair in lines.windows(2) {
+            let ((start, _, duration), (next_start, _, _)) = (pair[0], pair[1]);
+            assert!(start < next_start);
+            // the intervals follow each other without gaps
+            assert_eq!(start + duration, next_start);
+        }
+    }
+}
@@ -1,17 +1,102 @@
 // Copyright 2024 Ulvetanna Inc.
 use std::{
-    collections::{BTreeMap, HashMap},
-    sync::Mutex,
-    time::Instant,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ffi::OsString,
+    sync::{Arc, LockResult, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "perfetto")]
+use crate::data::PerfettoTraceWriter;
 use crate::{
-    data::{insert_to_span_storage, with_span_storage_mut, FieldVisitor, GraphMetadata, LogTree},
-    err_msg,
+    data::{
+        FieldVisitor, GraphMetadata, LockContention, LockStats, LogTree, ResourceUsage,
+        SpanTimings, SpanUid, StackProbe, TreeNode,
+    },
+    err_msg, ProfileError,
 };
-use tracing::span;
+use regex::Regex;
+use thread_local::ThreadLocal;
+use tracing::{span, Level};
+use tracing_subscriber::{
+    layer::SubscriberExt,
+    registry::{LookupSpan, SpanRef},
+};
+
+/// Target of the events emitted by `Sink::Tracing`. Events with this target are ignored by the
+/// layers of this crate.
+pub const TRACING_TARGET: &str = "tracing_profile";
+
+/// Fields starting with this prefix control the profiler and are not displayed, except for
+/// `CALLER_FIELD` which the profiler adds itself.
+const PROFILE_FIELD_PREFIX: &str = "profile.";
+
+/// Spans with `profile.expected_slow = true` are never displayed in the attention color.
+const EXPECTED_SLOW_FIELD: &str = "profile.expected_slow";
+
+/// Spans with `profile.kind = "io"`, `"compute"` or `"wait"` are colored by their `SpanKind`
+/// and their time is summarized per kind.
+const KIND_FIELD: &str = "profile.kind";
+
+/// Spans with `profile.root = true` are printed as the root of a tree even if they have a parent.
+const ROOT_FIELD: &str = "profile.root";
+
+/// Events with a `profile.phase = "name"` field mark the start of a phase and are displayed as a
+/// divider among the children of the span they occur in, e.g. `— phase: warmup complete —`.
+const PHASE_FIELD: &str = "profile.phase";
+
+/// The field holding the function which created a span if `Config::capture_caller` is set.
+const CALLER_FIELD: &str = "profile.caller";
+
+/// Numeric fields starting with this prefix, e.g. `count.rows`, are summed when calls are
+/// aggregated instead of listing their distinct values.
+const COUNT_FIELD_PREFIX: &str = "count.";
+
+/// Separates the distinct values of a field on nodes aggregating several calls.
+const FIELD_VALUE_SEPARATOR: &str = " | ";
+
+/// Spans with `profile.parent_id = id.into_u64()` are attached to the span with that id instead of
+/// their `tracing` parent, e.g. work submitted to a thread pool. The logical parent must still be
+/// open when the span exits.
+const PARENT_ID_FIELD: &str = "profile.parent_id";
+
+/// The span whose node `span` is attached to, `None` for the roots of trees.
+fn tree_parent<'a, S>(
+    config: &Config,
+    ctx: &'a tracing_subscriber::layer::Context<'_, S>,
+    span: &SpanRef<'a, S>,
+    fields: &BTreeMap<String, String>,
+) -> Option<SpanRef<'a, S>>
+where
+    S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    let is_designated_root = config.root_spans.contains(span.name())
+        || fields.get(ROOT_FIELD).is_some_and(|x| x == "true");
+    if is_designated_root {
+        return None;
+    }
+    match parent_override(fields) {
+        Some(parent_id) => ctx.span(&span::Id::from_u64(parent_id)),
+        None => span.parent(),
+    }
+}
+
+/// Returns the parent set with `profile.parent_id`, if any.
+pub(crate) fn parent_override<V: std::fmt::Display>(fields: &BTreeMap<String, V>) -> Option<u64> {
+    fields
+        .get(PARENT_ID_FIELD)
+        .and_then(|id| id.to_string().parse().ok())
+        .filter(|id| *id != 0)
+}
 
+/// PrintTreeLayer configuration (internally called layer::graph::Config)
+///
+/// New options are added over time, so start from `Config::default()` and override the options
+/// you care about, e.g. `Config::default().with_hide_below_percent(0.5)`.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct Config {
     /// Display anything above this percentage in bold red
     pub attention_above_percent: f64,
@@ -24,282 +109,4040 @@ pub struct Config {
     /// This is checked after duplicate calls below relevant_above_percent are aggregated.
     pub hide_below_percent: f64,
 
+    /// Absolute alternative to `attention_above_percent`, which is ignored when this is set.
+    pub attention_above: Option<std::time::Duration>,
+
+    /// Absolute alternative to `relevant_above_percent`, which is ignored when this is set.
+    pub relevant_above: Option<std::time::Duration>,
+
+    /// Absolute alternative to `hide_below_percent`, which is ignored when this is set.
+    pub hide_below: Option<std::time::Duration>,
+
     /// Whether to display parent time minus time of all children as
     /// `[unaccounted]`. Useful to sanity check that you are measuring all the bottlenecks
     pub display_unaccounted: bool,
+
+    /// Whether `[unaccounted]` is the first or the last child.
+    pub unaccounted_position: UnaccountedPosition,
+
+    /// What to do when the children of a span took longer in total than the span.
+    pub child_overlap: ChildOverlap,
+
+    /// Whether `[unaccounted]` is left out when it is below `hide_below_percent` or `hide_below`
+    /// like the other children. By default it is always displayed.
+    pub hide_unaccounted: bool,
+
+    /// Whether to add an `index` field to repeated siblings that are displayed
+    /// individually instead of being aggregated.
+    pub annotate_index: bool,
+
+    /// Whether consecutive siblings with the same name but different fields are merged into a
+    /// single node listing all values or displayed individually.
+    pub field_aggregation: FieldAggregation,
+
+    /// Whether to pad the names and right-align the `[ duration | percent ]` of siblings so the
+    /// numbers line up in columns.
+    pub align_columns: bool,
+
+    /// Whether to display the time including the children of a span, excluding them, or both.
+    /// The colors and thresholds always use the inclusive time.
+    pub time_mode: TimeMode,
+
+    /// Where the rendered tree is written to.
+    pub sink: Sink,
+
+    /// Formats and destinations of every completed tree, all rendered from the same spans when
+    /// the root exits. When empty, the ASCII tree is written to `sink`.
+    pub outputs: Vec<(TreeFormat, Sink)>,
+
+    /// Whether to color the tree. An explicit `Always` or `Never` takes precedence over `NO_COLOR`.
+    pub color: ColorChoice,
+
+    /// Whether to print a legend after every tree, explaining the colors with their thresholds
+    /// and markers such as `[...]` and `(N calls)`. The colors are only explained if the tree
+    /// is colored.
+    pub show_legend: bool,
+
+    /// Whether to display the wall time of a span (from its first enter to its exit) during which
+    /// none of its children were running. Overlapping children, e.g. concurrently awaited
+    /// futures, are only subtracted once.
+    pub display_own_wall_time: bool,
+
+    /// Whether to display the time between the creation of a span and its first enter, e.g. the
+    /// time a task waited in a queue before it was polled. Aggregated calls show the maximum.
+    pub display_schedule_latency: bool,
+
+    /// Whether to display the busy time of a span and its share of the busy time of the whole
+    /// tree, e.g. `(busy 48.00ms | 20.00%)`. The busy time is the time the span was entered plus
+    /// the busy time of its descendants which ran concurrently on other threads, so spans which
+    /// fan out onto a thread pool show the work they caused while their wall percentage is
+    /// bounded by the time the root waited for them. It is wall time, not CPU time: a span which
+    /// sleeps while entered is busy.
+    pub display_busy_share: bool,
+
+    /// Events of this level or more severe are displayed as leaves below the span they occurred
+    /// in, with the time since the span was entered, e.g. `• [+1.20ms] cache miss`. Aggregated
+    /// calls don't list their events.
+    pub event_annotations: Option<Level>,
+
+    /// Whether to display how far the stack of the thread grew while a span was entered, e.g.
+    /// `(stack 12.50 KiB)`, to find deep recursions close to a stack overflow. The stack is only
+    /// probed when spans are entered and exited and when events are emitted, so this is a lower
+    /// bound. Aggregated calls show the maximum.
+    pub display_stack_usage: bool,
+
+    /// Field keys which aren't displayed in the tree. Other layers, e.g. `CsvLayer`, still
+    /// record them.
+    pub hidden_fields: HashSet<String>,
+
+    /// Field values longer than this many characters are truncated and end with `…`.
+    pub max_field_len: Option<usize>,
+
+    /// Lines longer than this many characters, including the tree connectors, are cut off and
+    /// end with `…`. The fields and other details are shortened first, then the span name.
+    pub max_width: Option<usize>,
+
+    /// Whether to display how long exiting spans waited for the lock of the graph in total on
+    /// every root line, e.g. `(profiler lock contended 12/4000 times, waited 1.20ms)`. The
+    /// numbers are also available from `Layer::lock_contention`.
+    pub display_lock_contention: bool,
+
+    /// Samples the resource usage of the process when a root span exits and displays it on the
+    /// root line, e.g. `Some(ResourceUsage::sample)` for the peak RSS.
+    pub root_resources: Option<fn() -> ResourceUsage>,
+
+    /// Writes every completed tree to this file in Perfetto's protobuf trace format, which can be
+    /// opened in the Perfetto UI without a running tracing service. Every enter and exit of a span
+    /// is a slice of its own, so the slices of a re-entered span don't overlap.
+    #[cfg(feature = "perfetto")]
+    pub perfetto_trace: Option<std::path::PathBuf>,
+
+    /// Whether to add a `profile.caller` field with the function which created the span, found by walking
+    /// the stack and skipping the frames of `tracing`, the standard library and this crate, e.g.
+    /// to tell apart spans with generic names. This is expensive: the stack is walked every time
+    /// a span is created, and every call site is symbolicated once per thread, so only enable it
+    /// while investigating. The binary needs debug symbols.
+    #[cfg(feature = "backtrace")]
+    pub capture_caller: bool,
+
+    /// Rewrites span names before siblings are aggregated, e.g. to collapse `task-1234` into `task`.
+    pub name_normalizer: Option<NameNormalizer>,
+
+    /// Spans with these names are printed as the root of a tree when they exit, even if they have
+    /// a parent, e.g. the entry point of a library whose spans are children of the host
+    /// application's spans. Spans can also be marked with `profile.root = true`.
+    pub root_spans: HashSet<String>,
+
+    /// Whether to merge the trees of all roots with the same name into a single tree instead of
+    /// printing a tree per root, e.g. for servers where every request is a root. The merged trees
+    /// are printed every `print_interval`, when the layer is flushed and when it is dropped.
+    pub merge_roots: bool,
+
+    /// Doesn't print or merge the tree of a root whose duration doesn't exceed this, e.g. to only
+    /// print the slow requests of a server. The fast roots still count towards the per-kind
+    /// totals and the perfetto export, but nothing is rendered for them.
+    pub emit_only_above: Option<Duration>,
+
+    /// Orders the children of every span by the numeric value of this field, e.g. `stage_index`,
+    /// instead of the order they exited in. Children without a numeric value of the field come
+    /// last. Siblings are aggregated after they are sorted.
+    pub sort_by_field: Option<String>,
+
+    /// How often the merged trees are printed if `merge_roots` is set. They are only printed
+    /// when a root exits, so there is no output while the program is idle.
+    pub print_interval: Option<Duration>,
+
+    /// Only the roots which exited within this window, e.g. the last 60s, are aggregated into the
+    /// merged trees if `merge_roots` is set, so a live view shows the current behavior instead
+    /// of the whole run. The roots are merged per sixtieth of the window, e.g. per second of 60s,
+    /// and such a bucket is dropped once all of its roots exited more than the window before, so
+    /// roots are evicted up to a sixtieth of the window late.
+    pub merge_window: Option<Duration>,
+
+    /// Bounds the memory of merged trees: every node has at most this many children with
+    /// distinct names, further names are merged into `[other]`.
+    pub max_distinct_names: usize,
+
+    /// Whether to subtract the cost of measuring the descendants of a span from its duration.
+    /// The overhead of an empty child span is calibrated once when the layer is created. This
+    /// assumes the children run on the thread of their parent.
+    pub overhead_compensation: bool,
+
+    /// Whether to keep printing trees after a thread panicked while holding the lock of the
+    /// graph, e.g. in a `Drop` of a field value. The poisoned lock is taken over and the partial
+    /// tree the thread was printing is discarded. By default the layer reports
+    /// `ProfileError::MutexPoisoned` and stops printing trees.
+    pub recover_poisoned: bool,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            attention_above_percent: 25.0,
-            relevant_above_percent: 2.5,
-            hide_below_percent: 1.0,
-            display_unaccounted: false,
-        }
+impl Config {
+    pub fn with_attention_above_percent(mut self, attention_above_percent: f64) -> Self {
+        self.attention_above_percent = attention_above_percent;
+        self
     }
-}
-/// GraphLayer (internally called layer::graph)
-/// This Layer prints a call graph to stdout
-///
-/// example output:
-/// ```bash
-/// cargo test all_layers -- --nocapture
-///
-/// running 1 test
-/// root span [ 123.79µs | 100.00% ]
-/// ├── child span1 [ 2.88µs | 2.32% ] { field1 = value1 }
-/// └── child span2 [ 51.00µs | 41.20% ] { field2 = value2 }
-///    ├── child span3 [ 1.88µs | 1.51% ] { field3 = value3 }
-///    └── child span4 [ 1.58µs | 1.28% ] { field4 = value4 }
-/// test tests::all_layers ... ok
-/// ```
-pub struct Layer {
-    graph: Mutex<TracingGraph>,
-}
 
-impl Default for Layer {
-    fn default() -> Self {
-        Layer::new(Config::default())
+    pub fn with_relevant_above_percent(mut self, relevant_above_percent: f64) -> Self {
+        self.relevant_above_percent = relevant_above_percent;
+        self
     }
-}
 
-impl Layer {
-    pub fn new(config: Config) -> Self {
-        let graph = TracingGraph::new(config).into();
-        Self { graph }
+    pub fn with_hide_below_percent(mut self, hide_below_percent: f64) -> Self {
+        self.hide_below_percent = hide_below_percent;
+        self
     }
-}
 
-impl<S> tracing_subscriber::Layer<S> for Layer
-where
-    S: tracing::Subscriber,
-    // no idea what this is but it lets you access the parent span.
-    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
-{
-    fn on_record(
-        &self,
-        id: &span::Id,
-        values: &span::Record<'_>,
-        ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) {
-        with_span_storage_mut(id, ctx, |storage: &mut GraphMetadata| {
-            let mut visitor = FieldVisitor(&mut storage.fields);
-            values.record(&mut visitor);
-        });
+    pub fn with_attention_above(mut self, attention_above: std::time::Duration) -> Self {
+        self.attention_above = Some(attention_above);
+        self
     }
 
-    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        with_span_storage_mut(id, ctx, |storage: &mut GraphMetadata| {
-            storage.start_time.replace(Instant::now());
-        });
+    pub fn with_relevant_above(mut self, relevant_above: std::time::Duration) -> Self {
+        self.relevant_above = Some(relevant_above);
+        self
     }
 
-    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let Some(span) = ctx.span(id) else {
-            return err_msg!("failed to get span on_exit");
-        };
-        let mut storage = span.extensions_mut();
-        let Some(storage) = storage.get_mut::<GraphMetadata>() else {
-            return err_msg!("failed to get storage on_exit");
-        };
+    pub fn with_hide_below(mut self, hide_below: std::time::Duration) -> Self {
+        self.hide_below = Some(hide_below);
+        self
+    }
 
-        let graph_node = GraphNode {
-            id: span.id().into_u64(),
-            execution_duration: storage.start_time.map(|x| x.elapsed()).unwrap_or_default(),
-            name: span.name().into(),
-            metadata: std::mem::take(&mut storage.fields),
-            call_count: 1,
-        };
+    pub fn with_display_unaccounted(mut self, display_unaccounted: bool) -> Self {
+        self.display_unaccounted = display_unaccounted;
+        self
+    }
 
-        let Ok(mut graph) = self.graph.lock() else {
-            return err_msg!("failed to get mutex");
-        };
-        match span.parent() {
-            Some(p) => {
-                graph
-                    .children
-                    .entry(p.id().into_u64())
-                    .or_default()
-                    .push(graph_node);
-            }
-            None => {
-                let tree = graph.render_tree(&graph_node, graph_node.execution_duration);
-                graph.children.clear();
-                println!("{}", tree);
-            }
-        }
+    pub fn with_unaccounted_position(mut self, unaccounted_position: UnaccountedPosition) -> Self {
+        self.unaccounted_position = unaccounted_position;
+        self
     }
 
-    fn on_new_span(
-        &self,
-        attrs: &span::Attributes<'_>,
-        id: &span::Id,
-        ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) {
-        let mut storage = GraphMetadata {
-            start_time: None,
-            fields: BTreeMap::new(),
-        };
-        // warning: the library user must use #[instrument(skip_all)] or else too much data will be logged
-        let mut visitor = FieldVisitor(&mut storage.fields);
-        attrs.record(&mut visitor);
+    pub fn with_child_overlap(mut self, child_overlap: ChildOverlap) -> Self {
+        self.child_overlap = child_overlap;
+        self
+    }
 
-        insert_to_span_storage(id, ctx, storage);
+    pub fn with_hide_unaccounted(mut self, hide_unaccounted: bool) -> Self {
+        self.hide_unaccounted = hide_unaccounted;
+        self
     }
-}
 
-#[derive(Default)]
-struct TracingGraph {
-    children: HashMap<u64, Vec<GraphNode>>,
-    config: Config,
-    no_color: bool,
-}
+    pub fn with_annotate_index(mut self, annotate_index: bool) -> Self {
+        self.annotate_index = annotate_index;
+        self
+    }
 
-impl TracingGraph {
-    fn new(config: Config) -> Self {
-        Self {
-            children: HashMap::new(),
-            config,
-            no_color: std::env::var("NO_COLOR").map_or(false, |var| !var.is_empty()),
-        }
+    pub fn with_field_aggregation(mut self, field_aggregation: FieldAggregation) -> Self {
+        self.field_aggregation = field_aggregation;
+        self
     }
 
-    fn render_tree(&self, node: &GraphNode, root_time: std::time::Duration) -> LogTree {
-        let mut children = vec![];
-        let mut aggregated_node: Option<GraphNode> = None;
-        let mut name_counter: HashMap<&str, usize> = HashMap::new();
+    pub fn with_align_columns(mut self, align_columns: bool) -> Self {
+        self.align_columns = align_columns;
+        self
+    }
 
-        if let Some(unprocessed_children) = self.children.get(&node.id) {
-            for (i, child) in unprocessed_children.iter().enumerate() {
-                let name_count = name_counter.entry(&child.name).or_insert(0);
-                *name_count += 1;
+    pub fn with_time_mode(mut self, time_mode: TimeMode) -> Self {
+        self.time_mode = time_mode;
+        self
+    }
 
-                let next = unprocessed_children.get(i + 1);
-                if next.is_some_and(|next| next.name == child.name) {
-                    if child.execution_percentage(root_time) > self.config.relevant_above_percent {
-                        let mut indexed_child = child.clone();
-                        indexed_child
-                            .metadata
-                            .insert("index".into(), format!("{}", name_count));
-                        children.push(indexed_child);
-                    } else {
-                        aggregated_node = aggregated_node
-                            .map(|node| node.clone().aggregate(child))
-                            .or_else(|| Some(child.clone()));
-                    }
-                } else {
-                    let child = aggregated_node.take().unwrap_or_else(|| child.clone());
-                    children.push(child);
-                }
-            }
-        }
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        self.sink = sink;
+        self
+    }
 
-        if self.config.hide_below_percent > 0.0 {
-            children = children.into_iter().fold(vec![], |acc, child| {
-                let mut acc = acc;
-                if child.execution_percentage(root_time) < self.config.hide_below_percent {
-                    if let Some(x) = acc.last_mut() {
-                        if x.name == "[...]" {
-                            *x = x.clone().aggregate(&child);
-                        } else {
-                            acc.push(GraphNode::new("[...]".into()).aggregate(&child))
-                        }
-                    }
-                } else {
-                    acc.push(child);
-                }
-                acc
-            });
-        }
+    /// Adds an output, e.g. `.with_output(TreeFormat::Ascii, Sink::Stdout)
+    /// .with_output(TreeFormat::Json, Sink::File("trees.jsonl".into()))`.
+    pub fn with_output(mut self, format: TreeFormat, sink: Sink) -> Self {
+        self.outputs.push((format, sink));
+        self
+    }
 
-        if self.config.display_unaccounted && !children.is_empty() {
-            let mut unaccounted = GraphNode::new("[unaccounted]".into());
-            unaccounted.execution_duration = node.execution_duration
-                - self
-                    .children
-                    .get(&node.id)
-                    .map_or(std::time::Duration::new(0, 0), |children| {
-                        children
-                            .iter()
-                            .map(|x| x.execution_duration)
-                            .fold(std::time::Duration::new(0, 0), |x, y| x + y)
-                    });
-            children.insert(0, unaccounted);
-        }
+    pub fn with_color(mut self, color: ColorChoice) -> Self {
+        self.color = color;
+        self
+    }
 
-        LogTree {
-            label: node.label(root_time, &self.config, self.no_color),
-            children: children
-                .into_iter()
-                .map(|child| self.render_tree(&child, root_time))
-                .collect(),
-        }
+    pub fn with_show_legend(mut self, show_legend: bool) -> Self {
+        self.show_legend = show_legend;
+        self
     }
-}
 
-#[derive(Default, Debug, Clone)]
-struct GraphNode {
-    name: String,
-    id: u64,
-    execution_duration: std::time::Duration,
-    metadata: BTreeMap<String, String>,
-    call_count: usize,
-}
+    pub fn with_display_own_wall_time(mut self, display_own_wall_time: bool) -> Self {
+        self.display_own_wall_time = display_own_wall_time;
+        self
+    }
 
-impl GraphNode {
-    fn new(name: String) -> Self {
-        Self {
-            name,
-            ..Default::default()
-        }
+    pub fn with_display_schedule_latency(mut self, display_schedule_latency: bool) -> Self {
+        self.display_schedule_latency = display_schedule_latency;
+        self
     }
 
-    fn execution_percentage(&self, root_time: std::time::Duration) -> f64 {
-        100.0 * self.execution_duration.as_secs_f64() / root_time.as_secs_f64()
+    pub fn with_display_busy_share(mut self, display_busy_share: bool) -> Self {
+        self.display_busy_share = display_busy_share;
+        self
     }
 
-    fn label(&self, root_time: std::time::Duration, config: &Config, no_color: bool) -> String {
-        let mut info = vec![];
-        if self.call_count > 1 {
-            info.push(format!("({} calls)", self.call_count))
-        } else if !self.metadata.is_empty() {
-            let kv: Vec<_> = self
-                .metadata
-                .iter()
-                .map(|(k, v)| format!("{k} = {v}"))
-                .collect();
-            info.push(format!("{{ {} }}", kv.join(", ")))
-        }
+    pub fn with_event_annotations(mut self, level: Level) -> Self {
+        self.event_annotations = Some(level);
+        self
+    }
 
-        let name = &self.name;
-        let execution_time = self.execution_duration;
-        let execution_time_percent = self.execution_percentage(root_time);
-        let mut result = format!("{name} [ {execution_time:.2?} | {execution_time_percent:.2}% ]");
-        if !info.is_empty() {
-            result = format!("{result} {}", info.join(" "));
-        }
+    pub fn with_display_stack_usage(mut self, display_stack_usage: bool) -> Self {
+        self.display_stack_usage = display_stack_usage;
+        self
+    }
 
-        if no_color {
-            result
-        } else {
-            format!(
-                "{}{}\x1b[0m",
-                if execution_time_percent > config.attention_above_percent {
-                    "\x1b[1;31m" // bold red
-                } else if execution_time_percent > config.relevant_above_percent {
-                    "\x1b[0m" // white
-                } else {
-                    "\x1b[2m" // gray
-                },
-                result
-            )
-        }
+    pub fn with_hidden_fields<T: Into<String>>(
+        mut self,
+        hidden_fields: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.hidden_fields = hidden_fields.into_iter().map(Into::into).collect();
+        self
     }
 
-    fn aggregate(mut self, other: &GraphNode) -> Self {
-        self.execution_duration += other.execution_duration;
-        self.call_count += other.call_count;
+    pub fn with_max_field_len(mut self, max_field_len: usize) -> Self {
+        self.max_field_len = Some(max_field_len);
+        self
+    }
+
+    pub fn with_max_width(mut self, max_width: usize) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+
+    pub fn with_display_lock_contention(mut self, display_lock_contention: bool) -> Self {
+        self.display_lock_contention = display_lock_contention;
         self
     }
+
+    pub fn with_root_resources(mut self, root_resources: fn() -> ResourceUsage) -> Self {
+        self.root_resources = Some(root_resources);
+        self
+    }
+
+    #[cfg(feature = "perfetto")]
+    pub fn with_perfetto_trace(mut self, perfetto_trace: impl Into<std::path::PathBuf>) -> Self {
+        self.perfetto_trace = Some(perfetto_trace.into());
+        self
+    }
+
+    #[cfg(feature = "backtrace")]
+    pub fn with_capture_caller(mut self, capture_caller: bool) -> Self {
+        self.capture_caller = capture_caller;
+        self
+    }
+
+    pub fn with_name_normalizer(mut self, name_normalizer: NameNormalizer) -> Self {
+        self.name_normalizer = Some(name_normalizer);
+        self
+    }
+
+    pub fn with_root_spans<T: Into<String>>(
+        mut self,
+        root_spans: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.root_spans = root_spans.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_merge_roots(mut self, merge_roots: bool) -> Self {
+        self.merge_roots = merge_roots;
+        self
+    }
+
+    pub fn with_emit_only_above(mut self, emit_only_above: Duration) -> Self {
+        self.emit_only_above = Some(emit_only_above);
+        self
+    }
+
+    pub fn with_sort_by_field(mut self, sort_by_field: impl Into<String>) -> Self {
+        self.sort_by_field = Some(sort_by_field.into());
+        self
+    }
+
+    pub fn with_print_interval(mut self, print_interval: Duration) -> Self {
+        self.print_interval = Some(print_interval);
+        self
+    }
+
+    pub fn with_merge_window(mut self, merge_window: Duration) -> Self {
+        self.merge_window = Some(merge_window);
+        self
+    }
+
+    pub fn with_max_distinct_names(mut self, max_distinct_names: usize) -> Self {
+        self.max_distinct_names = max_distinct_names;
+        self
+    }
+
+    pub fn with_overhead_compensation(mut self, overhead_compensation: bool) -> Self {
+        self.overhead_compensation = overhead_compensation;
+        self
+    }
+
+    pub fn with_recover_poisoned(mut self, recover_poisoned: bool) -> Self {
+        self.recover_poisoned = recover_poisoned;
+        self
+    }
+
+    /// Whether two consecutive siblings are aggregated into a single node.
+    fn aggregates(&self, a: &GraphNode, b: &GraphNode) -> bool {
+        fn fields(node: &GraphNode) -> impl Iterator<Item = (&String, &String)> {
+            node.metadata
+                .iter()
+                .filter(|(k, _)| !k.starts_with(COUNT_FIELD_PREFIX))
+        }
+        a.name == b.name
+            && match self.field_aggregation {
+                FieldAggregation::Merge => true,
+                FieldAggregation::Split => fields(a).eq(fields(b)),
+            }
+    }
+
+    fn is_attention(&self, duration: std::time::Duration, percent: f64) -> bool {
+        match self.attention_above {
+            Some(threshold) => duration > threshold,
+            None => percent > self.attention_above_percent,
+        }
+    }
+
+    fn is_relevant(&self, duration: std::time::Duration, percent: f64) -> bool {
+        match self.relevant_above {
+            Some(threshold) => duration > threshold,
+            None => percent > self.relevant_above_percent,
+        }
+    }
+
+    /// Describes the threshold of `is_attention`, `is_relevant` or `is_hidden`.
+    fn describe_threshold(duration: Option<Duration>, percent: f64) -> String {
+        match duration {
+            Some(duration) => format!("{duration:.2?}"),
+            None => format!("{percent:.2}% of the root"),
+        }
+    }
+
+    fn is_hidden(&self, duration: std::time::Duration, percent: f64) -> bool {
+        match self.hide_below {
+            Some(threshold) => duration < threshold,
+            None => percent < self.hide_below_percent,
+        }
+    }
+
+    fn outputs(&self) -> impl Iterator<Item = (TreeFormat, &Sink)> {
+        let default = self
+            .outputs
+            .is_empty()
+            .then_some((TreeFormat::Ascii, &self.sink));
+        let outputs = self.outputs.iter().map(|(format, sink)| (*format, sink));
+        default.into_iter().chain(outputs)
+    }
+
+    /// The sinks of the ASCII trees, which also receive the time per kind.
+    fn ascii_sinks(&self) -> impl Iterator<Item = &Sink> {
+        self.outputs()
+            .filter(|(format, _)| *format == TreeFormat::Ascii)
+            .map(|(_, sink)| sink)
+    }
+}
+
+/// Replaces all matches of a regular expression in span names.
+#[derive(Debug)]
+pub struct NameNormalizer {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl NameNormalizer {
+    pub fn new(pattern: &str, replacement: impl Into<String>) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.into(),
+        })
+    }
+
+    fn normalize(&self, name: &str) -> String {
+        self.pattern
+            .replace_all(name, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Which time of a span the tree displays.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeMode {
+    /// The time of the span including its children, e.g. `[ 1.20ms | 60.00% ]`.
+    #[default]
+    Inclusive,
+    /// The time of the span minus the time of its children, e.g. `[ self 0.20ms | 10.00% ]`.
+    Exclusive,
+    /// The inclusive time followed by the exclusive time, e.g.
+    /// `[ 1.20ms | 60.00% | self 0.20ms ]`.
+    Both,
+}
+
+/// Where the `[unaccounted]` node is placed among the children of a span.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnaccountedPosition {
+    /// Before all children.
+    #[default]
+    First,
+    /// After all children.
+    Last,
+}
+
+/// What to do when the children of a span took longer in total than the span, e.g. concurrently
+/// awaited futures or clock skew between threads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChildOverlap {
+    /// Display the times as measured.
+    Ignore,
+    /// Report `ProfileError::ChildrenExceedParent` once per span name.
+    #[default]
+    Warn,
+    /// Report it and scale the times of the children down in the ASCII tree so they add up to
+    /// the time of the span, keeping their proportions.
+    Clamp,
+}
+
+/// How consecutive siblings with the same name but different fields are aggregated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FieldAggregation {
+    /// Aggregate them into a single node which lists the distinct values of every field, e.g.
+    /// `(2 calls) { table = users | orders }`.
+    #[default]
+    Merge,
+    /// Only aggregate siblings whose fields are equal, `count.*` fields aside, and display the
+    /// others individually.
+    Split,
+}
+
+/// Whether the tree is colored, following the `ColorChoice` convention of other terminal tools.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colored unless the `NO_COLOR` environment variable is set or the tree is written to
+    /// `Sink::Tracing`.
+    #[default]
+    Auto,
+    /// Always colored, even if `NO_COLOR` is set, e.g. in CI logs which support colors.
+    Always,
+    /// Never colored.
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self, sink: &Sink) -> bool {
+        self.enabled_with_env(sink, |key| std::env::var_os(key))
+    }
+
+    /// Like `enabled`, looking up environment variables with `var`.
+    fn enabled_with_env(self, sink: &Sink, var: fn(&str) -> Option<OsString>) -> bool {
+        match self {
+            Self::Auto => {
+                var("NO_COLOR").unwrap_or_default().is_empty()
+                    && !matches!(sink, Sink::Tracing(_) | Sink::File(_))
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Category of the work done by a span, set with the `profile.kind` field, e.g.
+/// `info_span!("read", profile.kind = "io")`. Other values of the field are ignored.
+///
+/// The time of spans without a kind counts towards the kind of their closest ancestor with one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SpanKind {
+    Io,
+    Compute,
+    Wait,
+}
+
+impl SpanKind {
+    pub const ALL: [SpanKind; 3] = [SpanKind::Io, SpanKind::Compute, SpanKind::Wait];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Io => "io",
+            Self::Compute => "compute",
+            Self::Wait => "wait",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+
+    /// Color of the names of the spans of this kind.
+    fn color(self) -> &'static str {
+        match self {
+            Self::Io => "\x1b[34m",      // blue
+            Self::Compute => "\x1b[32m", // green
+            Self::Wait => "\x1b[33m",    // yellow
+        }
+    }
+
+    fn label(self, no_color: bool) -> String {
+        match no_color {
+            true => self.as_str().into(),
+            false => format!("{}{self}\x1b[0m", self.color()),
+        }
+    }
+}
+
+impl std::fmt::Display for SpanKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Exclusive time of all trees per `SpanKind`.
+#[derive(Default)]
+struct KindTotals {
+    by_kind: BTreeMap<SpanKind, Duration>,
+    /// time of spans without a kind and without an ancestor with one
+    untagged: Duration,
+    /// whether anything was added since the summary was last printed
+    changed: bool,
+}
+
+impl KindTotals {
+    /// Adds the exclusive time of `node` and its descendants, collecting their kinds in `seen`.
+    fn add_tree(
+        &mut self,
+        all_children: &HashMap<u64, Vec<GraphNode>>,
+        node: &GraphNode,
+        inherited: Option<SpanKind>,
+        seen: &mut BTreeSet<SpanKind>,
+    ) {
+        let kind = node.kind().or(inherited);
+        match kind {
+            Some(kind) => {
+                *self.by_kind.entry(kind).or_default() += node.exclusive_duration();
+                seen.insert(kind);
+                self.changed = true;
+            }
+            None => self.untagged += node.exclusive_duration(),
+        }
+        for child in all_children.get(&node.id).into_iter().flatten() {
+            self.add_tree(all_children, child, kind, seen);
+        }
+    }
+
+    fn render(&self, no_color: bool) -> LogTree {
+        let total: Duration = self.by_kind.values().sum::<Duration>() + self.untagged;
+        let line = |name: String, duration: Duration| {
+            let percent = 100.0 * duration.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON);
+            LogTree {
+                label: format!("{name} [ {duration:.2?} | {percent:.2}% ]"),
+                children: vec![],
+            }
+        };
+        let mut children: Vec<_> = self
+            .by_kind
+            .iter()
+            .map(|(kind, duration)| line(kind.label(no_color), *duration))
+            .collect();
+        if !self.untagged.is_zero() {
+            children.push(line("untagged".into(), self.untagged));
+        }
+        LogTree {
+            label: format!("time by kind [ {total:.2?} ]"),
+            children,
+        }
+    }
+}
+
+/// Format of a completed tree.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TreeFormat {
+    /// The ASCII tree with the thresholds, aggregation and colors of the config.
+    #[default]
+    Ascii,
+    /// The tree as a single line of JSON in the format of `TreeNode::to_json`, with every span
+    /// and its fields regardless of the thresholds and aggregation.
+    Json,
+    /// The tree as a YAML document in the format of `TreeNode::to_yaml`, with every span, its
+    /// share of the root and its fields regardless of the thresholds and aggregation.
+    #[cfg(feature = "yaml")]
+    Yaml,
+}
+
+/// Destination of the rendered tree.
+#[derive(Debug, Default)]
+pub enum Sink {
+    /// Print the tree to stdout, or to the writer of `Layer::with_writer`.
+    #[default]
+    Stdout,
+    /// Emit every line of the tree as an event with the given level and the target
+    /// [`TRACING_TARGET`]. Colors are disabled.
+    Tracing(Level),
+    /// Append the tree to the file, which is truncated when the layer is created. Colors are
+    /// disabled.
+    File(std::path::PathBuf),
+}
+
+/// Replaces stdout for `Sink::Stdout`.
+type StdoutWriter = RefCell<Box<dyn std::io::Write + Send>>;
+
+impl Sink {
+    fn emit(&self, tree: &LogTree, stdout: Option<&StdoutWriter>) {
+        self.emit_str(&tree.to_string(), stdout)
+    }
+
+    fn emit_str(&self, text: &str, stdout: Option<&StdoutWriter>) {
+        use std::io::Write;
+
+        match self {
+            Sink::Stdout => {
+                let Some(writer) = stdout else {
+                    return println!("{text}");
+                };
+                let mut writer = writer.borrow_mut();
+                if let Err(e) = writeln!(writer, "{text}").and_then(|_| writer.flush()) {
+                    err_msg!(ProfileError::WriteFailed(e.kind()));
+                }
+            }
+            Sink::File(path) => {
+                let written = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| writeln!(file, "{text}"));
+                if let Err(e) = written {
+                    err_msg!(ProfileError::WriteFailed(e.kind()));
+                }
+            }
+            Sink::Tracing(level) => {
+                for line in text.lines() {
+                    match *level {
+                        Level::ERROR => tracing::error!(target: TRACING_TARGET, "{line}"),
+                        Level::WARN => tracing::warn!(target: TRACING_TARGET, "{line}"),
+                        Level::INFO => tracing::info!(target: TRACING_TARGET, "{line}"),
+                        Level::DEBUG => tracing::debug!(target: TRACING_TARGET, "{line}"),
+                        Level::TRACE => tracing::trace!(target: TRACING_TARGET, "{line}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            attention_above_percent: 25.0,
+            relevant_above_percent: 2.5,
+            hide_below_percent: 1.0,
+            attention_above: None,
+            relevant_above: None,
+            hide_below: None,
+            display_unaccounted: false,
+            unaccounted_position: UnaccountedPosition::First,
+            hide_unaccounted: false,
+            child_overlap: ChildOverlap::Warn,
+            annotate_index: true,
+            field_aggregation: FieldAggregation::Merge,
+            align_columns: false,
+            time_mode: TimeMode::Inclusive,
+            sink: Sink::Stdout,
+            outputs: Vec::new(),
+            color: ColorChoice::Auto,
+            show_legend: false,
+            hidden_fields: HashSet::new(),
+            max_field_len: None,
+            max_width: None,
+            display_lock_contention: false,
+            root_resources: None,
+            #[cfg(feature = "perfetto")]
+            perfetto_trace: None,
+            #[cfg(feature = "backtrace")]
+            capture_caller: false,
+            name_normalizer: None,
+            root_spans: HashSet::new(),
+            display_own_wall_time: false,
+            event_annotations: None,
+            display_stack_usage: false,
+            display_schedule_latency: false,
+            display_busy_share: false,
+            merge_roots: false,
+            emit_only_above: None,
+            sort_by_field: None,
+            print_interval: None,
+            merge_window: None,
+            max_distinct_names: 1000,
+            overhead_compensation: false,
+            recover_poisoned: false,
+        }
+    }
+}
+/// GraphLayer (internally called layer::graph)
+/// This Layer prints a call graph to stdout, or to the writer of `with_writer`
+///
+/// example output:
+/// ```bash
+/// cargo test all_layers -- --nocapture
+///
+/// running 1 test
+/// root span [ 123.79µs | 100.00% ]
+/// ├── child span1 [ 2.88µs | 2.32% ] { field1 = value1 }
+/// └── child span2 [ 51.00µs | 41.20% ] { field2 = value2 }
+///    ├── child span3 [ 1.88µs | 1.51% ] { field3 = value3 }
+///    └── child span4 [ 1.58µs | 1.28% ] { field4 = value4 }
+/// test tests::all_layers ... ok
+/// ```
+///
+/// Exiting spans only lock the graph when they are roots. The nodes of the other spans wait for
+/// their parent in a shard of the thread which exited them, and the parents add up the durations
+/// of their children in their own span storage. The shards are merged into the graph when the root
+/// exits, so threads working on the same tree don't wait for each other.
+pub struct Layer {
+    graph: Arc<Mutex<TracingGraph>>,
+    /// the nodes exited by every thread, keyed by the id of the parent they wait for
+    pending: ThreadLocal<Mutex<Shard>>,
+    /// shared with the graph so exiting spans don't lock it
+    config: Arc<Config>,
+    overhead: Duration,
+    lock_stats: Arc<LockStats>,
+    created: Instant,
+}
+
+/// The nodes exited by a thread which wait for their parent to exit.
+struct Shard {
+    /// the thread the shard belongs to. `ThreadLocal` hands the slot of an exited thread to the
+    /// next new one, which takes the shard over.
+    owner: std::thread::ThreadId,
+    pending: HashMap<u64, Vec<GraphNode>>,
+    /// the spans currently entered on the thread, for snapshots
+    open: HashMap<u64, OpenSpan>,
+    /// the tracks and names in the perfetto trace of the threads which owned the shard
+    #[cfg(feature = "perfetto")]
+    threads: Vec<(u64, Option<String>)>,
+}
+
+/// A span which is currently entered.
+#[derive(Clone, Copy)]
+struct OpenSpan {
+    name: &'static str,
+    parent: Option<u64>,
+    since: Instant,
+}
+
+/// The state of all threads collected by `Layer::snapshot`.
+struct Snapshot {
+    now: Instant,
+    open: HashMap<u64, OpenSpan>,
+    /// the open spans below every open span, `None` for those without an open parent
+    open_children: HashMap<Option<u64>, Vec<u64>>,
+    pending: HashMap<u64, Vec<GraphNode>>,
+}
+
+impl Snapshot {
+    fn open_nodes(&self, parent: Option<u64>) -> Vec<TreeNode> {
+        let ids = self.open_children.get(&parent).into_iter().flatten();
+        ids.map(|id| {
+            let span = self.open[id];
+            TreeNode {
+                name: span.name.into(),
+                duration: self.now - span.since,
+                open: true,
+                fields: BTreeMap::new(),
+                children: self.children(*id),
+            }
+        })
+        .collect()
+    }
+
+    /// The exited children of the span in the order they exited, followed by the open ones.
+    fn children(&self, id: u64) -> Vec<TreeNode> {
+        let mut exited: Vec<_> = self.pending.get(&id).into_iter().flatten().collect();
+        exited.sort_by_key(|child| child.wall_interval.map(|(_, end)| end));
+        let exited = exited.into_iter().map(|node| TreeNode {
+            name: node.name.clone(),
+            duration: node.execution_duration,
+            open: false,
+            fields: node.displayed_fields(),
+            children: self.children(node.id),
+        });
+        let open = match self.open.contains_key(&id) {
+            true => self.open_nodes(Some(id)),
+            false => vec![],
+        };
+        exited.chain(open).collect()
+    }
+}
+
+impl Default for Layer {
+    fn default() -> Self {
+        Layer::new(Config::default())
+    }
+}
+
+impl Layer {
+    pub fn new(config: Config) -> Self {
+        Self::with_graph(TracingGraph::new(config))
+    }
+
+    /// Writes the trees to `writer` instead of stdout, e.g. a file, a pipe or a buffer, for
+    /// programs whose stdout is taken by other output.
+    pub fn with_writer<W: std::io::Write + Send + 'static>(writer: W) -> Self {
+        Self::with_writer_and_config(writer, Config::default())
+    }
+
+    /// The outputs with `Sink::Stdout`, the default sink, are written to `writer`. Colors are
+    /// disabled unless `ColorChoice::Always` is set, since the writer is rarely a terminal.
+    pub fn with_writer_and_config<W: std::io::Write + Send + 'static>(
+        writer: W,
+        config: Config,
+    ) -> Self {
+        let mut graph = TracingGraph::new(config);
+        graph.stdout = Some(RefCell::new(Box::new(writer)));
+        graph.no_color |= graph.config.color == ColorChoice::Auto;
+        Self::with_graph(graph)
+    }
+
+    fn with_graph(graph: TracingGraph) -> Self {
+        Self {
+            pending: ThreadLocal::new(),
+            config: graph.config.clone(),
+            overhead: graph.overhead,
+            graph: Arc::new(Mutex::new(graph)),
+            lock_stats: Default::default(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Builds the tree of the spans which are currently entered, with the time since they were
+    /// entered, and of the spans below them which already exited, e.g. for a debug endpoint. The
+    /// returned node is named `snapshot` and lasts since the layer was created, its children are
+    /// the open roots.
+    ///
+    /// A span whose parent isn't entered at the moment, e.g. the parent of an idle future, is
+    /// listed as a root.
+    pub fn snapshot(&self) -> TreeNode {
+        let mut snapshot = Snapshot {
+            now: Instant::now(),
+            open: HashMap::new(),
+            open_children: HashMap::new(),
+            pending: HashMap::new(),
+        };
+        for shard in self.pending.iter() {
+            let Ok(shard) = shard.lock() else {
+                err_msg!(ProfileError::MutexPoisoned);
+                continue;
+            };
+            snapshot.open.extend(&shard.open);
+            for (parent, nodes) in &shard.pending {
+                let siblings = snapshot.pending.entry(*parent).or_default();
+                siblings.extend(nodes.iter().cloned());
+            }
+        }
+
+        // sorted by the time they were entered, the order in which siblings are displayed
+        let mut open: Vec<_> = snapshot.open.iter().collect();
+        open.sort_by_key(|(_, span)| span.since);
+        for (id, span) in open {
+            let parent = span
+                .parent
+                .filter(|parent| snapshot.open.contains_key(parent));
+            snapshot.open_children.entry(parent).or_default().push(*id);
+        }
+        TreeNode {
+            name: "snapshot".into(),
+            duration: snapshot.now - self.created,
+            open: true,
+            fields: BTreeMap::new(),
+            children: snapshot.open_nodes(None),
+        }
+    }
+
+    /// Exclusive time of all trees so far per `SpanKind`, also printed when the layer is flushed or
+    /// dropped.
+    pub fn kind_totals(&self) -> BTreeMap<SpanKind, Duration> {
+        let Some(graph) = lock_graph(&self.graph, self.graph.lock(), &self.config) else {
+            return BTreeMap::new();
+        };
+        graph.kinds.by_kind.clone()
+    }
+
+    /// How often exiting roots waited for the lock of the graph and for how long.
+    pub fn lock_contention(&self) -> LockContention {
+        self.lock_stats.contention()
+    }
+
+    /// Records a phase marker in the span it occurs in.
+    fn record_phase<S>(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) where
+        S: tracing::Subscriber + for<'lookup> LookupSpan<'lookup>,
+    {
+        let now = Instant::now();
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut fields = BTreeMap::new();
+        event.record(&mut FieldVisitor::with_max_len(
+            &mut fields,
+            self.config.max_field_len,
+        ));
+        let mut extensions = span.extensions_mut();
+        let Some(storage) = extensions.get_mut::<GraphMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_event"
+            });
+        };
+        if let Some(phase) = fields.remove(PHASE_FIELD) {
+            storage.phases.push((now, phase));
+        }
+    }
+
+    fn shard(&self) -> &Mutex<Shard> {
+        self.pending.get_or(|| {
+            Mutex::new(Shard {
+                owner: std::thread::current().id(),
+                pending: HashMap::new(),
+                open: HashMap::new(),
+                #[cfg(feature = "perfetto")]
+                threads: vec![perfetto_thread()],
+            })
+        })
+    }
+
+    /// Locks the shard of the current thread to add to it.
+    fn lock_shard(&self) -> Option<MutexGuard<'_, Shard>> {
+        let Ok(mut shard) = self.shard().lock() else {
+            err_msg!(ProfileError::MutexPoisoned);
+            return None;
+        };
+        let thread = std::thread::current().id();
+        if shard.owner != thread {
+            // the nodes of the exited thread keep waiting for their parents
+            shard.owner = thread;
+            #[cfg(feature = "perfetto")]
+            shard.threads.push(perfetto_thread());
+        }
+        Some(shard)
+    }
+
+    /// Forgets that the span is entered, usually in the shard of the current thread.
+    fn close_open_span(&self, id: u64) {
+        let current = self.shard();
+        let others = self
+            .pending
+            .iter()
+            .filter(|shard| !std::ptr::eq(*shard, current));
+        for shard in std::iter::once(current).chain(others) {
+            let Ok(mut shard) = shard.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            if shard.open.remove(&id).is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Moves the pending nodes of the tree below `root` from the shards of all threads into
+    /// `graph.children`.
+    fn collect_subtree(&self, graph: &mut TracingGraph, root: u64) {
+        let mut parents = vec![root];
+        while let Some(parent) = parents.pop() {
+            let mut found = false;
+            for shard in self.pending.iter() {
+                let Ok(mut shard) = shard.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                if let Some(nodes) = shard.pending.remove(&parent) {
+                    #[cfg(feature = "perfetto")]
+                    if let Some(writer) = &mut graph.perfetto_trace {
+                        for (thread, name) in &shard.threads {
+                            writer.register_thread(*thread, name.clone());
+                        }
+                    }
+                    graph.children.entry(parent).or_default().extend(nodes);
+                    found = true;
+                }
+            }
+            if !found {
+                continue;
+            }
+            if let Some(children) = graph.children.get_mut(&parent) {
+                // the children of a parent may have exited on several threads
+                children.sort_by_key(|child| child.wall_interval.map(|(_, end)| end));
+                if let Some(field) = &self.config.sort_by_field {
+                    sort_by_field(children, field);
+                }
+                parents.extend(children.iter().map(|child| child.id));
+            }
+        }
+    }
+
+    /// Drops the nodes which wait for the span although its tree was already built, i.e. the
+    /// children which exited after the root of the tree. A span closes after its children, so
+    /// unless its own node still waits for its parent, nothing collects them any more.
+    fn discard_late_children(&self, id: u64, parent: Option<u64>) {
+        let mut waiting = false;
+        let mut late = false;
+        for shard in self.pending.iter() {
+            let Ok(shard) = shard.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            waiting |= parent
+                .and_then(|parent| shard.pending.get(&parent))
+                .is_some_and(|siblings| siblings.iter().any(|node| node.id == id));
+            late |= shard.pending.contains_key(&id);
+        }
+        if waiting || !late {
+            return;
+        }
+
+        // a root which is exiting may still be collecting the nodes
+        let Some(_graph) = lock_graph(&self.graph, self.lock_stats.lock(&self.graph), &self.config)
+        else {
+            return;
+        };
+        let mut parents = vec![id];
+        while let Some(parent) = parents.pop() {
+            for shard in self.pending.iter() {
+                let Ok(mut shard) = shard.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                if let Some(nodes) = shard.pending.remove(&parent) {
+                    parents.extend(nodes.iter().map(|node| node.id));
+                }
+            }
+        }
+    }
+}
+
+/// Stably sorts `nodes` by the numeric value of `field`, the nodes without one last.
+fn sort_by_field(nodes: &mut [GraphNode], field: &str) {
+    let key = |node: &GraphNode| node.metadata.get(field)?.parse::<f64>().ok();
+    nodes.sort_by(|a, b| match (key(a), key(b)) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+}
+
+/// The track and name of the current thread in the perfetto trace.
+#[cfg(feature = "perfetto")]
+fn perfetto_thread() -> (u64, Option<String>) {
+    (
+        crate::data::perfetto_thread_id(),
+        std::thread::current().name().map(Into::into),
+    )
+}
+
+/// Returns the locked graph, or takes it over if it is poisoned and `Config::recover_poisoned`
+/// is set.
+fn lock_graph<'a>(
+    mutex: &Mutex<TracingGraph>,
+    result: LockResult<MutexGuard<'a, TracingGraph>>,
+    config: &Config,
+) -> Option<MutexGuard<'a, TracingGraph>> {
+    match result {
+        Ok(graph) => Some(graph),
+        Err(e) if config.recover_poisoned => {
+            crate::error::warn(&ProfileError::MutexPoisoned);
+            mutex.clear_poison();
+            let mut graph = e.into_inner();
+            // the nodes of the tree the panicking thread was printing
+            graph.children.clear();
+            Some(graph)
+        }
+        Err(_) => {
+            err_msg!(ProfileError::MutexPoisoned);
+            None
+        }
+    }
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        // a panicking thread may hold the lock, the merged trees are still worth printing
+        let mut graph = self.graph.lock().unwrap_or_else(|e| e.into_inner());
+        graph.print_merged();
+        graph.print_kinds();
+    }
+}
+
+/// Trees are printed as soon as their root exits, only merged trees and the time per kind are
+/// printed on flush.
+impl crate::Flush for Layer {
+    fn flush_handle(&self) -> Option<crate::FlushHandle> {
+        let graph = self.graph.clone();
+        let config = self.config.clone();
+        Some(crate::FlushHandle::new(move || {
+            let Some(mut graph) = lock_graph(&graph, graph.lock(), &config) else {
+                return;
+            };
+            graph.print_merged();
+            graph.print_kinds();
+        }))
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    // no idea what this is but it lets you access the parent span.
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_record"
+            });
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(storage) = extensions.get_mut::<GraphMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_record"
+            });
+        };
+        let exited = storage.start_time.is_none() && storage.first_enter.is_some();
+        if !exited {
+            let mut visitor =
+                FieldVisitor::with_max_len(&mut storage.fields, self.config.max_field_len);
+            return values.record(&mut visitor);
+        }
+
+        // the node was already built when the span exited, so the value is added to it as long
+        // as it is waiting for its parent to exit, usually in the shard of the current thread
+        let parent = tree_parent(&self.config, &ctx, &span, &storage.fields);
+        if let Some(parent_id) = parent.as_ref().and_then(SpanUid::of) {
+            let current = self.shard();
+            let others = self
+                .pending
+                .iter()
+                .filter(|shard| !std::ptr::eq(*shard, current));
+            for shard in std::iter::once(current).chain(others) {
+                let Ok(mut shard) = shard.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                let node = shard.pending.get_mut(&parent_id).and_then(|siblings| {
+                    siblings
+                        .iter_mut()
+                        .rev()
+                        .find(|node| node.id == storage.uid)
+                });
+                if let Some(node) = node {
+                    let mut visitor =
+                        FieldVisitor::with_max_len(&mut node.metadata, self.config.max_field_len);
+                    return values.record(&mut visitor);
+                }
+            }
+        }
+        let mut visitor =
+            FieldVisitor::with_max_len(&mut storage.fields, self.config.max_field_len);
+        values.record(&mut visitor);
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips_event(event, &ctx) {
+            return;
+        }
+        let metadata = event.metadata();
+        if metadata.target() == TRACING_TARGET {
+            return;
+        }
+        if self.config.display_stack_usage {
+            StackProbe::sample();
+        }
+        if metadata.fields().field(PHASE_FIELD).is_some() {
+            return self.record_phase(event, &ctx);
+        }
+        let level = *metadata.level();
+        let annotated = self
+            .config
+            .event_annotations
+            .is_some_and(|max| level <= max);
+        if level != Level::ERROR && level != Level::WARN && !annotated {
+            return;
+        }
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(storage) = extensions.get_mut::<GraphMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_event"
+            });
+        };
+        match level {
+            Level::ERROR => storage.errors += 1,
+            Level::WARN => storage.warnings += 1,
+            _ => {}
+        }
+        if annotated {
+            let since = storage.start_time.unwrap_or(storage.created).elapsed();
+            let mut fields = BTreeMap::new();
+            event.record(&mut FieldVisitor::with_max_len(
+                &mut fields,
+                self.config.max_field_len,
+            ));
+            let mut message = fields.remove("message").unwrap_or_default();
+            if !fields.is_empty() {
+                let kv: Vec<_> = fields.iter().map(|(k, v)| format!("{k} = {v}")).collect();
+                message = format!("{message} {{ {} }}", kv.join(", "));
+            }
+            storage.events.push((since, message));
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_enter"
+            });
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(storage) = extensions.get_mut::<GraphMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing {
+                context: "on_enter"
+            });
+        };
+        let now = Instant::now();
+        storage.start_time.replace(now);
+        storage.first_enter.get_or_insert(now);
+        storage.entered_on = Some(std::thread::current().id());
+        if self.config.display_stack_usage {
+            storage.stack = Some(StackProbe::enter());
+        }
+
+        let open = OpenSpan {
+            name: span.name(),
+            parent: tree_parent(&self.config, &ctx, &span, &storage.fields)
+                .as_ref()
+                .and_then(SpanUid::of),
+            since: now,
+        };
+        let Some(mut shard) = self.lock_shard() else {
+            return;
+        };
+        shard.open.insert(storage.uid, open);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
+        };
+        let mut extensions = span.extensions_mut();
+        let timings = extensions.get_mut::<SpanTimings>().map(|x| *x);
+        let Some(storage) = extensions.get_mut::<GraphMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing { context: "on_exit" });
+        };
+
+        let end_time = Instant::now();
+        let entered_at = storage.start_time.take();
+        let measured_duration = entered_at.map(|x| end_time - x).unwrap_or_default();
+        storage.entered_on = None;
+        let wall_interval = storage.first_enter.map(|x| (x, end_time));
+        let config = &self.config;
+        let parent = tree_parent(config, &ctx, &span, &storage.fields);
+        let parent_id = parent.as_ref().and_then(SpanUid::of);
+        storage.tree_parent = parent_id;
+        self.close_open_span(storage.uid);
+
+        let descendants = storage.descendants;
+        let execution_duration =
+            measured_duration.saturating_sub(self.overhead * descendants as u32);
+        let busy_time = execution_duration + std::mem::take(&mut storage.concurrent_busy_time);
+        let own_wall_time = wall_interval
+            .filter(|_| config.display_own_wall_time)
+            .map(|interval| own_wall_time(interval, storage.children_intervals.iter().copied()));
+        let mut graph_node = GraphNode {
+            id: storage.uid,
+            execution_duration,
+            busy_time,
+            wall_interval,
+            own_wall_time,
+            schedule_latency: storage.first_enter.map(|x| x - storage.created),
+            timings,
+            name: match &config.name_normalizer {
+                Some(normalizer) => normalizer.normalize(span.name()),
+                None => span.name().into(),
+            },
+            metadata: std::mem::take(&mut storage.fields),
+            errors: std::mem::take(&mut storage.errors),
+            warnings: std::mem::take(&mut storage.warnings),
+            events: std::mem::take(&mut storage.events),
+            phases: std::mem::take(&mut storage.phases),
+            stack_usage: storage.stack.take().map(StackProbe::exit),
+            call_count: 1,
+            descendants,
+            children_duration: storage.children_duration,
+            resources: config
+                .root_resources
+                .filter(|_| parent_id.is_none())
+                .map(|sample| sample()),
+            lock_contention: None,
+            #[cfg(feature = "perfetto")]
+            thread: crate::data::perfetto_thread_id(),
+            #[cfg(feature = "perfetto")]
+            entered_interval: entered_at.map(|x| (x, end_time)),
+        };
+        drop(extensions);
+
+        if let Some(parent_id) = parent_id {
+            if let Some(parent) = &parent {
+                if let Some(parent) = parent.extensions_mut().get_mut::<GraphMetadata>() {
+                    parent.descendants += descendants + 1;
+                    parent.children_duration += execution_duration;
+                    // a child running within the parent on its thread was already counted
+                    // while the parent was entered
+                    parent.concurrent_busy_time +=
+                        if parent.entered_on == Some(std::thread::current().id()) {
+                            busy_time.saturating_sub(execution_duration)
+                        } else {
+                            busy_time
+                        };
+                    if let Some(interval) = wall_interval.filter(|_| config.display_own_wall_time) {
+                        parent.children_intervals.push(interval);
+                    }
+                }
+            }
+            let Some(mut shard) = self.lock_shard() else {
+                return;
+            };
+            shard.pending.entry(parent_id).or_default().push(graph_node);
+            return;
+        }
+
+        let Some(mut graph) =
+            lock_graph(&self.graph, self.lock_stats.lock(&self.graph), &self.config)
+        else {
+            return;
+        };
+        if config.display_lock_contention {
+            graph_node.lock_contention = Some(self.lock_stats.contention());
+        }
+        #[cfg(feature = "perfetto")]
+        if let Some(writer) = &mut graph.perfetto_trace {
+            writer.register_thread(
+                graph_node.thread,
+                std::thread::current().name().map(Into::into),
+            );
+        }
+        self.collect_subtree(&mut graph, graph_node.id);
+        let fast = config
+            .emit_only_above
+            .is_some_and(|threshold| graph_node.execution_duration <= threshold);
+        if config.child_overlap != ChildOverlap::Ignore {
+            graph.warn_overlapping(&graph_node);
+        }
+        let mut kinds = BTreeSet::new();
+        {
+            let graph = &mut *graph;
+            graph
+                .kinds
+                .add_tree(&graph.children, &graph_node, None, &mut kinds);
+        }
+        match config.merge_roots {
+            _ if fast => {}
+            true => {
+                let graph = &mut *graph;
+                graph.merged.add(
+                    Instant::now(),
+                    &graph_node,
+                    &graph.children,
+                    config.max_distinct_names,
+                    config.merge_window,
+                );
+                graph.print_merged_if_due();
+            }
+            false => graph.emit_tree(&graph.children, &graph_node, &kinds),
+        }
+        #[cfg(feature = "perfetto")]
+        graph.export_perfetto(&graph_node);
+        graph.remove_subtree(graph_node.id);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(&id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_close"
+            });
+        };
+        let Some((uid, first_enter, descendants, tree_parent)) = span
+            .extensions()
+            .get::<GraphMetadata>()
+            .map(|x| (x.uid, x.first_enter, x.descendants, x.tree_parent))
+        else {
+            return;
+        };
+        if first_enter.is_some() {
+            // only spans with children can have nodes waiting for them
+            if descendants > 0 {
+                self.discard_late_children(uid, tree_parent);
+            }
+            return;
+        }
+
+        let Some(mut graph) =
+            lock_graph(&self.graph, self.lock_stats.lock(&self.graph), &self.config)
+        else {
+            return;
+        };
+        if graph.never_entered.insert(span.name()) {
+            crate::error::warn(&ProfileError::NeverEntered { name: span.name() });
+        }
+    }
+
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_new_span"
+            });
+        };
+        let mut storage = GraphMetadata {
+            uid: SpanUid::assign(&span),
+            start_time: None,
+            created: Instant::now(),
+            first_enter: None,
+            fields: BTreeMap::new(),
+            errors: 0,
+            warnings: 0,
+            events: Vec::new(),
+            phases: Vec::new(),
+            stack: None,
+            descendants: 0,
+            children_duration: Duration::ZERO,
+            entered_on: None,
+            concurrent_busy_time: Duration::ZERO,
+            children_intervals: Vec::new(),
+            tree_parent: None,
+        };
+        // warning: the library user must use #[instrument(skip_all)] or else too much data will be logged
+        let mut visitor =
+            FieldVisitor::with_max_len(&mut storage.fields, self.config.max_field_len);
+        attrs.record(&mut visitor);
+        #[cfg(feature = "backtrace")]
+        if self.config.capture_caller {
+            if let Some(caller) = crate::data::caller() {
+                storage.fields.insert(CALLER_FIELD.into(), caller);
+            }
+        }
+
+        span.extensions_mut().insert(storage);
+    }
+}
+
+#[derive(Default)]
+struct TracingGraph {
+    children: HashMap<u64, Vec<GraphNode>>,
+    config: Arc<Config>,
+    no_color: bool,
+    /// receives the output of `Sink::Stdout` instead of stdout if set
+    stdout: Option<StdoutWriter>,
+    /// names of the spans which have been reported as never entered
+    never_entered: HashSet<&'static str>,
+    /// names of the spans which have been reported for children exceeding them
+    overlapping: HashSet<String>,
+    /// subtracted from the duration of a span for each of its descendants
+    overhead: Duration,
+    /// the trees of all roots if `Config::merge_roots` is set
+    merged: MergedTree,
+    kinds: KindTotals,
+    #[cfg(feature = "perfetto")]
+    perfetto_trace: Option<PerfettoTraceWriter<std::io::BufWriter<std::fs::File>>>,
+}
+
+impl TracingGraph {
+    fn new(config: Config) -> Self {
+        for (_, sink) in config.outputs() {
+            if let Sink::File(path) = sink {
+                // panic right away instead of after the program ran, like `CsvLayer`
+                std::fs::File::create(path).expect("failed to create tree output");
+            }
+        }
+        // a single ASCII tree is rendered for all outputs
+        let no_color = !config.ascii_sinks().all(|sink| config.color.enabled(sink));
+        Self {
+            children: HashMap::new(),
+            stdout: None,
+            never_entered: HashSet::new(),
+            overlapping: HashSet::new(),
+            merged: MergedTree::default(),
+            kinds: KindTotals::default(),
+            overhead: if config.overhead_compensation {
+                calibrate_overhead()
+            } else {
+                Duration::ZERO
+            },
+            #[cfg(feature = "perfetto")]
+            perfetto_trace: config.perfetto_trace.as_ref().map(|path| {
+                // panic right away instead of after the program ran, like `CsvLayer`
+                let file = std::fs::File::create(path).expect("failed to create perfetto trace");
+                PerfettoTraceWriter::new(std::io::BufWriter::new(file))
+            }),
+            no_color,
+            config: Arc::new(config),
+        }
+    }
+
+    #[cfg(feature = "perfetto")]
+    fn export_perfetto(&mut self, root: &GraphNode) {
+        fn export_node<W: std::io::Write>(
+            writer: &mut PerfettoTraceWriter<W>,
+            children: &HashMap<u64, Vec<GraphNode>>,
+            node: &GraphNode,
+        ) -> std::io::Result<()> {
+            // one slice per enter and exit, so re-entered spans don't overlap on the track
+            if let Some((begin, end)) = node.entered_interval {
+                writer.slice(node.thread, &node.name, &node.metadata, begin, end)?;
+            }
+            for child in children.get(&node.id).into_iter().flatten() {
+                export_node(writer, children, child)?;
+            }
+            Ok(())
+        }
+
+        let Some(writer) = self.perfetto_trace.as_mut() else {
+            return;
+        };
+        if let Err(e) = export_node(writer, &self.children, root).and_then(|_| writer.flush()) {
+            err_msg!(ProfileError::WriteFailed(e.kind()));
+        }
+    }
+
+    fn remove_subtree(&mut self, id: u64) {
+        for child in self.children.remove(&id).into_iter().flatten() {
+            self.remove_subtree(child.id);
+        }
+    }
+
+    /// Reports the spans of the tree whose children took longer in total than them, once per
+    /// span name.
+    fn warn_overlapping(&mut self, root: &GraphNode) {
+        let mut nodes = vec![root];
+        while let Some(node) = nodes.pop() {
+            let Some(children) = self.children.get(&node.id) else {
+                continue;
+            };
+            let total: Duration = children.iter().map(|child| child.execution_duration).sum();
+            if total > node.execution_duration && self.overlapping.insert(node.name.clone()) {
+                crate::error::warn(&ProfileError::ChildrenExceedParent {
+                    name: node.name.clone(),
+                    parent: node.execution_duration,
+                    children: total,
+                });
+            }
+            nodes.extend(children);
+        }
+    }
+
+    #[cfg(test)]
+    fn render_tree(&self, root: &GraphNode) -> LogTree {
+        self.render_node(&self.children, root, root, &Columns::default(), 0)
+    }
+
+    /// Prints the merged trees if anything was merged since they were last printed.
+    fn print_merged(&mut self) {
+        if let Some(window) = self.config.merge_window {
+            self.merged.evict(Instant::now(), window);
+        }
+        if !self.merged.changed {
+            return;
+        }
+        let forest = self.merged.forest(self.config.max_distinct_names);
+        for root in &forest.roots {
+            self.emit_tree(&forest.children, root, &BTreeSet::new());
+        }
+        self.merged.changed = false;
+        self.merged.last_print = Some(Instant::now());
+    }
+
+    /// Prints the time per kind if spans with a kind exited since it was last printed.
+    fn print_kinds(&mut self) {
+        if !self.kinds.changed {
+            return;
+        }
+        let totals = self.kinds.render(self.no_color);
+        for sink in self.config.ascii_sinks() {
+            sink.emit(&totals, self.stdout.as_ref());
+        }
+        self.kinds.changed = false;
+    }
+
+    /// Writes the tree of `root` to every output, rendering each format only once.
+    fn emit_tree(
+        &self,
+        all_children: &HashMap<u64, Vec<GraphNode>>,
+        root: &GraphNode,
+        kinds: &BTreeSet<SpanKind>,
+    ) {
+        let mut ascii = None;
+        let mut json = None;
+        #[cfg(feature = "yaml")]
+        let mut yaml = None;
+        for (format, sink) in self.config.outputs() {
+            match format {
+                TreeFormat::Ascii => {
+                    let (tree, legend) = ascii.get_or_insert_with(|| {
+                        let tree =
+                            self.render_node(all_children, root, root, &Columns::default(), 0);
+                        (tree, self.legend(kinds))
+                    });
+                    sink.emit(tree, self.stdout.as_ref());
+                    if let Some(legend) = legend {
+                        sink.emit(legend, self.stdout.as_ref());
+                    }
+                }
+                TreeFormat::Json => sink.emit_str(
+                    json.get_or_insert_with(|| root.tree_node(all_children).to_json()),
+                    self.stdout.as_ref(),
+                ),
+                #[cfg(feature = "yaml")]
+                TreeFormat::Yaml => sink.emit_str(
+                    yaml.get_or_insert_with(|| root.tree_node(all_children).to_yaml()),
+                    self.stdout.as_ref(),
+                ),
+            }
+        }
+    }
+
+    /// Lists the colors of the kinds in a tree below it.
+    fn legend(&self, kinds: &BTreeSet<SpanKind>) -> Option<LogTree> {
+        let line = |label: String| LogTree {
+            label,
+            children: vec![],
+        };
+        let kinds = (!self.no_color && !kinds.is_empty()).then(|| {
+            let kinds: Vec<_> = kinds.iter().map(|kind| kind.label(false)).collect();
+            line(format!("kinds: {}", kinds.join(" ")))
+        });
+        if !self.config.show_legend {
+            return kinds;
+        }
+
+        let config = &self.config;
+        let mut children = vec![];
+        if !self.no_color {
+            children.extend([
+                line(format!(
+                    "\x1b[1;31mbold red\x1b[0m: slower than {}",
+                    Config::describe_threshold(
+                        config.attention_above,
+                        config.attention_above_percent
+                    )
+                )),
+                line(format!(
+                    "white: slower than {}",
+                    Config::describe_threshold(
+                        config.relevant_above,
+                        config.relevant_above_percent
+                    )
+                )),
+                line("\x1b[2mgray\x1b[0m: faster".into()),
+            ]);
+        }
+        children.extend([
+            line("[ time | percent ]: time of the span and its share of the root".into()),
+            line(format!(
+                "[...]: siblings faster than {}, summed up",
+                Config::describe_threshold(config.hide_below, config.hide_below_percent)
+            )),
+            line("(N calls): consecutive calls of the same span, summed up".into()),
+        ]);
+        if config.display_unaccounted {
+            children.push(line(
+                "[unaccounted]: time of the parent not spent in its children".into(),
+            ));
+        }
+        children.extend(kinds);
+        Some(LogTree {
+            label: "legend:".into(),
+            children,
+        })
+    }
+
+    fn print_merged_if_due(&mut self) {
+        let Some(interval) = self.config.print_interval else {
+            return;
+        };
+        let last_print = *self.merged.last_print.get_or_insert_with(Instant::now);
+        if last_print.elapsed() >= interval {
+            self.print_merged();
+        }
+    }
+
+    fn render_node(
+        &self,
+        all_children: &HashMap<u64, Vec<GraphNode>>,
+        node: &GraphNode,
+        root: &GraphNode,
+        columns: &Columns,
+        depth: usize,
+    ) -> LogTree {
+        let root_time = root.execution_duration;
+        let mut children = vec![];
+        let mut aggregated_node: Option<GraphNode> = None;
+        let mut name_counter: HashMap<&str, usize> = HashMap::new();
+
+        if let Some(unprocessed_children) = all_children.get(&node.id) {
+            for (i, child) in unprocessed_children.iter().enumerate() {
+                let name_count = name_counter.entry(&child.name).or_insert(0);
+                *name_count += 1;
+
+                let next = unprocessed_children.get(i + 1);
+                if next.is_some_and(|next| {
+                    self.config.aggregates(child, next) && !node.phase_between(child, next)
+                }) {
+                    if self.config.is_relevant(
+                        child.execution_duration,
+                        child.execution_percentage(root_time),
+                    ) {
+                        let mut indexed_child = child.clone();
+                        if self.config.annotate_index {
+                            indexed_child
+                                .metadata
+                                .insert("index".into(), format!("{}", name_count));
+                        }
+                        children.push(indexed_child);
+                    } else {
+                        aggregated_node = aggregated_node
+                            .map(|node| node.clone().aggregate_call(child))
+                            .or_else(|| Some(child.clone()));
+                    }
+                } else {
+                    let child = aggregated_node
+                        .take()
+                        .map(|node| node.aggregate_call(child))
+                        .unwrap_or_else(|| child.clone());
+                    children.push(child);
+                }
+            }
+        }
+
+        if self.config.child_overlap == ChildOverlap::Clamp {
+            let total: Duration = children.iter().map(|child| child.execution_duration).sum();
+            if total > node.execution_duration {
+                // their own children are scaled down in turn when they are rendered
+                let scale = node.execution_duration.as_secs_f64() / total.as_secs_f64();
+                for child in &mut children {
+                    child.execution_duration = child.execution_duration.mul_f64(scale);
+                }
+            }
+        }
+
+        if self.config.hide_below_percent > 0.0 || self.config.hide_below.is_some() {
+            children = children.into_iter().fold(vec![], |acc, child| {
+                let mut acc = acc;
+                if self.config.is_hidden(
+                    child.execution_duration,
+                    child.execution_percentage(root_time),
+                ) {
+                    match acc.last_mut() {
+                        Some(x) if x.name == "[...]" => *x = x.clone().aggregate(&child),
+                        _ => acc.push(GraphNode::new("[...]".into()).aggregate(&child)),
+                    }
+                } else {
+                    acc.push(child);
+                }
+                acc
+            });
+        }
+
+        if self.config.display_unaccounted && !children.is_empty() {
+            let mut unaccounted = GraphNode::new("[unaccounted]".into());
+            unaccounted.execution_duration =
+                node.execution_duration
+                    .saturating_sub(all_children.get(&node.id).map_or(
+                        std::time::Duration::new(0, 0),
+                        |children| {
+                            children
+                                .iter()
+                                .map(|x| x.execution_duration)
+                                .fold(std::time::Duration::new(0, 0), |x, y| x + y)
+                        },
+                    ));
+            let hidden = self.config.hide_unaccounted
+                && self.config.is_hidden(
+                    unaccounted.execution_duration,
+                    unaccounted.execution_percentage(root_time),
+                );
+            match self.config.unaccounted_position {
+                _ if hidden => {}
+                UnaccountedPosition::First => children.insert(0, unaccounted),
+                UnaccountedPosition::Last => children.push(unaccounted),
+            }
+        }
+
+        let child_columns = if self.config.align_columns {
+            Columns::fit(&children, root_time, self.config.time_mode)
+        } else {
+            Columns::default()
+        };
+        // the root is 100% by definition, even if it took no measurable time
+        let (percent, busy_percent) = if depth == 0 {
+            (100.0, 100.0)
+        } else {
+            (
+                node.execution_percentage(root_time),
+                node.busy_percentage(root.busy_time),
+            )
+        };
+        LogTree {
+            label: node.label(
+                percent,
+                self.config.display_busy_share.then_some(busy_percent),
+                &self.config,
+                self.no_color,
+                columns,
+                self.config
+                    .max_width
+                    .map(|width| width.saturating_sub(LogTree::prefix_width(depth))),
+            ),
+            children: node
+                .events
+                .iter()
+                .map(|(since, message)| LogTree {
+                    label: format!("• [+{since:.2?}] {message}"),
+                    children: vec![],
+                })
+                .chain(node.with_phases(children.iter().map(|child| {
+                    let tree =
+                        self.render_node(all_children, child, root, &child_columns, depth + 1);
+                    (child.wall_interval.map(|(start, _)| start), tree)
+                })))
+                .collect(),
+        }
+    }
+}
+
+/// Number of buckets the roots within `Config::merge_window` are merged into.
+const WINDOW_BUCKETS: u32 = 60;
+
+/// The trees of all roots, merged by span name.
+#[derive(Default)]
+struct MergedTree {
+    /// all roots, if `Config::merge_window` isn't set
+    all: Forest,
+    /// the roots within `Config::merge_window`, merged per `WINDOW_BUCKETS`th of the window by
+    /// the time they exited, oldest first
+    buckets: VecDeque<(Instant, Forest)>,
+    /// whether anything was merged since the trees were last printed
+    changed: bool,
+    last_print: Option<Instant>,
+}
+
+/// Trees merged by span name.
+#[derive(Default, Clone)]
+struct Forest {
+    roots: Vec<GraphNode>,
+    /// the children of the merged nodes, by the synthetic id of their parent
+    children: HashMap<u64, Vec<GraphNode>>,
+    next_id: u64,
+}
+
+impl MergedTree {
+    /// Merges the tree of a root which exited at `now`, evicting the buckets of the roots which
+    /// exited more than `window` before.
+    fn add(
+        &mut self,
+        now: Instant,
+        root: &GraphNode,
+        pending: &HashMap<u64, Vec<GraphNode>>,
+        max_names: usize,
+        window: Option<Duration>,
+    ) {
+        self.changed = true;
+        let Some(window) = window else {
+            return self.all.insert(None, root, pending, max_names);
+        };
+        self.evict(now, window);
+        let width = window / WINDOW_BUCKETS;
+        let current = self
+            .buckets
+            .back()
+            .is_some_and(|(start, _)| now.saturating_duration_since(*start) < width);
+        if !current {
+            self.buckets.push_back((now, Forest::default()));
+        }
+        if let Some((_, bucket)) = self.buckets.back_mut() {
+            bucket.insert(None, root, pending, max_names);
+        }
+    }
+
+    /// Drops the buckets whose roots all exited more than `window` before `now`.
+    fn evict(&mut self, now: Instant, window: Duration) {
+        let width = window / WINDOW_BUCKETS;
+        while let Some((start, _)) = self.buckets.front() {
+            if now.saturating_duration_since(*start) < window + width {
+                break;
+            }
+            self.buckets.pop_front();
+            // the evicted roots change the trees, even if none is left
+            self.changed = true;
+        }
+    }
+
+    /// The merged trees of the roots within the window, or of all roots without one.
+    fn forest(&self, max_names: usize) -> Cow<'_, Forest> {
+        if self.buckets.is_empty() {
+            return Cow::Borrowed(&self.all);
+        }
+        let mut forest = Forest::default();
+        for (_, bucket) in &self.buckets {
+            for root in &bucket.roots {
+                forest.insert(None, root, &bucket.children, max_names);
+            }
+        }
+        Cow::Owned(forest)
+    }
+}
+
+impl Forest {
+    /// Merges `node` and its descendants in `pending` into the children of `parent`, or into
+    /// the roots if `parent` is `None`.
+    fn insert(
+        &mut self,
+        parent: Option<u64>,
+        node: &GraphNode,
+        pending: &HashMap<u64, Vec<GraphNode>>,
+        max_names: usize,
+    ) {
+        let mut call = node.clone();
+        // the other fields differ between calls, e.g. request ids, and would grow without bound
+        call.metadata
+            .retain(|key, _| key.starts_with(COUNT_FIELD_PREFIX));
+        call.events.clear();
+        call.phases.clear();
+        let siblings = match parent {
+            Some(parent) => self.children.entry(parent).or_default(),
+            None => &mut self.roots,
+        };
+        if siblings.len() >= max_names && !siblings.iter().any(|x| x.name == call.name) {
+            call.name = "[other]".into();
+        }
+        let merged_id = match siblings.iter_mut().find(|x| x.name == call.name) {
+            Some(merged) => {
+                *merged = merged.clone().aggregate_call(&call);
+                merged.id
+            }
+            None => {
+                self.next_id += 1;
+                call.id = self.next_id;
+                siblings.push(call);
+                self.next_id
+            }
+        };
+
+        for child in pending.get(&node.id).into_iter().flatten() {
+            self.insert(Some(merged_id), child, pending, max_names);
+        }
+    }
+
+    /// Number of merged nodes.
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.roots.len() + self.children.values().map(Vec::len).sum::<usize>()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct GraphNode {
+    name: String,
+    id: u64,
+    execution_duration: std::time::Duration,
+    /// the execution time plus the busy time of the descendants on other threads
+    busy_time: std::time::Duration,
+    metadata: BTreeMap<String, String>,
+    call_count: usize,
+    /// `error!` events within the span, not counting its children
+    errors: usize,
+    /// `warn!` events within the span, not counting its children
+    warnings: usize,
+    /// messages of the events annotated below the span, with the time since it was entered
+    events: Vec<(Duration, String)>,
+    /// phase markers emitted within the span, with the time they were emitted
+    phases: Vec<(Instant, String)>,
+    /// bytes the stack grew by while the span was entered
+    stack_usage: Option<usize>,
+    /// number of spans below this one, whose overhead was subtracted from the duration
+    descendants: usize,
+    /// sum of the durations of the children
+    children_duration: std::time::Duration,
+    wall_interval: Option<(Instant, Instant)>,
+    own_wall_time: Option<std::time::Duration>,
+    /// time between the creation and the first enter
+    schedule_latency: Option<std::time::Duration>,
+    timings: Option<SpanTimings>,
+    /// only sampled for root spans
+    resources: Option<ResourceUsage>,
+    /// only set for root spans
+    lock_contention: Option<LockContention>,
+    /// track of the thread which exited the span
+    #[cfg(feature = "perfetto")]
+    thread: u64,
+    /// the enter and exit which produced this node, unlike `wall_interval` it doesn't include
+    /// the earlier enters of a re-entered span
+    #[cfg(feature = "perfetto")]
+    entered_interval: Option<(Instant, Instant)>,
+}
+
+impl GraphNode {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    /// Whether a phase of the span started between the first enters of two of its children.
+    fn phase_between(&self, a: &GraphNode, b: &GraphNode) -> bool {
+        let (Some((a, _)), Some((b, _))) = (a.wall_interval, b.wall_interval) else {
+            return false;
+        };
+        self.phases.iter().any(|(at, _)| a < *at && *at <= b)
+    }
+
+    /// Puts a divider for every phase before the first of the rendered children which was
+    /// entered after the phase started. Children without a start time, e.g. `[...]`, don't
+    /// move the dividers.
+    fn with_phases(
+        &self,
+        children: impl Iterator<Item = (Option<Instant>, LogTree)>,
+    ) -> Vec<LogTree> {
+        let divider = |phase: &str| LogTree {
+            label: format!("— phase: {phase} —"),
+            children: vec![],
+        };
+        let mut phases = self.phases.iter().peekable();
+        let mut trees = vec![];
+        for (start, tree) in children {
+            if let Some(start) = start {
+                while let Some((_, phase)) = phases.next_if(|(at, _)| *at <= start) {
+                    trees.push(divider(phase));
+                }
+            }
+            trees.push(tree);
+        }
+        trees.extend(phases.map(|(_, phase)| divider(phase)));
+        trees
+    }
+
+    /// The fields of the span, without the ones which configure the profiler.
+    fn displayed_fields(&self) -> BTreeMap<String, String> {
+        self.metadata
+            .iter()
+            .filter(|(k, _)| is_displayed_field(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// The exited span and all its descendants in `all_children`.
+    fn tree_node(&self, all_children: &HashMap<u64, Vec<GraphNode>>) -> TreeNode {
+        TreeNode {
+            name: self.name.clone(),
+            duration: self.execution_duration,
+            open: false,
+            fields: self.displayed_fields(),
+            children: all_children
+                .get(&self.id)
+                .into_iter()
+                .flatten()
+                .map(|child| child.tree_node(all_children))
+                .collect(),
+        }
+    }
+
+    /// The time not spent in the children of the span.
+    fn exclusive_duration(&self) -> std::time::Duration {
+        self.execution_duration
+            .saturating_sub(self.children_duration)
+    }
+
+    /// Returns the duration and percentage displayed as `[ duration | percent ]`, given the
+    /// inclusive percentage.
+    fn displayed_time(&self, percent: f64, time_mode: TimeMode) -> (std::time::Duration, f64) {
+        match time_mode {
+            TimeMode::Inclusive | TimeMode::Both => (self.execution_duration, percent),
+            TimeMode::Exclusive if self.execution_duration.is_zero() => (Duration::ZERO, 0.0),
+            TimeMode::Exclusive => {
+                let exclusive = self.exclusive_duration();
+                let ratio = exclusive.as_secs_f64() / self.execution_duration.as_secs_f64();
+                (exclusive, percent * ratio)
+            }
+        }
+    }
+
+    /// Returns 0 if the root took no measurable time, instead of NaN or infinity.
+    fn execution_percentage(&self, root_time: std::time::Duration) -> f64 {
+        if root_time.is_zero() {
+            return 0.0;
+        }
+        100.0 * self.execution_duration.as_secs_f64() / root_time.as_secs_f64()
+    }
+
+    /// The share of the busy time of the tree, 0 if the root took no measurable time.
+    fn busy_percentage(&self, root_busy_time: std::time::Duration) -> f64 {
+        if root_busy_time.is_zero() {
+            return 0.0;
+        }
+        100.0 * self.busy_time.as_secs_f64() / root_busy_time.as_secs_f64()
+    }
+
+    fn label(
+        &self,
+        execution_time_percent: f64,
+        busy_percent: Option<f64>,
+        config: &Config,
+        no_color: bool,
+        columns: &Columns,
+        max_width: Option<usize>,
+    ) -> String {
+        let mut info = vec![];
+        if let Some(busy_percent) = busy_percent {
+            info.push(format!(
+                "(busy {:.2?} | {busy_percent:.2}%)",
+                self.busy_time
+            ));
+        }
+        if let Some(own_wall_time) = self.own_wall_time {
+            info.push(format!("(own wall time {own_wall_time:.2?})"));
+        }
+        if let Some(latency) = self
+            .schedule_latency
+            .filter(|_| config.display_schedule_latency)
+        {
+            info.push(format!("(scheduled after {latency:.2?})"));
+        }
+        if let Some(timings) = self.timings {
+            info.push(format!(
+                "(busy {:.2?}, idle {:.2?})",
+                timings.busy, timings.idle
+            ));
+        }
+        if let Some(stack_usage) = self.stack_usage {
+            info.push(format!("(stack {:.2} KiB)", stack_usage as f64 / 1024.0));
+        }
+        if let Some(resources) = self.resources {
+            info.push(format!("({resources})"));
+        }
+        if let Some(contention) = self.lock_contention {
+            info.push(format!("({contention})"));
+        }
+        if self.call_count > 1 {
+            info.push(format!("({} calls)", self.call_count))
+        }
+        match self.errors {
+            0 => {}
+            1 => info.push("(1 error)".into()),
+            errors => info.push(format!("({errors} errors)")),
+        }
+        match self.warnings {
+            0 => {}
+            1 => info.push("(1 warning)".into()),
+            warnings => info.push(format!("({warnings} warnings)")),
+        }
+        let kv: Vec<_> = self
+            .metadata
+            .iter()
+            .filter(|(k, _)| is_displayed_field(k) && !config.hidden_fields.contains(*k))
+            .map(|(k, v)| format!("{k} = {v}"))
+            .collect();
+        if !kv.is_empty() {
+            info.push(format!("{{ {} }}", kv.join(", ")))
+        }
+
+        let name = &self.name;
+        let execution_time = self.execution_duration;
+        let Columns {
+            name: name_width,
+            duration: duration_width,
+            percent: percent_width,
+            exclusive: exclusive_width,
+        } = *columns;
+        let mut name = format!("{name:<name_width$}");
+        let (duration, percent) = self.displayed_time(execution_time_percent, config.time_mode);
+        let (self_prefix, self_suffix) = match config.time_mode {
+            TimeMode::Inclusive => (String::new(), String::new()),
+            TimeMode::Exclusive => ("self ".into(), String::new()),
+            TimeMode::Both => (
+                String::new(),
+                format!(
+                    " | self {:>exclusive_width$}",
+                    format!("{:.2?}", self.exclusive_duration())
+                ),
+            ),
+        };
+        let stats = format!(
+            " [ {self_prefix}{:>duration_width$} | {:>percent_width$}%{self_suffix} ]",
+            format!("{duration:.2?}"),
+            format!("{percent:.2}"),
+        );
+        let mut info = if info.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", info.join(" "))
+        };
+        if let Some(width) = max_width {
+            let stats_width = stats.chars().count();
+            info = truncate(
+                &info,
+                width.saturating_sub(name.chars().count() + stats_width),
+            );
+            name = truncate(&name, width.saturating_sub(stats_width));
+        }
+        let mut result = format!("{name}{stats}{info}");
+        if let Some(width) = max_width {
+            result = truncate(&result, width);
+        }
+
+        if no_color {
+            return result;
+        }
+        let color = if config.is_attention(execution_time, execution_time_percent)
+            && !self.is_expected_slow()
+        {
+            "\x1b[1;31m" // bold red
+        } else if config.is_relevant(execution_time, execution_time_percent) {
+            "\x1b[0m" // white
+        } else {
+            "\x1b[2m" // gray
+        };
+        // the name is colored by the kind, the rest of the line by the time
+        match (self.kind(), result.strip_prefix(name.as_str())) {
+            (Some(kind), Some(rest)) => {
+                format!("{}{name}\x1b[0m{color}{rest}\x1b[0m", kind.color())
+            }
+            _ => format!("{color}{result}\x1b[0m"),
+        }
+    }
+
+    fn kind(&self) -> Option<SpanKind> {
+        self.metadata
+            .get(KIND_FIELD)
+            .and_then(|value| SpanKind::parse(value))
+    }
+
+    fn is_expected_slow(&self) -> bool {
+        self.metadata
+            .get(EXPECTED_SLOW_FIELD)
+            .is_some_and(|x| x == "true")
+    }
+
+    /// Aggregates another call of the same span, summing the `count.*` fields and collecting the
+    /// distinct values of the other fields.
+    fn aggregate_call(self, other: &GraphNode) -> Self {
+        let mut node = self.aggregate(other);
+        for (key, value) in &other.metadata {
+            let Some(current) = node.metadata.get_mut(key) else {
+                node.metadata.insert(key.clone(), value.clone());
+                continue;
+            };
+            if key.starts_with(COUNT_FIELD_PREFIX) {
+                if let Some(total) = add_numbers(current, value) {
+                    *current = total;
+                }
+            } else if !current.split(FIELD_VALUE_SEPARATOR).any(|x| x == value) {
+                current.push_str(FIELD_VALUE_SEPARATOR);
+                current.push_str(value);
+            }
+        }
+        node
+    }
+
+    fn aggregate(mut self, other: &GraphNode) -> Self {
+        self.execution_duration += other.execution_duration;
+        self.busy_time += other.busy_time;
+        self.call_count += other.call_count;
+        self.errors += other.errors;
+        self.warnings += other.warnings;
+        // the timelines of several calls can't be told apart
+        self.events.clear();
+        self.phases.clear();
+        self.descendants += other.descendants;
+        self.children_duration += other.children_duration;
+        if let (Some(timings), Some(other)) = (&mut self.timings, other.timings) {
+            timings.busy += other.busy;
+            timings.idle += other.idle;
+        }
+        self.schedule_latency = self.schedule_latency.max(other.schedule_latency);
+        self.stack_usage = self.stack_usage.max(other.stack_usage);
+        self.own_wall_time = match (self.own_wall_time, other.own_wall_time) {
+            (Some(x), Some(y)) => Some(x + y),
+            (x, y) => x.or(y),
+        };
+        self
+    }
+}
+
+/// Whether a field is displayed, i.e. it doesn't configure the profiler.
+fn is_displayed_field(key: &str) -> bool {
+    !key.starts_with(PROFILE_FIELD_PREFIX) || key == CALLER_FIELD
+}
+
+/// Adds two numeric field values, keeping integers exact. Returns `None` if either isn't a number.
+fn add_numbers(a: &str, b: &str) -> Option<String> {
+    if let (Ok(a), Ok(b)) = (a.parse::<i128>(), b.parse::<i128>()) {
+        return Some((a + b).to_string());
+    }
+    Some((a.parse::<f64>().ok()? + b.parse::<f64>().ok()?).to_string())
+}
+
+/// Cuts `s` off after `width` characters, replacing the last one with `…`.
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.into();
+    }
+    match width {
+        0 => String::new(),
+        _ => s.chars().take(width - 1).chain(['…']).collect(),
+    }
+}
+
+/// Measures how much time the hooks of an empty child span add to the duration of its parent.
+///
+/// Takes the minimum of a few rounds, so preemption doesn't inflate the estimate.
+fn calibrate_overhead() -> Duration {
+    const ROUNDS: usize = 5;
+    const CALLS: u32 = 1000;
+
+    let layer = Layer::new(Config::default().with_sink(Sink::Tracing(Level::TRACE)));
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        (0..ROUNDS)
+            .filter_map(|_| {
+                let root = tracing::info_span!("calibration");
+                let _scope = root.enter();
+                let start = Instant::now();
+                for _ in 0..CALLS {
+                    drop(tracing::info_span!("empty").entered());
+                }
+                let elapsed = start.elapsed();
+
+                // the measured durations of the children are not overhead
+                let layer = dispatch.downcast_ref::<Layer>()?;
+                let shard = layer.shard().lock().ok()?;
+                let children = shard.pending.get(&registry_uid(&dispatch, &root)?)?;
+                let measured: Duration = children.iter().map(|x| x.execution_duration).sum();
+                Some(elapsed.saturating_sub(measured) / CALLS)
+            })
+            .min()
+            .unwrap_or_default()
+    })
+}
+
+/// The `SpanUid` of a span of a subscriber built on a `Registry`, which keys its node.
+fn registry_uid(dispatch: &tracing::Dispatch, span: &tracing::Span) -> Option<u64> {
+    let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>()?;
+    SpanUid::of(&registry.span(&span.id()?)?)
+}
+
+/// Widths of the label columns of siblings, all zero if they aren't aligned.
+#[derive(Debug, Default, Clone, Copy)]
+struct Columns {
+    name: usize,
+    duration: usize,
+    percent: usize,
+    /// the exclusive time of `TimeMode::Both`
+    exclusive: usize,
+}
+
+impl Columns {
+    fn fit(nodes: &[GraphNode], root_time: std::time::Duration, time_mode: TimeMode) -> Self {
+        nodes.iter().fold(Self::default(), |columns, node| {
+            let (duration, percent) =
+                node.displayed_time(node.execution_percentage(root_time), time_mode);
+            Self {
+                name: columns.name.max(node.name.chars().count()),
+                duration: columns
+                    .duration
+                    .max(format!("{duration:.2?}").chars().count()),
+                percent: columns.percent.max(format!("{percent:.2}").len()),
+                exclusive: columns
+                    .exclusive
+                    .max(format!("{:.2?}", node.exclusive_duration()).chars().count()),
+            }
+        })
+    }
+}
+
+/// Returns the part of `interval` that isn't covered by any of the `children` intervals.
+fn own_wall_time(
+    interval: (Instant, Instant),
+    children: impl Iterator<Item = (Instant, Instant)>,
+) -> std::time::Duration {
+    let (start, end) = interval;
+    let mut children: Vec<_> = children
+        .map(|(child_start, child_end)| (child_start.max(start), child_end.min(end)))
+        .filter(|(child_start, child_end)| child_start < child_end)
+        .collect();
+    children.sort();
+
+    let mut covered = std::time::Duration::ZERO;
+    let mut merged: Option<(Instant, Instant)> = None;
+    for (child_start, child_end) in children {
+        match &mut merged {
+            Some((_, merged_end)) if child_start <= *merged_end => {
+                *merged_end = (*merged_end).max(child_end);
+            }
+            _ => {
+                if let Some((merged_start, merged_end)) = merged {
+                    covered += merged_end - merged_start;
+                }
+                merged = Some((child_start, child_end));
+            }
+        }
+    }
+    if let Some((merged_start, merged_end)) = merged {
+        covered += merged_end - merged_start;
+    }
+
+    (end - start).saturating_sub(covered)
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::layers::shared_buffer::SharedBuffer;
+    use crate::Flush;
+
+    fn node(id: u64, name: &str, duration_ms: u64) -> GraphNode {
+        GraphNode {
+            id,
+            name: name.into(),
+            execution_duration: Duration::from_millis(duration_ms),
+            call_count: 1,
+            ..Default::default()
+        }
+    }
+
+    fn graph(config: Config, root: &GraphNode, children: Vec<GraphNode>) -> TracingGraph {
+        let mut graph = TracingGraph::new(config);
+        graph.no_color = true;
+        graph.children.insert(root.id, children);
+        graph
+    }
+
+    fn render(config: Config, root: &GraphNode, children: Vec<GraphNode>) -> String {
+        graph(config, root, children).render_tree(root).to_string()
+    }
+
+    #[test]
+    fn annotate_index() {
+        let root = node(1, "root", 100);
+        let children = vec![node(2, "child", 40), node(3, "child", 40)];
+
+        let output = render(Config::default(), &root, children.clone());
+        assert!(output.contains("index = 1"));
+
+        let config = Config {
+            annotate_index: false,
+            ..Default::default()
+        };
+        let output = render(config, &root, children);
+        assert!(!output.contains("index ="));
+    }
+
+    #[test]
+    fn unaccounted() {
+        let root = node(1, "root", 1000);
+        // 5ms or 0.5% are unaccounted, below the default `hide_below_percent` of 1%
+        let children = vec![node(2, "a", 600), node(3, "b", 395)];
+        let config = || Config::default().with_display_unaccounted(true);
+
+        let output = render(config(), &root, children.clone());
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[1].starts_with("├── [unaccounted] [ 5.00ms |"));
+
+        let last = config().with_unaccounted_position(UnaccountedPosition::Last);
+        let output = render(last, &root, children.clone());
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[3].starts_with("└── [unaccounted] [ 5.00ms |"));
+
+        let output = render(config().with_hide_unaccounted(true), &root, children);
+        assert!(!output.contains("[unaccounted]"));
+        assert_eq!(output.lines().count(), 3);
+    }
+
+    #[test]
+    fn expected_slow() {
+        let root = node(1, "root", 100);
+        let slow = node(2, "sleep", 80);
+        let mut expected_slow = slow.clone();
+        expected_slow
+            .metadata
+            .insert(EXPECTED_SLOW_FIELD.into(), "true".into());
+
+        for (child, attention) in [(slow, true), (expected_slow, false)] {
+            let mut tracing_graph = graph(Config::default(), &root, vec![child]);
+            tracing_graph.no_color = false;
+            let output = tracing_graph.render_tree(&root).to_string();
+            let line = output.lines().find(|x| x.contains("sleep")).unwrap();
+            assert_eq!(line.contains("\x1b[1;31m"), attention);
+            assert!(!line.contains(EXPECTED_SLOW_FIELD));
+        }
+    }
+
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Capture {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            // events of the profiled code
+            if event.metadata().target() != TRACING_TARGET {
+                return;
+            }
+            assert_eq!(*event.metadata().level(), Level::INFO);
+            let mut fields = BTreeMap::<String, String>::new();
+            event.record(&mut FieldVisitor::new(&mut fields));
+            self.0.lock().unwrap().push(fields["message"].clone());
+        }
+    }
+
+    /// Runs `f` with a `Layer` using the given config and returns the lines of the rendered trees.
+    fn capture_tree(config: Config, f: impl FnOnce()) -> Vec<String> {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config {
+            sink: Sink::Tracing(Level::INFO),
+            ..config
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        tracing::subscriber::with_default(subscriber, f);
+
+        let lines = lines.lock().unwrap();
+        lines.clone()
+    }
+
+    #[test]
+    fn with_writer() {
+        let buffer = SharedBuffer::default();
+        let layer = Layer::with_writer(buffer.clone());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = tracing::debug_span!("root span").entered();
+            drop(tracing::debug_span!("child span").entered());
+        });
+
+        let output = buffer.output();
+        let lines: Vec<_> = output.lines().collect();
+        assert!(lines[0].starts_with("root span ["), "{output}");
+        assert!(lines[1].contains("child span ["), "{output}");
+        // colors are for terminals
+        assert!(!output.contains('\x1b'), "{output}");
+    }
+
+    #[test]
+    fn outputs() {
+        let path = std::env::temp_dir().join("tracing_profile_outputs.jsonl");
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config::default()
+            .with_output(TreeFormat::Ascii, Sink::Tracing(Level::INFO))
+            .with_output(TreeFormat::Json, Sink::File(path.clone()));
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..2 {
+                let _root = tracing::debug_span!("root span").entered();
+                drop(tracing::debug_span!("child span", field = "value").entered());
+            }
+        });
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("root span ["));
+        assert!(lines[1].starts_with("└── child span ["));
+        assert!(lines[1].ends_with("{ field = value }"));
+
+        let json = std::fs::read_to_string(&path).unwrap();
+        let trees: Vec<_> = json.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(trees.len(), 2);
+        for tree in trees {
+            assert!(tree.starts_with(r#"{"name":"root span","duration_ns":"#));
+            assert!(tree.contains(r#"[{"name":"child span","duration_ns":"#));
+            assert!(tree.contains(r#""fields":{"field":"value"},"children":[]}]}"#));
+        }
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_output() {
+        use yaml_rust2::{Yaml, YamlLoader};
+
+        fn parse(yaml: &Yaml) -> (TreeNode, f64) {
+            let fields = yaml["fields"].as_hash().unwrap();
+            let node = TreeNode {
+                name: yaml["name"].as_str().unwrap().into(),
+                duration: Duration::from_nanos(yaml["duration_ns"].as_i64().unwrap() as u64),
+                open: false,
+                fields: fields
+                    .iter()
+                    .map(|(k, v)| (k.as_str().unwrap().into(), v.as_str().unwrap().into()))
+                    .collect(),
+                children: yaml["children"]
+                    .as_vec()
+                    .unwrap()
+                    .iter()
+                    .map(|child| parse(child).0)
+                    .collect(),
+            };
+            (node, yaml["percent"].as_f64().unwrap())
+        }
+
+        let path = std::env::temp_dir().join("tracing_profile_outputs.yaml");
+        let config = Config::default().with_output(TreeFormat::Yaml, Sink::File(path.clone()));
+        let subscriber = tracing_subscriber::registry().with(Layer::new(config));
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..2 {
+                let _root = tracing::debug_span!("root span", run = i).entered();
+                let _child = tracing::debug_span!("child: span", query = r#"a "b": c"#).entered();
+                drop(tracing::debug_span!("leaf").entered());
+            }
+        });
+
+        let documents = YamlLoader::load_from_str(&std::fs::read_to_string(&path).unwrap());
+        let documents = documents.unwrap();
+        assert_eq!(documents.len(), 2);
+        for (i, document) in documents.iter().enumerate() {
+            let (root, percent) = parse(document);
+            assert_eq!(percent, 100.0);
+            assert_eq!(root.name, "root span");
+            assert_eq!(root.fields["run"], i.to_string());
+            let [child] = root.children.as_slice() else {
+                panic!("{root:?}");
+            };
+            assert_eq!(child.name, "child: span");
+            assert_eq!(child.fields["query"], r#"a "b": c"#);
+            assert!(child.duration <= root.duration);
+            let [leaf] = child.children.as_slice() else {
+                panic!("{child:?}");
+            };
+            assert_eq!(leaf.name, "leaf");
+            assert!(leaf.fields.is_empty() && leaf.children.is_empty());
+
+            let (_, percent) = parse(&document["children"][0]);
+            let expected = child.duration.as_secs_f64() / root.duration.as_secs_f64() * 100.0;
+            assert!((percent - expected).abs() <= 0.005, "{percent} {expected}");
+        }
+    }
+
+    #[test]
+    fn tracing_sink() {
+        let lines = capture_tree(Config::default(), || {
+            let span = tracing::debug_span!("root span");
+            let _scope = span.enter();
+            let span = tracing::debug_span!("child span");
+            let _scope = span.enter();
+        });
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("root span ["));
+        assert!(lines[1].starts_with("└── child span ["));
+    }
+
+    #[test]
+    fn event_annotations() {
+        let config = Config::default().with_event_annotations(Level::INFO);
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("root span").entered();
+            let _child = tracing::debug_span!("child span").entered();
+            tracing::info!("cache miss");
+            std::thread::sleep(Duration::from_millis(2));
+            tracing::warn!(key = "a", "slow fetch");
+            // below the configured level
+            tracing::debug!("ignored");
+        });
+
+        assert_eq!(lines.len(), 4, "{lines:?}");
+        assert!(lines[1].starts_with("└── child span ["));
+        assert!(lines[1].ends_with("(1 warning)"));
+        let annotation = |line: &str| {
+            let (since, message) = line
+                .strip_prefix("   ├── • [+")
+                .or_else(|| line.strip_prefix("   └── • [+"))
+                .and_then(|x| x.split_once("] "))
+                .unwrap_or_else(|| panic!("{line}"));
+            (since.to_string(), message.to_string())
+        };
+        let (first, message) = annotation(&lines[2]);
+        assert_eq!(message, "cache miss");
+        let (second, message) = annotation(&lines[3]);
+        assert_eq!(message, "slow fetch { key = a }");
+        assert!(lines[3].starts_with("   └── "));
+        let millis = |since: &str| match since.strip_suffix("ms") {
+            Some(millis) => millis.parse::<f64>().unwrap(),
+            None => 0.0,
+        };
+        assert!(millis(&second) >= 2.0, "{second}");
+        assert!(millis(&first) < millis(&second));
+    }
+
+    #[test]
+    fn recover_poisoned() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config::default()
+            .with_sink(Sink::Tracing(Level::INFO))
+            .with_recover_poisoned(true);
+        let dispatch = tracing::Dispatch::new(
+            tracing_subscriber::registry()
+                .with(Layer::new(config))
+                .with(Capture(lines.clone())),
+        );
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+        std::thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _graph = layer.graph.lock().unwrap();
+                panic!("poisons the lock of the graph");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(layer.graph.is_poisoned());
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _root = tracing::debug_span!("root span").entered();
+            drop(tracing::debug_span!("child span").entered());
+        });
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("root span ["));
+        assert!(lines[1].starts_with("└── child span ["));
+        assert!(!layer.graph.is_poisoned());
+    }
+
+    #[test]
+    fn stack_usage() {
+        fn recurse(depth: usize) {
+            let _span = tracing::debug_span!("recurse").entered();
+            let buffer = std::hint::black_box([0u8; 1024]);
+            if depth > 0 {
+                recurse(depth - 1);
+            }
+            std::hint::black_box(&buffer);
+        }
+
+        let config = Config::default()
+            .with_display_stack_usage(true)
+            .with_hide_below_percent(0.0);
+        let lines = capture_tree(config, || {
+            recurse(4);
+            recurse(32);
+        });
+
+        let stack = |line: &String| -> f64 {
+            line.split("(stack ")
+                .nth(1)
+                .and_then(|x| x.split(" KiB)").next())
+                .and_then(|x| x.parse().ok())
+                .unwrap_or_else(|| panic!("{line}"))
+        };
+        let roots: Vec<_> = lines.iter().filter(|x| x.starts_with("recurse")).collect();
+        assert_eq!(roots.len(), 2);
+        let (shallow, deep) = (stack(roots[0]), stack(roots[1]));
+        // every level adds at least its buffer
+        assert!(shallow >= 4.0, "{shallow}");
+        assert!(deep >= 32.0, "{deep}");
+        assert!(deep > shallow);
+        // the innermost calls of a recursion use less stack than the outer ones
+        let levels: Vec<_> = lines[..5].iter().map(stack).collect();
+        assert!(
+            levels.windows(2).all(|pair| pair[0] > pair[1]),
+            "{levels:?}"
+        );
+    }
+
+    #[test]
+    fn name_normalizer() {
+        fn spans() {
+            let span = tracing::debug_span!("root span");
+            let _scope = span.enter();
+            drop(tracing::debug_span!("task-1").entered());
+            drop(tracing::debug_span!("task-2").entered());
+            drop(tracing::debug_span!("task-3").entered());
+        }
+        let config = || Config {
+            relevant_above_percent: 100.0,
+            hide_below_percent: 0.0,
+            ..Default::default()
+        };
+
+        let lines = capture_tree(config(), spans);
+        assert_eq!(lines.len(), 4);
+
+        let config = Config {
+            name_normalizer: Some(NameNormalizer::new(r"task-\d+", "task").unwrap()),
+            ..config()
+        };
+        let lines = capture_tree(config, spans);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("└── task ["));
+        assert!(lines[1].ends_with("(3 calls)"));
+    }
+
+    #[test]
+    fn own_wall_time_merges_overlapping_children() {
+        let base = Instant::now();
+        let interval = |start: u64, end: u64| {
+            (
+                base + Duration::from_millis(start),
+                base + Duration::from_millis(end),
+            )
+        };
+
+        let children = [
+            interval(10, 30),
+            interval(20, 50),
+            interval(25, 35),
+            interval(60, 70),
+            interval(90, 120),
+        ];
+        // covered: 10..50, 60..70 and 90..100
+        assert_eq!(
+            own_wall_time(interval(0, 100), children.into_iter()),
+            Duration::from_millis(40)
+        );
+        assert_eq!(
+            own_wall_time(interval(0, 100), std::iter::empty()),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn display_own_wall_time() {
+        let config = Config {
+            display_own_wall_time: true,
+            ..Default::default()
+        };
+        let lines = capture_tree(config, || {
+            let span = tracing::debug_span!("root span");
+            let _scope = span.enter();
+            let child = tracing::debug_span!("child span");
+            drop(child.enter());
+        });
+        assert!(lines[0].contains("(own wall time "));
+        assert!(lines[1].contains("(own wall time "));
+    }
+
+    #[test]
+    fn busy_share() {
+        let sleep = Duration::from_millis(40);
+        let config = Config::default()
+            .with_display_busy_share(true)
+            .with_annotate_index(false);
+        let lines = capture_tree(config, || {
+            let root = tracing::debug_span!("root").entered();
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        tracing::dispatcher::with_default(&dispatch, || {
+                            let _worker = tracing::debug_span!(parent: &root, "worker").entered();
+                            std::thread::sleep(sleep);
+                        })
+                    });
+                }
+            });
+            let _sequential = tracing::debug_span!("sequential").entered();
+            std::thread::sleep(sleep);
+        });
+
+        // (wall percent, busy time, busy percent) of a line
+        let shares = |line: &String| -> (f64, Duration, f64) {
+            let parse = || {
+                let (wall, busy) = line.split_once(" (busy ")?;
+                let wall = wall.rsplit_once("| ")?.1.strip_suffix("% ]")?;
+                let (busy_millis, busy_percent) = busy.split_once("ms | ")?;
+                let busy_percent = busy_percent.split_once("%)")?.0;
+                Some((
+                    wall.trim().parse().ok()?,
+                    Duration::from_secs_f64(busy_millis.parse::<f64>().ok()? / 1000.0),
+                    busy_percent.parse().ok()?,
+                ))
+            };
+            parse().unwrap_or_else(|| panic!("{line}"))
+        };
+        assert_eq!(lines.len(), 6, "{lines:?}");
+        let (_, root_busy, root_percent) = shares(&lines[0]);
+        assert_eq!(root_percent, 100.0);
+        // the root was entered for both phases while the workers ran in parallel
+        assert!(root_busy >= sleep * 6, "{}", lines[0]);
+
+        let workers: Vec<_> = lines.iter().filter(|x| x.contains("worker")).collect();
+        assert_eq!(workers.len(), 4);
+        let mut wall_total = 0.0;
+        let mut busy_total = 0.0;
+        for worker in workers {
+            let (wall, busy, percent) = shares(worker);
+            // sleeping while entered counts as busy
+            assert!(busy >= sleep, "{worker}");
+            // every worker took about half of the wall time, but a sixth of the busy time
+            assert!(percent < wall / 2.0, "{worker}");
+            wall_total += wall;
+            busy_total += percent;
+        }
+        assert!(wall_total > 100.0, "{lines:?}");
+        assert!(busy_total < 100.0, "{lines:?}");
+
+        let sequential = lines.iter().find(|x| x.contains("sequential")).unwrap();
+        let (wall, _, percent) = shares(sequential);
+        assert!(percent < wall, "{sequential}");
+    }
+
+    #[test]
+    fn absolute_hide_threshold() {
+        let micros = |id, name: &str, duration| GraphNode {
+            execution_duration: Duration::from_micros(duration),
+            ..node(id, name, 0)
+        };
+        let root = micros(1, "root", 1000);
+        let children = vec![
+            micros(2, "fast", 50),
+            micros(3, "other", 60),
+            micros(4, "slow", 500),
+            micros(5, "tiny", 10),
+        ];
+
+        // 5% and more isn't hidden by the default percentage
+        let output = render(Config::default(), &root, children.clone());
+        assert!(!output.contains("[...]"));
+
+        let config = Config {
+            hide_below: Some(Duration::from_micros(100)),
+            ..Default::default()
+        };
+        let output = render(config, &root, children);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("├── [...] [ 110.00µs"));
+        assert!(lines[1].ends_with("(2 calls)"));
+        assert!(lines[2].starts_with("├── slow"));
+        assert!(lines[3].starts_with("└── [...] [ 10.00µs"));
+    }
+
+    #[test]
+    fn hidden_fields() {
+        let csv = SharedBuffer::default();
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config {
+            sink: Sink::Tracing(Level::INFO),
+            hidden_fields: HashSet::from(["query".to_string()]),
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::new(config))
+            .with(crate::CsvLayer::new_synchronous(csv.clone()))
+            .with(Capture(lines.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::debug_span!("root span", query = "SELECT 1", rows = 1);
+            drop(span.enter());
+        });
+
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].contains("{ rows = 1 }"));
+        assert!(!lines[0].contains("SELECT 1"));
+        assert!(csv.output().contains("SELECT 1"));
+    }
+
+    #[test]
+    fn busy_idle_timings() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config {
+            sink: Sink::Tracing(Level::INFO),
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TimingLayer::default())
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::debug_span!("root span");
+            std::thread::sleep(Duration::from_millis(5));
+            drop(span.enter());
+        });
+
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].contains("(busy "));
+        assert!(!lines[0].contains("idle 0ns"));
+    }
+
+    #[test]
+    fn root_resources() {
+        fn sample() -> ResourceUsage {
+            ResourceUsage {
+                allocations: Some(42),
+                ..ResourceUsage::sample()
+            }
+        }
+        let config = Config {
+            root_resources: Some(sample),
+            ..Default::default()
+        };
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("root span").entered();
+            drop(tracing::debug_span!("child span").entered());
+        });
+
+        assert!(lines[0].contains("42 allocations)"), "{}", lines[0]);
+        #[cfg(unix)]
+        assert!(lines[0].contains("(peak rss "), "{}", lines[0]);
+        assert!(!lines[1].contains("allocations"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn parent_override() {
+        let lines = capture_tree(Config::default(), || {
+            let _root = tracing::debug_span!("root span").entered();
+            let logical_parent = tracing::debug_span!("logical parent");
+            let parent_id = logical_parent.id().unwrap().into_u64();
+            let _scope = logical_parent.enter();
+            // lexically a child of the root, e.g. running on a thread pool
+            let child =
+                tracing::debug_span!(parent: None, "child span", profile.parent_id = parent_id);
+            drop(child.enter());
+        });
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("root span ["));
+        assert!(lines[1].starts_with("└── logical parent ["));
+        assert!(lines[2].starts_with("   └── child span ["), "{}", lines[2]);
+    }
+
+    #[test]
+    fn time_mode() {
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::new(
+            Config::default().with_sink(Sink::Tracing(Level::INFO)),
+        )));
+        tracing::dispatcher::with_default(&dispatch, || {
+            // keeps the parent in the graph after it exits
+            let outer = tracing::debug_span!("outer");
+            let _outer = outer.enter();
+            let parent = tracing::debug_span!("parent").entered();
+            std::thread::sleep(Duration::from_millis(5));
+            for _ in 0..2 {
+                let _child = tracing::debug_span!("child").entered();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            drop(parent);
+
+            let layer = dispatch.downcast_ref::<Layer>().unwrap();
+            // the nodes wait in the shard of this thread until the root exits
+            let shard = layer.shard().lock().unwrap();
+            let parent = &shard.pending[&registry_uid(&dispatch, &outer).unwrap()][0];
+            let children: Duration = shard.pending[&parent.id]
+                .iter()
+                .map(|x| x.execution_duration)
+                .sum();
+            assert_eq!(
+                parent.exclusive_duration(),
+                parent.execution_duration - children
+            );
+            assert!(parent.exclusive_duration() >= Duration::from_millis(5));
+        });
+
+        let mut root = node(1, "root", 100);
+        root.children_duration = Duration::from_millis(50);
+        let children = vec![node(2, "a", 30), node(3, "b", 20)];
+        let render = |time_mode| {
+            let config = Config::default().with_time_mode(time_mode);
+            let output = render(config, &root, children.clone());
+            output.lines().map(String::from).collect::<Vec<_>>()
+        };
+        let lines = render(TimeMode::Exclusive);
+        assert_eq!(lines[0], "root [ self 50.00ms | 50.00% ]");
+        assert_eq!(lines[1], "├── a [ self 30.00ms | 30.00% ]");
+        let lines = render(TimeMode::Both);
+        assert_eq!(lines[0], "root [ 100.00ms | 100.00% | self 50.00ms ]");
+    }
+
+    #[test]
+    fn schedule_latency() {
+        let config = Config::default().with_display_schedule_latency(true);
+        let lines = capture_tree(config, || {
+            let root = tracing::debug_span!("root span");
+            std::thread::sleep(Duration::from_millis(10));
+            let _root = root.enter();
+        });
+
+        let latency = lines[0]
+            .split("(scheduled after ")
+            .nth(1)
+            .and_then(|x| x.strip_suffix("ms)"))
+            .and_then(|x| x.parse::<f64>().ok());
+        assert!(latency.is_some_and(|x| x >= 10.0), "{}", lines[0]);
+    }
+
+    #[test]
+    fn root_spans() {
+        let config = Config::default().with_root_spans(["library call"]);
+        let lines = capture_tree(config, || {
+            let _host = tracing::debug_span!("host").entered();
+            for _ in 0..2 {
+                let _root = tracing::debug_span!("library call").entered();
+                drop(tracing::debug_span!("child span").entered());
+            }
+            let _marked = tracing::debug_span!("marked", profile.root = true).entered();
+        });
+
+        // printed when the designated roots exit, before their parent
+        assert_eq!(lines.len(), 6, "{lines:?}");
+        assert!(lines[0].starts_with("library call ["), "{}", lines[0]);
+        assert!(lines[1].starts_with("└── child span ["), "{}", lines[1]);
+        assert!(lines[2].starts_with("library call ["), "{}", lines[2]);
+        assert!(lines[3].starts_with("└── child span ["), "{}", lines[3]);
+        assert!(lines[4].starts_with("marked ["), "{}", lines[4]);
+        assert!(lines[5].starts_with("host ["), "{}", lines[5]);
+    }
+
+    #[test]
+    fn align_columns() {
+        let config = Config {
+            align_columns: true,
+            hide_below_percent: 0.0,
+            ..Default::default()
+        };
+        let root = node(1, "root", 1000);
+        let children = vec![
+            node(2, "a", 5),
+            node(3, "longer name", 900),
+            node(4, "medium", 95),
+        ];
+        let output = render(config, &root, children);
+
+        let lines: Vec<_> = output.lines().skip(1).collect();
+        let column = |line: &str, pattern| line.chars().position(|c| c == pattern).unwrap();
+        for pattern in ['[', '|', ']'] {
+            let columns: Vec<_> = lines.iter().map(|line| column(line, pattern)).collect();
+            assert!(columns.iter().all(|x| *x == columns[0]), "{output}");
+        }
+    }
+
+    #[test]
+    fn with_methods() {
+        let config = Config::default().with_hide_below_percent(0.5);
+        let default = Config::default();
+        assert_eq!(config.hide_below_percent, 0.5);
+        assert_eq!(
+            format!(
+                "{:?}",
+                Config {
+                    hide_below_percent: 0.5,
+                    ..default
+                }
+            ),
+            format!("{config:?}")
+        );
+
+        let config = Config::default()
+            .with_hide_below(Duration::from_micros(100))
+            .with_hidden_fields(["secret"]);
+        assert_eq!(config.hide_below, Some(Duration::from_micros(100)));
+        assert!(config.hidden_fields.contains("secret"));
+    }
+
+    #[test]
+    fn zero_root_time() {
+        let output = render(
+            Config::default().with_hide_below_percent(0.0),
+            &node(1, "root", 0),
+            vec![node(2, "child", 0)],
+        );
+
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[0], "root [ 0.00ns | 100.00% ]");
+        assert_eq!(lines[1], "└── child [ 0.00ns | 0.00% ]");
+    }
+
+    #[test]
+    fn max_width() {
+        let config = Config::default()
+            .with_max_width(40)
+            .with_hide_below_percent(0.0);
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("root span", query = "SELECT * FROM t").entered();
+            let _child = tracing::debug_span!("a child span with a rather long name").entered();
+            let _grandchild = tracing::debug_span!("short", x = 1).entered();
+        });
+
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            assert!(line.chars().count() <= 40, "{line}");
+        }
+        // the fields are cut off first
+        assert!(lines[0].starts_with("root span ["), "{}", lines[0]);
+        assert!(lines[0].contains("% ] {"), "{}", lines[0]);
+        assert!(lines[0].ends_with('…'), "{}", lines[0]);
+        // then the name, keeping the duration
+        assert!(lines[1].starts_with("└── a child span"), "{}", lines[1]);
+        assert!(lines[1].contains("… ["), "{}", lines[1]);
+        assert!(lines[1].ends_with("% ]"), "{}", lines[1]);
+        assert!(lines[2].starts_with("   └── short ["), "{}", lines[2]);
+    }
+
+    #[test]
+    fn overhead_compensation() {
+        const CHILDREN: u32 = 10_000;
+
+        let config = Config::default()
+            .with_overhead_compensation(true)
+            .with_sink(Sink::Tracing(Level::INFO));
+        let layer = Layer::new(config);
+        let overhead = layer.graph.lock().unwrap().overhead;
+        assert!(!overhead.is_zero());
+
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            // keeps the parent in the graph after it exits
+            let outer = tracing::debug_span!("outer");
+            let _outer = outer.enter();
+            let parent = tracing::debug_span!("parent").entered();
+            for _ in 0..CHILDREN {
+                drop(tracing::debug_span!("child").entered());
+            }
+            drop(parent);
+
+            let layer = dispatch.downcast_ref::<Layer>().unwrap();
+            // the nodes wait in the shard of this thread until the root exits
+            let shard = layer.shard().lock().unwrap();
+            let parent = &shard.pending[&registry_uid(&dispatch, &outer).unwrap()][0];
+            let children = &shard.pending[&parent.id];
+            assert_eq!(parent.descendants, CHILDREN as usize);
+
+            // the parent does no work besides running its children
+            let work: Duration = children.iter().map(|x| x.execution_duration).sum();
+            let compensated = parent.execution_duration;
+            // off by less than half of the subtracted overhead
+            let tolerance = overhead * CHILDREN / 2;
+            assert!(
+                compensated.abs_diff(work) < tolerance,
+                "work {work:?}, compensated {compensated:?}, tolerance {tolerance:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn late_counts() {
+        let config = Config::default().with_relevant_above(Duration::from_secs(60));
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("root").entered();
+            for rows in [1, 2, 3] {
+                let span = tracing::debug_span!("query", count.rows = tracing::field::Empty);
+                let _scope = span.enter();
+                span.record("count.rows", rows);
+            }
+            // recorded after the span exited, while the node waits for its parent
+            let span = tracing::debug_span!("load", count.bytes = tracing::field::Empty);
+            drop(span.enter());
+            span.record("count.bytes", 42);
+        });
+
+        assert!(lines[1].starts_with("├── query"));
+        assert!(lines[1].ends_with("(3 calls) { count.rows = 6 }"));
+        assert!(lines[2].starts_with("└── load"));
+        assert!(lines[2].ends_with("{ count.bytes = 42 }"));
+    }
+
+    #[test]
+    fn show_legend() {
+        let config = Config::default()
+            .with_show_legend(true)
+            .with_display_unaccounted(true);
+        let lines = capture_tree(config, || {
+            drop(tracing::debug_span!("root span").entered());
+        });
+        assert!(lines[0].starts_with("root span ["));
+        assert_eq!(lines[1], "legend:");
+        let legend = lines[2..].join("\n");
+        assert!(legend.contains("[...]: siblings faster than 1.00% of the root"));
+        assert!(legend.contains("(N calls): "));
+        assert!(legend.contains("[unaccounted]: "));
+        // `Sink::Tracing` isn't colored, so there are no colors to explain
+        assert!(!legend.contains("red"));
+
+        let root = node(1, "root", 100);
+        let config = Config::default()
+            .with_show_legend(true)
+            .with_attention_above(Duration::from_millis(50));
+        let mut colored = graph(config, &root, vec![]);
+        colored.no_color = false;
+        let legend = colored.legend(&BTreeSet::new()).unwrap().to_string();
+        assert!(legend.contains("bold red\x1b[0m: slower than 50.00ms\n"));
+        assert!(legend.contains("white: slower than 2.50% of the root\n"));
+
+        let mut disabled = graph(Config::default(), &root, vec![]);
+        disabled.no_color = false;
+        assert!(disabled.legend(&BTreeSet::new()).is_none());
+    }
+
+    #[test]
+    fn color_always_overrides_no_color() {
+        let no_color = |key: &str| (key == "NO_COLOR").then(|| OsString::from("1"));
+        let empty_no_color = |key: &str| (key == "NO_COLOR").then(OsString::new);
+        let unset = |_: &str| None;
+        assert!(ColorChoice::Always.enabled_with_env(&Sink::Stdout, no_color));
+        assert!(!ColorChoice::Auto.enabled_with_env(&Sink::Stdout, no_color));
+        assert!(ColorChoice::Auto.enabled_with_env(&Sink::Stdout, empty_no_color));
+        assert!(ColorChoice::Auto.enabled_with_env(&Sink::Stdout, unset));
+        assert!(!ColorChoice::Auto.enabled_with_env(&Sink::Tracing(Level::INFO), unset));
+        assert!(!ColorChoice::Never.enabled_with_env(&Sink::Stdout, unset));
+
+        let root = node(1, "root", 100);
+        let render = |color| {
+            let mut graph = TracingGraph::new(Config::default().with_color(color));
+            graph.children.insert(root.id, vec![node(2, "child", 80)]);
+            graph.render_tree(&root).to_string()
+        };
+        assert!(render(ColorChoice::Always).contains("\x1b[1;31m"));
+        assert!(!render(ColorChoice::Never).contains('\x1b'));
+    }
+
+    #[test]
+    fn field_aggregation() {
+        let root = node(1, "root", 100);
+        let query = |id, table: &str| {
+            let mut node = node(id, "query", 1);
+            node.metadata.insert("table".into(), table.into());
+            node
+        };
+        let children = vec![query(2, "users"), query(3, "orders"), query(4, "users")];
+
+        let output = render(Config::default(), &root, children.clone());
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with("(3 calls) { table = users | orders }"));
+
+        let config = Config::default().with_field_aggregation(FieldAggregation::Split);
+        let output = render(config, &root, children);
+        let tables: Vec<_> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split("table = ").nth(1).unwrap())
+            .collect();
+        assert_eq!(tables, ["users }", "orders }", "users }"]);
+    }
+
+    #[test]
+    fn error_counts() {
+        let lines = capture_tree(Config::default(), || {
+            let _root = tracing::debug_span!("root").entered();
+            let _handle = tracing::debug_span!("handle").entered();
+            for _ in 0..3 {
+                tracing::error!("failed");
+            }
+            tracing::warn!("retrying");
+            tracing::info!("done");
+        });
+
+        assert!(!lines[0].contains("error"));
+        assert!(lines[1].starts_with("└── handle ["));
+        assert!(lines[1].ends_with("] (3 errors) (1 warning)"));
+    }
+
+    #[test]
+    fn emit_only_above() {
+        let config = Config::default().with_emit_only_above(Duration::from_millis(20));
+        let lines = capture_tree(config, || {
+            for (request, millis) in [("fast", 1), ("slow", 30), ("fast", 2)] {
+                let _root = tracing::debug_span!("request", request).entered();
+                let _child = tracing::debug_span!("handler").entered();
+                std::thread::sleep(Duration::from_millis(millis));
+            }
+        });
+        let roots: Vec<_> = lines
+            .iter()
+            .filter(|line| line.starts_with("request"))
+            .collect();
+        assert_eq!(roots.len(), 1, "{lines:?}");
+        assert!(roots[0].ends_with("{ request = slow }"), "{lines:?}");
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        assert!(lines[1].contains("handler ["));
+
+        // the fast roots still count towards the kind totals
+        let layer = Layer::new(Config::default().with_emit_only_above(Duration::from_secs(60)));
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _root = tracing::debug_span!("request", profile.kind = "io").entered();
+            std::thread::sleep(Duration::from_millis(5));
+        });
+        let totals = dispatch.downcast_ref::<Layer>().unwrap().kind_totals();
+        assert!(totals[&SpanKind::Io] >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn sort_by_field() {
+        let config = Config::default()
+            .with_hide_below_percent(0.0)
+            .with_field_aggregation(FieldAggregation::Split)
+            .with_sort_by_field("stage_index");
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("pipeline").entered();
+            for (stage, stage_index) in [("link", 3), ("parse", 1), ("emit", 10), ("check", 2)] {
+                drop(tracing::debug_span!("stage", stage, stage_index).entered());
+            }
+            drop(tracing::debug_span!("cleanup").entered());
+        });
+        let order: Vec<_> = lines[1..]
+            .iter()
+            .map(|line| line.split(" [").next().unwrap())
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "├── stage",
+                "├── stage",
+                "├── stage",
+                "├── stage",
+                "└── cleanup"
+            ],
+            "{lines:?}"
+        );
+        let stages: Vec<_> = lines[1..5]
+            .iter()
+            .map(|line| line.split("stage = ").nth(1).unwrap())
+            .collect();
+        assert!(stages[0].starts_with("parse"), "{lines:?}");
+        assert!(stages[1].starts_with("check"), "{lines:?}");
+        assert!(stages[2].starts_with("link"), "{lines:?}");
+        assert!(stages[3].starts_with("emit"), "{lines:?}");
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn capture_caller() {
+        #[inline(never)]
+        fn load_config() {
+            drop(tracing::debug_span!("load").entered());
+        }
+
+        let config = Config::default()
+            .with_hide_below_percent(0.0)
+            .with_capture_caller(true);
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("startup").entered();
+            load_config();
+        });
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        assert!(
+            lines[0].contains("profile.caller = tracing_profile::layers::graph::tests::"),
+            "{lines:?}"
+        );
+        assert!(
+            lines[1].ends_with(
+                "{ profile.caller = tracing_profile::layers::graph::tests::capture_caller::load_config }"
+            ),
+            "{lines:?}"
+        );
+
+        let lines = capture_tree(Config::default(), load_config);
+        assert!(!lines[0].contains("caller"), "{lines:?}");
+    }
+
+    #[test]
+    fn phase_markers() {
+        let config = Config::default().with_hide_below_percent(0.0);
+        let lines = capture_tree(config, || {
+            let _root = tracing::debug_span!("benchmark").entered();
+            drop(tracing::debug_span!("load").entered());
+            tracing::info!(profile.phase = "warmup complete");
+            for _ in 0..3 {
+                drop(tracing::debug_span!("step").entered());
+            }
+            tracing::info!(profile.phase = "measurement complete");
+            for _ in 0..3 {
+                drop(tracing::debug_span!("step").entered());
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        });
+
+        let names: Vec<_> = lines
+            .iter()
+            .skip(1)
+            .map(|line| line.split(" [").next().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "├── load",
+                "├── — phase: warmup complete —",
+                "├── step",
+                "├── — phase: measurement complete —",
+                "└── step",
+            ],
+            "{lines:?}"
+        );
+        // the steps of a phase are aggregated, but not across the divider
+        assert!(lines[3].contains("(3 calls)"), "{lines:?}");
+        assert!(lines[5].contains("(3 calls)"), "{lines:?}");
+    }
+
+    #[test]
+    fn merge_roots() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config::default()
+            .with_sink(Sink::Tracing(Level::INFO))
+            .with_hide_below_percent(0.0)
+            .with_merge_roots(true)
+            .with_max_distinct_names(4);
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        let dispatch = tracing::Dispatch::new(subscriber);
+        tracing::dispatcher::with_default(&dispatch, || {
+            for i in 0..100 {
+                let _request = tracing::debug_span!("request", id = i).entered();
+                drop(tracing::debug_span!("parse").entered());
+                drop(tracing::debug_span!("query", table = "users").entered());
+                drop(tracing::debug_span!("query", table = "orders").entered());
+                drop(tracing::debug_span!("cache", key = i).entered());
+                drop(tracing::debug_span!("handler").entered());
+                // names beyond the first four children end up in `[other]`
+                drop(tracing::debug_span!("audit").entered());
+                drop(tracing::debug_span!("respond").entered());
+            }
+            assert!(lines.lock().unwrap().is_empty());
+
+            let layer = dispatch.downcast_ref::<Layer>().unwrap();
+            layer.flush_handle().unwrap().flush();
+            // request, parse, query, cache, handler and [other]
+            assert_eq!(layer.graph.lock().unwrap().merged.all.len(), 6);
+        });
+
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].starts_with("request ["));
+        assert!(lines[0].ends_with("(100 calls)"));
+        let calls = |name: &str| {
+            let line = lines.iter().find(|line| line.contains(name)).unwrap();
+            line.split_once(" calls)")
+                .unwrap()
+                .0
+                .rsplit('(')
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(calls("parse"), "100");
+        assert_eq!(calls("query"), "200");
+        assert_eq!(calls("[other]"), "200");
+    }
+
+    #[test]
+    fn merge_window() {
+        let node = |id, name: &str| GraphNode {
+            id,
+            name: name.into(),
+            call_count: 1,
+            execution_duration: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let window = Duration::from_secs(60);
+        // a fake clock, the roots exit at these offsets
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut merged = MergedTree::default();
+        for (id, secs) in [(1, 0), (3, 30), (5, 70)] {
+            let root = node(id, "request");
+            let pending = HashMap::from([(id, vec![node(id + 1, "query")])]);
+            merged.add(at(secs), &root, &pending, 1000, Some(window));
+        }
+
+        // the root which exited at 0s left the window at 70s
+        let forest = merged.forest(1000);
+        let request = &forest.roots[0];
+        assert_eq!(request.call_count, 2);
+        assert_eq!(request.execution_duration, Duration::from_millis(20));
+        assert_eq!(forest.children[&request.id][0].call_count, 2);
+        assert_eq!(merged.buckets.len(), 2);
+
+        merged.changed = false;
+        merged.evict(at(90), window);
+        assert!(!merged.changed);
+        merged.evict(at(120), window);
+        assert!(merged.changed);
+        assert_eq!(merged.forest(1000).roots[0].call_count, 1);
+        assert_eq!(merged.forest(1000).len(), 2);
+
+        merged.evict(at(200), window);
+        assert_eq!(merged.forest(1000).len(), 0);
+    }
+
+    #[test]
+    fn lock_contention() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config::default()
+            .with_sink(Sink::Tracing(Level::INFO))
+            .with_display_lock_contention(true);
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        let dispatch = tracing::Dispatch::new(subscriber);
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+
+        // hold the lock while another thread exits its spans
+        let graph = layer.graph.lock().unwrap();
+        let thread = std::thread::spawn({
+            let dispatch = dispatch.clone();
+            move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    let _root = tracing::debug_span!("root").entered();
+                    drop(tracing::debug_span!("child").entered());
+                })
+            }
+        });
+        // the acquisition is counted before the lock is tried, which fails while it is held here
+        while layer.lock_contention().acquisitions == 0 {
+            std::thread::yield_now();
+        }
+        drop(graph);
+        thread.join().unwrap();
+
+        let contention = layer.lock_contention();
+        // only the root locks the graph
+        assert_eq!(contention.acquisitions, 1);
+        assert_eq!(contention.contended, 1);
+        assert!(!contention.waited.is_zero());
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].contains("(profiler lock contended 1/1 times"));
+    }
+
+    #[test]
+    fn kind_totals() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config::default().with_sink(Sink::Tracing(Level::INFO));
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        let dispatch = tracing::Dispatch::new(subscriber);
+        let sleep = |ms| std::thread::sleep(Duration::from_millis(ms));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _job = tracing::info_span!("job", profile.kind = "compute").entered();
+            sleep(10);
+            {
+                let _read = tracing::info_span!("read", profile.kind = "io").entered();
+                sleep(10);
+                // spans without a kind count towards the kind of their parent
+                let _parse = tracing::info_span!("parse").entered();
+                sleep(20);
+            }
+            let _lock = tracing::info_span!("lock", profile.kind = "wait").entered();
+            sleep(10);
+        });
+
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+        let totals = layer.kind_totals();
+        assert!(totals[&SpanKind::Io] >= Duration::from_millis(30));
+        assert!(totals[&SpanKind::Compute] >= Duration::from_millis(10));
+        assert!(totals[&SpanKind::Compute] < totals[&SpanKind::Io]);
+        assert!(totals[&SpanKind::Wait] >= Duration::from_millis(10));
+
+        let flush = layer.flush_handle().unwrap();
+        tracing::dispatcher::with_default(&dispatch, || flush.flush());
+        let lines = lines.lock().unwrap();
+        let summary = lines
+            .iter()
+            .position(|line| line.starts_with("time by kind ["))
+            .unwrap();
+        assert!(lines[summary + 1].starts_with("├── io ["));
+        assert!(lines[summary + 2].starts_with("├── compute ["));
+        assert!(lines[summary + 3].starts_with("└── wait ["));
+        // the kind field itself isn't displayed
+        assert!(!lines.iter().any(|line| line.contains("profile.kind")));
+    }
+
+    #[test]
+    fn snapshot() {
+        let config = Config::default().with_sink(Sink::Tracing(Level::TRACE));
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::new(config)));
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _root = tracing::debug_span!("root").entered();
+            drop(tracing::debug_span!("done", rows = 3).entered());
+            let _running = tracing::debug_span!("running").entered();
+            std::thread::sleep(Duration::from_millis(5));
+
+            let snapshot = layer.snapshot();
+            assert_eq!(snapshot.children.len(), 1);
+            let root = &snapshot.children[0];
+            assert_eq!(root.name, "root");
+            assert!(root.open);
+            let [done, running] = &root.children[..] else {
+                panic!("unexpected children {:?}", root.children);
+            };
+            assert_eq!((done.name.as_str(), done.open), ("done", false));
+            assert_eq!(done.fields["rows"], "3");
+            assert_eq!((running.name.as_str(), running.open), ("running", true));
+            assert!(running.duration >= Duration::from_millis(5));
+            assert!(root.duration >= running.duration);
+            assert!(snapshot
+                .to_json()
+                .contains(",\"children\":[{\"name\":\"done\",\"duration_ns\":"));
+        });
+        // nothing is entered anymore
+        assert!(layer.snapshot().children.is_empty());
+    }
+
+    #[test]
+    fn reused_span_ids() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let config = Config::default()
+            .with_sink(Sink::Tracing(Level::INFO))
+            .with_hide_below_percent(0.0);
+        let subscriber = crate::layers::reused_ids::ReusedIds::default()
+            .with(Layer::new(config))
+            .with(Capture(lines.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = tracing::debug_span!("root").entered();
+            // the children point at their parent by id, so the parents can close before them
+            let child = |name, parent_id: u64| {
+                drop(tracing::debug_span!(parent: None, "child", profile.parent_id = parent_id, name).entered())
+            };
+            let first = tracing::debug_span!("first");
+            let id = first.id().unwrap().into_u64();
+            first.in_scope(|| child("first child", id));
+            drop(first);
+            let second = tracing::debug_span!("second");
+            assert_eq!(second.id().unwrap().into_u64(), id);
+            second.in_scope(|| child("second child", id));
+        });
+
+        let lines = lines.lock().unwrap();
+        let names: Vec<_> = lines
+            .iter()
+            .map(|line| line.split(" [").next().unwrap())
+            .collect();
+        assert!(lines[2].contains("{ name = first child }"));
+        assert!(lines[4].contains("{ name = second child }"));
+        assert_eq!(
+            names,
+            [
+                "root",
+                "├── first",
+                "│  └── child",
+                "└── second",
+                "   └── child"
+            ]
+        );
+    }
+
+    #[test]
+    fn contention_per_root() {
+        const THREADS: u64 = 8;
+        const SPANS: usize = 1000;
+        let config = Config::default().with_sink(Sink::Tracing(Level::TRACE));
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::new(config)));
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+        let (exited, children_exited) = std::sync::mpsc::channel();
+        let release = std::sync::Barrier::new(THREADS as usize + 1);
+        let waited = std::thread::scope(|scope| {
+            // as if a root was exiting on another thread the whole time
+            let graph = layer.graph.lock().unwrap();
+            for _ in 0..THREADS {
+                let exited = exited.clone();
+                let (dispatch, release) = (&dispatch, &release);
+                scope.spawn(move || {
+                    tracing::dispatcher::with_default(dispatch, || {
+                        let _root = tracing::debug_span!("root").entered();
+                        for _ in 0..SPANS {
+                            let _child = tracing::debug_span!("child").entered();
+                            drop(tracing::debug_span!("grandchild").entered());
+                        }
+                        exited.send(()).unwrap();
+                        release.wait();
+                    })
+                });
+            }
+            let timeout = Duration::from_secs(10);
+            let waited = (0..THREADS).any(|_| children_exited.recv_timeout(timeout).is_err());
+            drop(graph);
+            release.wait();
+            waited
+        });
+
+        // the threads exited 16000 spans but only locked the graph when their roots exited
+        assert!(!waited, "exiting children waited for the graph");
+        assert_eq!(layer.lock_contention().acquisitions, THREADS);
+    }
+
+    #[test]
+    fn late_children_dont_leak() {
+        let config = Config::default().with_sink(Sink::Tracing(Level::TRACE));
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::new(config)));
+        tracing::dispatcher::with_default(&dispatch, || {
+            for _ in 0..100 {
+                let root = tracing::debug_span!("root");
+                let child = tracing::debug_span!(parent: &root, "child");
+                drop(root.enter());
+                drop(root);
+                // the tree of the root was already built
+                drop(child.entered());
+            }
+        });
+
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+        for shard in layer.pending.iter() {
+            assert!(shard.lock().unwrap().pending.is_empty());
+        }
+    }
+
+    #[test]
+    fn threads_merge_like_single_thread() {
+        fn work(root: &tracing::Span) {
+            let _work = tracing::debug_span!(parent: root, "work").entered();
+            for _ in 0..2 {
+                let _step = tracing::debug_span!("step").entered();
+                drop(tracing::debug_span!("read").entered());
+            }
+        }
+        // the timings differ between the runs, only the structure is compared
+        let structure = |lines: Vec<String>| -> Vec<String> {
+            lines
+                .iter()
+                .map(|line| {
+                    let (name, rest) = line.split_once(" [").unwrap();
+                    format!("{name}{}", rest.split_once(']').unwrap().1)
+                })
+                .collect()
+        };
+        let config = || {
+            Config::default()
+                .with_relevant_above(Duration::from_secs(60))
+                .with_hide_below(Duration::ZERO)
+        };
+
+        let single = capture_tree(config(), || {
+            let root = tracing::debug_span!("root").entered();
+            for _ in 0..4 {
+                work(&root);
+            }
+        });
+        let threads = capture_tree(config(), || {
+            let root = tracing::debug_span!("root").entered();
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| tracing::dispatcher::with_default(&dispatch, || work(&root)));
+                }
+            });
+        });
+
+        assert_eq!(structure(single.clone()), structure(threads));
+        assert!(single
+            .iter()
+            .any(|line| line.contains("work") && line.contains("(4 calls)")));
+    }
+
+    #[test]
+    fn warn_child_overlap() {
+        let sleep = Duration::from_millis(20);
+        let run = |name: &'static str, child_overlap| {
+            let config = Config::default()
+                .with_child_overlap(child_overlap)
+                .with_display_unaccounted(true);
+            capture_tree(config, || {
+                let root = tracing::debug_span!("overlapping root", name).entered();
+                let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+                std::thread::scope(|scope| {
+                    for _ in 0..3 {
+                        scope.spawn(|| {
+                            tracing::dispatcher::with_default(&dispatch, || {
+                                let _worker =
+                                    tracing::debug_span!(parent: &root, "worker").entered();
+                                std::thread::sleep(sleep);
+                            })
+                        });
+                    }
+                });
+            })
+        };
+        let errors = crate::error::collected_errors();
+        let warnings = || {
+            errors
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|x| {
+                    matches!(x, ProfileError::ChildrenExceedParent { name, parent, children }
+                        if name == "overlapping root" && children > parent)
+                })
+                .count()
+        };
+        let percents = |lines: &[String]| -> Vec<f64> {
+            lines
+                .iter()
+                .filter(|line| line.contains("worker"))
+                .map(|line| {
+                    let percent = line.split(" | ").nth(1).unwrap();
+                    percent.split('%').next().unwrap().trim().parse().unwrap()
+                })
+                .collect()
+        };
+
+        let before = warnings();
+        let lines = run("ignore", ChildOverlap::Ignore);
+        assert_eq!(warnings(), before);
+        let measured: f64 = percents(&lines).iter().sum();
+        assert!(measured > 100.0, "{lines:?}");
+        // the unaccounted time doesn't underflow
+        assert!(lines[1].contains("[unaccounted] [ 0.00ns |"), "{lines:?}");
+
+        let lines = run("warn", ChildOverlap::Warn);
+        assert_eq!(warnings(), before + 1);
+        assert!(percents(&lines).iter().sum::<f64>() > 100.0, "{lines:?}");
+
+        let lines = run("clamp", ChildOverlap::Clamp);
+        assert_eq!(warnings(), before + 2);
+        let clamped: f64 = percents(&lines).iter().sum();
+        assert!(clamped <= 100.01, "{lines:?}");
+    }
+
+    #[test]
+    fn warn_never_entered() {
+        let errors = crate::error::collected_errors();
+        let subscriber = tracing_subscriber::registry().with(Layer::default());
+        tracing::subscriber::with_default(subscriber, || {
+            drop(tracing::debug_span!("forgotten span"));
+            drop(tracing::debug_span!("forgotten span"));
+            drop(tracing::debug_span!("entered span").entered());
+        });
+
+        let errors = errors.lock().unwrap();
+        let count = |name| {
+            errors
+                .iter()
+                .filter(|x| **x == ProfileError::NeverEntered { name })
+                .count()
+        };
+        assert_eq!(count("forgotten span"), 1);
+        assert_eq!(count("entered span"), 0);
+    }
 }
@@ -0,0 +1,235 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::span;
+
+use crate::{
+    data::{insert_to_span_storage, with_span_storage_mut, HistogramMetadata},
+    err_msg, Flush, FlushHandle, ProfileError,
+};
+
+/// Maximum number of `#` characters in a histogram bar.
+const MAX_BAR_WIDTH: usize = 40;
+
+/// HistogramLayer (internally called layer::histogram)
+/// This Layer collects the duration of every call of the span with the given name and prints a
+/// histogram of them to stdout when the layer or the `ProfileGuard` is dropped.
+///
+/// example output:
+/// ```bash
+/// child span3:
+/// [1-2µs]     ######################################## 12
+/// [2-5µs]     ############# 4
+/// [5-10µs]     0
+/// [10-20µs]   ### 1
+/// ```
+pub struct Layer {
+    span_name: String,
+    histogram: Arc<Mutex<Histogram>>,
+    /// set once the histogram has been printed, so that it isn't printed again on drop
+    printed: Arc<AtomicBool>,
+}
+
+impl Layer {
+    pub fn new(span_name: impl Into<String>) -> Self {
+        Self {
+            span_name: span_name.into(),
+            histogram: Default::default(),
+            printed: Default::default(),
+        }
+    }
+
+    fn is_tracked<S>(&self, id: &span::Id, ctx: &tracing_subscriber::layer::Context<'_, S>) -> bool
+    where
+        S: tracing::Subscriber,
+        S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        ctx.metadata(id)
+            .is_some_and(|metadata| metadata.name() == self.span_name)
+    }
+}
+
+fn print_once(span_name: &str, histogram: &Mutex<Histogram>, printed: &AtomicBool) {
+    if printed.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let Ok(histogram) = histogram.lock() else {
+        return err_msg!(ProfileError::MutexPoisoned);
+    };
+    print!("{}:\n{}", span_name, histogram);
+}
+
+impl Flush for Layer {
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let span_name = self.span_name.clone();
+        let (histogram, printed) = (self.histogram.clone(), self.printed.clone());
+        Some(FlushHandle::new(move || {
+            print_once(&span_name, &histogram, &printed)
+        }))
+    }
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        print_once(&self.span_name, &self.histogram, &self.printed);
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        if attrs.metadata().name() == self.span_name {
+            insert_to_span_storage(id, ctx, HistogramMetadata { start_time: None });
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        if !self.is_tracked(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |storage: &mut HistogramMetadata| {
+            storage.start_time.replace(Instant::now());
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        if !self.is_tracked(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |storage: &mut HistogramMetadata| {
+            let Some(start_time) = storage.start_time.take() else {
+                return;
+            };
+            let Ok(mut histogram) = self.histogram.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            histogram.record(start_time.elapsed());
+        });
+    }
+}
+
+/// Counts durations in buckets following a 1-2-5 series, i.e. 1-2ns, 2-5ns, 5-10ns, 10-20ns...
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<usize>,
+}
+
+impl Histogram {
+    fn record(&mut self, duration: Duration) {
+        let bucket = Self::bucket(duration);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    fn bucket(duration: Duration) -> usize {
+        let nanos = duration.as_nanos().max(1);
+        (0..)
+            .find(|&bucket| nanos < Self::lower_bound(bucket + 1))
+            .expect("durations are finite")
+    }
+
+    fn lower_bound(bucket: usize) -> u128 {
+        [1, 2, 5][bucket % 3] * 10u128.pow((bucket / 3) as u32)
+    }
+
+    fn label(bucket: usize) -> String {
+        let (low, low_unit) = human_nanos(Self::lower_bound(bucket));
+        let (high, high_unit) = human_nanos(Self::lower_bound(bucket + 1));
+        if low_unit == high_unit {
+            format!("[{low}-{high}{high_unit}]")
+        } else {
+            format!("[{low}{low_unit}-{high}{high_unit}]")
+        }
+    }
+}
+
+impl std::fmt::Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(first) = self.buckets.iter().position(|x| *x > 0) else {
+            return Ok(());
+        };
+        let max = self.buckets.iter().copied().max().unwrap_or_default();
+        let labels: Vec<_> = (first..self.buckets.len()).map(Self::label).collect();
+        let width = labels
+            .iter()
+            .map(|x| x.chars().count())
+            .max()
+            .unwrap_or_default();
+
+        for (label, count) in labels.iter().zip(&self.buckets[first..]) {
+            let bar = "#".repeat((count * MAX_BAR_WIDTH).div_ceil(max));
+            writeln!(f, "{label:<width$} {bar} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+fn human_nanos(nanos: u128) -> (u128, &'static str) {
+    match nanos {
+        0..=999 => (nanos, "ns"),
+        1_000..=999_999 => (nanos / 1_000, "µs"),
+        1_000_000..=999_999_999 => (nanos / 1_000_000, "ms"),
+        _ => (nanos / 1_000_000_000, "s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_counts() {
+        let mut histogram = Histogram::default();
+        for micros in [1, 1, 3, 4, 15, 1500, 1999] {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        let output = histogram.to_string();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines.first().unwrap().split_whitespace().next(),
+            Some("[1-2µs]")
+        );
+        for (label, count) in [
+            ("[1-2µs]", 2),
+            ("[2-5µs]", 2),
+            ("[5-10µs]", 0),
+            ("[10-20µs]", 1),
+            ("[500µs-1ms]", 0),
+            ("[1-2ms]", 2),
+        ] {
+            let line = lines
+                .iter()
+                .find(|x| x.starts_with(&format!("{label} ")))
+                .unwrap();
+            assert!(line.ends_with(&format!(" {count}")), "{line}");
+            assert_eq!(line.matches('#').count() > 0, count > 0);
+        }
+        assert!(lines.last().unwrap().starts_with("[1-2ms]"));
+    }
+}
@@ -1,8 +1,26 @@
+pub mod budget;
+pub mod callback;
 pub mod csv;
+pub mod flamechart;
 pub mod graph;
+pub mod histogram;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod perf_script;
+pub mod ring_buffer;
+pub mod summary;
+#[cfg(target_os = "linux")]
+pub mod syscalls;
+pub mod tcp;
+pub mod timing;
 
 #[cfg(feature = "perfetto")]
 pub mod perfetto;
 
 #[cfg(feature = "perf_counters")]
 pub mod print_perf_counters;
+
+#[cfg(test)]
+pub(crate) mod reused_ids;
+#[cfg(test)]
+pub(crate) mod shared_buffer;
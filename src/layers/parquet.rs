@@ -0,0 +1,295 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    fs::File,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use arrow_array::{
+    builder::{MapBuilder, StringBuilder, UInt64Builder},
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef};
+use parquet::{arrow::ArrowWriter, errors::ParquetError};
+
+use super::callback;
+use crate::{data::ClosedSpan, err_msg, Flush, FlushHandle, ProfileError};
+
+/// ParquetLayer (internally called layer::parquet)
+/// This Layer writes the closed spans to a Parquet file with typed columns, for analysis with
+/// DataFusion, DuckDB or pandas without parsing CSV:
+///
+/// | column       | type                      |
+/// |--------------|---------------------------|
+/// | `id`         | `UInt64`                  |
+/// | `parent_id`  | `UInt64`, null for roots  |
+/// | `elapsed_ns` | `UInt64`                  |
+/// | `name`       | `Utf8`                    |
+/// | `depth`      | `UInt64`, 1 for roots     |
+/// | `fields`     | `Map<Utf8, Utf8>`         |
+///
+/// The spans are buffered into Arrow record batches, and every full batch is written as a row
+/// group. The file is only readable once its footer is written when the layer is flushed, e.g.
+/// by the `ProfileGuard`, or dropped. Unlike the other layers a flush therefore ends the
+/// recording: spans which close after it aren't written, which is reported once as
+/// [`ProfileError::OutputFinished`].
+pub struct Layer {
+    writer: Arc<Mutex<Writer>>,
+    inner: callback::Layer,
+}
+
+impl Layer {
+    /// Writes to the given file, 8192 spans per row group.
+    pub fn new<T: AsRef<Path>>(output_file: T) -> Self {
+        Self::with_batch_size(output_file, 8192)
+    }
+
+    /// Writes to the given file, `batch_size` spans per row group.
+    pub fn with_batch_size<T: AsRef<Path>>(output_file: T, batch_size: usize) -> Self {
+        // panic right away, like `CsvLayer`, rather than after the program ran
+        let file = File::create(output_file).expect("ParquetLayer failed to open file");
+        let writer = ArrowWriter::try_new(file, schema(), None)
+            .expect("ParquetLayer failed to create writer");
+        let writer = Arc::new(Mutex::new(Writer {
+            rows: Rows::default(),
+            batch_size: batch_size.max(1),
+            out: Some(writer),
+            reported_finished: false,
+        }));
+        let inner = {
+            let writer = writer.clone();
+            callback::Layer::new(move |span| {
+                let Ok(mut writer) = writer.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                if let Err(e) = writer.push(span) {
+                    err_msg!(ProfileError::WriteFailed(std::io::Error::from(e).kind()));
+                }
+            })
+        };
+        Self { writer, inner }
+    }
+}
+
+/// The columns of the file.
+fn schema() -> SchemaRef {
+    let entries = Fields::from(vec![
+        Field::new("keys", DataType::Utf8, false),
+        Field::new("values", DataType::Utf8, true),
+    ]);
+    let fields = DataType::Map(
+        Arc::new(Field::new("entries", DataType::Struct(entries), false)),
+        false,
+    );
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::UInt64, false),
+        Field::new("parent_id", DataType::UInt64, true),
+        Field::new("elapsed_ns", DataType::UInt64, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("depth", DataType::UInt64, false),
+        Field::new("fields", fields, false),
+    ]))
+}
+
+/// The spans of the next record batch.
+struct Rows {
+    len: usize,
+    id: UInt64Builder,
+    parent_id: UInt64Builder,
+    elapsed_ns: UInt64Builder,
+    name: StringBuilder,
+    depth: UInt64Builder,
+    fields: MapBuilder<StringBuilder, StringBuilder>,
+}
+
+impl Default for Rows {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            id: UInt64Builder::new(),
+            parent_id: UInt64Builder::new(),
+            elapsed_ns: UInt64Builder::new(),
+            name: StringBuilder::new(),
+            depth: UInt64Builder::new(),
+            fields: MapBuilder::new(None, StringBuilder::new(), StringBuilder::new()),
+        }
+    }
+}
+
+impl Rows {
+    fn push(&mut self, span: &ClosedSpan) -> Result<(), ParquetError> {
+        self.id.append_value(span.id);
+        self.parent_id.append_option(span.parent_id);
+        self.elapsed_ns
+            .append_value(span.duration.as_nanos() as u64);
+        self.name.append_value(span.name);
+        self.depth.append_value(span.depth);
+        for (key, value) in &span.fields {
+            self.fields.keys().append_value(key);
+            self.fields.values().append_value(value);
+        }
+        self.fields.append(true)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Takes the buffered spans as a record batch.
+    fn finish(&mut self) -> Result<RecordBatch, ParquetError> {
+        self.len = 0;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.id.finish()),
+            Arc::new(self.parent_id.finish()),
+            Arc::new(self.elapsed_ns.finish()),
+            Arc::new(self.name.finish()),
+            Arc::new(self.depth.finish()),
+            Arc::new(self.fields.finish()),
+        ];
+        Ok(RecordBatch::try_new(schema(), columns)?)
+    }
+}
+
+struct Writer {
+    rows: Rows,
+    batch_size: usize,
+    /// `None` once the footer is written
+    out: Option<ArrowWriter<File>>,
+    /// Whether a span closing after the footer was reported
+    reported_finished: bool,
+}
+
+impl Writer {
+    fn push(&mut self, span: &ClosedSpan) -> Result<(), ParquetError> {
+        let Some(out) = &mut self.out else {
+            if !std::mem::replace(&mut self.reported_finished, true) {
+                crate::error::warn(&ProfileError::OutputFinished {
+                    context: "ParquetLayer",
+                });
+            }
+            return Ok(());
+        };
+        self.rows.push(span)?;
+        if self.rows.len >= self.batch_size {
+            out.write(&self.rows.finish()?)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered spans and the footer.
+    fn close(&mut self) -> Result<(), ParquetError> {
+        let Some(mut out) = self.out.take() else {
+            return Ok(());
+        };
+        if self.rows.len > 0 {
+            out.write(&self.rows.finish()?)?;
+        }
+        out.close()?;
+        Ok(())
+    }
+}
+
+fn close(writer: &Mutex<Writer>) {
+    // a panicking thread may hold the lock, the spans are still worth writing
+    let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = writer.close() {
+        err_msg!(ProfileError::WriteFailed(std::io::Error::from(e).kind()));
+    }
+}
+
+impl Flush for Layer {
+    /// Writes the remaining spans and finishes the file. A Parquet file can't be appended to once
+    /// its footer is written, so only the first flush has an effect and the spans closing after
+    /// it are left out.
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let writer = self.writer.clone();
+        Some(FlushHandle::new(move || close(&writer)))
+    }
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        close(&self.writer);
+    }
+}
+
+callback::forward_to_callback!(Layer);
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{cast::AsArray, types::UInt64Type, Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn read_back() {
+        let path = std::env::temp_dir().join(format!(
+            "tracing_profile_parquet_{}.parquet",
+            std::process::id()
+        ));
+        let subscriber = tracing_subscriber::registry().with(Layer::with_batch_size(&path, 4));
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = debug_span!("root span").entered();
+            for i in 0..10 {
+                drop(debug_span!("child span", i, kind = "io").entered());
+            }
+        });
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        // full batches of 4 spans and the remaining 3
+        assert_eq!(builder.metadata().num_row_groups(), 3);
+        let batches = builder
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.schema(), schema());
+        assert_eq!(batch.num_rows(), 11);
+
+        // the root closes last
+        let id = batch.column(0).as_primitive::<UInt64Type>();
+        let parent_id = batch.column(1).as_primitive::<UInt64Type>();
+        assert!(parent_id.is_null(10));
+        assert_eq!(parent_id.value(9), id.value(10));
+        assert_eq!(batch.column(3).as_string::<i32>().value(10), "root span");
+        assert_eq!(batch.column(4).as_primitive::<UInt64Type>().value(9), 2);
+
+        let fields = batch.column(5).as_map();
+        let entries = fields.value(0);
+        let keys = entries.column(0).as_string::<i32>();
+        let values = entries.column(1).as_string::<i32>();
+        assert_eq!((keys.value(0), values.value(0)), ("i", "0"));
+        assert_eq!((keys.value(1), values.value(1)), ("kind", "io"));
+        assert_eq!(fields.value(10).len(), 0);
+    }
+
+    #[test]
+    fn spans_after_flush() {
+        let errors = crate::error::collected_errors();
+        let path = std::env::temp_dir().join(format!(
+            "tracing_profile_parquet_after_flush_{}.parquet",
+            std::process::id()
+        ));
+        let layer = Layer::new(&path);
+        let flush = layer.flush_handle().unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(debug_span!("before").entered());
+            flush.flush();
+            drop(debug_span!("after").entered());
+            drop(debug_span!("after").entered());
+        });
+
+        let finished = ProfileError::OutputFinished {
+            context: "ParquetLayer",
+        };
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.iter().filter(|e| **e == finished).count(), 1);
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(builder.metadata().file_metadata().num_rows(), 1);
+    }
+}
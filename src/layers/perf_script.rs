@@ -0,0 +1,247 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::ThreadId,
+    time::Instant,
+};
+
+use tracing::{span, Metadata};
+
+use crate::{data::os_thread_id, err_msg, Flush, FlushHandle, ProfileError};
+
+/// PerfScriptLayer (internally called layer::perf_script)
+/// This Layer writes the time spent in every stack of spans as samples in the text format of
+/// `perf script`, so tools built for `perf` can read it:
+/// ```bash
+/// main 4711/4711 0.000001: 1520 span-clock:
+///     55d0c3a2b1c0 root span ([tracing])
+///
+/// main 4711/4711 0.001521: 3194 span-clock:
+///     55d0c3a2b3e8 child span1 ([tracing])
+///     55d0c3a2b1c0 root span ([tracing])
+///
+/// ```
+///
+/// Every interval during which the stack of entered spans on a thread didn't change becomes a
+/// sample. The header holds the name of the thread (the executable for unnamed threads), the
+/// process and thread id, the start of the interval in seconds since the layer was created and
+/// the length of the interval in nanoseconds as the period. The stack follows with the innermost
+/// span first, the address identifies the callsite of the span. Fold the samples with inferno:
+/// ```bash
+/// inferno-collapse-perf trace.perf | inferno-flamegraph > flamegraph.svg
+/// ```
+pub struct Layer {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    threads: Mutex<HashMap<ThreadId, ThreadStack>>,
+    init_time: Instant,
+    pid: u32,
+    /// `comm` of threads without a name
+    process_name: String,
+}
+
+/// The spans entered on a thread.
+struct ThreadStack {
+    comm: String,
+    tid: u64,
+    frames: Vec<&'static Metadata<'static>>,
+    /// nanoseconds since `init_time` at which `frames` became the stack
+    since: u64,
+}
+
+impl Layer {
+    pub fn new<T: AsRef<Path>>(output_file: T) -> Self {
+        // this should panic, like `CsvLayer`, so the user doesn't run their program for nothing
+        let f = std::fs::File::create(output_file).expect("PerfScriptLayer failed to open file");
+        Self::with_writer(BufWriter::new(f))
+    }
+
+    /// Writes the samples to `writer`. The samples are written while the spans are entered and
+    /// exited, so wrap slow writers in a `BufWriter`.
+    pub fn with_writer<W: Write + Send + 'static>(writer: W) -> Self {
+        let process_name = std::env::current_exe()
+            .ok()
+            .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "unknown".into());
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            threads: Default::default(),
+            init_time: Instant::now(),
+            pid: std::process::id(),
+            process_name,
+        }
+    }
+
+    /// Writes the sample of the interval which ends now for the current stack of the thread and
+    /// replaces the stack with `update`.
+    fn transition(&self, update: impl FnOnce(&mut Vec<&'static Metadata<'static>>)) {
+        let now = self.init_time.elapsed().as_nanos() as u64;
+        let Ok(mut threads) = self.threads.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        let stack = threads
+            .entry(std::thread::current().id())
+            .or_insert_with(|| ThreadStack {
+                comm: std::thread::current()
+                    .name()
+                    .map_or_else(|| self.process_name.clone(), Into::into),
+                tid: os_thread_id(),
+                frames: vec![],
+                since: now,
+            });
+        if !stack.frames.is_empty() && now > stack.since {
+            let Ok(mut writer) = self.writer.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            let sample = self.sample(stack, now - stack.since);
+            if let Err(e) = writer.write_all(sample.as_bytes()) {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        }
+        update(&mut stack.frames);
+        stack.since = now;
+    }
+
+    fn sample(&self, stack: &ThreadStack, period: u64) -> String {
+        let mut sample = format!(
+            "{} {}/{} {}.{:06}: {} span-clock:\n",
+            stack.comm,
+            self.pid,
+            stack.tid,
+            stack.since / 1_000_000_000,
+            stack.since % 1_000_000_000 / 1000,
+            period
+        );
+        for metadata in stack.frames.iter().rev() {
+            let address = *metadata as *const Metadata<'static> as usize;
+            let _ = writeln!(sample, "\t{address:x} {} ([tracing])", metadata.name());
+        }
+        sample.push('\n');
+        sample
+    }
+}
+
+impl Flush for Layer {
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let writer = self.writer.clone();
+        Some(FlushHandle::new(move || {
+            let Ok(mut writer) = writer.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            if let Err(e) = writer.flush() {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        }))
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        // marks the spans created while profiling is paused, which are then left out
+        crate::pause::skip_new_span(id, &ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_enter"
+            });
+        };
+        self.transition(|frames| frames.push(span.metadata()));
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
+        };
+        self.transition(|frames| {
+            // spans are usually exited in reverse order, but guards can be dropped in any order
+            if let Some(i) = frames
+                .iter()
+                .rposition(|metadata| std::ptr::eq(*metadata, span.metadata()))
+            {
+                frames.remove(i);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::layers::shared_buffer::SharedBuffer;
+
+    #[test]
+    fn perf_script_grammar() {
+        let buffer = SharedBuffer::default();
+        let subscriber = tracing_subscriber::registry().with(Layer::with_writer(buffer.clone()));
+        std::thread::Builder::new()
+            .name("profiled".into())
+            .spawn(|| {
+                tracing::subscriber::with_default(subscriber, || {
+                    let _root = debug_span!("root span").entered();
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                    {
+                        let _child = debug_span!("child span").entered();
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                })
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let output = buffer.output();
+        // the grammar `perf script` prints samples with callchains in, and which e.g.
+        // stackcollapse-perf.pl parses
+        let header =
+            Regex::new(r"^(\S.*?)\s+(\d+)/(\d+)\s+(\d+)\.(\d{6}):\s+(\d+)\s+(\S+):$").unwrap();
+        let frame = Regex::new(r"^\s+([0-9a-f]+) (.+) \((.*)\)$").unwrap();
+
+        let mut samples = vec![];
+        let mut lines = output.lines();
+        while let Some(line) = lines.next() {
+            let captures = header.captures(line).expect(line);
+            assert_eq!(&captures[1], "profiled");
+            assert_eq!(captures[2].parse::<u32>().unwrap(), std::process::id());
+            assert_eq!(&captures[7], "span-clock");
+            let timestamp = format!("{}.{}", &captures[4], &captures[5]);
+            let mut stack = vec![];
+            for line in lines.by_ref().take_while(|line| !line.is_empty()) {
+                let captures = frame.captures(line).expect(line);
+                assert_eq!(&captures[3], "[tracing]");
+                stack.push(captures[2].to_string());
+            }
+            samples.push((timestamp.parse::<f64>().unwrap(), stack));
+        }
+        assert!(output.ends_with("\n\n"));
+
+        let stacks: Vec<_> = samples.iter().map(|(_, stack)| stack.join(";")).collect();
+        // the innermost span comes first
+        assert_eq!(stacks, ["root span", "child span;root span", "root span"]);
+        assert!(samples.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+}
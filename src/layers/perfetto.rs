@@ -1,11 +1,12 @@
-use std::fmt;
+use std::{collections::HashSet, fmt};
 use tracing::{
     field::{Field, Visit},
-    span,
+    span, Metadata,
 };
+use tracing_subscriber::filter::LevelFilter;
 
 use crate::data::{with_span_storage_mut, PerfettoMetadata};
-use crate::err_msg;
+use crate::{err_msg, ProfileError};
 
 // gets the needed data out of an Event by implementing the Visit trait
 #[derive(Default)]
@@ -32,8 +33,105 @@ impl Visit for FpgaThroughputEvent {
     fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
 }
 
+/// Selects the spans which create slices in the trace, by default all of them.
+///
+/// Skipping the spans of hot loops keeps the trace small enough to be opened. Events such as
+/// `fpga_throughput` are recorded regardless of the filter.
+#[derive(Debug, Clone)]
+pub struct SpanFilter {
+    /// only spans with one of these names create slices, all names if empty
+    pub names: HashSet<String>,
+    /// only spans enabled by this level create slices, e.g. `LevelFilter::INFO` skips
+    /// `debug_span!` and `trace_span!`
+    pub level: LevelFilter,
+}
+
+impl Default for SpanFilter {
+    fn default() -> Self {
+        Self {
+            names: HashSet::new(),
+            level: LevelFilter::TRACE,
+        }
+    }
+}
+
+impl SpanFilter {
+    pub fn with_names<T: Into<String>>(mut self, names: impl IntoIterator<Item = T>) -> Self {
+        self.names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    fn selects(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.level
+            && (self.names.is_empty() || self.names.contains(metadata.name()))
+    }
+}
+
+/// The unit of the `bps` field of `fpga_throughput` events, which are converted to bits per
+/// second before they are recorded.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ThroughputUnit {
+    #[default]
+    BitsPerSecond,
+    BytesPerSecond,
+    GigabitsPerSecond,
+    GigabytesPerSecond,
+    /// Bits per second are the value multiplied by the factor.
+    Scale(f64),
+}
+
+impl ThroughputUnit {
+    fn factor(self) -> f64 {
+        match self {
+            ThroughputUnit::BitsPerSecond => 1.0,
+            ThroughputUnit::BytesPerSecond => 8.0,
+            ThroughputUnit::GigabitsPerSecond => 1e9,
+            ThroughputUnit::GigabytesPerSecond => 8e9,
+            ThroughputUnit::Scale(factor) => factor,
+        }
+    }
+
+    /// Converts a value in this unit to bits per second.
+    fn to_bits_per_second(self, value: u64) -> u64 {
+        match self {
+            ThroughputUnit::BitsPerSecond => value,
+            // saturates instead of overflowing
+            unit => (value as f64 * unit.factor()).round() as u64,
+        }
+    }
+}
+
+/// A slice begun when a span is entered, which ends when it is dropped on exit.
+#[derive(Debug)]
+pub enum Slice {
+    Perfetto(perfetto_sys::TraceEvent),
+    /// records its begin and end instead, for the tests
+    #[cfg(test)]
+    Recorded {
+        name: &'static str,
+        number: usize,
+    },
+}
+
+#[cfg(test)]
+impl Drop for Slice {
+    fn drop(&mut self) {
+        if let Slice::Recorded { name, number } = self {
+            tests::end_slice(name, *number);
+        }
+    }
+}
+
 pub struct Layer {
     _perfetto_guard: Option<perfetto_sys::PerfettoGuard>,
+    filter: SpanFilter,
+    throughput_unit: ThroughputUnit,
+    begin_slice: fn(&'static str) -> Slice,
 }
 
 impl Default for Layer {
@@ -44,12 +142,60 @@ impl Default for Layer {
 
 impl Layer {
     pub fn new(backend: perfetto_sys::Backend) -> Self {
+        Self::new_with_filter(backend, SpanFilter::default())
+    }
+
+    /// Only the spans selected by `filter` create slices, the others are skipped when they are
+    /// entered and exited.
+    pub fn new_with_filter(backend: perfetto_sys::Backend, filter: SpanFilter) -> Self {
+        Self::with_guard(perfetto_sys::PerfettoGuard::new(backend), filter)
+    }
+
+    /// Registers the slices under the Perfetto data source `data_source_name` instead of the
+    /// default one, so a Perfetto config can enable or disable them on their own. Only the spans
+    /// selected by `filter` create slices.
+    pub fn with_data_source_name(
+        backend: perfetto_sys::Backend,
+        data_source_name: &str,
+        filter: SpanFilter,
+    ) -> Self {
+        Self::start_data_source(
+            backend,
+            data_source_name,
+            filter,
+            perfetto_sys::PerfettoGuard::with_data_source_name,
+        )
+    }
+
+    /// Starts the session with `start`, which the tests replace to see the name it receives.
+    fn start_data_source(
+        backend: perfetto_sys::Backend,
+        data_source_name: &str,
+        filter: SpanFilter,
+        start: fn(perfetto_sys::Backend, &str) -> perfetto_sys::PerfettoGuard,
+    ) -> Self {
+        Self::with_guard(start(backend, data_source_name), filter)
+    }
+
+    fn with_guard(perfetto_guard: perfetto_sys::PerfettoGuard, filter: SpanFilter) -> Self {
         Self {
-            _perfetto_guard: Some(perfetto_sys::PerfettoGuard::new(backend)),
+            _perfetto_guard: Some(perfetto_guard),
+            filter,
+            throughput_unit: ThroughputUnit::default(),
+            begin_slice: |name| Slice::Perfetto(perfetto_sys::TraceEvent::new(name)),
         }
     }
+
+    /// Sets the unit of the `bps` field of `fpga_throughput` events, e.g.
+    /// `ThroughputUnit::GigabytesPerSecond` for `bps = 2` to record 16 Gb/s.
+    pub fn with_throughput_unit(mut self, throughput_unit: ThroughputUnit) -> Self {
+        self.throughput_unit = throughput_unit;
+        self
+    }
 }
 
+impl crate::Flush for Layer {}
+
 impl<S> tracing_subscriber::Layer<S> for Layer
 where
     S: tracing::Subscriber,
@@ -70,10 +216,11 @@ where
         event.record(&mut data);
 
         let Some(card) = data.card else {
-            err_msg!("invalid fpga throughput event: {:?}", event);
+            err_msg!(ProfileError::InvalidEvent(format!("{:?}", event)));
             return;
         };
-        perfetto_sys::record_fpga_throughput(&card, data.bps);
+        let bps = self.throughput_unit.to_bits_per_second(data.bps);
+        perfetto_sys::record_fpga_throughput(&card, bps);
     }
 
     fn on_record(
@@ -85,29 +232,41 @@ where
     }
 
     fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let span_name = match ctx.span(id) {
-            Some(span) => span.name(),
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let (span_name, selected) = match ctx.span(id) {
+            Some(span) => (span.name(), self.filter.selects(span.metadata())),
             None => {
-                err_msg!("failed to get span on_enter");
+                err_msg!(ProfileError::SpanNotFound {
+                    context: "on_enter"
+                });
                 return;
             }
         };
+        if !selected {
+            return;
+        }
         with_span_storage_mut::<PerfettoMetadata, _>(id, ctx, |storage| {
-            storage
-                .trace_guard
-                .replace(perfetto_sys::TraceEvent::new(span_name));
+            storage.trace_guards.push((self.begin_slice)(span_name));
         });
     }
 
     fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
         if let Some(span) = ctx.span(id) {
+            if !self.filter.selects(span.metadata()) {
+                return;
+            }
             if let Some(storage) = span.extensions_mut().get_mut::<PerfettoMetadata>() {
-                storage.trace_guard.take();
+                storage.trace_guards.pop();
             } else {
-                err_msg!("failed to get storage on_exit");
+                err_msg!(ProfileError::StorageMissing { context: "on_exit" });
             }
         } else {
-            err_msg!("failed to get span on_exit");
+            err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
         }
     }
 
@@ -117,13 +276,146 @@ where
         id: &span::Id,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
         let Some(span) = ctx.span(id) else {
-            err_msg!("failed to get span on_new_span");
+            err_msg!(ProfileError::SpanNotFound {
+                context: "on_new_span"
+            });
             return;
         };
+        if !self.filter.selects(span.metadata()) {
+            return;
+        }
 
-        let storage = PerfettoMetadata { trace_guard: None };
+        let storage = PerfettoMetadata {
+            trace_guards: Default::default(),
+        };
         let mut extensions = span.extensions_mut();
         extensions.insert(storage);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    thread_local! {
+        static SLICES: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    }
+
+    /// Records the begin of a slice in `SLICES`, numbered by its position.
+    fn begin_slice(name: &'static str) -> Slice {
+        let number = SLICES.with(|slices| {
+            let mut slices = slices.borrow_mut();
+            let number = slices.len();
+            slices.push(format!("B {name} {number}"));
+            number
+        });
+        Slice::Recorded { name, number }
+    }
+
+    pub(super) fn end_slice(name: &str, number: usize) {
+        SLICES.with(|slices| slices.borrow_mut().push(format!("E {name} {number}")));
+    }
+
+    /// Returns the slices recorded on this thread by a layer filtered by `filter`.
+    fn recorded_slices(filter: SpanFilter, f: impl FnOnce()) -> Vec<String> {
+        let mut layer = Layer::new_with_filter(perfetto_sys::Backend::InProcess, filter);
+        layer.begin_slice = begin_slice;
+        SLICES.with(|slices| slices.borrow_mut().clear());
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), f);
+        SLICES.with(|slices| slices.take())
+    }
+
+    #[test]
+    fn reentered_span() {
+        let slices = recorded_slices(SpanFilter::default(), || {
+            let span = tracing::info_span!("outer");
+            let _outer = span.enter();
+            drop(tracing::info_span!("child").entered());
+            // entering again begins a nested slice instead of ending the first one
+            let inner = span.enter();
+            SLICES.with(|slices| assert_eq!(slices.borrow().last().unwrap(), "B outer 3"));
+            drop(inner);
+        });
+        assert_eq!(
+            slices,
+            [
+                "B outer 0",
+                "B child 1",
+                "E child 1",
+                "B outer 3",
+                "E outer 3",
+                "E outer 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn filtered_spans_create_no_slices() {
+        let filter = SpanFilter::default()
+            .with_names(["selected", "hot loop"])
+            .with_level(LevelFilter::INFO);
+        let slices = recorded_slices(filter, || {
+            let _selected = tracing::info_span!("selected").entered();
+            // not selected by name
+            drop(tracing::info_span!("other").entered());
+            // selected by name but below the level
+            for _ in 0..2 {
+                drop(tracing::debug_span!("hot loop").entered());
+            }
+        });
+        assert_eq!(slices, ["B selected 0", "E selected 0"]);
+    }
+
+    #[test]
+    fn data_source_name() {
+        thread_local! {
+            static STARTED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+        }
+        fn start(
+            backend: perfetto_sys::Backend,
+            data_source_name: &str,
+        ) -> perfetto_sys::PerfettoGuard {
+            STARTED.with(|started| started.borrow_mut().push(data_source_name.into()));
+            perfetto_sys::PerfettoGuard::new(backend)
+        }
+
+        let _layer = Layer::start_data_source(
+            perfetto_sys::Backend::InProcess,
+            "tracing_profile.slices",
+            SpanFilter::default(),
+            start,
+        );
+        STARTED.with(|started| assert_eq!(*started.borrow(), ["tracing_profile.slices"]));
+    }
+
+    #[test]
+    fn throughput_unit() {
+        assert_eq!(ThroughputUnit::default().to_bits_per_second(100), 100);
+        assert_eq!(ThroughputUnit::BytesPerSecond.to_bits_per_second(100), 800);
+        assert_eq!(
+            ThroughputUnit::GigabitsPerSecond.to_bits_per_second(3),
+            3_000_000_000
+        );
+        assert_eq!(
+            ThroughputUnit::GigabytesPerSecond.to_bits_per_second(2),
+            16_000_000_000
+        );
+        assert_eq!(ThroughputUnit::Scale(0.5).to_bits_per_second(101), 51);
+        assert_eq!(
+            ThroughputUnit::Scale(2.0).to_bits_per_second(u64::MAX),
+            u64::MAX
+        );
+
+        let layer = Layer::new(perfetto_sys::Backend::InProcess)
+            .with_throughput_unit(ThroughputUnit::BytesPerSecond);
+        assert_eq!(layer.throughput_unit.to_bits_per_second(1), 8);
+    }
+}
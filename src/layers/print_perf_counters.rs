@@ -1,41 +1,118 @@
 use std::{
+    collections::HashMap,
     io::Write,
     ops::{AddAssign, Sub},
-    sync::Mutex,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use perf_event::{events::Event, Builder, Counter, Group};
+use thread_local::ThreadLocal;
 use tracing::span;
 use tracing_subscriber::{layer, registry::LookupSpan};
 
-use crate::data::{insert_to_span_storage, with_span_storage, with_span_storage_mut};
-
-#[derive(Debug, Default)]
-struct PerfCountersValues(Vec<u64>);
+use crate::{
+    data::{format_thousands, insert_to_span_storage, with_span_storage, with_span_storage_mut},
+    err_msg, ProfileError,
+};
 
-impl Sub<&PerfCountersValues> for &PerfCountersValues {
-    type Output = PerfCountersValues;
+#[derive(Debug, Default, Clone, PartialEq)]
+struct PerfCountersValues {
+    values: Vec<u64>,
+    /// whether the kernel multiplexed the counters, i.e. the values are scaled estimates
+    multiplexed: bool,
+}
 
-    fn sub(self, rhs: &PerfCountersValues) -> Self::Output {
-        PerfCountersValues(
-            self.0
-                .iter()
-                .zip(rhs.0.iter())
-                .map(|(a, b)| a - b)
-                .collect(),
-        )
+impl PerfCountersValues {
+    fn zero(size: usize) -> Self {
+        Self {
+            values: vec![0; size],
+            multiplexed: false,
+        }
     }
 }
 
 impl AddAssign<&PerfCountersValues> for PerfCountersValues {
     fn add_assign(&mut self, rhs: &PerfCountersValues) {
-        self.0
+        self.values
             .iter_mut()
-            .zip(rhs.0.iter())
+            .zip(rhs.values.iter())
             .for_each(|(a, b)| *a += b);
+        if rhs.multiplexed {
+            self.multiplexed = true;
+        }
+    }
+}
+
+/// Values read from a counter group before correcting for multiplexing.
+#[derive(Debug, Clone)]
+struct RawCounts {
+    values: Vec<u64>,
+    time_enabled: u64,
+    time_running: u64,
+}
+
+impl RawCounts {
+    fn zero(size: usize) -> Self {
+        Self {
+            values: vec![0; size],
+            time_enabled: 0,
+            time_running: 0,
+        }
+    }
+
+    /// When more events are requested than there are hardware counters the kernel multiplexes
+    /// them, so each counter only runs for `time_running` out of `time_enabled`. The raw counts
+    /// are scaled by `time_enabled / time_running` to estimate the full counts.
+    ///
+    /// The ratio changes over time, so only the difference of two reads can be scaled, by the
+    /// ratio of that interval, see `Sub`.
+    fn scale(self) -> PerfCountersValues {
+        let multiplexed = self.time_running < self.time_enabled;
+        let values = if !multiplexed {
+            self.values
+        } else if self.time_running == 0 {
+            // the counters never ran, nothing can be estimated
+            vec![0; self.values.len()]
+        } else {
+            self.values
+                .iter()
+                .map(|value| {
+                    (*value as u128 * self.time_enabled as u128 / self.time_running as u128) as u64
+                })
+                .collect()
+        };
+
+        PerfCountersValues {
+            values,
+            multiplexed,
+        }
+    }
+}
+
+impl Sub<&RawCounts> for &RawCounts {
+    type Output = RawCounts;
+
+    /// The counts and times between two reads, the raw values only ever increase.
+    fn sub(self, rhs: &RawCounts) -> Self::Output {
+        RawCounts {
+            values: self
+                .values
+                .iter()
+                .zip(rhs.values.iter())
+                .map(|(a, b)| a - b)
+                .collect(),
+            time_enabled: self.time_enabled - rhs.time_enabled,
+            time_running: self.time_running - rhs.time_running,
+        }
     }
 }
 
+/// A group of counters which are read together.
+trait CounterGroup: Send {
+    fn read(&mut self) -> std::io::Result<RawCounts>;
+}
+
 struct PerfCountersData {
     group: Group,
     counters: Vec<Counter>,
@@ -53,58 +130,435 @@ impl PerfCountersData {
 
         Ok(Self { group, counters })
     }
+}
 
-    pub fn read(&mut self) -> std::io::Result<PerfCountersValues> {
+impl CounterGroup for PerfCountersData {
+    fn read(&mut self) -> std::io::Result<RawCounts> {
         let counts = self.group.read()?;
 
-        Ok(PerfCountersValues(
-            self.counters.iter().map(|c| counts[c]).collect(),
-        ))
+        Ok(RawCounts {
+            values: self.counters.iter().map(|c| counts[c]).collect(),
+            time_enabled: counts.time_enabled(),
+            time_running: counts.time_running(),
+        })
     }
 }
 
 struct SpanData {
     aggregate: PerfCountersValues,
-    last_enter: PerfCountersValues,
+    last_enter: RawCounts,
 }
 
 impl SpanData {
     fn new(size: usize) -> Self {
         Self {
-            aggregate: PerfCountersValues(vec![0; size]),
-            last_enter: PerfCountersValues(vec![0; size]),
+            aggregate: PerfCountersValues::zero(size),
+            last_enter: RawCounts::zero(size),
         }
     }
 
-    fn on_enter(&mut self, counters: PerfCountersValues) {
+    fn on_enter(&mut self, counters: RawCounts) {
         self.last_enter = counters;
     }
 
-    fn on_exit(&mut self, counters: PerfCountersValues) {
-        self.aggregate += &(&counters - &self.last_enter);
+    fn on_exit(&mut self, counters: RawCounts) {
+        self.aggregate += &(&counters - &self.last_enter).scale();
     }
 
-    fn print_table(&self, field_names: &[String], out: &mut impl Write) -> std::io::Result<()> {
-        for (name, value) in field_names.iter().zip(self.aggregate.0.iter()) {
-            writeln!(out, "    {}: {}", name, value)?;
+    fn print_table(
+        &self,
+        field_names: &[String],
+        config: &Config,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let values: Vec<_> = self
+            .aggregate
+            .values
+            .iter()
+            .map(|x| format_thousands(*x, config.thousands_separator))
+            .collect();
+        let (name_width, value_width) = if config.align {
+            let width = |x: &[String]| x.iter().map(|x| x.chars().count()).max();
+            (
+                width(field_names).unwrap_or_default(),
+                width(&values).unwrap_or_default(),
+            )
+        } else {
+            (0, 0)
+        };
+        let Config {
+            indent, separator, ..
+        } = config;
+        for (name, value) in field_names.iter().zip(values.iter()) {
+            writeln!(
+                out,
+                "{indent}{name:<name_width$}{separator}{value:>value_width$}"
+            )?;
+        }
+        if self.aggregate.multiplexed {
+            writeln!(
+                out,
+                "{indent}(counters were multiplexed, values are scaled estimates)"
+            )?;
         }
 
         Ok(())
     }
 }
 
-struct PerfCountersInner {
+/// A group of counters which the kernel schedules onto the hardware together.
+struct NamedGroup {
+    /// `None` for the single group passed to `Layer::new`, which is printed without a header.
+    name: Option<String>,
     names: Vec<String>,
-    counters: PerfCountersData,
+    counters: Box<dyn CounterGroup>,
 }
 
-impl PerfCountersInner {
-    pub fn new(events: Vec<(String, Event)>) -> std::io::Result<Self> {
+impl NamedGroup {
+    fn new(name: Option<String>, events: Vec<(String, Event)>) -> std::io::Result<Self> {
         Ok(Self {
+            name,
             names: events.iter().map(|(name, _)| name.clone()).collect(),
-            counters: PerfCountersData::new(events.into_iter().map(|(_, event)| event).collect())?,
+            counters: Box::new(PerfCountersData::new(
+                events.into_iter().map(|(_, event)| event).collect(),
+            )?),
+        })
+    }
+
+    /// Reads the counters, which are corrected for multiplexing once subtracted.
+    fn read(&mut self) -> std::io::Result<RawCounts> {
+        self.counters.read()
+    }
+}
+
+/// The name of a group and its events, `None` for the group passed to `Layer::new`.
+type GroupEvents = (Option<String>, Vec<(String, Event)>);
+
+/// The counters of a thread. `ThreadLocal` hands the slot of an exited thread to the next new
+/// thread, whose events the old counters don't count.
+struct PerfCountersInner {
+    thread: std::thread::ThreadId,
+    groups: Vec<NamedGroup>,
+}
+
+impl PerfCountersInner {
+    /// Opens the counters of `groups`, which count the events of the calling thread.
+    fn open(groups: &[GroupEvents]) -> std::io::Result<Self> {
+        let groups = groups
+            .iter()
+            .map(|(name, events)| NamedGroup::new(name.clone(), events.clone()))
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            thread: std::thread::current().id(),
+            groups,
         })
     }
+
+    /// Reads the counters of every group.
+    fn read(&mut self) -> std::io::Result<Vec<RawCounts>> {
+        self.groups.iter_mut().map(NamedGroup::read).collect()
+    }
+
+    /// Prints the name of a span followed by its tables and samples, unless it counted nothing
+    /// and `Config::skip_zero_spans` is set.
+    fn print_span(
+        &self,
+        name: &str,
+        spans: &[SpanData],
+        samples: &[Sample],
+        config: &Config,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let zero = || {
+            spans
+                .iter()
+                .all(|span| span.aggregate.values.iter().all(|value| *value == 0))
+        };
+        if config.skip_zero_spans && zero() {
+            return Ok(());
+        }
+        writeln!(out, "{name}:")?;
+        self.print_tables(spans, config, out)?;
+        self.print_samples(samples, config, out)
+    }
+
+    fn print_tables(
+        &self,
+        spans: &[SpanData],
+        config: &Config,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        for (group, span) in self.groups.iter().zip(spans) {
+            match &group.name {
+                None => span.print_table(&group.names, config, out)?,
+                Some(name) => {
+                    writeln!(out, "{}{name}:", config.indent)?;
+                    let nested = Config {
+                        indent: config.indent.repeat(2),
+                        ..config.clone()
+                    };
+                    span.print_table(&group.names, &nested, out)?;
+                }
+            }
+        }
+        self.print_derived(spans, config, out)
+    }
+
+    /// The count of every event of the spans, by its name and by `group.name`.
+    fn counts(&self, spans: &[SpanData]) -> HashMap<String, u64> {
+        let mut counts = HashMap::new();
+        for (group, span) in self.groups.iter().zip(spans) {
+            for (name, value) in group.names.iter().zip(&span.aggregate.values) {
+                if let Some(group) = &group.name {
+                    counts.insert(format!("{group}.{name}"), *value);
+                }
+                counts.insert(name.clone(), *value);
+            }
+        }
+        counts
+    }
+
+    /// Prints the derived metrics of the spans below their tables, skipping the ones whose
+    /// denominator is zero.
+    fn print_derived(
+        &self,
+        spans: &[SpanData],
+        config: &Config,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let counts = self.counts(spans);
+        let metrics: Vec<_> = config
+            .derived_metrics
+            .iter()
+            .filter_map(|metric| Some((&metric.label, metric.format(metric.compute(&counts)?))))
+            .collect();
+        let (label_width, value_width) = match config.align {
+            true => (
+                metrics.iter().map(|(x, _)| x.chars().count()).max(),
+                metrics.iter().map(|(_, x)| x.chars().count()).max(),
+            ),
+            false => (None, None),
+        };
+        let (label_width, value_width) = (label_width.unwrap_or(0), value_width.unwrap_or(0));
+        let Config {
+            indent, separator, ..
+        } = config;
+        for (label, value) in metrics {
+            writeln!(
+                out,
+                "{indent}{label:<label_width$}{separator}{value:>value_width$}"
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Prints the counts of every sample on one line.
+    fn print_samples(
+        &self,
+        samples: &[Sample],
+        config: &Config,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let Config {
+            indent, separator, ..
+        } = config;
+        writeln!(out, "{indent}samples:")?;
+        for sample in samples {
+            let counts: Vec<_> = self
+                .groups
+                .iter()
+                .zip(&sample.values)
+                .flat_map(|(group, values)| {
+                    group.names.iter().zip(&values.values).map(|(name, value)| {
+                        let value = format_thousands(*value, config.thousands_separator);
+                        match &group.name {
+                            None => format!("{name}{separator}{value}"),
+                            Some(group) => format!("{group}.{name}{separator}{value}"),
+                        }
+                    })
+                })
+                .collect();
+            writeln!(
+                out,
+                "{indent}{indent}{:.2?}{separator}{}",
+                sample.since_start,
+                counts.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A metric computed from the counts of a span and printed below its table, e.g.
+/// `DerivedMetric::ratio("IPC", "instructions", "cycles")` prints `IPC: 1.29`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedMetric {
+    label: String,
+    numerator: String,
+    denominator: String,
+    scale: f64,
+    precision: usize,
+}
+
+impl DerivedMetric {
+    /// The count of `numerator` over the count of `denominator`, with 2 decimal places. The
+    /// events are referred to by their names, or by `group.name` if the layer has several groups
+    /// which count events with the same name.
+    pub fn ratio(
+        label: impl Into<String>,
+        numerator: impl Into<String>,
+        denominator: impl Into<String>,
+    ) -> Self {
+        Self {
+            label: label.into(),
+            numerator: numerator.into(),
+            denominator: denominator.into(),
+            scale: 1.0,
+            precision: 2,
+        }
+    }
+
+    /// Number of decimal places of the printed value.
+    pub fn with_precision(mut self, precision: usize) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Multiplies the ratio, e.g. by `100.0` for a `"cache miss %"`.
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// The names of the events the metric is computed from.
+    fn events(&self) -> [&str; 2] {
+        [&self.numerator, &self.denominator]
+    }
+
+    fn compute(&self, counts: &HashMap<String, u64>) -> Option<f64> {
+        let numerator = *counts.get(&self.numerator)?;
+        let denominator = *counts.get(&self.denominator)?;
+        (denominator != 0).then(|| numerator as f64 / denominator as f64 * self.scale)
+    }
+
+    fn format(&self, value: f64) -> String {
+        format!("{value:.*}", self.precision)
+    }
+}
+
+/// The counts of a span during one interval of `Config::sample_interval`.
+struct Sample {
+    /// the end of the interval since the span was first entered
+    since_start: Duration,
+    /// the events of every group counted since the previous sample
+    values: Vec<PerfCountersValues>,
+}
+
+/// A span the sampler reads the counters of while it is entered.
+struct SampledSpan {
+    /// the counters of the thread which entered the span
+    counters: Arc<Mutex<PerfCountersInner>>,
+    entered: bool,
+    first_enter: Instant,
+    /// the counters at the previous sample or when the span was entered
+    last: Vec<RawCounts>,
+    samples: Vec<Sample>,
+}
+
+/// Reads the counters of every entered span periodically on a timer thread, see
+/// `Config::sample_interval`.
+///
+/// The counters of a thread can be read by any thread, they keep counting the events of the
+/// thread which opened them.
+#[derive(Default)]
+struct Sampler {
+    spans: Mutex<HashMap<span::Id, SampledSpan>>,
+}
+
+impl Sampler {
+    /// Starts the timer thread, which stops once the sampler is dropped.
+    fn start(interval: Duration) -> Arc<Self> {
+        let sampler = Arc::new(Self::default());
+        let weak = Arc::downgrade(&sampler);
+        std::thread::Builder::new()
+            .name("perf-counter-sampler".into())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                let Some(sampler) = weak.upgrade() else {
+                    break;
+                };
+                sampler.sample();
+            })
+            .expect("failed to spawn the perf counter sampler");
+        sampler
+    }
+
+    fn sample(&self) {
+        let Ok(mut spans) = self.spans.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        for span in spans.values_mut().filter(|span| span.entered) {
+            let Ok(mut counters) = span.counters.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            let values = counters.read().expect("failed to read perf counters");
+            span.samples.push(Sample {
+                since_start: span.first_enter.elapsed(),
+                values: values
+                    .iter()
+                    .zip(&span.last)
+                    .map(|(a, b)| (a - b).scale())
+                    .collect(),
+            });
+            span.last = values;
+        }
+    }
+
+    /// Starts sampling the span, `values` are the counters of the thread read at the enter.
+    fn enter(
+        &self,
+        id: &span::Id,
+        counters: &Arc<Mutex<PerfCountersInner>>,
+        values: Vec<RawCounts>,
+    ) {
+        let Ok(mut spans) = self.spans.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        let span = spans.entry(id.clone()).or_insert_with(|| SampledSpan {
+            counters: counters.clone(),
+            entered: false,
+            first_enter: Instant::now(),
+            last: vec![],
+            samples: vec![],
+        });
+        span.counters = counters.clone();
+        span.entered = true;
+        span.last = values;
+    }
+
+    fn exit(&self, id: &span::Id) {
+        let Ok(mut spans) = self.spans.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        if let Some(span) = spans.get_mut(id) {
+            span.entered = false;
+        }
+    }
+
+    /// Stops sampling the span and returns its samples.
+    fn close(&self, id: &span::Id) -> Vec<Sample> {
+        let Ok(mut spans) = self.spans.lock() else {
+            err_msg!(ProfileError::MutexPoisoned);
+            return vec![];
+        };
+        spans
+            .remove(id)
+            .map(|span| span.samples)
+            .unwrap_or_default()
+    }
 }
 
 /// PrintPerfCountersLayer (internally called layer::print_perf_counters::Layer)
@@ -131,18 +585,145 @@ impl PerfCountersInner {
 ///     cycles: 738894
 /// test tests::all_layers ... ok
 /// ```
+///
+/// Every thread which enters a span opens its own counters, so the threads never wait for each
+/// other and each span only counts the events of its thread. Spans have to be exited on the thread
+/// which entered them.
 pub struct Layer {
-    inner: Mutex<PerfCountersInner>,
+    /// the events of every group, opened again on each thread
+    groups: Vec<GroupEvents>,
+    counters: ThreadLocal<Arc<Mutex<PerfCountersInner>>>,
+    config: Config,
+    sampler: Option<Arc<Sampler>>,
+}
+
+/// PrintPerfCountersLayer configuration (internally called layer::print_perf_counters::Config)
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Written before every counter, defaults to four spaces.
+    pub indent: String,
+    /// Written between the name and the value of a counter, defaults to `": "`. Use `","`
+    /// together with an empty indent for a CSV-like layout.
+    pub separator: String,
+    /// Whether to pad the names and right-align the values so they line up in columns.
+    pub align: bool,
+    /// Written between groups of three digits, e.g. `Some(',')` for `282,256`.
+    pub thousands_separator: Option<char>,
+    /// Reads the counters of every entered span at this interval on a timer thread and prints
+    /// the counts of each interval below the table, which shows how a long span goes through
+    /// phases. Defaults to `None`, only counting between enter and exit.
+    pub sample_interval: Option<Duration>,
+    /// Metrics computed from the counts of every span, printed below its table in this order.
+    pub derived_metrics: Vec<DerivedMetric>,
+    /// Whether to print nothing, not even the name, for spans which counted zero events of every
+    /// kind, e.g. spans which did no measurable work.
+    pub skip_zero_spans: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            indent: "    ".into(),
+            separator: ": ".into(),
+            align: false,
+            thousands_separator: None,
+            sample_interval: None,
+            derived_metrics: vec![],
+            skip_zero_spans: false,
+        }
+    }
 }
 
 impl Layer {
     pub fn new(events: Vec<(String, Event)>) -> std::io::Result<Self> {
-        Ok(Self {
-            inner: Mutex::new(PerfCountersInner::new(events)?),
+        Self::new_with_config(events, Config::default())
+    }
+
+    pub fn new_with_config(events: Vec<(String, Event)>, config: Config) -> std::io::Result<Self> {
+        Self::open(vec![(None, events)], config)
+    }
+
+    /// Measures several named groups of events, e.g. `("cache", vec![...])` and
+    /// `("branches", vec![...])`, and prints the counts of every span per group.
+    ///
+    /// The events of a group are always counted together, but the kernel schedules the groups
+    /// independently. When they don't fit onto the hardware counters at the same time the groups
+    /// take turns and their values are scaled estimates, so events which should be compared with
+    /// each other belong into the same group.
+    pub fn new_with_groups(groups: Vec<(String, Vec<(String, Event)>)>) -> std::io::Result<Self> {
+        Self::new_with_groups_and_config(groups, Config::default())
+    }
+
+    pub fn new_with_groups_and_config(
+        groups: Vec<(String, Vec<(String, Event)>)>,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        let groups = groups
+            .into_iter()
+            .map(|(name, events)| (Some(name), events))
+            .collect();
+        Self::open(groups, config)
+    }
+
+    fn open(groups: Vec<GroupEvents>, config: Config) -> std::io::Result<Self> {
+        let known = |event: &str| {
+            groups.iter().any(|(group, events)| {
+                events.iter().any(|(name, _)| {
+                    name == event
+                        || group
+                            .as_ref()
+                            .is_some_and(|group| event == format!("{group}.{name}"))
+                })
+            })
+        };
+        if let Some(event) = config
+            .derived_metrics
+            .iter()
+            .flat_map(DerivedMetric::events)
+            .find(|event| !known(event))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("derived metric of unknown event {event}"),
+            ));
+        }
+        let layer = Self {
+            groups,
+            counters: ThreadLocal::new(),
+            sampler: config.sample_interval.map(Sampler::start),
+            config,
+        };
+        // open the counters of the calling thread right away, so invalid events are reported here
+        layer.counters()?;
+        Ok(layer)
+    }
+
+    /// The counters of the calling thread.
+    fn counters(&self) -> std::io::Result<&Arc<Mutex<PerfCountersInner>>> {
+        self.counters.get_or_try(|| {
+            PerfCountersInner::open(&self.groups).map(|inner| Arc::new(Mutex::new(inner)))
         })
     }
+
+    /// Reads the counters of the calling thread.
+    fn read(&self) -> Option<Vec<RawCounts>> {
+        let Ok(mut inner) = self
+            .counters()
+            .expect("failed to open perf counters")
+            .lock()
+        else {
+            err_msg!(ProfileError::MutexPoisoned);
+            return None;
+        };
+        if inner.thread != std::thread::current().id() {
+            *inner = PerfCountersInner::open(&self.groups).expect("failed to open perf counters");
+        }
+        Some(inner.read().expect("failed to read perf counters"))
+    }
 }
 
+impl crate::Flush for Layer {}
+
 impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Layer
 where
     for<'lookup> S: LookupSpan<'lookup>,
@@ -153,33 +734,410 @@ where
         id: &span::Id,
         ctx: layer::Context<'_, S>,
     ) {
-        insert_to_span_storage(
-            id,
-            ctx,
-            SpanData::new(self.inner.lock().unwrap().names.len()),
-        );
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        let spans: Vec<_> = self
+            .groups
+            .iter()
+            .map(|(_, events)| SpanData::new(events.len()))
+            .collect();
+        insert_to_span_storage(id, ctx, spans);
     }
 
     fn on_enter(&self, id: &span::Id, ctx: layer::Context<'_, S>) {
-        let mut inner = self.inner.lock().unwrap();
-        with_span_storage_mut::<SpanData, _>(id, ctx, |storage| {
-            storage.on_enter(inner.counters.read().expect("failed to read perf counters"));
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        // the counters are unlocked again before the sampler is, which locks them the other way
+        let Some(values) = self.read() else { return };
+        if let Some(sampler) = &self.sampler {
+            let counters = self.counters().expect("failed to open perf counters");
+            sampler.enter(id, counters, values.clone());
+        }
+        with_span_storage_mut::<Vec<SpanData>, _>(id, ctx, |storage| {
+            for (span, values) in storage.iter_mut().zip(values) {
+                span.on_enter(values);
+            }
         });
     }
 
     fn on_exit(&self, id: &span::Id, ctx: layer::Context<'_, S>) {
-        let mut inner = self.inner.lock().unwrap();
-        with_span_storage_mut::<SpanData, _>(id, ctx, |storage| {
-            storage.on_exit(inner.counters.read().expect("failed to read perf counters"));
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        if let Some(sampler) = &self.sampler {
+            sampler.exit(id);
+        }
+        let Some(values) = self.read() else { return };
+        with_span_storage_mut::<Vec<SpanData>, _>(id, ctx, |storage| {
+            for (span, values) in storage.iter_mut().zip(values) {
+                span.on_exit(values);
+            }
         });
     }
 
     fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        println!("{}:", ctx.span(&id).expect("span not found").name());
-        with_span_storage::<SpanData, _>(&id, ctx, |storage| {
-            storage
-                .print_table(&self.inner.lock().unwrap().names, &mut std::io::stdout())
+        if crate::pause::skips(&id, &ctx) {
+            return;
+        }
+        let name = ctx.span(&id).expect("span not found").name();
+        let samples = self
+            .sampler
+            .as_ref()
+            .map(|sampler| sampler.close(&id))
+            .unwrap_or_default();
+        with_span_storage::<Vec<SpanData>, _>(&id, ctx, |storage| {
+            let Ok(inner) = self
+                .counters()
+                .expect("failed to open perf counters")
+                .lock()
+            else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            inner
+                .print_span(
+                    name,
+                    storage,
+                    &samples,
+                    &self.config,
+                    &mut std::io::stdout(),
+                )
                 .expect("failed to print table");
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the given counts instead of reading hardware counters.
+    struct MockGroup(Vec<RawCounts>);
+
+    impl CounterGroup for MockGroup {
+        fn read(&mut self) -> std::io::Result<RawCounts> {
+            Ok(self.0.remove(0))
+        }
+    }
+
+    #[test]
+    fn multiplexing_scaling() {
+        let mut group = NamedGroup {
+            name: None,
+            names: vec!["instructions".into(), "cycles".into()],
+            counters: Box::new(MockGroup(vec![
+                RawCounts {
+                    values: vec![100, 200],
+                    time_enabled: 1000,
+                    time_running: 1000,
+                },
+                RawCounts {
+                    values: vec![100, 200],
+                    time_enabled: 1000,
+                    time_running: 250,
+                },
+            ])),
+        };
+
+        assert_eq!(
+            group.read().unwrap().scale(),
+            PerfCountersValues {
+                values: vec![100, 200],
+                multiplexed: false,
+            }
+        );
+        assert_eq!(
+            group.read().unwrap().scale(),
+            PerfCountersValues {
+                values: vec![400, 800],
+                multiplexed: true,
+            }
+        );
+    }
+
+    #[test]
+    fn multiplexing_ratio_changes() {
+        let mut group = NamedGroup {
+            name: None,
+            names: vec!["instructions".into()],
+            counters: Box::new(MockGroup(vec![
+                // not multiplexed until the span is entered
+                RawCounts {
+                    values: vec![100],
+                    time_enabled: 1000,
+                    time_running: 1000,
+                },
+                // then running a quarter of the time
+                RawCounts {
+                    values: vec![200],
+                    time_enabled: 2000,
+                    time_running: 1250,
+                },
+            ])),
+        };
+
+        let mut span = SpanData::new(1);
+        span.on_enter(group.read().unwrap());
+        span.on_exit(group.read().unwrap());
+        assert_eq!(
+            span.aggregate,
+            PerfCountersValues {
+                values: vec![400],
+                multiplexed: true,
+            }
+        );
+    }
+
+    #[test]
+    fn table_format() {
+        let mut span = SpanData::new(2);
+        span.on_exit(RawCounts {
+            values: vec![1234567, 5],
+            time_enabled: 1000,
+            time_running: 1000,
+        });
+        let names = ["instructions".to_string(), "cycles".to_string()];
+
+        let mut out = vec![];
+        let config = Config {
+            indent: "".into(),
+            separator: " = ".into(),
+            align: true,
+            thousands_separator: Some(','),
+            sample_interval: None,
+            derived_metrics: vec![],
+            skip_zero_spans: false,
+        };
+        span.print_table(&names, &config, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "instructions = 1,234,567\ncycles       =         5\n"
+        );
+    }
+
+    #[test]
+    fn multiple_groups() {
+        let counts = |values: Vec<u64>| RawCounts {
+            values,
+            time_enabled: 1000,
+            time_running: 1000,
+        };
+        let mut inner = PerfCountersInner {
+            thread: std::thread::current().id(),
+            groups: vec![
+                NamedGroup {
+                    name: Some("cache".into()),
+                    names: vec!["references".into(), "misses".into()],
+                    counters: Box::new(MockGroup(vec![counts(vec![10, 1]), counts(vec![50, 5])])),
+                },
+                NamedGroup {
+                    name: Some("branches".into()),
+                    names: vec!["instructions".into()],
+                    counters: Box::new(MockGroup(vec![counts(vec![100]), counts(vec![300])])),
+                },
+            ],
+        };
+
+        let mut spans = vec![SpanData::new(2), SpanData::new(1)];
+        for (span, values) in spans.iter_mut().zip(inner.read().unwrap()) {
+            span.on_enter(values);
+        }
+        for (span, values) in spans.iter_mut().zip(inner.read().unwrap()) {
+            span.on_exit(values);
+        }
+
+        let mut out = vec![];
+        inner
+            .print_tables(&spans, &Config::default(), &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "    cache:\n        references: 40\n        misses: 4\n    branches:\n        instructions: 200\n"
+        );
+    }
+
+    #[test]
+    fn derived_metrics() {
+        let counts = |values: Vec<u64>| RawCounts {
+            values,
+            time_enabled: 1000,
+            time_running: 1000,
+        };
+        let mut inner = PerfCountersInner {
+            thread: std::thread::current().id(),
+            groups: vec![NamedGroup {
+                name: None,
+                names: vec![
+                    "instructions".into(),
+                    "cycles".into(),
+                    "references".into(),
+                    "misses".into(),
+                ],
+                counters: Box::new(MockGroup(vec![
+                    counts(vec![0, 0, 0, 0]),
+                    counts(vec![3890, 3000, 800, 100]),
+                ])),
+            }],
+        };
+        let mut span = SpanData::new(4);
+        span.on_enter(inner.read().unwrap().remove(0));
+        span.on_exit(inner.read().unwrap().remove(0));
+
+        let config = Config {
+            indent: "".into(),
+            align: true,
+            derived_metrics: vec![
+                DerivedMetric::ratio("IPC", "instructions", "cycles").with_precision(3),
+                DerivedMetric::ratio("cache miss %", "misses", "references")
+                    .with_scale(100.0)
+                    .with_precision(1),
+                // no events counted, so it isn't printed
+                DerivedMetric::ratio("idle", "cycles", "nothing"),
+            ],
+            ..Config::default()
+        };
+        let mut out = vec![];
+        inner
+            .print_derived(std::slice::from_ref(&span), &config, &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "IPC         : 1.297\ncache miss %:  12.5\n"
+        );
+    }
+
+    #[test]
+    fn skip_zero_spans() {
+        let counts = |values: Vec<u64>| RawCounts {
+            values,
+            time_enabled: 1000,
+            time_running: 1000,
+        };
+        let mut inner = PerfCountersInner {
+            thread: std::thread::current().id(),
+            groups: vec![NamedGroup {
+                name: None,
+                names: vec!["instructions".into(), "misses".into()],
+                counters: Box::new(MockGroup(vec![
+                    // a no-op span
+                    counts(vec![100, 7]),
+                    counts(vec![100, 7]),
+                    // a span which counted a single kind of event
+                    counts(vec![100, 7]),
+                    counts(vec![100, 8]),
+                ])),
+            }],
+        };
+        let mut noop = SpanData::new(2);
+        noop.on_enter(inner.read().unwrap().remove(0));
+        noop.on_exit(inner.read().unwrap().remove(0));
+        let mut miss = SpanData::new(2);
+        miss.on_enter(inner.read().unwrap().remove(0));
+        miss.on_exit(inner.read().unwrap().remove(0));
+
+        let print = |span: &SpanData, name, config: &Config| {
+            let mut out = vec![];
+            inner
+                .print_span(name, std::slice::from_ref(span), &[], config, &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            print(&noop, "noop", &Config::default()),
+            "noop:\n    instructions: 0\n    misses: 0\n"
+        );
+        let config = Config {
+            skip_zero_spans: true,
+            ..Config::default()
+        };
+        assert_eq!(print(&noop, "noop", &config), "");
+        assert_eq!(
+            print(&miss, "miss", &config),
+            "miss:\n    instructions: 0\n    misses: 1\n"
+        );
+    }
+
+    /// Counts `step` events between reads, like a thread busy at a constant rate.
+    struct SteadyGroup {
+        count: u64,
+        step: u64,
+    }
+
+    impl CounterGroup for SteadyGroup {
+        fn read(&mut self) -> std::io::Result<RawCounts> {
+            self.count += self.step;
+            Ok(RawCounts {
+                values: vec![self.count],
+                time_enabled: 1000,
+                time_running: 1000,
+            })
+        }
+    }
+
+    #[test]
+    fn periodic_samples() {
+        use tracing_subscriber::prelude::*;
+
+        let interval = Duration::from_millis(5);
+        let layer = Layer {
+            groups: vec![(
+                None,
+                vec![(
+                    "instructions".into(),
+                    perf_event::events::Hardware::INSTRUCTIONS.into(),
+                )],
+            )],
+            counters: ThreadLocal::new(),
+            config: Config {
+                sample_interval: Some(interval),
+                ..Config::default()
+            },
+            sampler: Some(Sampler::start(interval)),
+        };
+        // the counters of the test thread, opened before the layer would open real ones
+        let counters = layer.counters.get_or(|| {
+            Arc::new(Mutex::new(PerfCountersInner {
+                thread: std::thread::current().id(),
+                groups: vec![NamedGroup {
+                    name: None,
+                    names: vec!["instructions".into()],
+                    counters: Box::new(SteadyGroup { count: 0, step: 10 }),
+                }],
+            }))
+        });
+        let sampler = layer.sampler.clone().unwrap();
+        let counters = counters.clone();
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("long span");
+            let id = span.id().unwrap();
+            let _entered = span.enter();
+            std::thread::sleep(interval * 20);
+
+            let spans = sampler.spans.lock().unwrap();
+            let samples = &spans[&id].samples;
+            assert!(samples.len() >= 3, "{} samples", samples.len());
+            // every read counts another step, so each interval counted exactly one
+            assert!(samples.iter().all(|sample| sample.values
+                == [PerfCountersValues {
+                    values: vec![10],
+                    multiplexed: false
+                }]));
+            assert!(samples
+                .windows(2)
+                .all(|pair| pair[0].since_start < pair[1].since_start));
+
+            let mut out = vec![];
+            counters
+                .lock()
+                .unwrap()
+                .print_samples(&samples[..1], &Config::default(), &mut out)
+                .unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("    samples:\n        "), "{out}");
+            assert!(out.ends_with(": instructions: 10\n"), "{out}");
+        });
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{collections::HashMap, sync::Mutex};
+
+use tracing::{
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Event, Metadata, Subscriber,
+};
+use tracing_subscriber::registry::{Extensions, ExtensionsMut, LookupSpan, Registry, SpanData};
+
+/// A `Registry` which hands the id of a closed span to the next new span, which the `Registry`
+/// itself only does after many generations, to test that layers don't confuse the two spans.
+///
+/// The spans of the inner registry are never removed, so a span that had children never closes,
+/// children which should outlive their parent name it with `parent: None` and `profile.parent_id`.
+/// Explicit parents aren't supported otherwise, and events aren't attributed to spans.
+#[derive(Default)]
+pub(crate) struct ReusedIds {
+    registry: Registry,
+    ids: Mutex<Ids>,
+}
+
+#[derive(Default)]
+struct Ids {
+    inner: HashMap<u64, Id>,
+    outer: HashMap<u64, u64>,
+    free: Vec<u64>,
+    next: u64,
+}
+
+impl ReusedIds {
+    fn inner(&self, id: &Id) -> Id {
+        self.ids.lock().unwrap().inner[&id.into_u64()].clone()
+    }
+
+    fn outer(&self, id: &Id) -> Id {
+        Id::from_u64(self.ids.lock().unwrap().outer[&id.into_u64()])
+    }
+}
+
+impl Subscriber for ReusedIds {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.registry.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.registry.enabled(metadata)
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        assert!(
+            attrs.parent().is_none(),
+            "explicit parents aren't supported"
+        );
+        let inner = self.registry.new_span(attrs);
+        let mut ids = self.ids.lock().unwrap();
+        let outer = match ids.free.pop() {
+            Some(outer) => outer,
+            None => {
+                ids.next += 1;
+                ids.next
+            }
+        };
+        if let Some(old) = ids.inner.insert(outer, inner.clone()) {
+            ids.outer.remove(&old.into_u64());
+        }
+        ids.outer.insert(inner.into_u64(), outer);
+        Id::from_u64(outer)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        self.registry.record(&self.inner(span), values)
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        self.registry
+            .record_follows_from(&self.inner(span), &self.inner(follows))
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        self.registry.event(event)
+    }
+
+    fn enter(&self, span: &Id) {
+        self.registry.enter(&self.inner(span))
+    }
+
+    fn exit(&self, span: &Id) {
+        self.registry.exit(&self.inner(span))
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.registry.clone_span(&self.inner(id));
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        // the layers still look the span up in `on_close`, the mapping is only replaced when the
+        // id is handed out again
+        let closed = self.registry.try_close(self.inner(&id));
+        if closed {
+            self.ids.lock().unwrap().free.push(id.into_u64());
+        }
+        closed
+    }
+}
+
+impl<'a> LookupSpan<'a> for ReusedIds {
+    type Data = Data<'a>;
+
+    fn span_data(&'a self, id: &Id) -> Option<Self::Data> {
+        let inner = self.registry.span_data(&self.inner(id))?;
+        Some(Data {
+            parent: inner.parent().map(|parent| self.outer(parent)),
+            id: id.clone(),
+            inner,
+        })
+    }
+}
+
+pub(crate) struct Data<'a> {
+    inner: <Registry as LookupSpan<'a>>::Data,
+    id: Id,
+    parent: Option<Id>,
+}
+
+impl<'a> SpanData<'a> for Data<'a> {
+    fn id(&self) -> Id {
+        self.id.clone()
+    }
+
+    fn metadata(&self) -> &'static Metadata<'static> {
+        self.inner.metadata()
+    }
+
+    fn parent(&self) -> Option<&Id> {
+        self.parent.as_ref()
+    }
+
+    fn extensions(&self) -> Extensions<'_> {
+        self.inner.extensions()
+    }
+
+    fn extensions_mut(&self) -> ExtensionsMut<'_> {
+        self.inner.extensions_mut()
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::VecDeque,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    callback,
+    csv::{escape, format_fields},
+};
+use crate::{data::ClosedSpan, err_msg, ProfileError};
+
+type Records = Arc<Mutex<VecDeque<(Instant, ClosedSpan)>>>;
+
+/// RingBufferLayer (internally called layer::ring_buffer)
+/// This Layer keeps the spans closed within the last `window` in memory and only writes them to
+/// disk when `dump` is called, e.g. from the panic hook installed by `install_panic_hook`. Normal
+/// operation does no I/O.
+///
+/// The dump is a CSV file which can be read with `CsvReader`:
+/// ```bash
+/// id,parent_id,elapsed_ns,span_name,call_depth,metadata
+/// 2,1,3194,child span1,2,"{""field1"":""value1""}"
+/// 1,0,79099,root span,1,{}
+/// ```
+pub struct Layer {
+    records: Records,
+    inner: callback::Layer,
+}
+
+impl Layer {
+    /// Keeps the spans closed within the last `window`.
+    pub fn new(window: Duration) -> Self {
+        Self::with_capacity(window, usize::MAX)
+    }
+
+    /// Keeps the spans closed within the last `window`, but no more than `capacity` spans.
+    pub fn with_capacity(window: Duration, capacity: usize) -> Self {
+        let records: Records = Default::default();
+        let inner = {
+            let records = records.clone();
+            callback::Layer::new(move |span| {
+                let Ok(mut records) = records.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                let now = Instant::now();
+                while records.len() >= capacity
+                    || records
+                        .front()
+                        .is_some_and(|(closed, _)| now.duration_since(*closed) > window)
+                {
+                    if records.pop_front().is_none() {
+                        break;
+                    }
+                }
+                if capacity > 0 {
+                    records.push_back((now, span.clone()));
+                }
+            })
+        };
+        Self { records, inner }
+    }
+
+    /// Writes the buffered spans, oldest first, to the given file.
+    pub fn dump<T: AsRef<Path>>(&self, path: T) -> std::io::Result<()> {
+        dump(&self.records, path.as_ref())
+    }
+
+    /// Installs a panic hook that dumps the buffered spans to `path` before calling the
+    /// previously installed hook.
+    pub fn install_panic_hook<T: AsRef<Path>>(&self, path: T) {
+        let records = self.records.clone();
+        let path = path.as_ref().to_path_buf();
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // panicking inside the panic hook would abort before the previous hook runs
+            if let Err(e) = dump(&records, &path) {
+                crate::error::warn(&ProfileError::WriteFailed(e.kind()));
+            }
+            previous_hook(info);
+        }));
+    }
+}
+
+fn dump(records: &Records, path: &Path) -> std::io::Result<()> {
+    // a panicking thread may hold the lock, the records are still worth dumping
+    let records = records.lock().unwrap_or_else(|e| e.into_inner());
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(f, "id,parent_id,elapsed_ns,span_name,call_depth,metadata")?;
+    for (_, span) in records.iter() {
+        writeln!(
+            f,
+            "{},{},{},{},{},{}",
+            span.id,
+            span.parent_id.unwrap_or_default(),
+            span.duration.as_nanos(),
+            escape(span.name, ','),
+            span.depth,
+            escape(&format_fields(&span.fields), ',')
+        )?;
+    }
+    f.flush()
+}
+
+impl crate::Flush for Layer {}
+
+callback::forward_to_callback!(Layer);
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::CsvReader;
+
+    #[test]
+    fn dump_most_recent() {
+        let dispatch = tracing::Dispatch::new(
+            tracing_subscriber::registry().with(Layer::with_capacity(Duration::from_secs(60), 3)),
+        );
+        tracing::dispatcher::with_default(&dispatch, || {
+            for i in 0..5 {
+                drop(debug_span!("span", i).entered());
+            }
+        });
+
+        let path = std::env::temp_dir().join("tracing_profile_ring_buffer.csv");
+        let layer = dispatch.downcast_ref::<Layer>().unwrap();
+        layer.dump(&path).unwrap();
+
+        let rows = CsvReader::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let indices: Vec<_> = rows.iter().map(|row| row.fields["i"].as_str()).collect();
+        assert_eq!(indices, ["2", "3", "4"]);
+        assert!(rows.iter().all(|row| row.span_name == "span"));
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+/// A writer appending to a buffer which the test keeps a clone of, to check the output of a
+/// layer which owns its writer.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Everything written so far.
+    pub(crate) fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,641 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::span;
+
+use crate::{
+    data::{
+        format_thousands, insert_to_span_storage, thread_cpu_time, with_span_storage_mut,
+        SummaryMetadata,
+    },
+    err_msg, Flush, FlushHandle, ProfileError,
+};
+
+/// SummaryLayer (internally called layer::summary)
+/// This Layer aggregates statistics for every span name and prints them to stdout when the layer
+/// is dropped, or earlier when the `ProfileGuard` is dropped. The CPU utilization (CPU time / wall time) of a span tells I/O bound spans apart
+/// from CPU bound ones.
+///
+/// example output:
+/// ```bash
+/// span                     calls      wall time       cpu time      cpu %
+/// root span                    1        1.01s        12.34ms       1.22%
+/// compute                      3       12.01ms       11.97ms      99.67%
+/// ```
+///
+/// Layers created with `with_cpu_share` additionally show how many cores a span name kept busy
+/// on average, e.g. `385.20%` for a span running on four threads in parallel.
+///
+/// With `with_top_self_time(n)` only the `n` span names with the most self time, i.e. time not
+/// spent in child spans, are printed instead, like the flat view of a classic profiler:
+/// ```bash
+/// span                     self time     self %      calls
+/// compute                    11.97ms     94.02%          3
+/// root span                   0.76ms      5.98%          1
+/// ```
+#[derive(Default)]
+pub struct Layer {
+    summary: Arc<Mutex<Summary>>,
+    /// set once the summary has been printed, so that it isn't printed again on drop
+    printed: Arc<AtomicBool>,
+    /// `None` unless the CPU share is measured
+    active: Option<Mutex<HashMap<&'static str, Active>>>,
+    report: Report,
+}
+
+/// How the summary is printed.
+#[derive(Debug, Default, Clone, Copy)]
+struct Report {
+    /// written between groups of three digits of the call counts
+    thousands_separator: Option<char>,
+    /// if set, only this many spans with the most self time are printed
+    top_self_time: Option<usize>,
+}
+
+/// Instances of a span name which are currently entered on any thread.
+struct Active {
+    count: usize,
+    since: Instant,
+}
+
+impl Layer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a layer which also measures the wall-clock time during which at least one span of
+    /// each name was entered on any thread, see `SpanStats::cpu_share`. This takes a lock on
+    /// every enter and exit.
+    pub fn with_cpu_share() -> Self {
+        Self {
+            summary: Arc::default(),
+            printed: Arc::default(),
+            active: Some(Mutex::default()),
+            report: Report::default(),
+        }
+    }
+
+    /// Prints the call counts with `separator` between groups of three digits, e.g. `1,000,000`.
+    pub fn with_thousands_separator(mut self, separator: char) -> Self {
+        self.report.thousands_separator = Some(separator);
+        self
+    }
+
+    /// Prints only the `n` span names with the most self time, see `Summary::top_self_time`.
+    pub fn with_top_self_time(mut self, n: usize) -> Self {
+        self.report.top_self_time = Some(n);
+        self
+    }
+
+    /// Returns the statistics collected so far.
+    pub fn summary(&self) -> Summary {
+        match self.summary.lock() {
+            Ok(summary) => summary.clone(),
+            Err(_) => {
+                err_msg!(ProfileError::MutexPoisoned);
+                Summary::default()
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_printed(&self) -> bool {
+        self.printed.load(Ordering::Relaxed)
+    }
+}
+
+fn print_once(summary: &Mutex<Summary>, printed: &AtomicBool, report: Report) {
+    if printed.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let Ok(summary) = summary.lock() else {
+        return err_msg!(ProfileError::MutexPoisoned);
+    };
+    let separator = report.thousands_separator;
+    match report.top_self_time {
+        Some(n) => print!("{}", TopSelfTime(&summary, n, separator)),
+        None => print!("{}", SummaryDisplay(&summary, separator)),
+    }
+}
+
+impl Flush for Layer {
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let (summary, printed) = (self.summary.clone(), self.printed.clone());
+        let report = self.report;
+        Some(FlushHandle::new(move || {
+            print_once(&summary, &printed, report)
+        }))
+    }
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        print_once(&self.summary, &self.printed, self.report);
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        let parent_depth = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .and_then(|parent| Some(parent.extensions().get::<SummaryMetadata>()?.depth))
+            .unwrap_or_default();
+        let storage = SummaryMetadata {
+            start_time: None,
+            start_cpu_time: None,
+            children_time: Duration::ZERO,
+            depth: parent_depth + 1,
+        };
+        insert_to_span_storage(id, ctx, storage);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        if let Some(active) = &self.active {
+            let Some(name) = ctx.metadata(id).map(|x| x.name()) else {
+                return err_msg!(ProfileError::SpanNotFound {
+                    context: "on_enter"
+                });
+            };
+            let Ok(mut active) = active.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            let active = active.entry(name).or_insert(Active {
+                count: 0,
+                since: Instant::now(),
+            });
+            if active.count == 0 {
+                active.since = Instant::now();
+            }
+            active.count += 1;
+        }
+        with_span_storage_mut(id, ctx, |storage: &mut SummaryMetadata| {
+            storage.start_time = Some(Instant::now());
+            storage.start_cpu_time = thread_cpu_time();
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let Some(name) = ctx.metadata(id).map(|x| x.name()) else {
+            return err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
+        };
+        // the time since the first instance was entered is added once the last one exits
+        let elapsed = match &self.active {
+            Some(active) => {
+                let Ok(mut active) = active.lock() else {
+                    return err_msg!(ProfileError::MutexPoisoned);
+                };
+                match active.get_mut(name).filter(|x| x.count > 0) {
+                    Some(active) => {
+                        active.count -= 1;
+                        Some(if active.count == 0 {
+                            active.since.elapsed()
+                        } else {
+                            Duration::ZERO
+                        })
+                    }
+                    None => Some(Duration::ZERO),
+                }
+            }
+            None => None,
+        };
+        let Some(span) = ctx.span(id) else {
+            return err_msg!(ProfileError::SpanNotFound { context: "on_exit" });
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(storage) = extensions.get_mut::<SummaryMetadata>() else {
+            return err_msg!(ProfileError::StorageMissing { context: "on_exit" });
+        };
+        let Some(start_time) = storage.start_time.take() else {
+            return;
+        };
+        let depth = storage.depth;
+        let wall_time = start_time.elapsed();
+        let self_time = wall_time.saturating_sub(std::mem::take(&mut storage.children_time));
+        let cpu_time = storage
+            .start_cpu_time
+            .take()
+            .zip(thread_cpu_time())
+            .map(|(start, end)| end.saturating_sub(start));
+        drop(extensions);
+
+        if let Some(parent) = span.parent() {
+            if let Some(parent) = parent.extensions_mut().get_mut::<SummaryMetadata>() {
+                parent.children_time += wall_time;
+            }
+        }
+
+        let Ok(mut summary) = self.summary.lock() else {
+            return err_msg!(ProfileError::MutexPoisoned);
+        };
+        summary.record(name, wall_time, self_time, cpu_time, elapsed);
+        summary.max_depth = summary.max_depth.max(depth);
+    }
+}
+
+/// Statistics of all spans with the same name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SpanStats {
+    /// Number of times the span was entered and exited.
+    pub count: u64,
+    pub wall_time: Duration,
+    /// Wall time not spent in child spans.
+    pub self_time: Duration,
+    /// `None` if the platform doesn't support measuring the CPU time of a thread.
+    pub cpu_time: Option<Duration>,
+    /// Wall-clock time during which at least one span with this name was entered on any thread.
+    /// Unlike `wall_time`, parallel spans are only counted once. `None` unless the layer was
+    /// created with `Layer::with_cpu_share`.
+    pub elapsed: Option<Duration>,
+}
+
+impl SpanStats {
+    /// Ratio of CPU time to wall time. Close to 1 for CPU bound spans and close to 0 for spans
+    /// waiting on I/O or sleeping.
+    pub fn cpu_utilization(&self) -> Option<f64> {
+        let wall_time = self.wall_time.as_secs_f64();
+        self.cpu_time
+            .filter(|_| wall_time > 0.0)
+            .map(|cpu_time| cpu_time.as_secs_f64() / wall_time)
+    }
+
+    /// Ratio of CPU time summed over all threads to `elapsed`, i.e. the average number of cores
+    /// kept busy while a span with this name was running. Exceeds 1 for spans running in
+    /// parallel, unlike `cpu_utilization` which is per span.
+    pub fn cpu_share(&self) -> Option<f64> {
+        let elapsed = self.elapsed?.as_secs_f64();
+        self.cpu_time
+            .filter(|_| elapsed > 0.0)
+            .map(|cpu_time| cpu_time.as_secs_f64() / elapsed)
+    }
+}
+
+/// Statistics of all spans, keyed by span name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Summary {
+    pub spans: BTreeMap<String, SpanStats>,
+    /// The deepest nesting of spans which exited, 1 if only roots did. Unexpectedly deep
+    /// nesting usually means runaway recursion.
+    pub max_depth: usize,
+}
+
+impl Summary {
+    /// Returns the `n` span names with the most self time, in descending order.
+    pub fn top_self_time(&self, n: usize) -> Vec<(&str, &SpanStats)> {
+        let mut spans: Vec<_> = self
+            .spans
+            .iter()
+            .map(|(name, stats)| (name.as_str(), stats))
+            .collect();
+        spans.sort_by_key(|(_, x)| std::cmp::Reverse(x.self_time));
+        spans.truncate(n);
+        spans
+    }
+
+    /// Renders the statistics in the Prometheus text exposition format, e.g. for a metrics
+    /// endpoint:
+    /// ```text
+    /// # HELP span_duration_seconds Wall time of the spans by name.
+    /// # TYPE span_duration_seconds summary
+    /// span_duration_seconds_sum{name="compute"} 0.012
+    /// span_duration_seconds_count{name="compute"} 3
+    /// ```
+    /// The self time, and the CPU time where it is measured, follow as `span_self_seconds_total`
+    /// and `span_cpu_seconds_total` counters.
+    pub fn to_prometheus_text(&self) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        let spans = || {
+            self.spans
+                .iter()
+                .map(|(name, stats)| (prometheus_label(name), stats))
+        };
+        out.push_str("# HELP span_duration_seconds Wall time of the spans by name.\n");
+        out.push_str("# TYPE span_duration_seconds summary\n");
+        for (name, stats) in spans() {
+            let sum = stats.wall_time.as_secs_f64();
+            let _ = writeln!(out, "span_duration_seconds_sum{{name=\"{name}\"}} {sum}");
+            let _ = writeln!(
+                out,
+                "span_duration_seconds_count{{name=\"{name}\"}} {}",
+                stats.count
+            );
+        }
+        out.push_str(
+            "# HELP span_self_seconds_total Wall time of the spans not spent in child spans.\n",
+        );
+        out.push_str("# TYPE span_self_seconds_total counter\n");
+        for (name, stats) in spans() {
+            let self_time = stats.self_time.as_secs_f64();
+            let _ = writeln!(
+                out,
+                "span_self_seconds_total{{name=\"{name}\"}} {self_time}"
+            );
+        }
+        if self.spans.values().any(|stats| stats.cpu_time.is_some()) {
+            out.push_str("# HELP span_cpu_seconds_total CPU time of the spans by name.\n");
+            out.push_str("# TYPE span_cpu_seconds_total counter\n");
+            for (name, stats) in spans() {
+                if let Some(cpu_time) = stats.cpu_time {
+                    let cpu_time = cpu_time.as_secs_f64();
+                    let _ = writeln!(out, "span_cpu_seconds_total{{name=\"{name}\"}} {cpu_time}");
+                }
+            }
+        }
+        out
+    }
+
+    fn record(
+        &mut self,
+        name: &str,
+        wall_time: Duration,
+        self_time: Duration,
+        cpu_time: Option<Duration>,
+        elapsed: Option<Duration>,
+    ) {
+        let stats = self.spans.entry(name.into()).or_insert_with(|| SpanStats {
+            cpu_time: cpu_time.map(|_| Duration::ZERO),
+            elapsed: elapsed.map(|_| Duration::ZERO),
+            ..Default::default()
+        });
+        stats.count += 1;
+        stats.wall_time += wall_time;
+        stats.self_time += self_time;
+        stats.cpu_time = stats.cpu_time.zip(cpu_time).map(|(x, y)| x + y);
+        stats.elapsed = stats.elapsed.zip(elapsed).map(|(x, y)| x + y);
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        SummaryDisplay(self, None).fmt(f)
+    }
+}
+
+/// Escapes a label value of the Prometheus text format.
+fn prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Displays a summary with the given thousands separator.
+struct SummaryDisplay<'a>(&'a Summary, Option<char>);
+
+impl std::fmt::Display for SummaryDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let SummaryDisplay(summary, separator) = *self;
+        let width = summary
+            .spans
+            .keys()
+            .map(|x| x.chars().count())
+            .chain(["span".len()])
+            .max()
+            .unwrap_or_default();
+        let cpu_share = summary.spans.values().any(|x| x.elapsed.is_some());
+        write!(
+            f,
+            "{:<width$} {:>8} {:>14} {:>14} {:>10}",
+            "span", "calls", "wall time", "cpu time", "cpu %"
+        )?;
+        if cpu_share {
+            write!(f, " {:>10}", "cpu share")?;
+        }
+        writeln!(f)?;
+
+        let mut spans: Vec<_> = summary.spans.iter().collect();
+        spans.sort_by_key(|(_, x)| std::cmp::Reverse(x.wall_time));
+        for (name, stats) in spans {
+            let wall_time = format!("{:.2?}", stats.wall_time);
+            let cpu_time = stats.cpu_time.map_or("-".into(), |x| format!("{:.2?}", x));
+            let utilization = stats
+                .cpu_utilization()
+                .map_or("-".into(), |x| format!("{:.2}%", 100.0 * x));
+            write!(
+                f,
+                "{name:<width$} {:>8} {wall_time:>14} {cpu_time:>14} {utilization:>10}",
+                format_thousands(stats.count, separator)
+            )?;
+            if cpu_share {
+                let share = stats
+                    .cpu_share()
+                    .map_or("-".into(), |x| format!("{:.2}%", 100.0 * x));
+                write!(f, " {share:>10}")?;
+            }
+            writeln!(f)?;
+        }
+        if summary.max_depth > 0 {
+            writeln!(f, "deepest span stack: {}", summary.max_depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// Displays the spans with the most self time.
+struct TopSelfTime<'a>(&'a Summary, usize, Option<char>);
+
+impl std::fmt::Display for TopSelfTime<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let TopSelfTime(summary, n, separator) = *self;
+        let spans = summary.top_self_time(n);
+        let width = spans
+            .iter()
+            .map(|(name, _)| name.chars().count())
+            .chain(["span".len()])
+            .max()
+            .unwrap_or_default();
+        // the self time of all spans adds up to the time spent in root spans
+        let total: Duration = summary.spans.values().map(|x| x.self_time).sum();
+        writeln!(
+            f,
+            "{:<width$} {:>14} {:>10} {:>10}",
+            "span", "self time", "self %", "calls"
+        )?;
+        for (name, stats) in spans {
+            let percent = if total.is_zero() {
+                0.0
+            } else {
+                100.0 * stats.self_time.as_secs_f64() / total.as_secs_f64()
+            };
+            writeln!(
+                f,
+                "{name:<width$} {:>14} {:>10} {:>10}",
+                format!("{:.2?}", stats.self_time),
+                format!("{percent:.2}%"),
+                format_thousands(stats.count, separator)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn cpu_utilization() {
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::new()));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _scope = debug_span!("sleeping").entered();
+            std::thread::sleep(Duration::from_millis(50));
+        });
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _scope = debug_span!("spinning").entered();
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(50) {
+                std::hint::spin_loop();
+            }
+        });
+
+        let summary = dispatch.downcast_ref::<Layer>().unwrap().summary();
+        let sleeping = summary.spans["sleeping"].cpu_utilization().unwrap();
+        let spinning = summary.spans["spinning"].cpu_utilization().unwrap();
+        assert!(sleeping < 0.2, "{sleeping}");
+        assert!(spinning > 0.5, "{spinning}");
+        assert_eq!(summary.spans["sleeping"].count, 1);
+    }
+
+    #[test]
+    fn top_self_time() {
+        let layer = Layer::new().with_top_self_time(2);
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _a = debug_span!("a").entered();
+            std::thread::sleep(Duration::from_millis(20));
+            for _ in 0..2 {
+                let _b = debug_span!("b").entered();
+                std::thread::sleep(Duration::from_millis(15));
+                let _c = debug_span!("c").entered();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        let summary = dispatch.downcast_ref::<Layer>().unwrap().summary();
+        let top = summary.top_self_time(3);
+        let names: Vec<_> = top.iter().map(|(name, _)| *name).collect();
+        // self times of 30ms, 20ms and 10ms, while `a` has the longest wall time
+        assert_eq!(names, ["b", "a", "c"]);
+        let (a, c) = (&summary.spans["a"], &summary.spans["c"]);
+        assert!(a.wall_time >= Duration::from_millis(60));
+        assert!(a.self_time < Duration::from_millis(30), "{a:?}");
+        assert_eq!(c.self_time, c.wall_time);
+
+        let report = TopSelfTime(&summary, 2, None).to_string();
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.lines().nth(1).unwrap().starts_with("b "), "{report}");
+    }
+
+    #[test]
+    fn max_depth() {
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::new()));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _a = debug_span!("a").entered();
+            {
+                let _b = debug_span!("b").entered();
+                drop(debug_span!("c").entered());
+            }
+            // a sibling which doesn't nest deeper
+            drop(debug_span!("b").entered());
+        });
+
+        let summary = dispatch.downcast_ref::<Layer>().unwrap().summary();
+        assert_eq!(summary.max_depth, 3);
+        assert!(summary.to_string().ends_with("deepest span stack: 3\n"));
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let mut summary = Summary::default();
+        let ms = Duration::from_millis;
+        summary.record("compute", ms(30), ms(20), Some(ms(25)), None);
+        summary.record("compute", ms(10), ms(10), Some(ms(5)), None);
+        summary.record("load \"config\"\n", ms(5), ms(5), Some(ms(1)), None);
+
+        let text = summary.to_prometheus_text();
+        // the text exposition format without timestamps
+        let comment = regex::Regex::new(r"^# (HELP|TYPE) [a-zA-Z_:][a-zA-Z0-9_:]* .+$").unwrap();
+        let sample = regex::Regex::new(
+            r#"^[a-zA-Z_:][a-zA-Z0-9_:]*\{name="(?:[^"\\\n]|\\["\\n])*"\} [0-9]+(\.[0-9]+)?$"#,
+        )
+        .unwrap();
+        for line in text.lines() {
+            assert!(comment.is_match(line) || sample.is_match(line), "{line}");
+        }
+        assert!(text.ends_with('\n'));
+
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE span_duration_seconds summary"));
+        assert!(lines.contains(&r#"span_duration_seconds_sum{name="compute"} 0.04"#));
+        assert!(lines.contains(&r#"span_duration_seconds_count{name="compute"} 2"#));
+        assert!(lines.contains(&r#"span_self_seconds_total{name="compute"} 0.03"#));
+        assert!(lines.contains(&r#"span_cpu_seconds_total{name="compute"} 0.03"#));
+        assert!(lines.contains(&r#"span_duration_seconds_count{name="load \"config\"\n"} 1"#));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn cpu_share() {
+        let threads = 4;
+        let cores = std::thread::available_parallelism().map_or(1, |x| x.get());
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(Layer::with_cpu_share()));
+        let barrier = std::sync::Barrier::new(threads);
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        barrier.wait();
+                        let _scope = debug_span!("worker").entered();
+                        let start = thread_cpu_time().unwrap();
+                        while thread_cpu_time().unwrap() - start < Duration::from_millis(30) {
+                            std::hint::spin_loop();
+                        }
+                    });
+                });
+            }
+        });
+
+        let summary = dispatch.downcast_ref::<Layer>().unwrap().summary();
+        let worker = &summary.spans["worker"];
+        assert_eq!(worker.count, threads as u64);
+        let share = worker.cpu_share().unwrap();
+        // every core running a worker is busy the whole time, which is more than 100% when
+        // there are several
+        let parallel = threads.min(cores) as f64;
+        assert!(share > 0.8 * parallel, "{share}");
+        assert!(share < 1.2 * parallel, "{share}");
+        assert!(summary.to_string().contains("cpu share"));
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::fd::{FromRawFd, OwnedFd},
+    sync::Mutex,
+    thread::ThreadId,
+};
+
+use thread_local::ThreadLocal;
+use tracing::span;
+use tracing_subscriber::{layer, registry::LookupSpan};
+
+use crate::{
+    data::{insert_to_span_storage, with_span_storage, with_span_storage_mut},
+    err_msg, ProfileError,
+};
+
+/// Where tracefs lists the id of the tracepoint, depending on where it is mounted.
+const TRACEPOINT_IDS: [&str; 2] = [
+    "/sys/kernel/tracing/events/raw_syscalls/sys_enter/id",
+    "/sys/kernel/debug/tracing/events/raw_syscalls/sys_enter/id",
+];
+
+/// `PERF_TYPE_TRACEPOINT` of `linux/perf_event.h`.
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+
+/// `PERF_FLAG_FD_CLOEXEC` of `linux/perf_event.h`.
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+
+/// The first version of `struct perf_event_attr`, which every kernel accepts.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Counts the syscalls of the calling thread.
+trait SyscallCounter: Send {
+    fn read(&mut self) -> std::io::Result<u64>;
+}
+
+/// The `raw_syscalls:sys_enter` tracepoint, counted for the thread which opened it.
+struct Tracepoint {
+    file: File,
+}
+
+impl Tracepoint {
+    fn open() -> std::io::Result<Self> {
+        let id = TRACEPOINT_IDS
+            .iter()
+            .find_map(|path| std::fs::read_to_string(path).ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "the raw_syscalls:sys_enter tracepoint isn't available, is tracefs mounted?",
+                )
+            })?;
+        let attr = PerfEventAttr {
+            kind: PERF_TYPE_TRACEPOINT,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config: id
+                .trim()
+                .parse()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            ..Default::default()
+        };
+        // SAFETY: `attr` is a valid `perf_event_attr` of the size it declares, the event counts
+        // the calling thread on any CPU and isn't part of a group.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0,
+                -1,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: the file descriptor was just opened and isn't owned by anything else.
+        Ok(Self {
+            file: File::from(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+        })
+    }
+}
+
+impl SyscallCounter for Tracepoint {
+    fn read(&mut self) -> std::io::Result<u64> {
+        let mut count = [0u8; 8];
+        // a single `read`, which is the only syscall the spans count themselves
+        (&self.file).read_exact(&mut count)?;
+        Ok(u64::from_ne_bytes(count))
+    }
+}
+
+/// The counter of a thread. `ThreadLocal` hands the slot of an exited thread to the next new
+/// thread, whose syscalls the old counter doesn't count.
+struct ThreadCounter {
+    thread: ThreadId,
+    counter: Box<dyn SyscallCounter>,
+}
+
+/// The syscalls of a span, summed over its enters.
+#[derive(Debug, Default)]
+struct SpanSyscalls {
+    count: u64,
+    /// `None` if the counter couldn't be read when the span was entered
+    last_enter: Option<u64>,
+    /// whether the counter failed during one of the enters, so the count is incomplete
+    failed: bool,
+}
+
+/// SyscallLayer (internally called layer::syscalls)
+/// This Layer counts the syscalls made while each span is entered with the
+/// `raw_syscalls:sys_enter` tracepoint of `perf` and prints them like `PrintPerfCountersLayer`
+/// when the span closes, to find the spans doing a lot of I/O:
+/// ```bash
+/// read config:
+///     syscalls: 12
+/// root span:
+///     syscalls: 1204
+/// ```
+///
+/// Linux only. The tracepoint is listed by tracefs, which must be mounted and readable, usually
+/// at `/sys/kernel/tracing`. Every thread which enters a span opens its own counter, so spans only
+/// count the syscalls of the thread they are entered on.
+pub struct Layer {
+    counters: ThreadLocal<Mutex<ThreadCounter>>,
+    open: fn() -> std::io::Result<Box<dyn SyscallCounter>>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Layer {
+    /// Prints the counts to stdout. Fails if the tracepoint can't be opened, e.g. because
+    /// tracefs isn't mounted.
+    pub fn new() -> std::io::Result<Self> {
+        Self::with_writer(std::io::stdout())
+    }
+
+    /// Writes the counts to `writer` when the spans close.
+    pub fn with_writer<W: Write + Send + 'static>(writer: W) -> std::io::Result<Self> {
+        Self::open(
+            || Ok(Box::new(Tracepoint::open()?) as Box<dyn SyscallCounter>),
+            Box::new(writer),
+        )
+    }
+
+    fn open(
+        open: fn() -> std::io::Result<Box<dyn SyscallCounter>>,
+        out: Box<dyn Write + Send>,
+    ) -> std::io::Result<Self> {
+        let layer = Self {
+            counters: ThreadLocal::new(),
+            open,
+            out: Mutex::new(out),
+        };
+        // open the counter of the calling thread right away, so missing permissions are reported
+        // here
+        layer.read()?;
+        Ok(layer)
+    }
+
+    /// Reads the counter of the calling thread, opening it on the first call.
+    fn read(&self) -> std::io::Result<u64> {
+        let thread = std::thread::current().id();
+        let counter = self.counters.get_or_try(|| {
+            (self.open)().map(|counter| Mutex::new(ThreadCounter { thread, counter }))
+        })?;
+        let Ok(mut counter) = counter.lock() else {
+            return Err(std::io::Error::other(ProfileError::MutexPoisoned));
+        };
+        if counter.thread != thread {
+            *counter = ThreadCounter {
+                thread,
+                counter: (self.open)()?,
+            };
+        }
+        counter.counter.read()
+    }
+
+    /// Reads the counter of the calling thread, reporting failures instead of panicking in a
+    /// tracing hook: `open` only checked the constructing thread.
+    fn read_or_report(&self) -> Option<u64> {
+        self.read()
+            .map_err(|e| err_msg!(ProfileError::CounterFailed(e.kind())))
+            .ok()
+    }
+}
+
+impl crate::Flush for Layer {}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: layer::Context<'_, S>,
+    ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        insert_to_span_storage(id, ctx, SpanSyscalls::default());
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let count = self.read_or_report();
+        with_span_storage_mut::<SpanSyscalls, _>(id, ctx, |span| {
+            span.last_enter = count;
+            span.failed |= count.is_none();
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        let count = self.read_or_report();
+        with_span_storage_mut::<SpanSyscalls, _>(id, ctx, |span| {
+            match (span.last_enter.take(), count) {
+                (Some(last_enter), Some(count)) => span.count += count.saturating_sub(last_enter),
+                _ => span.failed = true,
+            }
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: layer::Context<'_, S>) {
+        if crate::pause::skips(&id, &ctx) {
+            return;
+        }
+        let Some(name) = ctx.span(&id).map(|span| span.name()) else {
+            return err_msg!(ProfileError::SpanNotFound {
+                context: "on_close"
+            });
+        };
+        with_span_storage::<SpanSyscalls, _>(&id, ctx, |span| {
+            if span.failed {
+                return;
+            }
+            let Ok(mut out) = self.out.lock() else {
+                return err_msg!(ProfileError::MutexPoisoned);
+            };
+            if let Err(e) = writeln!(out, "{name}:\n    syscalls: {}", span.count) {
+                err_msg!(ProfileError::WriteFailed(e.kind()));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::layers::shared_buffer::SharedBuffer;
+
+    /// Counts one syscall per read.
+    struct EveryRead(u64);
+
+    impl SyscallCounter for EveryRead {
+        fn read(&mut self) -> std::io::Result<u64> {
+            self.0 += 1;
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn counts_per_span() {
+        let buffer = SharedBuffer::default();
+        let layer = Layer::open(
+            || Ok(Box::new(EveryRead(0)) as Box<dyn SyscallCounter>),
+            Box::new(buffer.clone()),
+        )
+        .unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _root = tracing::info_span!("root span").entered();
+            for _ in 0..3 {
+                drop(tracing::info_span!("child span").entered());
+            }
+        });
+
+        let output = buffer.output();
+        // the reads of the children's enters and exits count within the root
+        assert_eq!(
+            output,
+            "child span:\n    syscalls: 1\n".repeat(3) + "root span:\n    syscalls: 7\n"
+        );
+    }
+
+    /// Opens counters on named threads only, like the test threads.
+    fn open_on_named_threads() -> std::io::Result<Box<dyn SyscallCounter>> {
+        match std::thread::current().name() {
+            Some(_) => Ok(Box::new(EveryRead(0))),
+            None => Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied)),
+        }
+    }
+
+    #[test]
+    fn unavailable_counter_skips_span() {
+        let errors = crate::error::collected_errors();
+        let buffer = SharedBuffer::default();
+        let layer = Layer::open(open_on_named_threads, Box::new(buffer.clone())).unwrap();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        std::thread::spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                drop(tracing::info_span!("unnamed thread span").entered());
+            });
+        })
+        .join()
+        .unwrap();
+
+        assert!(buffer.0.lock().unwrap().is_empty());
+        assert!(errors
+            .lock()
+            .unwrap()
+            .contains(&ProfileError::CounterFailed(
+                std::io::ErrorKind::PermissionDenied
+            )));
+    }
+
+    #[test]
+    fn counts_syscalls() {
+        let buffer = SharedBuffer::default();
+        let layer = match Layer::with_writer(buffer.clone()) {
+            Ok(layer) => layer,
+            // tracefs isn't mounted or perf_event_open isn't permitted, e.g. in containers
+            Err(e) => return eprintln!("skipping, the syscall tracepoint can't be opened: {e}"),
+        };
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(tracing::info_span!("empty").entered());
+            let _span = tracing::info_span!("io").entered();
+            let path = std::env::temp_dir().join("tracing_profile_syscalls.txt");
+            for _ in 0..10 {
+                std::fs::write(&path, "data").unwrap();
+            }
+        });
+
+        let output = buffer.output();
+        let count = |name: &str| -> u64 {
+            output
+                .split_once(&format!("{name}:\n    syscalls: "))
+                .and_then(|(_, rest)| rest.lines().next()?.parse().ok())
+                .unwrap_or_else(|| panic!("{output}"))
+        };
+        // only the read of the exit is counted
+        assert!(count("empty") <= 1, "{output}");
+        // every write opens, writes and closes the file
+        assert!(count("io") >= 30, "{output}");
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::{
+    io::{BufWriter, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use super::callback;
+use crate::{Flush, FlushHandle, ProfileError};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// TcpLayer (internally called layer::tcp)
+/// This Layer streams every closed span as a line of JSON to a TCP server, e.g. a collector
+/// aggregating the profiles of several processes:
+/// ```bash
+/// {"id":2,"parent_id":1,"name":"child span1","duration_ns":3194,"depth":2,"fields":{"field1":"value1"}}
+/// {"id":1,"parent_id":null,"name":"root span","duration_ns":79099,"depth":1,"fields":{}}
+/// ```
+///
+/// The records are sent by a background thread. When the connection fails the thread reconnects,
+/// waiting twice as long after every failed attempt, up to 30 seconds. Spans closed while
+/// disconnected are dropped rather than queued, so an unreachable server never blocks or grows the
+/// profiled program.
+pub struct Layer {
+    tx: mpsc::Sender<Message>,
+    inner: callback::Layer,
+}
+
+/// Messages to the background sender thread.
+enum Message {
+    Record(String),
+    /// Acknowledged once all records sent before have been written to the socket.
+    Flush(mpsc::Sender<()>),
+}
+
+impl Layer {
+    /// Resolves `addr` and starts connecting to it in the background. Fails only if the address
+    /// can't be resolved, connection errors are reported as `ProfileError::WriteFailed`.
+    pub fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no addresses to connect to",
+            ));
+        }
+
+        let (tx, rx) = mpsc::channel::<Message>();
+        std::thread::spawn(move || {
+            let mut connection = Connection::new(addrs);
+            connection.connected();
+            while let Ok(msg) = rx.recv() {
+                connection.handle(msg);
+                // batch the records which queued up in the meantime into a single write
+                while let Ok(msg) = rx.try_recv() {
+                    connection.handle(msg);
+                }
+                connection.flush();
+            }
+        });
+
+        let inner = {
+            let tx = tx.clone();
+            callback::Layer::new(move |span| {
+                let _ = tx.send(Message::Record(span.to_json()));
+            })
+        };
+        Ok(Self { tx, inner })
+    }
+}
+
+struct Connection {
+    addrs: Vec<SocketAddr>,
+    stream: Option<BufWriter<TcpStream>>,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl Connection {
+    fn new(addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            addrs,
+            stream: None,
+            backoff: MIN_BACKOFF,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Returns the stream, reconnecting if the backoff since the last failed attempt has elapsed.
+    fn connected(&mut self) -> Option<&mut BufWriter<TcpStream>> {
+        if self.stream.is_none() {
+            if Instant::now() < self.next_attempt {
+                return None;
+            }
+            match TcpStream::connect(&self.addrs[..]) {
+                Ok(stream) => {
+                    self.stream = Some(BufWriter::new(stream));
+                    self.backoff = MIN_BACKOFF;
+                }
+                Err(e) => {
+                    crate::error::warn(&ProfileError::WriteFailed(e.kind()));
+                    self.next_attempt = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    return None;
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+
+    fn handle(&mut self, msg: Message) {
+        match msg {
+            Message::Record(mut line) => {
+                line.push('\n');
+                // a broken connection is usually only noticed when writing, so the record is
+                // retried once on a fresh connection
+                for _ in 0..2 {
+                    let Some(stream) = self.connected() else {
+                        return;
+                    };
+                    match stream.write_all(line.as_bytes()) {
+                        Ok(()) => return,
+                        Err(e) => self.disconnect(e),
+                    }
+                }
+            }
+            Message::Flush(ack) => {
+                self.flush();
+                let _ = ack.send(());
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(stream) = &mut self.stream {
+            if let Err(e) = stream.flush() {
+                self.disconnect(e);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, e: std::io::Error) {
+        crate::error::warn(&ProfileError::WriteFailed(e.kind()));
+        // the buffered records are lost with the connection
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.into_parts();
+        }
+    }
+}
+
+impl Flush for Layer {
+    /// Blocks until every record of the spans which closed before has been written to the socket.
+    fn flush_handle(&self) -> Option<FlushHandle> {
+        let tx = self.tx.clone();
+        Some(FlushHandle::new(move || {
+            let (ack, done) = mpsc::channel();
+            if tx.send(Message::Flush(ack)).is_ok() {
+                let _ = done.recv();
+            }
+        }))
+    }
+}
+
+callback::forward_to_callback!(Layer);
+
+#[cfg(test)]
+mod tests {
+    use std::{io::BufRead, net::TcpListener};
+
+    use tracing::debug_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn records_arrive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let layer = Layer::connect(listener.local_addr().unwrap()).unwrap();
+        let flush = layer.flush_handle().unwrap();
+
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let root = debug_span!("root").entered();
+            drop(debug_span!("child", quote = "say \"hi\"").entered());
+            drop(root);
+        });
+        flush.flush();
+
+        let (stream, _) = listener.accept().unwrap();
+        let lines: Vec<_> = std::io::BufReader::new(stream)
+            .lines()
+            .take(2)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let (child, root) = (&lines[0], &lines[1]);
+        // span ids aren't predictable, the child has to point at whatever id the root got
+        let root_id = root["{\"id\":".len()..].split(',').next().unwrap();
+        assert!(root.contains(",\"parent_id\":null,\"name\":\"root\",\"duration_ns\":"));
+        assert!(root.ends_with(",\"depth\":1,\"fields\":{}}"));
+        assert!(child.contains(&format!(",\"parent_id\":{root_id},\"name\":\"child\",")));
+        assert!(child.ends_with(",\"depth\":2,\"fields\":{\"quote\":\"say \\\"hi\\\"\"}}"));
+    }
+}
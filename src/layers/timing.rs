@@ -0,0 +1,177 @@
+// Copyright 2024 Ulvetanna Inc.
+use tracing::span;
+
+use crate::data::{insert_to_span_storage, with_span_storage_mut, ClockSource, SpanTimings};
+
+/// TimingLayer (internally called layer::timing)
+/// This Layer records the busy and idle time of every span as `SpanTimings` in the span's
+/// extensions, so the other layers of this crate and user layers share a single measurement.
+///
+/// Layers only see the timings of the current enter/exit if `TimingLayer` is added before them,
+/// e.g. `registry().with(TimingLayer::default()).with(PrintTreeLayer::default())`.
+///
+/// The spans are measured with `Instant` unless another `ClockSource` is given to `with_clock`.
+#[derive(Debug, Default)]
+pub struct Layer {
+    clock: ClockSource,
+}
+
+impl Layer {
+    pub fn with_clock(clock: ClockSource) -> Self {
+        Self { clock }
+    }
+}
+
+impl crate::Flush for Layer {}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: tracing::Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if crate::pause::skip_new_span(id, &ctx) {
+            return;
+        }
+        insert_to_span_storage(id, ctx, SpanTimings::new(&self.clock));
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |timings: &mut SpanTimings| {
+            timings.on_enter(&self.clock)
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(id, ctx, |timings: &mut SpanTimings| {
+            timings.on_exit(&self.clock)
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if crate::pause::skips(&id, &ctx) {
+            return;
+        }
+        with_span_storage_mut(&id, ctx, |timings: &mut SpanTimings| {
+            timings.on_close(&self.clock)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, Waker},
+        time::Duration,
+    };
+
+    use tracing::Instrument;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    /// Sleeps on every poll and returns `Pending` until polled `polls` times.
+    struct Polled {
+        polls: usize,
+    }
+
+    impl Future for Polled {
+        type Output = ();
+
+        fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            std::thread::sleep(Duration::from_millis(5));
+            self.polls -= 1;
+            if self.polls == 0 {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Reads the timings when the span closes.
+    struct Capture(Arc<Mutex<Option<SpanTimings>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for Capture
+    where
+        S: tracing::Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            *self.0.lock().unwrap() = span.extensions().get::<SpanTimings>().copied();
+        }
+    }
+
+    #[test]
+    fn async_polled_span() {
+        let timings = Arc::new(Mutex::new(None));
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::default())
+            .with(Capture(timings.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let mut future = pin!(Polled { polls: 3 }.instrument(tracing::debug_span!("task")));
+            let mut cx = Context::from_waker(Waker::noop());
+            while future.as_mut().poll(&mut cx).is_pending() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        let timings = timings.lock().unwrap().unwrap();
+        assert!(timings.busy >= Duration::from_millis(15), "{timings:?}");
+        assert!(timings.idle >= Duration::from_millis(10), "{timings:?}");
+    }
+
+    #[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+    #[test]
+    fn tsc_cycles() {
+        use crate::data::Tsc;
+
+        let mut last = Tsc::now();
+        for _ in 0..1000 {
+            let now = Tsc::now();
+            assert!(now >= last);
+            last = now;
+        }
+
+        let clock = ClockSource::tsc();
+        let ClockSource::Tsc(tsc) = clock else {
+            unreachable!()
+        };
+        let timings = Arc::new(Mutex::new(None));
+        let subscriber = tracing_subscriber::registry()
+            .with(Layer::with_clock(clock))
+            .with(Capture(timings.clone()));
+        let mut wall = Duration::ZERO;
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::debug_span!("sleep");
+            for _ in 0..2 {
+                let start = std::time::Instant::now();
+                span.in_scope(|| std::thread::sleep(Duration::from_millis(10)));
+                wall += start.elapsed();
+            }
+        });
+
+        let timings = timings.lock().unwrap().unwrap();
+        // the cycles are proportional to the time the span was entered
+        let expected = wall.as_nanos() as f64 * tsc.cycles_per_ns();
+        let ratio = timings.busy_cycles as f64 / expected;
+        assert!((0.9..1.1).contains(&ratio), "{ratio} {timings:?}");
+        assert!(timings.busy >= wall * 9 / 10 && timings.busy <= wall * 11 / 10);
+        assert!(timings.idle_cycles > 0);
+    }
+}
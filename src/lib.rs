@@ -5,9 +5,18 @@
 //! a span took to execute, along with any user supplied metadata and
 //! information necessary to construct a call graph from the resulting logs.
 //!
-//! Four `Layer` implementations are provided:
+//! The following `Layer` implementations are provided:
 //!     `CsvLayer`: logs data in CSV format
 //!     `PrintTreeLayer`: prints a call graph
+//!     `FlamechartLayer`: writes timestamped folded stacks for time-ordered flamecharts
+//!     `PerfScriptLayer`: writes samples in the text format of `perf script` for existing `perf` tooling
+//!     `HistogramLayer`: prints a histogram of the durations of a single span
+//!     `CallbackLayer`: calls a user supplied function for every closed span
+//!     `SummaryLayer`: prints aggregated statistics for each span name
+//!     `RingBufferLayer`: keeps recent spans in memory and dumps them on demand, e.g. on panic
+//!     `TcpLayer`: streams closed spans as newline-delimited JSON to a TCP server
+//!     `BudgetLayer`: fails when a span exceeds its latency budget
+//!     `TimingLayer`: records busy and idle time shared by the other layers
 //!     `PrintPerfCountersLayer`: prints aggregated performance counters for each span.
 //!     `PerfettoLayer`: Connects to a system-wide perfetto logging service which will create a fused trace. Be warned - the program will block until a connection is established with perfetto's traced service.
 //!
@@ -62,32 +71,90 @@
 //! }
 //! ```
 //!
+//! Layers installed as the global default are never dropped. Use `ProfileBuilder::init` to get a
+//! `ProfileGuard` which flushes the CSV file and prints the summaries when it is dropped.
+//!
 //! Note that if `#[instrument]` is used, `skip_all` is recommended. Omitting this will result in
 //! all the function arguments being included as fields.
 //!
 //! # Features
 //! The `panic` feature will turn eprintln! into panic!, causing the program to halt on errors.
+//! Errors can be handled programmatically as `ProfileError`s by installing a callback with
+//! `set_error_callback`.
+//!
+//! The `tsc` feature adds `ClockSource::Tsc` on x86_64, which measures spans in CPU cycles with
+//! `rdtsc`.
+//!
+//! The `tokio` feature adds `profile_task`, which spawns a future inside a span named after the
+//! task.
 
 mod data;
+mod drop_timer;
+mod error;
+mod guard;
+mod iter;
 mod layers;
-
+mod pause;
+#[cfg(feature = "tokio")]
+mod task;
+
+#[cfg(feature = "binary")]
+pub use data::BinaryReader;
+#[cfg(all(feature = "tsc", target_arch = "x86_64"))]
+pub use data::Tsc;
+pub use data::{
+    diff_trees, ClockSource, ClosedSpan, CsvIndex, CsvReader, CsvRow, CsvTreeNode, CsvTrees,
+    FieldValue, LockContention, Regression, ResourceUsage, SpanTimings, TreeNode,
+};
+pub use drop_timer::DropTimer;
+pub use error::{set_error_callback, ProfileError};
+pub use guard::{Flush, FlushHandle, ProfileBuilder, ProfileGuard};
+pub use iter::{profile_iter, ProfileIter};
+#[cfg(feature = "parquet")]
+pub use layers::parquet::Layer as ParquetLayer;
 #[cfg(feature = "perf_counters")]
-pub use layers::print_perf_counters::Layer as PrintPerfCountersLayer;
+pub use layers::print_perf_counters::{
+    Config as PrintPerfCountersConfig, DerivedMetric, Layer as PrintPerfCountersLayer,
+};
 pub use layers::{
-    csv::Layer as CsvLayer,
-    graph::{Config as PrintTreeConfig, Layer as PrintTreeLayer},
+    budget::{BudgetAction, BudgetViolation, Layer as BudgetLayer},
+    callback::Layer as CallbackLayer,
+    csv::{
+        Config as CsvConfig, FieldSerializer, FlushGuard as CsvFlushGuard, HeaderCase,
+        Layer as CsvLayer, NeverEntered, RowFormat,
+    },
+    flamechart::Layer as FlamechartLayer,
+    graph::{
+        ChildOverlap, ColorChoice, Config as PrintTreeConfig, FieldAggregation,
+        Layer as PrintTreeLayer, NameNormalizer, Sink as PrintTreeSink, SpanKind, TimeMode,
+        TreeFormat, UnaccountedPosition, TRACING_TARGET,
+    },
+    histogram::Layer as HistogramLayer,
+    perf_script::Layer as PerfScriptLayer,
+    ring_buffer::Layer as RingBufferLayer,
+    summary::{Layer as SummaryLayer, SpanStats, Summary},
+    tcp::Layer as TcpLayer,
+    timing::Layer as TimingLayer,
 };
+pub use pause::{is_paused, pause, resume};
+
+#[cfg(target_os = "linux")]
+pub use layers::syscalls::Layer as SyscallLayer;
+#[cfg(feature = "tokio")]
+pub use task::profile_task;
 
 #[cfg(feature = "perfetto")]
-pub use layers::perfetto::Layer as PerfettoLayer;
+pub use layers::perfetto::{
+    Layer as PerfettoLayer, SpanFilter as PerfettoSpanFilter,
+    ThroughputUnit as PerfettoThroughputUnit,
+};
 #[cfg(feature = "perfetto")]
 pub use perfetto_sys::Backend as PerfettoBackend;
 
 // use this instead of eprintln!
 macro_rules! err_msg {
-    ($($arg:tt)*) => {{
-        eprintln!($($arg)*);
-        assert!(cfg!(not(feature = "panic")))
+    ($error:expr) => {{
+        $crate::error::report($error)
     }};
 }
 
@@ -0,0 +1,114 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::{span::Id, Event, Subscriber};
+use tracing_subscriber::{
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+};
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// whether spans need to be checked at all
+static EVER_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Stops profiling until `resume` is called, e.g. to leave the setup of a benchmark out of the
+/// profile without restructuring its spans.
+///
+/// The layers of this crate leave out the spans created while profiling is paused, the spans
+/// created within them and their events, even after profiling resumed. Other layers of the
+/// subscriber still record them. Spans created before keep being measured, including the time
+/// they are entered while paused.
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+///
+/// use tracing_profile::CallbackLayer;
+/// use tracing_subscriber::prelude::*;
+///
+/// let closed = Arc::new(Mutex::new(vec![]));
+/// let collect = closed.clone();
+/// let subscriber = tracing_subscriber::registry()
+///     .with(CallbackLayer::new(move |span| collect.lock().unwrap().push(span.name)));
+/// tracing::subscriber::with_default(subscriber, || {
+///     let _root = tracing::info_span!("benchmark").entered();
+///     tracing_profile::pause();
+///     let setup = tracing::info_span!("setup").entered();
+///     // other layers still record the span
+///     assert!(!setup.is_disabled());
+///     tracing_profile::resume();
+///     drop(tracing::info_span!("download").entered());
+///     drop(setup);
+///     drop(tracing::info_span!("measured").entered());
+/// });
+/// assert_eq!(*closed.lock().unwrap(), ["measured", "benchmark"]);
+/// ```
+pub fn pause() {
+    EVER_PAUSED.store(true, Ordering::Relaxed);
+    PAUSED.store(true, Ordering::Relaxed);
+}
+
+/// Resumes profiling after `pause`.
+pub fn resume() {
+    PAUSED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Whether the layers of this crate leave out a span. The first of them to see the span decides
+/// for all of them, so they agree even if profiling is paused or resumed meanwhile.
+#[derive(Debug, Clone, Copy)]
+struct Skipped(bool);
+
+/// Whether the layers of this crate leave out the new span, because profiling is paused or its
+/// parent was left out.
+pub(crate) fn skip_new_span<S>(id: &Id, ctx: &Context<'_, S>) -> bool
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    // spans are only marked once profiling was paused
+    if !EVER_PAUSED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let Some(span) = ctx.span(id) else {
+        return false;
+    };
+    if let Some(Skipped(skipped)) = span.extensions().get::<Skipped>() {
+        return *skipped;
+    }
+    let skipped = is_paused() || span.parent().is_some_and(|parent| skipped(&parent));
+    let mut extensions = span.extensions_mut();
+    if extensions.get_mut::<Skipped>().is_none() {
+        extensions.insert(Skipped(skipped));
+    }
+    skipped
+}
+
+/// Whether the layers of this crate left out the span.
+pub(crate) fn skipped<S>(span: &SpanRef<'_, S>) -> bool
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    EVER_PAUSED.load(Ordering::Relaxed)
+        && span
+            .extensions()
+            .get::<Skipped>()
+            .is_some_and(|Skipped(skipped)| *skipped)
+}
+
+/// Whether the layers of this crate left out the span with the given id.
+pub(crate) fn skips<S>(id: &Id, ctx: &Context<'_, S>) -> bool
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    EVER_PAUSED.load(Ordering::Relaxed) && ctx.span(id).is_some_and(|span| skipped(&span))
+}
+
+/// Whether the layers of this crate leave out the event, because they left out its span.
+pub(crate) fn skips_event<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> bool
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    EVER_PAUSED.load(Ordering::Relaxed) && ctx.event_span(event).is_some_and(|span| skipped(&span))
+}
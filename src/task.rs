@@ -0,0 +1,105 @@
+// Copyright 2024 Ulvetanna Inc.
+use std::future::Future;
+
+use tracing::Instrument;
+
+/// Spawns `future` on the current Tokio runtime inside an info span named `task` with a `task`
+/// field set to `name`, which tells the tasks apart. The spans of all tasks share the name
+/// `task`, so `PrintTreeLayer` merges sibling tasks into one node listing their names, unless
+/// it is configured with `FieldAggregation::Split`.
+///
+/// The span is entered on every poll, so with `TimingLayer` installed its busy time is the time
+/// spent polling the task and its idle time the time spent waiting to be woken.
+///
+/// ```
+/// # let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// # runtime.block_on(async {
+/// let handle = tracing_profile::profile_task("fetch", async { 42 });
+/// assert_eq!(handle.await.unwrap(), 42);
+/// # });
+/// ```
+pub fn profile_task<F>(name: &str, future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.instrument(tracing::info_span!("task", task = name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use tracing_subscriber::prelude::*;
+
+    use tracing::span;
+
+    use super::*;
+    use crate::{CallbackLayer, ClosedSpan, SpanTimings, TimingLayer};
+
+    /// Collects the timings of the spans by id when they close.
+    struct Timings(Arc<Mutex<HashMap<u64, SpanTimings>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for Timings
+    where
+        S: tracing::Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let timings = *span.extensions().get::<SpanTimings>().unwrap();
+            self.0.lock().unwrap().insert(id.into_u64(), timings);
+        }
+    }
+
+    async fn work() {
+        for _ in 0..2 {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(5) {
+                std::hint::spin_loop();
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn named_tasks() {
+        let closed = Arc::new(Mutex::new(Vec::<ClosedSpan>::new()));
+        let collect = closed.clone();
+        let timings = Arc::new(Mutex::new(HashMap::new()));
+        let subscriber = tracing_subscriber::registry()
+            .with(TimingLayer::default())
+            .with(CallbackLayer::new(move |span| {
+                collect.lock().unwrap().push(span.clone())
+            }))
+            .with(Timings(timings.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let first = profile_task("first", work());
+                let second = profile_task("second", work());
+                first.await.unwrap();
+                second.await.unwrap();
+            });
+        });
+
+        let mut closed = closed.lock().unwrap().clone();
+        closed.sort_by(|a, b| a.fields["task"].cmp(&b.fields["task"]));
+        assert_eq!(closed.len(), 2);
+        for (span, name) in closed.iter().zip(["first", "second"]) {
+            assert_eq!(span.name, "task");
+            assert_eq!(span.fields["task"], name);
+            // the span is only entered while the task is being polled
+            assert!(span.duration >= Duration::from_millis(10), "{span:?}");
+            let timings = timings.lock().unwrap()[&span.id];
+            assert!(timings.busy >= Duration::from_millis(10), "{timings:?}");
+            // the other task was polled while this one yielded
+            assert!(!timings.idle.is_zero(), "{timings:?}");
+        }
+    }
+}